@@ -0,0 +1,108 @@
+//! Mining the shell history and smartshell's own history for long commands that
+//! are typed over and over, as candidates for an alias or function
+
+use crate::history;
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+
+/// Commands shorter than this aren't worth an alias
+const MIN_LENGTH: usize = 20;
+
+/// A command must have been run this many times
+const MIN_COUNT: usize = 3;
+
+/// A command typed again and again
+#[derive(Debug, Clone)]
+pub struct Frequent {
+    pub command: String,
+    pub count: usize,
+}
+
+impl Frequent {
+    /// Characters that an alias of a few letters would save in total
+    fn savings(&self) -> usize {
+        self.count * self.command.chars().count().saturating_sub(4)
+    }
+}
+
+/// $HISTFILE, or ~/.zsh_history
+fn history_file() -> Option<PathBuf> {
+    env::var_os("HISTFILE")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".zsh_history")))
+}
+
+/// Undo zsh's metafication of history bytes (0x83 followed by the byte xor 32)
+fn unmetafy(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut iter = bytes.iter();
+    while let Some(&b) = iter.next() {
+        if b == 0x83 {
+            if let Some(&next) = iter.next() {
+                out.push(next ^ 32);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+/// The commands in a zsh history file, in plain or EXTENDED_HISTORY format
+/// (`: 1700000000:0;command`), with continuation lines joined
+fn shell_history() -> Vec<String> {
+    let Some(bytes) = history_file().and_then(|p| std::fs::read(p).ok()) else {
+        return Vec::new();
+    };
+    let text = String::from_utf8_lossy(&unmetafy(&bytes)).into_owned();
+    let extended = regex::Regex::new(r"^: \d+:\d+;").expect("valid history pattern");
+    let mut commands = Vec::new();
+    let mut current = String::new();
+    for line in text.lines() {
+        let line = extended.replace(line, "");
+        match line.strip_suffix('\\') {
+            Some(start) => {
+                current.push_str(start);
+                current.push('\n');
+            }
+            None => {
+                current.push_str(&line);
+                commands.push(std::mem::take(&mut current));
+            }
+        }
+    }
+    commands
+}
+
+/// The most frequently repeated long commands from the shell history and the
+/// suggestions run through smartshell, the ones an alias would save the most
+/// typing on first, at most `limit`
+pub fn frequent_commands(limit: usize) -> Vec<Frequent> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let accepted = history::open()
+        .and_then(|conn| history::since(&conn, None))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|e| e.accepted_at.is_some())
+        .map(|e| e.ran.unwrap_or(e.result));
+    for command in shell_history().into_iter().chain(accepted) {
+        let command = command.split_whitespace().collect::<Vec<_>>().join(" ");
+        if command.chars().count() >= MIN_LENGTH {
+            *counts.entry(command).or_default() += 1;
+        }
+    }
+    let mut frequent: Vec<_> = counts
+        .into_iter()
+        .filter(|(_, count)| *count >= MIN_COUNT)
+        .map(|(command, count)| Frequent { command, count })
+        .collect();
+    frequent.sort_by(|a, b| {
+        b.savings()
+            .cmp(&a.savings())
+            .then_with(|| a.command.cmp(&b.command))
+    });
+    frequent.truncate(limit);
+    frequent
+}
@@ -0,0 +1,193 @@
+//! Hash-chained audit log (`SMSH_AUDIT_LOG`)
+
+use crate::provider::Provider;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const AUDIT_GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    use sha2::Digest;
+    sha2::Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub(crate) fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| {
+            Command::new("hostname")
+                .output()
+                .ok()
+                .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+        })
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+pub fn audit_path() -> Option<PathBuf> {
+    env::var_os("SMSH_AUDIT_LOG")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Hash of the last entry in the audit log, read from the tail of the file
+fn last_audit_hash(file: &mut File) -> String {
+    let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let _ = file.seek(SeekFrom::Start(len.saturating_sub(64 * 1024)));
+    let mut tail = Vec::new();
+    let _ = file.read_to_end(&mut tail);
+    String::from_utf8_lossy(&tail)
+        .lines()
+        .rev()
+        .find_map(|l| serde_json::from_str::<serde_json::Value>(l).ok())
+        .and_then(|v| v["hash"].as_str().map(String::from))
+        .unwrap_or_else(|| AUDIT_GENESIS.to_string())
+}
+
+/// Append a hash-chained record to the audit log when `SMSH_AUDIT_LOG` is set.
+/// Each entry's hash covers the previous hash and the entry itself, so editing
+/// or removing a line breaks verification of everything after it. The file is
+/// locked from reading the last hash to appending, so concurrent shells (and
+/// `--batch` or `serve`) extend one chain instead of forking it. `provider` is
+/// the one that answered, or was asked.
+pub fn audit_entry(
+    cmd: &str,
+    prompt: &str,
+    result: &str,
+    decision: &str,
+    provider: Option<Provider>,
+) {
+    let Some(path) = audit_path() else {
+        return;
+    };
+    let Ok(mut file) = OpenOptions::new()
+        .read(true)
+        .create(true)
+        .append(true)
+        .open(&path)
+    else {
+        eprintln!("Cannot write audit log {}", path.display());
+        return;
+    };
+    if file.lock().is_err() {
+        eprintln!("Cannot lock audit log {}", path.display());
+        return;
+    }
+    let mut entry = serde_json::json!({
+        "ts": chrono::Local::now().to_rfc3339(),
+        "user": env::var("USER").or_else(|_| env::var("LOGNAME")).unwrap_or_default(),
+        "host": hostname(),
+        "provider": provider.map(Provider::as_str),
+        "subcommand": cmd,
+        "prompt": prompt,
+        "result": result,
+        "decision": decision,
+        "prev": last_audit_hash(&mut file),
+    });
+    entry["hash"] = serde_json::Value::String(sha256_hex(entry.to_string().as_bytes()));
+    let _ = writeln!(file, "{}", entry);
+}
+
+/// Verify the audit chain, returning the number of valid entries and the last
+/// entry's hash, or the first broken line. Entries cut off the end leave a valid
+/// shorter chain; only comparing the last hash with one noted earlier shows that.
+pub fn verify_audit(path: &Path) -> Result<(usize, String), String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    verify_chain(&text)
+}
+
+/// Verify the chain of audit log entries in `text`, one JSON object per line
+fn verify_chain(text: &str) -> Result<(usize, String), String> {
+    let mut prev = AUDIT_GENESIS.to_string();
+    let mut count = 0;
+    for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.is_empty()) {
+        let broken = |why: &str| format!("Audit log broken at line {}: {}", n + 1, why);
+        let mut entry: serde_json::Value =
+            serde_json::from_str(line).map_err(|_| broken("not valid JSON"))?;
+        let hash = entry
+            .as_object_mut()
+            .and_then(|o| o.remove("hash"))
+            .and_then(|h| h.as_str().map(String::from))
+            .ok_or_else(|| broken("missing hash"))?;
+        if entry["prev"].as_str() != Some(prev.as_str()) {
+            return Err(broken("previous hash does not match"));
+        }
+        if sha256_hex(entry.to_string().as_bytes()) != hash {
+            return Err(broken("entry was modified"));
+        }
+        prev = hash;
+        count += 1;
+    }
+    Ok((count, prev))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An audit log of `count` chained entries, as audit_entry writes them
+    fn chain(count: usize) -> Vec<serde_json::Value> {
+        let mut prev = AUDIT_GENESIS.to_string();
+        (0..count)
+            .map(|n| {
+                let mut entry = serde_json::json!({
+                    "subcommand": "complete",
+                    "prompt": format!("prompt {}", n),
+                    "result": "ls",
+                    "prev": prev,
+                });
+                prev = sha256_hex(entry.to_string().as_bytes());
+                entry["hash"] = serde_json::Value::String(prev.clone());
+                entry
+            })
+            .collect()
+    }
+
+    fn lines(entries: &[serde_json::Value]) -> String {
+        entries.iter().map(|e| format!("{}\n", e)).collect()
+    }
+
+    #[test]
+    fn accepts_an_intact_chain() {
+        let entries = chain(3);
+        let last = entries[2]["hash"].as_str().unwrap().to_string();
+        assert_eq!(verify_chain(&lines(&entries)), Ok((3, last)));
+        assert_eq!(verify_chain(""), Ok((0, AUDIT_GENESIS.to_string())));
+    }
+
+    #[test]
+    fn detects_a_modified_entry() {
+        let mut entries = chain(3);
+        entries[1]["result"] = "rm -rf ~".into();
+        assert_eq!(
+            verify_chain(&lines(&entries)).unwrap_err(),
+            "Audit log broken at line 2: entry was modified"
+        );
+    }
+
+    #[test]
+    fn detects_a_removed_entry() {
+        let mut entries = chain(3);
+        entries.remove(1);
+        assert_eq!(
+            verify_chain(&lines(&entries)).unwrap_err(),
+            "Audit log broken at line 2: previous hash does not match"
+        );
+    }
+
+    #[test]
+    fn truncation_shows_only_in_the_last_hash() {
+        let entries = chain(3);
+        let (count, last) = verify_chain(&lines(&entries[..2])).unwrap();
+        assert_eq!(count, 2);
+        assert_ne!(last, entries[2]["hash"].as_str().unwrap());
+    }
+}
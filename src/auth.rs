@@ -0,0 +1,174 @@
+//! `smartshell auth`: store, check and delete provider API keys in the macOS
+//! Keychain, the Linux Secret Service or the Windows Credential Manager, under the
+//! service names the CLI and the zsh widget look up
+
+use crate::config::{api_key_source, keychain_entry_name};
+use crate::keys::split_keys;
+use crate::oauth;
+use crate::provider::Provider;
+
+pub use crate::oauth::{login, logout};
+use std::io::{self, BufRead, IsTerminal};
+
+/// Whether this build can read and write the keychain
+const KEYCHAIN: bool = cfg!(all(
+    any(target_os = "macos", target_os = "linux", windows),
+    feature = "keychain"
+));
+
+/// Ask for `provider`'s key without echoing it (or read it from piped stdin) and
+/// store it in the keychain. With `require_presence` (macOS only), every later
+/// read asks for Touch ID or the login password.
+pub fn set_key(provider: Provider, require_presence: bool) -> Result<(), String> {
+    let (service, account) = entry_name(provider)?;
+    if require_presence && !cfg!(all(target_os = "macos", feature = "keychain")) {
+        return Err("--require-presence is only available on macOS.".to_string());
+    }
+    let key = if io::stdin().is_terminal() {
+        rpassword::prompt_password(format!("{} API key: ", provider.as_str()))
+            .map_err(|e| format!("Could not read the key: {}", e))?
+    } else {
+        let mut line = String::new();
+        io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .map_err(|e| format!("Could not read the key: {}", e))?;
+        line
+    };
+    let key = key.trim();
+    if key.is_empty() {
+        return Err("No key given, nothing stored.".to_string());
+    }
+    if require_presence {
+        // A plain copy would be found first and make the protection moot
+        let _ = delete(&service, &account);
+        return store_protected(&service, &account, key);
+    }
+    store(&service, &account, key)
+}
+
+/// Remove `provider`'s key from the keychain
+pub fn delete_key(provider: Provider) -> Result<(), String> {
+    let (service, account) = entry_name(provider)?;
+    delete(&service, &account)
+}
+
+/// One line per provider saying where its keys come from, with the keys masked
+pub fn key_status() -> Vec<String> {
+    [Provider::OpenAi, Provider::Claude]
+        .into_iter()
+        .map(|provider| match api_key_source(provider) {
+            _ if oauth::uses_oauth(provider) => {
+                format!("{:<8}{}", provider.as_str(), oauth::status(provider))
+            }
+            Some((source, key)) => {
+                let masked: Vec<String> = split_keys(&key).into_iter().map(mask).collect();
+                format!("{:<8}{} ({})", provider.as_str(), source, masked.join(", "))
+            }
+            None => format!("{:<8}not set", provider.as_str()),
+        })
+        .collect()
+}
+
+/// `sk-…wxyz`: enough to tell keys apart without revealing them
+fn mask(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() < 12 {
+        return "…".to_string();
+    }
+    let head: String = chars[..3].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}…{}", head, tail)
+}
+
+fn entry_name(provider: Provider) -> Result<(String, String), String> {
+    let name = keychain_entry_name(provider)
+        .ok_or_else(|| format!("{} does not use an API key.", provider.as_str()))?;
+    if !KEYCHAIN {
+        return Err(format!(
+            "Keychain storage is only available on macOS, Linux and Windows; set {}_API_KEY instead.",
+            provider.env_prefix().unwrap_or_default()
+        ));
+    }
+    Ok(name)
+}
+
+#[cfg(all(
+    any(target_os = "macos", target_os = "linux", windows),
+    feature = "keychain"
+))]
+fn store(service: &str, account: &str, key: &str) -> Result<(), String> {
+    keyring::Entry::new(service, account)
+        .and_then(|entry| entry.set_password(key))
+        .map_err(|e| format!("Could not store the key in the keychain: {}", e))
+}
+
+#[cfg(all(
+    any(target_os = "macos", target_os = "linux", windows),
+    feature = "keychain"
+))]
+fn delete(service: &str, account: &str) -> Result<(), String> {
+    let deleted = keyring::Entry::new(service, account)
+        .and_then(|entry| entry.delete_credential())
+        .map_err(|e| format!("Could not delete the key from the keychain: {}", e));
+    if delete_protected(service, account) {
+        return Ok(());
+    }
+    deleted
+}
+
+/// Store the key in the data protection keychain with a user-presence check, so
+/// reading it takes Touch ID (or the login password where there's no sensor)
+#[cfg(all(target_os = "macos", feature = "keychain"))]
+fn store_protected(service: &str, account: &str, key: &str) -> Result<(), String> {
+    use security_framework::passwords::{
+        set_generic_password_options, AccessControlOptions, PasswordOptions,
+    };
+    const ERR_SEC_MISSING_ENTITLEMENT: i32 = -34018;
+    delete_protected(service, account);
+    let mut options = PasswordOptions::new_generic_password(service, account);
+    options.use_protected_keychain();
+    options.set_access_control_options(AccessControlOptions::USER_PRESENCE);
+    set_generic_password_options(key.as_bytes(), options).map_err(|e| {
+        if e.code() == ERR_SEC_MISSING_ENTITLEMENT {
+            "This build is not signed with a keychain access group, which Touch ID protected keys need.".to_string()
+        } else {
+            format!("Could not store the key in the keychain: {}", e)
+        }
+    })
+}
+
+/// Remove a key stored with `--require-presence`; true if there was one
+#[cfg(all(target_os = "macos", feature = "keychain"))]
+fn delete_protected(service: &str, account: &str) -> bool {
+    use security_framework::passwords::{delete_generic_password_options, PasswordOptions};
+    let mut options = PasswordOptions::new_generic_password(service, account);
+    options.use_protected_keychain();
+    delete_generic_password_options(options).is_ok()
+}
+
+#[cfg(not(all(target_os = "macos", feature = "keychain")))]
+fn store_protected(_service: &str, _account: &str, _key: &str) -> Result<(), String> {
+    Err("--require-presence is only available on macOS.".to_string())
+}
+
+#[cfg(all(any(target_os = "linux", windows), feature = "keychain"))]
+fn delete_protected(_service: &str, _account: &str) -> bool {
+    false
+}
+
+#[cfg(not(all(
+    any(target_os = "macos", target_os = "linux", windows),
+    feature = "keychain"
+)))]
+fn store(_service: &str, _account: &str, _key: &str) -> Result<(), String> {
+    Err("Keychain storage is only available on macOS, Linux and Windows.".to_string())
+}
+
+#[cfg(not(all(
+    any(target_os = "macos", target_os = "linux", windows),
+    feature = "keychain"
+)))]
+fn delete(_service: &str, _account: &str) -> Result<(), String> {
+    Err("Keychain storage is only available on macOS, Linux and Windows.".to_string())
+}
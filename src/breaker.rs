@@ -0,0 +1,166 @@
+//! Per-provider circuit breaker shared by all processes through the state dir
+
+use crate::config::{env_parse, log_event, read_state, update_state};
+use crate::error::Failure;
+use crate::provider::Provider;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const DEFAULT_BREAKER_THRESHOLD: u32 = 3;
+const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 60;
+
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct Circuit {
+    /// Consecutive failures since the last success
+    failures: u32,
+    /// Unix time until which requests are short-circuited
+    open_until: i64,
+}
+
+impl Circuit {
+    /// Count a failure at unix time `now`; once `threshold` happened in a row the
+    /// circuit opens for `cooldown` seconds. Returns whether it is now open.
+    fn fail(&mut self, now: i64, threshold: u32, cooldown: u64) -> bool {
+        self.failures += 1;
+        let open = self.failures >= threshold;
+        if open {
+            self.open_until = now + cooldown as i64;
+        }
+        open
+    }
+
+    /// How much longer after `now` the circuit stays open
+    fn open_for(&self, now: i64) -> Option<Duration> {
+        let left = self.open_until - now;
+        (left > 0).then(|| Duration::from_secs(left as u64))
+    }
+}
+
+/// Consecutive failures per provider, persisted in the state dir
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct BreakerState {
+    circuits: HashMap<String, Circuit>,
+}
+
+const BREAKER_FILE: &str = "breaker.json";
+
+/// Failures that say the provider itself is in trouble, as opposed to the request,
+/// the key or the local network
+pub(crate) fn trips_breaker(failure: &Failure) -> bool {
+    match failure {
+        Failure::Network { .. } | Failure::Timeout { .. } | Failure::RateLimited { .. } => true,
+        Failure::Api { status, .. } => status.is_none_or(|s| s >= 500),
+        _ => false,
+    }
+}
+
+/// How much longer `provider` is short-circuited, if its circuit is open
+pub(crate) fn circuit_open(provider: Provider) -> Option<Duration> {
+    let state = read_state::<BreakerState>(BREAKER_FILE);
+    let circuit = state.circuits.get(provider.as_str())?;
+    circuit.open_for(chrono::Utc::now().timestamp())
+}
+
+/// Count a failure; after SMSH_BREAKER_THRESHOLD (default 3) in a row the circuit
+/// opens for SMSH_BREAKER_COOLDOWN seconds (default 60). A failure of the trial
+/// request after a cool-down reopens it at once. Returns whether it is now open.
+pub(crate) fn record_failure(provider: Provider) -> bool {
+    let threshold = env_parse("SMSH_BREAKER_THRESHOLD")
+        .unwrap_or(DEFAULT_BREAKER_THRESHOLD)
+        .max(1);
+    let cooldown = env_parse("SMSH_BREAKER_COOLDOWN").unwrap_or(DEFAULT_BREAKER_COOLDOWN_SECS);
+    let mut open = false;
+    update_state(BREAKER_FILE, |state: &mut BreakerState| {
+        let circuit = state
+            .circuits
+            .entry(provider.as_str().to_string())
+            .or_default();
+        open = circuit.fail(chrono::Utc::now().timestamp(), threshold, cooldown);
+        if open {
+            log_event(
+                "breaker",
+                provider.as_str(),
+                &format!(
+                    "open for {}s after {} consecutive failures",
+                    cooldown, circuit.failures
+                ),
+            );
+        }
+        true
+    });
+    open
+}
+
+/// Close the circuit after a successful request
+pub(crate) fn record_success(provider: Provider) {
+    update_state(BREAKER_FILE, |state: &mut BreakerState| {
+        let Some(circuit) = state.circuits.remove(provider.as_str()) else {
+            return false;
+        };
+        if circuit.open_until > 0 {
+            log_event("breaker", provider.as_str(), "closed");
+        }
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold_consecutive_failures() {
+        let mut circuit = Circuit::default();
+        assert!(!circuit.fail(1000, 3, 60));
+        assert!(!circuit.fail(1001, 3, 60));
+        assert_eq!(circuit.open_for(1001), None);
+        assert!(circuit.fail(1002, 3, 60));
+        assert_eq!(circuit.open_for(1002), Some(Duration::from_secs(60)));
+        assert_eq!(circuit.open_for(1032), Some(Duration::from_secs(30)));
+        assert_eq!(circuit.open_for(1062), None);
+    }
+
+    #[test]
+    fn a_failed_trial_request_reopens_at_once() {
+        let mut circuit = Circuit::default();
+        for now in 0..3 {
+            circuit.fail(now, 3, 60);
+        }
+        assert_eq!(circuit.open_for(100), None);
+        assert!(circuit.fail(100, 3, 60));
+        assert_eq!(circuit.open_for(100), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn only_provider_trouble_trips_the_breaker() {
+        let provider = Provider::OpenAi;
+        let message = String::new();
+        let api = |status| Failure::Api {
+            provider,
+            status,
+            message: message.clone(),
+        };
+        assert!(trips_breaker(&Failure::Network {
+            provider,
+            message: message.clone()
+        }));
+        assert!(trips_breaker(&Failure::Timeout {
+            provider,
+            after: Duration::from_secs(30)
+        }));
+        assert!(trips_breaker(&Failure::RateLimited {
+            provider,
+            retry_after: None,
+            message: message.clone()
+        }));
+        assert!(trips_breaker(&api(Some(503))));
+        assert!(trips_breaker(&api(None)));
+        assert!(!trips_breaker(&api(Some(400))));
+        assert!(!trips_breaker(&Failure::Auth {
+            provider,
+            message: message.clone()
+        }));
+        assert!(!trips_breaker(&Failure::refused("unclear")));
+        assert!(!trips_breaker(&Failure::Cancelled));
+    }
+}
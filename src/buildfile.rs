@@ -0,0 +1,118 @@
+//! Makefiles and justfiles: finding the one a new target is written for, and
+//! fixing up the target's indentation before it is printed
+
+use std::path::{Path, PathBuf};
+
+/// Build files larger than this are sent cut short at a line break; the top of a
+/// file (variables, conventions, the first targets) shows its style well enough
+const MAX_CONTEXT_BYTES: usize = 48 * 1024;
+
+/// Names looked for in the working directory, in GNU make's own search order,
+/// then just's
+const FILE_NAMES: &[&str] = &[
+    "GNUmakefile",
+    "makefile",
+    "Makefile",
+    "justfile",
+    "Justfile",
+    ".justfile",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildTool {
+    Make,
+    Just,
+}
+
+impl BuildTool {
+    /// What the file is called in prompts and messages
+    pub fn name(self) -> &'static str {
+        match self {
+            BuildTool::Make => "Makefile",
+            BuildTool::Just => "justfile",
+        }
+    }
+
+    /// The tool a file is for, from its name (`justfile`, `build.just`);
+    /// anything else is taken for a Makefile
+    pub fn for_path(path: &Path) -> Self {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if name.contains("justfile") || name.ends_with(".just") {
+            BuildTool::Just
+        } else {
+            BuildTool::Make
+        }
+    }
+}
+
+/// A build file and as much of its text as goes into the prompt
+#[derive(Debug, Clone)]
+pub struct BuildFile {
+    pub path: PathBuf,
+    pub tool: BuildTool,
+    pub text: String,
+    /// The text was cut at `MAX_CONTEXT_BYTES`
+    pub truncated: bool,
+}
+
+fn read(path: &Path) -> std::io::Result<BuildFile> {
+    let mut text = std::fs::read_to_string(path)?;
+    let truncated = text.len() > MAX_CONTEXT_BYTES;
+    if truncated {
+        let mut end = MAX_CONTEXT_BYTES;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        end = text[..end].rfind('\n').map_or(end, |i| i + 1);
+        text.truncate(end);
+    }
+    Ok(BuildFile {
+        path: path.to_path_buf(),
+        tool: BuildTool::for_path(path),
+        text,
+        truncated,
+    })
+}
+
+/// `explicit` (`--file`), which may not exist yet, or the first build file in
+/// the working directory; None if there is neither
+pub fn find(explicit: Option<&Path>) -> Result<Option<BuildFile>, String> {
+    if let Some(path) = explicit {
+        return match read(path) {
+            Ok(file) => Ok(Some(file)),
+            // A new file: the target is written in the tool's usual style
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Some(BuildFile {
+                path: path.to_path_buf(),
+                tool: BuildTool::for_path(path),
+                text: String::new(),
+                truncated: false,
+            })),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        };
+    }
+    for name in FILE_NAMES {
+        let path = Path::new(name);
+        if path.is_file() {
+            return read(path)
+                .map(Some)
+                .map_err(|e| format!("{}: {}", path.display(), e));
+        }
+    }
+    Ok(None)
+}
+
+/// `target` with every indented line starting with a tab, as make requires of
+/// recipe lines; models often indent them with spaces
+pub(crate) fn recipe_tabs(target: &str) -> String {
+    target
+        .lines()
+        .map(|line| match line.strip_prefix(' ') {
+            Some(_) => format!("\t{}", line.trim_start()),
+            None => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,399 @@
+//! Exact-match and semantic response caches
+
+use crate::audit::sha256_hex;
+use crate::client::SmartshellClient;
+use crate::config::{cache_dir, env_parse, log_mode, private_file, LogMode};
+use crate::error::Failure;
+use crate::keys::with_key_rotation;
+use crate::provider::{
+    authorize, check_egress, post_json, provider_client, provider_url, Provider,
+};
+use crate::redact::redact;
+use crate::response::{parse_structured, LlmResponse};
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+const DEFAULT_CACHE_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Cache file for a request: the prompt with whitespace collapsed, plus the model
+/// and a hash of the system prompt, which carries the OS and other context
+pub(crate) fn cache_path(provider: Provider, intro: &str, prompt: &str) -> Option<PathBuf> {
+    let normalized = prompt.split_whitespace().collect::<Vec<_>>().join(" ");
+    let key = format!(
+        "{}\0{}\0{}\0{}",
+        provider.as_str(),
+        provider.model(),
+        sha256_hex(intro.as_bytes()),
+        normalized
+    );
+    cache_dir().map(|d| {
+        d.join("responses")
+            .join(format!("{}.json", sha256_hex(key.as_bytes())))
+    })
+}
+
+pub(crate) fn cache_ttl() -> u64 {
+    env_parse("SMSH_CACHE_TTL").unwrap_or(DEFAULT_CACHE_TTL_SECS)
+}
+
+/// The cached answer at `path` if it is at most `ttl` seconds old
+pub(crate) fn cache_lookup(path: &Path, ttl: u64) -> Option<LlmResponse> {
+    let age = std::fs::metadata(path)
+        .ok()?
+        .modified()
+        .ok()?
+        .elapsed()
+        .ok()?;
+    if age.as_secs() > ttl {
+        return None;
+    }
+    let cached: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+    let mut resp = parse_structured(&cached).ok()?;
+    resp.cached = true;
+    Some(resp)
+}
+
+/// Whether the caches keep answers at all: only when `SMSH_LOG_MODE` is full,
+/// since they hold what was asked
+pub fn caches_answers() -> bool {
+    log_mode() == LogMode::Full
+}
+
+/// Save `resp` at `path`, readable only by the user. Nothing is kept unless
+/// `SMSH_LOG_MODE` is full, as for the semantic cache.
+pub(crate) fn cache_store(path: &Path, resp: &LlmResponse) {
+    if !caches_answers() {
+        return;
+    }
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    if let Ok(mut file) = private_file(OpenOptions::new().write(true))
+        .create(true)
+        .truncate(true)
+        .open(path)
+    {
+        let _ = file.write_all(resp.structured().to_string().as_bytes());
+    }
+}
+
+const EMBEDDING_DIM: usize = 512;
+const DEFAULT_SEMANTIC_THRESHOLD: f32 = 0.9;
+const SEMANTIC_CACHE_MAX_ENTRIES: usize = 1000;
+const OPENAI_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// `SMSH_SEMANTIC_CACHE`: `local` (hashed n-gram vectors, no network) or `openai`
+/// (OpenAI's embeddings endpoint, used only when the provider is OpenAI)
+pub(crate) fn semantic_embedder() -> Option<String> {
+    env::var("SMSH_SEMANTIC_CACHE")
+        .ok()
+        .filter(|e| e == "local" || e == "openai")
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn normalize(mut v: Vec<f32>) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+    v
+}
+
+/// Feature-hashed bag of words and character trigrams, L2-normalized
+pub(crate) fn local_embedding(text: &str) -> Vec<f32> {
+    let mut v = vec![0f32; EMBEDDING_DIM];
+    let lower = text.to_lowercase();
+    for word in lower
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        v[(fnv1a(word.as_bytes()) % EMBEDDING_DIM as u64) as usize] += 1.0;
+        let padded: Vec<char> = format!(" {} ", word).chars().collect();
+        for gram in padded.windows(3) {
+            let gram: String = gram.iter().collect();
+            v[(fnv1a(gram.as_bytes()) % EMBEDDING_DIM as u64) as usize] += 0.5;
+        }
+    }
+    normalize(v)
+}
+
+pub(crate) async fn openai_embedding(
+    client: &SmartshellClient,
+    text: &str,
+) -> Result<Vec<f32>, Failure> {
+    let url = check_egress(&provider_url(
+        "SMSH_OPENAI_BASE_URL",
+        "https://api.openai.com/v1",
+        "/embeddings",
+    ))?;
+    let body = serde_json::json!({ "model": OPENAI_EMBEDDING_MODEL, "input": text });
+    let (url, body) = (&url, &body);
+    let json = with_key_rotation(client, Provider::OpenAi, || async move {
+        let request = provider_client(Provider::OpenAi)?
+            .post(url.clone())
+            .timeout(client.timeout);
+        let request = authorize(client, Provider::OpenAi, request).await?;
+        post_json(client, request, body).await
+    })
+    .await?;
+    let v: Vec<f32> = json["data"][0]["embedding"]
+        .as_array()
+        .map(|a| {
+            a.iter()
+                .filter_map(|x| x.as_f64())
+                .map(|x| x as f32)
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(normalize(v))
+}
+
+/// Tokens whose exact value matters (numbers, paths, flags, globs); near-duplicate
+/// queries only share an answer when these are identical
+fn literal_tokens(text: &str) -> std::collections::BTreeSet<String> {
+    text.split_whitespace()
+        .filter(|t| {
+            t.chars()
+                .any(|c| c.is_ascii_digit() || "/.-_~*$=".contains(c))
+        })
+        .map(|t| {
+            t.trim_matches(|c: char| ",;:!?\"'`".contains(c))
+                .to_string()
+        })
+        .collect()
+}
+
+fn semantic_cache_path() -> Option<PathBuf> {
+    cache_dir().map(|d| d.join("semantic.jsonl"))
+}
+
+/// The cached response of the most similar earlier query above SMSH_SEMANTIC_THRESHOLD,
+/// among those at most `ttl` seconds old
+pub(crate) fn semantic_lookup(
+    scope: &str,
+    query: &str,
+    embedding: &[f32],
+    ttl: u64,
+) -> Option<LlmResponse> {
+    let mut file = std::fs::File::open(semantic_cache_path()?).ok()?;
+    // Not while an entry is being added
+    file.lock_shared().ok()?;
+    let mut text = String::new();
+    file.read_to_string(&mut text).ok()?;
+    drop(file);
+    semantic_match(&text, scope, query, embedding, ttl)
+}
+
+/// Like `semantic_lookup`, among the cache entries in `text`, one per line
+fn semantic_match(
+    text: &str,
+    scope: &str,
+    query: &str,
+    embedding: &[f32],
+    ttl: u64,
+) -> Option<LlmResponse> {
+    let threshold = env_parse("SMSH_SEMANTIC_THRESHOLD").unwrap_or(DEFAULT_SEMANTIC_THRESHOLD);
+    let now = chrono::Utc::now().timestamp();
+    let literals = literal_tokens(query);
+    let (score, entry) = text
+        .lines()
+        .filter_map(|l| serde_json::from_str::<serde_json::Value>(l).ok())
+        .filter(|e| e["scope"] == scope)
+        .filter(|e| now - e["created"].as_i64().unwrap_or(0) <= ttl.min(i64::MAX as u64) as i64)
+        .filter(|e| literal_tokens(e["query"].as_str().unwrap_or_default()) == literals)
+        .map(|e| {
+            let score: f32 = e["embedding"]
+                .as_array()
+                .map(|a| {
+                    a.iter()
+                        .zip(embedding)
+                        .map(|(x, y)| x.as_f64().unwrap_or(0.0) as f32 * y)
+                        .sum()
+                })
+                .unwrap_or(0.0);
+            (score, e)
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0))?;
+    if score < threshold {
+        return None;
+    }
+    let mut resp = parse_structured(&entry["response"]).ok()?;
+    resp.cached = true;
+    Some(resp)
+}
+
+/// Add an answer to the semantic cache, keeping the newest
+/// SEMANTIC_CACHE_MAX_ENTRIES; nothing is added unless `SMSH_LOG_MODE` is full,
+/// since entries hold the query. The query is stored with credentials masked, in a
+/// file readable only by the user, rewritten under a lock so concurrent requests
+/// don't drop each other's entries.
+pub(crate) fn semantic_store(scope: &str, query: &str, embedding: &[f32], resp: &LlmResponse) {
+    if !caches_answers() {
+        return;
+    }
+    let Some(path) = semantic_cache_path() else {
+        return;
+    };
+    let entry = serde_json::json!({
+        "scope": scope,
+        "query": redact(query),
+        "created": chrono::Utc::now().timestamp(),
+        "embedding": embedding,
+        "response": resp.structured(),
+    });
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let Ok(mut file) = private_file(OpenOptions::new().read(true).write(true))
+        .create(true)
+        .truncate(false)
+        .open(&path)
+    else {
+        return;
+    };
+    if file.lock().is_err() {
+        return;
+    }
+    let mut text = String::new();
+    let _ = file.read_to_string(&mut text);
+    let mut lines: Vec<&str> = text.lines().collect();
+    let entry = entry.to_string();
+    lines.push(&entry);
+    let skip = lines.len().saturating_sub(SEMANTIC_CACHE_MAX_ENTRIES);
+    let _ = file.set_len(0);
+    let _ = file.rewind();
+    let _ = file.write_all((lines[skip..].join("\n") + "\n").as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::scratch_dir;
+
+    #[test]
+    fn cache_key_ignores_whitespace_but_not_context() {
+        let key = cache_path(Provider::OpenAi, "intro", "find  large\n files ");
+        assert!(key.is_some());
+        assert_eq!(
+            key,
+            cache_path(Provider::OpenAi, "intro", "find large files")
+        );
+        assert_ne!(
+            key,
+            cache_path(Provider::OpenAi, "intro", "find large file")
+        );
+        assert_ne!(
+            key,
+            cache_path(Provider::OpenAi, "other intro", "find large files")
+        );
+        assert_ne!(
+            key,
+            cache_path(Provider::Claude, "intro", "find large files")
+        );
+    }
+
+    #[test]
+    fn cache_lookup_skips_expired_entries() {
+        let path = scratch_dir("cache-ttl").join("entry.json");
+        cache_store(&path, &LlmResponse::from_result("du -sh *"));
+        assert_eq!(
+            cache_lookup(&path, DEFAULT_CACHE_TTL_SECS)
+                .map(|r| r.result)
+                .as_deref(),
+            Some("du -sh *")
+        );
+        let expired = std::time::SystemTime::now()
+            - std::time::Duration::from_secs(DEFAULT_CACHE_TTL_SECS + 60);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(expired)
+            .unwrap();
+        assert!(cache_lookup(&path, DEFAULT_CACHE_TTL_SECS).is_none());
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    /// A semantic cache line answering `query` with `result`
+    fn semantic_entry(scope: &str, query: &str, result: &str) -> String {
+        serde_json::json!({
+            "scope": scope,
+            "query": query,
+            "created": chrono::Utc::now().timestamp(),
+            "embedding": local_embedding(query),
+            "response": LlmResponse::from_result(result).structured(),
+        })
+        .to_string()
+    }
+
+    fn similarity(a: &str, b: &str) -> f32 {
+        local_embedding(a)
+            .iter()
+            .zip(local_embedding(b))
+            .map(|(x, y)| x * y)
+            .sum()
+    }
+
+    #[test]
+    fn local_embedding_scores_rewordings_above_unrelated_queries() {
+        let query = "show the ten largest files in this directory";
+        assert!(similarity(query, query) > 0.99);
+        assert!(similarity(query, "show the ten largest files in this directory please") > 0.9);
+        assert!(similarity(query, "list running docker containers") < 0.5);
+    }
+
+    #[test]
+    fn literal_tokens_keep_numbers_paths_and_flags() {
+        let tokens = literal_tokens("delete *.log files in /var/log older than 30 days, with -f");
+        for token in ["*.log", "/var/log", "30", "-f"] {
+            assert!(tokens.contains(token), "missing {}", token);
+        }
+        assert!(!tokens.contains("delete"));
+        assert_ne!(
+            literal_tokens("files older than 30 days"),
+            literal_tokens("files older than 60 days")
+        );
+    }
+
+    #[test]
+    fn semantic_match_serves_near_duplicates_only() {
+        let cached = "compress the logs folder into a tarball";
+        let text = semantic_entry("openai/complete", cached, "tar -czf logs.tar.gz logs");
+        let lookup = |scope: &str, query: &str| {
+            semantic_match(
+                &text,
+                scope,
+                query,
+                &local_embedding(query),
+                DEFAULT_CACHE_TTL_SECS,
+            )
+        };
+        let hit = lookup(
+            "openai/complete",
+            "compress the logs folder into a tarball please",
+        );
+        assert!(hit.as_ref().is_some_and(|r| r.cached));
+        assert_eq!(hit.unwrap().result, "tar -czf logs.tar.gz logs");
+        assert!(lookup("claude/complete", cached).is_none());
+        assert!(lookup("openai/complete", "list running docker containers").is_none());
+        // Same wording, different literal: never the same answer
+        let text = semantic_entry("openai/complete", "delete logs older than 30 days", "x");
+        let query = "delete logs older than 60 days";
+        assert!(semantic_match(
+            &text,
+            "openai/complete",
+            query,
+            &local_embedding(query),
+            DEFAULT_CACHE_TTL_SECS
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,1715 @@
+//! The public entry point: a configured client and the requests it answers
+
+use crate::aliases::Frequent;
+use crate::audit::sha256_hex;
+use crate::breaker::{circuit_open, record_failure, record_success, trips_breaker};
+use crate::buildfile::{recipe_tabs, BuildTool};
+use crate::cache::{
+    cache_lookup, cache_path, cache_store, cache_ttl, caches_answers, local_embedding,
+    openai_embedding, semantic_embedder, semantic_lookup, semantic_store,
+};
+use crate::config::{
+    custom_instructions, env_parse, get_api_key, get_os_context, is_wsl, log_entry, log_event,
+    trace, with_new_request_id,
+};
+use crate::console::Console;
+use crate::daemon::{daemon_call, daemon_prefetch};
+use crate::error::{Failure, Outcome};
+use crate::examples;
+use crate::history;
+use crate::injection::{guard_injection, injection_lines, untrusted_block, UNTRUSTED_NOTICE};
+use crate::policy::Policy;
+use crate::probe::probe_call;
+use crate::provider::{
+    provider_call, provider_candidates, provider_reachable, provider_variants, stream_call,
+    stream_tty, Provider,
+};
+use crate::redact::redact;
+use crate::replay::record_exchange;
+use crate::response::{LlmResponse, Schema, Step, TokenMeaning};
+use crate::review::review;
+use crate::sanitize::{fenced, sanitize_response};
+use crate::shell::{
+    canonical_command, check_privileges, prompt_tty, repair_lint, repair_syntax, sudo_forbidden,
+    TargetShell,
+};
+use crate::spend::{check_budget, measure_request, Usage};
+use crate::style;
+use crate::telemetry;
+use crate::versions::{tool_versions, version_instruction};
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Samples `--careful` takes a majority vote over
+const CAREFUL_SAMPLES: usize = 5;
+/// The approaches `--creative` asks for, one request each
+const CREATIVE_STYLES: [&str; 3] = [
+    "use only the shell and POSIX coreutils (find, sed, awk, sort, xargs and the like).",
+    "write a python3 one-liner with python3 -c, or perl -e where that is shorter.",
+    "use the specialized tool best suited to the job (such as rg, fd, jq, ffmpeg or \
+    imagemagick), even one that may need to be installed.",
+];
+/// Requests slower than this end with a bell or notification, since the user has
+/// likely switched away by then
+const DEFAULT_NOTIFY_AFTER_SECS: u64 = 10;
+
+/// Where the command to modify or explain comes from
+#[derive(Debug, Clone, Default)]
+pub enum ContextSource {
+    /// No existing command; generate one from scratch
+    #[default]
+    None,
+    /// The given command line
+    Buffer(String),
+    /// Whatever text is on the system clipboard
+    Clipboard,
+}
+
+impl ContextSource {
+    /// The context text, empty for `None`
+    pub fn read(&self) -> Result<String, Failure> {
+        match self {
+            ContextSource::None => Ok(String::new()),
+            ContextSource::Buffer(text) => Ok(text.clone()),
+            ContextSource::Clipboard => read_clipboard(),
+        }
+    }
+}
+
+fn read_clipboard() -> Result<String, Failure> {
+    let tools: &[(&str, &[&str])] = if cfg!(target_os = "macos") {
+        &[("pbpaste", &[])]
+    } else if is_wsl() {
+        &[
+            (
+                "powershell.exe",
+                &["-NoProfile", "-Command", "Get-Clipboard"],
+            ),
+            ("wl-paste", &["--no-newline"]),
+            ("xclip", &["-selection", "clipboard", "-o"]),
+        ]
+    } else {
+        &[
+            ("wl-paste", &["--no-newline"]),
+            ("xclip", &["-selection", "clipboard", "-o"]),
+        ]
+    };
+    for (bin, args) in tools {
+        if which::which(bin).is_err() {
+            continue;
+        }
+        // wl-paste fails outside Wayland sessions, so fall through to the next tool
+        if let Ok(out) = Command::new(bin).args(*args).output() {
+            if out.status.success() {
+                // Windows line endings, from the WSL host's clipboard
+                return Ok(String::from_utf8_lossy(&out.stdout)
+                    .replace("\r\n", "\n")
+                    .trim()
+                    .to_string());
+            }
+        }
+    }
+    Err(Failure::InvalidInput(
+        "Could not read clipboard (requires pbpaste, wl-paste, xclip or powershell.exe)"
+            .to_string(),
+    ))
+}
+
+/// A natural-language request for a shell command
+#[derive(Debug, Clone, Default)]
+pub struct CompletionRequest {
+    pub query: String,
+    /// An existing command to alter instead of writing one from scratch
+    pub context: ContextSource,
+    /// Lint the result with shellcheck and ask the model to fix warnings
+    pub lint: bool,
+    /// Let the model run approved read-only probes (ls, which, git status, ...) first
+    pub probe: bool,
+    /// The earlier query and command this request follows up on, so `query` only
+    /// needs to say what to change
+    pub previous: Option<(String, String)>,
+    /// Tool versions to write the command for, as (`ffmpeg`, `4.4`); the installed
+    /// versions of other tools the query names are looked up
+    pub tool_versions: Vec<(String, String)>,
+}
+
+impl CompletionRequest {
+    pub fn new(query: impl Into<String>) -> Self {
+        CompletionRequest {
+            query: query.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// How much an explanation goes into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Detail {
+    /// A single line, for the inline widget
+    #[default]
+    Brief,
+    /// A short paragraph
+    Normal,
+    /// A flag-by-flag breakdown with caveats and common mistakes
+    Deep,
+}
+
+impl Detail {
+    fn instructions(self) -> &'static str {
+        match self {
+            Detail::Brief => {
+                "Return a short, single-line explanation in the result field \
+                and leave breakdown empty."
+            }
+            Detail::Normal => {
+                "Return a short paragraph (two to four sentences) in the result field \
+                explaining what the command does and any notable side effects. \
+                In breakdown, list every command, flag and argument in order with a short meaning."
+            }
+            Detail::Deep => {
+                "Return a thorough explanation in the result field, formatted as markdown: \
+                a one-line summary, then caveats and common mistakes with this command. \
+                In breakdown, list every command, flag and argument in order with its meaning, \
+                including what each pipeline stage or redirection does."
+            }
+        }
+    }
+
+    /// Like `instructions`, for the explanation of a whole script put together
+    /// from its sections, whose summaries serve as the breakdown
+    fn script_instructions(self) -> &'static str {
+        match self {
+            Detail::Brief => {
+                "Return a short, single-line summary of what the whole script does \
+                in the result field and leave breakdown empty."
+            }
+            Detail::Normal => {
+                "Return a short paragraph (two to four sentences) in the result field \
+                on what the whole script does and its notable side effects. \
+                Leave breakdown empty."
+            }
+            Detail::Deep => {
+                "Return a thorough explanation in the result field, formatted as markdown: \
+                a one-line summary, how the sections work together, then caveats and \
+                risky parts. Leave breakdown empty."
+            }
+        }
+    }
+}
+
+/// Buffers longer than this many lines are explained section by section
+const LONG_SCRIPT_LINES: usize = 150;
+
+/// The most lines of a script explained in one request
+const SECTION_LINES: usize = 120;
+
+/// `script` cut into sections of at most `SECTION_LINES` lines, at a blank line
+/// near the end of each where there is one, as (first line, last line, text)
+fn script_sections(script: &str) -> Vec<(usize, usize, String)> {
+    let lines: Vec<&str> = script.lines().collect();
+    let mut sections = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let mut end = (start + SECTION_LINES).min(lines.len());
+        if end < lines.len() {
+            // Prefer ending a section between functions or paragraphs
+            if let Some(blank) = (end - SECTION_LINES / 4..end)
+                .rev()
+                .find(|&i| lines[i].trim().is_empty())
+            {
+                end = blank + 1;
+            }
+        }
+        sections.push((start + 1, end, lines[start..end].join("\n")));
+        start = end;
+    }
+    sections
+}
+
+/// The system and user prompts for a completion request with context `buffer`
+fn completion_prompt(
+    request: &CompletionRequest,
+    buffer: &str,
+    language: Option<&str>,
+    shell: TargetShell,
+) -> (String, String) {
+    let mut intro = format!(
+        "Generate a {} command.{} {} \
+        If the request is unclear or not a valid shell task, set error=true and put an explanation in result. \
+        If it is ambiguous in a way that changes the command (which environment, file or branch), \
+        ask one short question in question instead of guessing. \
+        If no single command can do the job safely, because one step's outcome must be checked \
+        before the next runs, list the commands in steps instead. {} {}",
+        shell.name(),
+        shell.instructions(),
+        charset_instruction(&request.query, buffer),
+        UNTRUSTED_NOTICE,
+        get_os_context()
+    );
+    if sudo_forbidden() {
+        intro.push_str(NO_ROOT);
+    }
+    intro.push_str(&language_instruction(language));
+    intro.push_str(&version_instruction(&tool_versions(
+        &request.query,
+        buffer,
+        &request.tool_versions,
+    )));
+    intro.push_str(&few_shot_examples(&request.query));
+    intro.push_str(&curated_examples(&request.query));
+    intro.push_str(&custom_instructions());
+    (intro, request_prompt(request, buffer, shell))
+}
+
+/// The system and user prompts for a plan of steps for `request`
+fn plan_prompt(
+    request: &CompletionRequest,
+    buffer: &str,
+    language: Option<&str>,
+    shell: TargetShell,
+) -> (String, String) {
+    let mut intro = format!(
+        "Plan the {} commands that carry out the request, as steps run one after another. \
+        Give each step a single command and a short note on what it does and what to check \
+        before going on.{} {} \
+        If the request is unclear or not a valid shell task, set error=true and explain why in reason. {} {}",
+        shell.name(),
+        shell.instructions(),
+        charset_instruction(&request.query, buffer),
+        UNTRUSTED_NOTICE,
+        get_os_context()
+    );
+    if sudo_forbidden() {
+        intro.push_str(NO_ROOT);
+    }
+    intro.push_str(&version_instruction(&tool_versions(
+        &request.query,
+        buffer,
+        &request.tool_versions,
+    )));
+    intro.push_str(&language_instruction(language));
+    intro.push_str(&custom_instructions());
+    (intro, request_prompt(request, buffer, shell))
+}
+
+/// Commands are ASCII, except for names the user typed with other characters:
+/// those are kept as written (and quoted) rather than transliterated, which
+/// would point the command at a file that doesn't exist
+fn charset_instruction(query: &str, buffer: &str) -> &'static str {
+    if query.is_ascii() && buffer.is_ascii() {
+        "Use only ASCII characters (straight quotes, no curly quotes)."
+    } else {
+        "Use ASCII for the command itself (straight quotes, no curly quotes), but copy \
+        file names, paths and other text from the request character for character, \
+        accents and non-Latin scripts included, never transliterated, and quote them \
+        so the shell reads each as one word."
+    }
+}
+
+const NO_ROOT: &str = " Root access is not available: never use sudo, doas or su. \
+    If the task cannot be done without root, set error=true and explain why.";
+
+/// The query, the request to change the command line `buffer` to fit it, or the
+/// follow-up to an earlier answer with that answer
+fn request_prompt(request: &CompletionRequest, buffer: &str, shell: TargetShell) -> String {
+    if let Some((query, command)) = &request.previous {
+        return format!(
+            "This follows up on the earlier request below, which you answered with the \
+            command after it. Change that command to comply with the follow-up `{}`, \
+            keeping whatever the follow-up doesn't mention.\n{}\n{}",
+            request.query,
+            untrusted_block("request", query),
+            untrusted_block("command", command)
+        );
+    }
+    if buffer.is_empty() {
+        request.query.clone()
+    } else {
+        format!(
+            "Alter the {} command below to comply with query `{}`\n{}",
+            shell.name(),
+            request.query,
+            untrusted_block("command", buffer)
+        )
+    }
+}
+
+/// With SMSH_FEW_SHOT=K, the K commands the user ran for the most similar earlier
+/// queries, as examples of their preferred tools, flags and aliases
+fn few_shot_examples(query: &str) -> String {
+    let k = env_parse::<usize>("SMSH_FEW_SHOT").unwrap_or(0).min(20);
+    let examples = history::accepted_examples(query, k);
+    if examples.is_empty() {
+        return String::new();
+    }
+    trace(
+        1,
+        format_args!(
+            "{} example{} from the history",
+            examples.len(),
+            if examples.len() == 1 { "" } else { "s" }
+        ),
+    );
+    let one_line = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut text = String::from(
+        " The user ran these commands for similar requests before. \
+        Prefer the same tools, flags and aliases where they fit:",
+    );
+    for (query, command) in examples {
+        text.push_str(&format!(
+            "\n- {} => {}",
+            one_line(&query),
+            one_line(&command)
+        ));
+    }
+    text
+}
+
+/// The user's own examples file, as the answers they want for such requests
+fn curated_examples(query: &str) -> String {
+    let examples = examples::curated(query);
+    if examples.is_empty() {
+        return String::new();
+    }
+    let one_line = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut text = String::from(
+        " The user wrote these examples of how they want requests answered. \
+        Follow their choice of tools, flags and style where a request is similar:",
+    );
+    for example in examples {
+        text.push_str(&format!(
+            "\n- {} => {}",
+            one_line(&example.query),
+            one_line(&example.command)
+        ));
+        if let Some(note) = &example.note {
+            text.push_str(&format!(" ({})", one_line(note)));
+        }
+    }
+    text
+}
+
+/// Write prose as `persona`. It is the user's own setting, but kept to one line
+/// of reasonable length all the same.
+fn persona_instruction(persona: Option<&str>) -> String {
+    let persona: String = persona
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(300)
+        .collect();
+    if persona.is_empty() {
+        return String::new();
+    }
+    format!(
+        " Write the explanation in this persona and tone: {}. \
+        The level of detail and the response format above still apply.",
+        persona.trim_end_matches('.')
+    )
+}
+
+/// Ask for prose in `language`, keeping commands as they are. Only letters, digits,
+/// spaces and dashes are kept, since the value ends up in the system prompt.
+fn language_instruction(language: Option<&str>) -> String {
+    let language: String = language
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        .take(32)
+        .collect();
+    if language.trim().is_empty() {
+        return String::new();
+    }
+    format!(
+        " Write explanations and error messages in the language `{}` (a language name or code). \
+        Commands, flags, paths and breakdown tokens stay exactly as they are, untranslated.",
+        language.trim()
+    )
+}
+
+#[derive(Default, Clone, Copy)]
+pub(crate) struct CallOptions {
+    /// Allow answers from the semantic cache
+    pub(crate) semantic: bool,
+    /// Render the answer on the terminal as it arrives
+    pub(crate) stream: bool,
+}
+
+pub(crate) fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start async runtime")
+    })
+}
+
+/// SIGINT handling while the runtime listens for Ctrl-C: tokio installs its
+/// handler the first time `ctrl_c()` is polled and never removes it, which would
+/// leave Ctrl-C silently ignored during synchronous work afterwards (shellcheck
+/// repair, sandbox copies, the gaps between `--batch` requests). While at least
+/// one guard is alive tokio's handler is in place; when the last one is dropped
+/// the disposition the process started with (normally the default action,
+/// which ends it) is put back, and tokio's is kept for the next guard.
+pub(crate) struct CtrlCGuard;
+
+#[cfg(unix)]
+struct SigintState {
+    guards: usize,
+    /// What SIGINT did before the first guard
+    original: Option<libc::sigaction>,
+    /// tokio's handler, while the original disposition is in place
+    saved: Option<libc::sigaction>,
+}
+
+#[cfg(unix)]
+static SIGINT_STATE: Mutex<SigintState> = Mutex::new(SigintState {
+    guards: 0,
+    original: None,
+    saved: None,
+});
+
+impl CtrlCGuard {
+    pub(crate) fn new() -> Self {
+        #[cfg(unix)]
+        {
+            let mut state = SIGINT_STATE.lock().unwrap_or_else(|e| e.into_inner());
+            if state.guards == 0 {
+                // SAFETY: sigaction only reads `act` and writes `oldact`, both
+                // valid sigaction structs (or null)
+                unsafe {
+                    if let Some(saved) = state.saved.take() {
+                        libc::sigaction(libc::SIGINT, &saved, std::ptr::null_mut());
+                    } else if state.original.is_none() {
+                        let mut original: libc::sigaction = std::mem::zeroed();
+                        if libc::sigaction(libc::SIGINT, std::ptr::null(), &mut original) == 0 {
+                            state.original = Some(original);
+                        }
+                    }
+                }
+            }
+            state.guards += 1;
+        }
+        CtrlCGuard
+    }
+}
+
+impl Drop for CtrlCGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            let mut state = SIGINT_STATE.lock().unwrap_or_else(|e| e.into_inner());
+            state.guards -= 1;
+            if state.guards == 0 {
+                if let Some(original) = state.original {
+                    // SAFETY: as in `new`
+                    unsafe {
+                        let mut previous: libc::sigaction = std::mem::zeroed();
+                        if libc::sigaction(libc::SIGINT, &original, &mut previous) == 0 {
+                            state.saved = Some(previous);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Set once a streamed answer has started replacing the spinner
+pub(crate) static STREAMING: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+/// What the status line says the client is doing, next to the spinner
+static PHASE: Mutex<String> = Mutex::new(String::new());
+
+/// The status line on the terminal while an operation is in progress
+static STATUS: Mutex<Option<Console>> = Mutex::new(None);
+
+/// Spinner frames from SMSH_SPINNER: `dots` (the default), `line`, `none` for the
+/// phase alone, or the characters of any other value as frames
+fn spinner_frames() -> Vec<String> {
+    let frames: &[&str] = match env::var("SMSH_SPINNER").as_deref().map(str::trim) {
+        Ok("none") => &[],
+        Ok("line") => &["|", "/", "-", "\\"],
+        Ok(custom) if !custom.is_empty() && custom != "dots" => {
+            return custom.chars().map(String::from).collect();
+        }
+        _ => &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"],
+    };
+    frames.iter().map(|f| f.to_string()).collect()
+}
+
+/// Erase the status line and bring the cursor back, e.g. before prompting
+pub(crate) fn clear_status() {
+    if let Some(mut tty) = STATUS.lock().unwrap().take() {
+        tty.clear();
+        tty.show_cursor(true);
+    }
+}
+
+/// Clears the status line and phase when an operation returns
+struct StatusLine;
+
+impl Drop for StatusLine {
+    fn drop(&mut self) {
+        clear_status();
+        PHASE.lock().unwrap().clear();
+    }
+}
+
+/// Generates and explains commands through one provider. `from_env` applies the
+/// same `SMSH_*` settings as the CLI; the setters override them per client.
+#[derive(Debug, Clone)]
+pub struct SmartshellClient {
+    pub(crate) provider: Provider,
+    pub(crate) api_key: Option<String>,
+    pub(crate) timeout: Duration,
+    cache: bool,
+    spinner: bool,
+    offline: bool,
+    fallback: Vec<Provider>,
+    language: Option<String>,
+    persona: Option<String>,
+    questions: bool,
+    shell: TargetShell,
+    /// The structured answer to ask for; `plan` switches to steps
+    pub(crate) schema: Schema,
+}
+
+impl SmartshellClient {
+    pub fn new(provider: Provider) -> Self {
+        SmartshellClient {
+            provider,
+            api_key: None,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            cache: true,
+            spinner: true,
+            offline: false,
+            fallback: Vec::new(),
+            language: None,
+            persona: None,
+            questions: true,
+            shell: TargetShell::Zsh,
+            schema: Schema::Response,
+        }
+    }
+
+    /// A client for `SMSH_LLM_PROVIDER`, with `SMSH_TIMEOUT`, `SMSH_NO_CACHE`,
+    /// `SMSH_OFFLINE`, `SMSH_FALLBACK`, `SMSH_SHELL` and the `SMSH_PERSONA*`
+    /// settings applied
+    pub fn from_env() -> Result<Self, Failure> {
+        let mut client = Self::new(Provider::from_env()?);
+        if let Some(secs) = env_parse("SMSH_TIMEOUT") {
+            client = client.timeout(Duration::from_secs(secs));
+        }
+        client.cache = env::var_os("SMSH_NO_CACHE").is_none();
+        client.offline = env::var("SMSH_OFFLINE").is_ok_and(|v| !v.is_empty() && v != "0");
+        client.spinner = !env::var("SMSH_NO_SPINNER").is_ok_and(|v| !v.is_empty() && v != "0");
+        client.language = env::var("SMSH_LANG").ok().filter(|l| !l.trim().is_empty());
+        client.persona = ["SMSH_PERSONA_EXPLAIN", "SMSH_PERSONA"]
+            .iter()
+            .find_map(|var| env::var(var).ok().filter(|p| !p.trim().is_empty()));
+        client.questions = !env::var("SMSH_NO_QUESTIONS").is_ok_and(|v| !v.is_empty() && v != "0");
+        client.shell = TargetShell::from_env();
+        if let Ok(chain) = env::var("SMSH_FALLBACK") {
+            let providers = chain
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<_>, _>>()?;
+            client = client.fallback(providers);
+        }
+        Ok(client)
+    }
+
+    /// Use this key rather than looking one up in the environment or keychain
+    pub fn api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    /// How long to wait for the provider (at least one second)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout.max(Duration::from_secs(1));
+        self
+    }
+
+    /// Whether earlier answers may be reused; fresh answers are cached either way
+    pub fn cache(mut self, enabled: bool) -> Self {
+        self.cache = enabled;
+        self
+    }
+
+    /// Whether to draw a spinner, and stream explanations, on the terminal while waiting
+    pub fn spinner(mut self, enabled: bool) -> Self {
+        self.spinner = enabled;
+        self
+    }
+
+    /// Answer only from the caches, however old the entry, and fail with
+    /// `Failure::Offline` otherwise. Clients also switch to this when the provider
+    /// turns out to be unreachable.
+    pub fn offline(mut self, enabled: bool) -> Self {
+        self.offline = enabled;
+        self
+    }
+
+    /// Providers to switch to, in order, while this one's circuit breaker is open
+    pub fn fallback(mut self, providers: Vec<Provider>) -> Self {
+        self.fallback.clear();
+        for provider in providers {
+            if !self.fallback.contains(&provider) {
+                self.fallback.push(provider);
+            }
+        }
+        self
+    }
+
+    /// Language for explanations and refusals, as a name or code (`sv`, `ja`, ...);
+    /// generated commands are not translated
+    pub fn language(mut self, language: impl Into<String>) -> Self {
+        self.language = Some(language.into());
+        self
+    }
+
+    /// The persona and tone explanations are written in, such as `terse sysadmin`
+    /// or `teaching mode with caveats`
+    pub fn persona(mut self, persona: impl Into<String>) -> Self {
+        self.persona = Some(persona.into());
+        self
+    }
+
+    /// Whether to ask the model's clarifying questions on the terminal; when off,
+    /// or without a terminal, a question comes back as a refusal carrying it
+    pub fn questions(mut self, enabled: bool) -> Self {
+        self.questions = enabled;
+        self
+    }
+
+    /// The shell to write commands for (zsh unless set)
+    pub fn shell(mut self, shell: TargetShell) -> Self {
+        self.shell = shell;
+        self
+    }
+
+    /// Send requests to `provider` instead
+    pub fn with_provider(mut self, provider: Provider) -> Self {
+        self.provider = provider;
+        self
+    }
+
+    pub fn provider(&self) -> Provider {
+        self.provider
+    }
+
+    pub fn target_shell(&self) -> TargetShell {
+        self.shell
+    }
+
+    pub fn shows_spinner(&self) -> bool {
+        self.spinner
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// This client switched to the provider after the current one in the fallback chain
+    fn next_in_chain(&self) -> Option<SmartshellClient> {
+        let next = match self.fallback.iter().position(|p| *p == self.provider) {
+            Some(i) => self.fallback.get(i + 1),
+            None => self.fallback.first(),
+        }?;
+        let mut client = self.clone();
+        client.provider = *next;
+        Some(client)
+    }
+
+    pub(crate) fn api_key_for(&self, provider: Provider) -> Option<String> {
+        self.api_key
+            .clone()
+            .filter(|_| provider == self.provider)
+            .or_else(|| get_api_key(provider))
+    }
+
+    /// Generate a command for `request`, then repair and lint it and check it
+    /// against the sudo, prompt-injection and policy rules
+    pub fn complete(&self, request: &CompletionRequest) -> Outcome {
+        let _status = StatusLine;
+        self.phase("gathering context…");
+        let context_span = telemetry::span("gather_context");
+        let policy = Policy::load()?;
+        let buffer = request.context.read()?;
+        let (intro, prompt) =
+            completion_prompt(request, &buffer, self.language.as_deref(), self.shell);
+        drop(context_span);
+        // Probes run commands and ask the model about them, so there is nothing to reuse
+        let mut untrusted = buffer.clone();
+        let first = if request.probe && !self.offline {
+            probe_call(self, &intro, &prompt, &mut untrusted)
+        } else {
+            self.call(
+                &intro,
+                &prompt,
+                CallOptions {
+                    semantic: buffer.is_empty(),
+                    ..Default::default()
+                },
+            )
+        };
+        first
+            .and_then(|r| self.clarify(&intro, &prompt, r))
+            .and_then(|r| self.finish(request, &policy, &untrusted, &intro, &prompt, r))
+    }
+
+    /// Break `request` into steps, each a command with a note, run through the
+    /// same checks as `complete` (which see every step's command). Probing is not
+    /// supported here.
+    pub fn plan(&self, request: &CompletionRequest) -> Outcome {
+        let mut planner = self.clone();
+        planner.schema = Schema::Plan;
+        let _status = StatusLine;
+        planner.phase("gathering context…");
+        let context_span = telemetry::span("gather_context");
+        let policy = Policy::load()?;
+        let buffer = request.context.read()?;
+        let (intro, prompt) = plan_prompt(request, &buffer, self.language.as_deref(), self.shell);
+        drop(context_span);
+        let mut resp = planner
+            .llm_api_call(&intro, &prompt)
+            .and_then(|r| planner.finish(request, &policy, &buffer, &intro, &prompt, r))?;
+        // A model that ignored the schema still answered with one command
+        if resp.steps.is_empty() && !resp.result.is_empty() {
+            resp.steps.push(Step {
+                command: resp.result.clone(),
+                note: resp.explanation.clone().unwrap_or_default(),
+            });
+        }
+        Ok(resp)
+    }
+
+    /// If the model asked a question instead of answering, put it to the user and
+    /// ask once more with their answer
+    fn clarify(&self, intro: &str, prompt: &str, resp: LlmResponse) -> Outcome {
+        let Some(question) = resp.question else {
+            return Ok(resp);
+        };
+        let answer = if self.questions {
+            prompt_tty(&format!("{} ", question)).filter(|a| !a.is_empty())
+        } else {
+            None
+        };
+        let Some(answer) = answer else {
+            return Err(Failure::refused(question));
+        };
+        log_entry("clarify", &question, &answer);
+        let follow_up = format!(
+            "{}\n\nYou asked: {}\nThe user answered: {}\nReturn the command now, without another question.",
+            prompt, question, answer
+        );
+        let resp = self.llm_api_call(intro, &follow_up)?;
+        match resp.question {
+            Some(question) => Err(Failure::refused(question)),
+            None => Ok(resp),
+        }
+    }
+
+    /// Sample up to `n` alternative commands for `request` concurrently and return
+    /// the distinct ones that pass the same checks as `complete`. Candidates that
+    /// only differ in spelling (quoting, spacing, flag order) count as one.
+    /// Fails with the first failure if none pass. Probing is not supported here.
+    pub fn complete_candidates(
+        &self,
+        request: &CompletionRequest,
+        n: usize,
+    ) -> Result<Vec<LlmResponse>, Failure> {
+        if request.probe {
+            return Err(Failure::InvalidInput(
+                "Probing cannot be combined with multiple candidates".to_string(),
+            ));
+        }
+        if self.offline {
+            return Err(Failure::Offline);
+        }
+        let mut seen = std::collections::HashSet::new();
+        let mut candidates = Vec::new();
+        let mut first_failure = None;
+        for sample in self.checked_samples(request, n)? {
+            match sample {
+                Ok(resp) => {
+                    if seen.insert(canonical_command(&resp.result)) {
+                        candidates.push(resp);
+                    }
+                }
+                Err(failure) => {
+                    first_failure.get_or_insert(failure);
+                }
+            }
+        }
+        match first_failure {
+            Some(failure) if candidates.is_empty() => Err(failure),
+            _ => Ok(candidates),
+        }
+    }
+
+    /// Sample several answers for `request` (SMSH_CAREFUL_SAMPLES, 3 to 9, default
+    /// 5) and return the command most of them agree on, up to
+    /// spelling. Without a majority, the distinct commands come back instead, the
+    /// most frequent first, each flagged with its share of the votes so it must be
+    /// confirmed. Fails with the first failure if no sample passes the checks.
+    pub fn complete_careful(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<Vec<LlmResponse>, Failure> {
+        if request.probe {
+            return Err(Failure::InvalidInput(
+                "Probing cannot be combined with --careful".to_string(),
+            ));
+        }
+        if self.offline {
+            return Err(Failure::Offline);
+        }
+        let n = env_parse("SMSH_CAREFUL_SAMPLES")
+            .unwrap_or(CAREFUL_SAMPLES)
+            .clamp(3, 9);
+        let samples = self.checked_samples(request, n)?;
+        let total = samples.len();
+        // (canonical form, votes, first response with that form)
+        let mut tally: Vec<(String, usize, LlmResponse)> = Vec::new();
+        let mut first_failure = None;
+        for sample in samples {
+            match sample {
+                Ok(resp) => {
+                    let form = canonical_command(&resp.result);
+                    match tally.iter_mut().find(|(f, _, _)| *f == form) {
+                        Some((_, votes, _)) => *votes += 1,
+                        None => tally.push((form, 1, resp)),
+                    }
+                }
+                Err(failure) => {
+                    first_failure.get_or_insert(failure);
+                }
+            }
+        }
+        tally.sort_by_key(|(_, votes, _)| std::cmp::Reverse(*votes));
+        match (tally.first(), first_failure) {
+            (None, Some(failure)) => return Err(failure),
+            (Some((_, votes, _)), _) if votes * 2 > total => {
+                trace(1, format_args!("{} of {} samples agree", votes, total));
+                let (_, _, resp) = tally.swap_remove(0);
+                return Ok(vec![resp]);
+            }
+            _ => {}
+        }
+        trace(
+            1,
+            format_args!("{} samples gave {} different commands", total, tally.len()),
+        );
+        Ok(tally
+            .into_iter()
+            .map(|(_, votes, mut resp)| {
+                resp.flagged.push(format!(
+                    "samples disagree: {} of {} chose this command",
+                    votes, total
+                ));
+                resp
+            })
+            .collect())
+    }
+
+    /// Sample `n` answers concurrently and put each through the checks of
+    /// `complete`
+    fn checked_samples(
+        &self,
+        request: &CompletionRequest,
+        n: usize,
+    ) -> Result<Vec<Outcome>, Failure> {
+        let _status = StatusLine;
+        self.phase("gathering context…");
+        let context_span = telemetry::span("gather_context");
+        let policy = Policy::load()?;
+        let buffer = request.context.read()?;
+        let (intro, prompt) =
+            completion_prompt(request, &buffer, self.language.as_deref(), self.shell);
+        drop(context_span);
+        check_budget()?;
+        self.trace_request(&intro, &prompt);
+        self.phase(&format!("waiting for {}…", self.provider.as_str()));
+        let mut span = telemetry::client_span("provider_call");
+        span.set_provider(self.provider);
+        span.set("smartshell.candidates", n as u64);
+        let samples = self
+            .run(provider_candidates(self, &intro, &prompt, n.max(1)))
+            .inspect_err(|failure| span.fail(failure))?;
+        drop(span);
+        Ok(samples
+            .into_iter()
+            .map(|sample| self.check_sample(request, &policy, &buffer, &intro, &prompt, sample))
+            .collect())
+    }
+
+    /// Complete each of `requests` as `complete` does, up to `jobs` at a time, each
+    /// with its own request id. `each` gets every outcome with its index, the
+    /// tokens it used and how long it took, in the order of `requests`, as soon
+    /// as the ones before it are done. Ctrl-C stops
+    /// new requests from starting.
+    pub fn complete_batch(
+        &self,
+        requests: &[CompletionRequest],
+        jobs: usize,
+        mut each: impl FnMut(usize, Outcome, Usage, Duration),
+    ) {
+        let next = AtomicUsize::new(0);
+        let cancelled = AtomicBool::new(false);
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            for _ in 0..jobs.clamp(1, requests.len().max(1)) {
+                let (tx, next, cancelled) = (tx.clone(), &next, &cancelled);
+                scope.spawn(move || {
+                    while !cancelled.load(Ordering::Relaxed) {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(request) = requests.get(i) else {
+                            break;
+                        };
+                        let started = Instant::now();
+                        let (outcome, usage) =
+                            with_new_request_id(|| measure_request(|| self.complete(request)));
+                        if matches!(outcome, Err(Failure::Cancelled)) {
+                            cancelled.store(true, Ordering::Relaxed);
+                        }
+                        if tx.send((i, (outcome, usage, started.elapsed()))).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+            // Outcomes that finished ahead of an earlier one wait here
+            let mut waiting = std::collections::BTreeMap::new();
+            let mut done = 0;
+            for (i, answer) in rx {
+                waiting.insert(i, answer);
+                while let Some((outcome, usage, latency)) = waiting.remove(&done) {
+                    each(done, outcome, usage, latency);
+                    done += 1;
+                }
+            }
+        });
+    }
+
+    /// Ask for one command per approach in `CREATIVE_STYLES` (coreutils, a script
+    /// one-liner, a specialized tool) at a high temperature, and return the
+    /// distinct ones that pass the checks of `complete`, each explained by what it
+    /// relies on. Fails with the first failure if none pass. Probing is not
+    /// supported here.
+    pub fn complete_creative(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<Vec<LlmResponse>, Failure> {
+        if request.probe {
+            return Err(Failure::InvalidInput(
+                "Probing cannot be combined with --creative".to_string(),
+            ));
+        }
+        if self.offline {
+            return Err(Failure::Offline);
+        }
+        let _status = StatusLine;
+        self.phase("gathering context…");
+        let context_span = telemetry::span("gather_context");
+        let policy = Policy::load()?;
+        let buffer = request.context.read()?;
+        let (intro, prompt) =
+            completion_prompt(request, &buffer, self.language.as_deref(), self.shell);
+        drop(context_span);
+        let intros: Vec<String> = CREATIVE_STYLES
+            .iter()
+            .map(|style| {
+                format!(
+                    "{} This answer must take one particular approach: {} \
+                    Say in explanation which tool or language it relies on.",
+                    intro, style
+                )
+            })
+            .collect();
+        check_budget()?;
+        self.trace_request(&intros[0], &prompt);
+        self.phase(&format!("waiting for {}…", self.provider.as_str()));
+        let mut span = telemetry::client_span("provider_call");
+        span.set_provider(self.provider);
+        span.set("smartshell.candidates", intros.len() as u64);
+        let variants = self
+            .run(provider_variants(self, &intros, &prompt))
+            .inspect_err(|failure| span.fail(failure))?;
+        drop(span);
+        let mut seen = std::collections::HashSet::new();
+        let mut distinct = Vec::new();
+        let mut first_failure = None;
+        for (variant, intro) in variants.into_iter().zip(&intros) {
+            match self.check_sample(request, &policy, &buffer, intro, &prompt, variant) {
+                Ok(resp) => {
+                    if seen.insert(canonical_command(&resp.result)) {
+                        distinct.push(resp);
+                    }
+                }
+                Err(failure) => {
+                    first_failure.get_or_insert(failure);
+                }
+            }
+        }
+        match first_failure {
+            Some(failure) if distinct.is_empty() => Err(failure),
+            _ => Ok(distinct),
+        }
+    }
+
+    /// Put one sampled answer through the checks of `complete`; a clarifying
+    /// question counts as a refusal, as there is no one to ask about several
+    /// answers at once
+    fn check_sample(
+        &self,
+        request: &CompletionRequest,
+        policy: &Policy,
+        buffer: &str,
+        intro: &str,
+        prompt: &str,
+        sample: Outcome,
+    ) -> Outcome {
+        sample
+            .and_then(|r| match r.question {
+                Some(question) => Err(Failure::refused(question)),
+                None => Ok(r),
+            })
+            .and_then(|r| self.finish(request, policy, buffer, intro, prompt, r))
+            .map(|mut resp| {
+                resp.provider.get_or_insert(self.provider);
+                resp
+            })
+    }
+
+    /// Start answering `request` ahead of time so a later `complete` for the same
+    /// request finds the raw answer waiting: in a running daemon if there is one,
+    /// otherwise in the response cache. Nothing is sent if it is already cached,
+    /// or if there is no daemon and the cache keeps nothing (see `caches_answers`).
+    pub fn prefetch(&self, request: &CompletionRequest) -> Result<(), Failure> {
+        let _status = StatusLine;
+        let buffer = request.context.read()?;
+        let (intro, prompt) =
+            completion_prompt(request, &buffer, self.language.as_deref(), self.shell);
+        let cache = cache_path(self.provider, &intro, &prompt);
+        if self.offline
+            || cache
+                .as_deref()
+                .and_then(|p| cache_lookup(p, cache_ttl()))
+                .is_some()
+        {
+            return Ok(());
+        }
+        check_budget()?;
+        if self.run(async { Ok(daemon_prefetch(self, &intro, &prompt).await) })? {
+            return Ok(());
+        }
+        if !caches_answers() {
+            trace(
+                1,
+                format_args!("prefetch skipped: nothing is cached unless SMSH_LOG_MODE is full"),
+            );
+            return Ok(());
+        }
+        self.llm_api_call(&intro, &prompt).map(|_| ())
+    }
+
+    /// Repair, lint and check a freshly generated answer, built from `untrusted`
+    /// input (the buffer and any probe output)
+    fn finish(
+        &self,
+        request: &CompletionRequest,
+        policy: &Policy,
+        untrusted: &str,
+        intro: &str,
+        prompt: &str,
+        resp: LlmResponse,
+    ) -> Outcome {
+        self.phase("validating…");
+        let mut span = telemetry::span("validate");
+        sanitize_response(resp)
+            .and_then(|r| repair_syntax(self, intro, prompt, r))
+            .and_then(|r| {
+                if request.lint {
+                    repair_lint(self, intro, prompt, r)
+                } else {
+                    Ok(r)
+                }
+            })
+            .and_then(|r| review(self, intro, prompt, r))
+            .and_then(check_privileges)
+            .and_then(|r| guard_injection(r, untrusted))
+            .and_then(|r| policy.apply(r))
+            .inspect_err(|failure| span.fail(failure))
+    }
+
+    /// Explain a command line in a single sentence
+    pub fn explain(&self, command: &str) -> Outcome {
+        self.explain_with_detail(command, Detail::Brief)
+    }
+
+    /// Explain a command line at the given level of detail
+    pub fn explain_with_detail(&self, command: &str, detail: Detail) -> Outcome {
+        let _status = StatusLine;
+        let opts = CallOptions {
+            stream: true,
+            ..Default::default()
+        };
+        let mut resp = if command.lines().count() > LONG_SCRIPT_LINES {
+            self.explain_script(command, detail, opts)?
+        } else {
+            let intro = format!(
+                "Explain {} commands. {} {} {}{}{}{}",
+                self.shell.name(),
+                detail.instructions(),
+                UNTRUSTED_NOTICE,
+                get_os_context(),
+                language_instruction(self.language.as_deref()),
+                persona_instruction(self.persona.as_deref()),
+                custom_instructions()
+            );
+            self.call(&intro, &untrusted_block("command", command), opts)?
+        };
+        if let Some(line) = injection_lines(command).first() {
+            resp.flagged.push(format!(
+                "possible prompt injection in input: {}",
+                line.trim()
+            ));
+        }
+        Ok(resp)
+    }
+
+    /// Explain a script too long for one answer: summarize each section, then
+    /// explain the whole from the summaries. At `normal` and `deep` detail the
+    /// breakdown lists the sections (`lines 1-120`) instead of every flag.
+    fn explain_script(&self, script: &str, detail: Detail, opts: CallOptions) -> Outcome {
+        let sections = script_sections(script);
+        let total = script.lines().count();
+        trace(
+            1,
+            format_args!("{} lines, explained in {} sections", total, sections.len()),
+        );
+        let mut summaries = Vec::with_capacity(sections.len());
+        for (first, last, text) in &sections {
+            self.phase(&format!(
+                "explaining lines {}-{} of {}…",
+                first, last, total
+            ));
+            let intro = format!(
+                "Summarize one section of a longer {} script, lines {} to {} of {}. In the \
+                result field, say in two to four sentences what this section does, naming the \
+                functions, commands and side effects (files written, services touched, \
+                network access) that matter. Leave breakdown empty. {} {}{}",
+                self.shell.name(),
+                first,
+                last,
+                total,
+                UNTRUSTED_NOTICE,
+                get_os_context(),
+                language_instruction(self.language.as_deref())
+            );
+            let resp = self.call(
+                &intro,
+                &untrusted_block("script", text),
+                CallOptions::default(),
+            )?;
+            summaries.push(TokenMeaning {
+                token: format!("lines {}-{}", first, last),
+                meaning: resp.result.split_whitespace().collect::<Vec<_>>().join(" "),
+            });
+        }
+        self.phase("summarizing…");
+        let intro = format!(
+            "Explain a long {} script from summaries of its sections, in order. {} {} {}{}{}{}",
+            self.shell.name(),
+            detail.script_instructions(),
+            UNTRUSTED_NOTICE,
+            get_os_context(),
+            language_instruction(self.language.as_deref()),
+            persona_instruction(self.persona.as_deref()),
+            custom_instructions()
+        );
+        let listed: Vec<_> = summaries
+            .iter()
+            .map(|s| format!("{}: {}", s.token, s.meaning))
+            .collect();
+        let mut resp = self.call(
+            &intro,
+            &untrusted_block("summaries", &listed.join("\n")),
+            opts,
+        )?;
+        resp.breakdown = match detail {
+            Detail::Brief => Vec::new(),
+            Detail::Normal | Detail::Deep => summaries,
+        };
+        Ok(resp)
+    }
+
+    /// Explain `script` line by line: the breakdown holds one annotation per line
+    /// or block, with its line numbers (`12`, `12-18`) as the token, in order,
+    /// and the result summarizes the script. Long scripts are annotated a
+    /// section at a time.
+    pub fn explain_annotated(&self, script: &str) -> Outcome {
+        let _status = StatusLine;
+        let numbered: Vec<String> = script
+            .lines()
+            .enumerate()
+            .map(|(i, line)| format!("{:>5}  {}", i + 1, line))
+            .collect();
+        let sections = if numbered.len() > LONG_SCRIPT_LINES {
+            script_sections(script)
+        } else {
+            vec![(1, numbered.len(), String::new())]
+        };
+        let mut annotated: Option<LlmResponse> = None;
+        let mut summaries = Vec::new();
+        for (first, last, _) in &sections {
+            if sections.len() > 1 {
+                self.phase(&format!(
+                    "annotating lines {}-{} of {}…",
+                    first,
+                    last,
+                    numbered.len()
+                ));
+            }
+            let intro = format!(
+                "Annotate a {} script, given with its line numbers, for someone reading \
+                it for the first time. In breakdown, add one entry per line or block of \
+                related lines (a function header, a loop, a heredoc, a command continued \
+                over several lines), in order, for every line that does something; skip \
+                blank lines and comments. Set token to the line number or range, as 12 \
+                or 12-18, and meaning to one or two sentences on what it does and why it \
+                matters, including side effects and risky parts. In the result field, \
+                summarize in one sentence what these lines do. {} {}{}{}{}",
+                self.shell.name(),
+                UNTRUSTED_NOTICE,
+                get_os_context(),
+                language_instruction(self.language.as_deref()),
+                persona_instruction(self.persona.as_deref()),
+                custom_instructions()
+            );
+            let text = numbered[first - 1..*last].join("\n");
+            let resp = self.call(
+                &intro,
+                &untrusted_block("script", &text),
+                CallOptions::default(),
+            )?;
+            summaries.push(if sections.len() > 1 {
+                format!("Lines {}-{}: {}", first, last, resp.result.trim())
+            } else {
+                resp.result.trim().to_string()
+            });
+            match annotated.as_mut() {
+                Some(all) => all.breakdown.extend(resp.breakdown),
+                None => annotated = Some(resp),
+            }
+        }
+        let mut resp =
+            annotated.ok_or_else(|| Failure::InvalidInput("Nothing to explain.".to_string()))?;
+        let total = numbered.len();
+        // Annotations of lines the script doesn't have, or out of order, can't be placed
+        let mut placed = 0;
+        resp.breakdown.retain(|a| match a.lines() {
+            Some((first, last)) if first > placed && last <= total => {
+                placed = last;
+                true
+            }
+            _ => {
+                trace(1, format_args!("dropped annotation for `{}`", a.token));
+                false
+            }
+        });
+        resp.result = summaries.join("\n");
+        if let Some(line) = injection_lines(script).first() {
+            resp.flagged.push(format!(
+                "possible prompt injection in input: {}",
+                line.trim()
+            ));
+        }
+        Ok(resp)
+    }
+
+    /// Write a new `tool` target for `query` in the style of `existing`, the build
+    /// file's text, or in the tool's usual style when that is empty. The result
+    /// is the target alone, ready to append to the file.
+    pub fn build_target(&self, query: &str, tool: BuildTool, existing: &str) -> Outcome {
+        let _status = StatusLine;
+        let rules = match tool {
+            BuildTool::Make => {
+                "Recipe lines start with a tab. Use $(VAR) for make variables and $$ for \
+                a literal shell $. Each recipe line runs in its own shell, so join commands \
+                that depend on each other with && or a trailing backslash."
+            }
+            BuildTool::Just => {
+                "Indent recipe lines the way the file does. Parameters follow the recipe \
+                name (name arg='default':), {{variable}} interpolates just variables and \
+                parameters, and a # comment right above a recipe is its documentation."
+            }
+        };
+        let intro = format!(
+            "Write one new {} target for the request, to be appended to the existing file. \
+            Match the file's style: target naming, indentation, .PHONY declarations, \
+            comments above targets and how recipes echo or silence commands. Reuse the \
+            variables it defines instead of hardcoding their values, and depend on existing \
+            targets that already do part of the job. {} Put only the new target in the \
+            result field, with its .PHONY line or comment if the file uses them, not the \
+            rest of the file. In explanation, say in one line what the target does. Leave \
+            breakdown empty. If the request is not something a build target can do, set \
+            error=true and explain why in result. {} {}{}{}",
+            tool.name(),
+            rules,
+            UNTRUSTED_NOTICE,
+            get_os_context(),
+            language_instruction(self.language.as_deref()),
+            custom_instructions()
+        );
+        let prompt = if existing.trim().is_empty() {
+            format!(
+                "{}\n\nThere is no {} yet: write the target in its most common style.",
+                query,
+                tool.name()
+            )
+        } else {
+            format!(
+                "{}\n\nThe existing {}:\n{}",
+                query,
+                tool.name(),
+                untrusted_block("buildfile", &redact(existing))
+            )
+        };
+        let mut resp = self.call(&intro, &prompt, CallOptions::default())?;
+        let target = fenced(&resp.result)
+            .unwrap_or(&resp.result)
+            .trim_matches('\n');
+        resp.result = match tool {
+            BuildTool::Make => recipe_tabs(target),
+            BuildTool::Just => target.to_string(),
+        };
+        if let Some(line) = injection_lines(existing).first() {
+            resp.flagged.push(format!(
+                "possible prompt injection in input: {}",
+                line.trim()
+            ));
+        }
+        Ok(resp)
+    }
+
+    /// Propose zsh aliases or functions for commands that are typed over and over
+    pub fn suggest_aliases(&self, commands: &[Frequent]) -> Outcome {
+        let _status = StatusLine;
+        let intro = format!(
+            "Suggest zsh aliases or shell functions for commands the user types often. \
+             Each line of the input is a command and how many times it was run. Reply \
+             with definitions ready to paste into ~/.zshrc, one per command worth \
+             shortening, each preceded by a `#` comment saying what it replaces. Use a \
+             function when parts of the command vary (paths, names, numbers) and an \
+             alias otherwise. Pick short, memorable names that don't shadow existing \
+             commands. {} {}{}",
+            UNTRUSTED_NOTICE,
+            get_os_context(),
+            language_instruction(self.language.as_deref())
+        );
+        let list: Vec<String> = commands
+            .iter()
+            .map(|f| format!("{}  ({} times)", redact(&f.command), f.count))
+            .collect();
+        self.call(
+            &intro,
+            &untrusted_block("commands", &list.join("\n")),
+            CallOptions::default(),
+        )
+    }
+
+    pub(crate) fn llm_api_call(&self, intro: &str, prompt: &str) -> Outcome {
+        self.call(intro, prompt, CallOptions::default())
+    }
+
+    /// Answer the request and note which provider the answer came from
+    pub(crate) fn call(&self, intro: &str, prompt: &str, opts: CallOptions) -> Outcome {
+        self.trace_request(intro, prompt);
+        let started = Instant::now();
+        let mut span = telemetry::client_span("provider_call");
+        span.set_provider(self.provider);
+        let mut resp = self
+            .answer(intro, prompt, opts)
+            .inspect_err(|failure| span.fail(failure))?;
+        span.set("smartshell.cached", resp.cached);
+        resp.provider.get_or_insert(self.provider);
+        trace(
+            1,
+            format_args!("answered in {}ms", started.elapsed().as_millis()),
+        );
+        Ok(resp)
+    }
+
+    /// Show the provider, model and (at `-vv`) the prompts about to be sent
+    fn trace_request(&self, intro: &str, prompt: &str) {
+        trace(
+            1,
+            format_args!(
+                "provider {}, model {}",
+                self.provider.as_str(),
+                self.provider.model()
+            ),
+        );
+        trace(2, format_args!("system prompt:\n{}", intro));
+        trace(2, format_args!("user prompt:\n{}", prompt));
+    }
+
+    /// Answer from the exact-match cache, then (for `semantic` requests, when
+    /// enabled) from the most similar earlier query, and only then the provider.
+    /// If the provider is unreachable, retry offline.
+    fn answer(&self, intro: &str, prompt: &str, opts: CallOptions) -> Outcome {
+        // Canned answers need none of the caching, budget or network handling
+        if !self.provider.is_remote() {
+            return runtime().block_on(provider_call(self, intro, prompt));
+        }
+        let cache = cache_path(self.provider, intro, prompt);
+        let use_cache = self.cache || self.offline;
+        let ttl = if self.offline { u64::MAX } else { cache_ttl() };
+        if let Some(hit) = cache
+            .as_deref()
+            .filter(|_| use_cache)
+            .and_then(|p| cache_lookup(p, ttl))
+        {
+            trace(1, format_args!("answered from the response cache"));
+            return Ok(hit);
+        }
+        let embedder = semantic_embedder().filter(|_| opts.semantic);
+        let scope = format!(
+            "{}\0{}\0{}\0{}",
+            embedder.as_deref().unwrap_or_default(),
+            self.provider.as_str(),
+            self.provider.model(),
+            sha256_hex(intro.as_bytes())
+        );
+        let embedding = match embedder.as_deref() {
+            Some("openai") if self.offline => None,
+            // Other providers' queries don't go to OpenAI just to be compared
+            Some("openai") if self.provider != Provider::OpenAi => {
+                trace(
+                    1,
+                    format_args!(
+                        "semantic cache skipped: OpenAI embeddings are only used with the openai provider"
+                    ),
+                );
+                None
+            }
+            Some("openai") => {
+                self.phase("matching earlier queries…");
+                self.run(openai_embedding(self, prompt)).ok()
+            }
+            Some(_) => Some(local_embedding(prompt)),
+            None => None,
+        };
+        if let Some(ref embedding) = embedding {
+            if use_cache {
+                if let Some(hit) = semantic_lookup(&scope, prompt, embedding, ttl) {
+                    trace(1, format_args!("answered from the semantic cache"));
+                    return Ok(hit);
+                }
+            }
+        }
+        if self.offline {
+            return Err(Failure::Offline);
+        }
+        if let Some(retry_in) = circuit_open(self.provider) {
+            trace(
+                1,
+                format_args!(
+                    "circuit open for {} for another {}s",
+                    self.provider.as_str(),
+                    retry_in.as_secs()
+                ),
+            );
+            return match self.next_in_chain() {
+                Some(next) => next.fail_over(self.provider, intro, prompt, opts),
+                None => Err(Failure::Unavailable {
+                    provider: self.provider,
+                    retry_in,
+                }),
+            };
+        }
+        check_budget()?;
+        self.phase(&format!("waiting for {}…", self.provider.as_str()));
+        let outcome = self.run(async {
+            let request = async {
+                if let Some(tty) = stream_tty().filter(|_| opts.stream && self.spinner) {
+                    return stream_call(self, intro, prompt, tty).await;
+                }
+                if let Some(outcome) = daemon_call(self, intro, prompt).await {
+                    trace(1, format_args!("answered by the daemon"));
+                    return outcome;
+                }
+                provider_call(self, intro, prompt).await
+            };
+            let outcome = request.await;
+            // A network error on a dead link is reported as offline, so the
+            // caches can answer instead
+            if let Err(Failure::Network { .. }) = outcome {
+                if !provider_reachable(self.provider).await {
+                    return Err(Failure::Offline);
+                }
+            }
+            outcome
+        });
+        match outcome {
+            Err(Failure::Offline) => {
+                trace(1, format_args!("provider unreachable, answering offline"));
+                self.clone().offline(true).call(intro, prompt, opts)
+            }
+            Err(failure) if trips_breaker(&failure) && record_failure(self.provider) => {
+                match self.next_in_chain() {
+                    Some(next) => next.fail_over(self.provider, intro, prompt, opts),
+                    None => Err(failure),
+                }
+            }
+            Ok(resp) => {
+                record_success(self.provider);
+                record_exchange(self.provider, intro, prompt, &resp);
+                if let Some(path) = &cache {
+                    cache_store(path, &resp);
+                }
+                if let Some(ref embedding) = embedding {
+                    semantic_store(&scope, prompt, embedding, &resp);
+                }
+                Ok(resp)
+            }
+            Err(failure) => Err(failure),
+        }
+    }
+
+    /// Answer with this (fallback) client because `from`'s circuit is open
+    fn fail_over(&self, from: Provider, intro: &str, prompt: &str, opts: CallOptions) -> Outcome {
+        log_event(
+            "breaker",
+            from.as_str(),
+            &format!("failing over to {}", self.provider.as_str()),
+        );
+        trace(
+            1,
+            format_args!(
+                "failing over from {} to {}",
+                from.as_str(),
+                self.provider.as_str()
+            ),
+        );
+        self.call(intro, prompt, opts)
+    }
+
+    /// Show `phase` ("waiting for claude…") on the status line until the next one.
+    /// Work between requests, such as validation, gets a still spinner.
+    pub(crate) fn phase(&self, phase: &str) {
+        *PHASE.lock().unwrap() = phase.to_string();
+        self.draw_status(0);
+    }
+
+    /// Draw spinner frame `frame` and the current phase, opening the status line
+    /// if there is none yet. Plain mode and a disabled spinner draw nothing.
+    fn draw_status(&self, frame: usize) {
+        if !self.spinner || style::plain() {
+            return;
+        }
+        let mut status = STATUS.lock().unwrap();
+        if status.is_none() {
+            *status = Console::open().map(|mut tty| {
+                tty.show_cursor(false);
+                tty
+            });
+        }
+        let Some(tty) = status.as_mut() else {
+            return;
+        };
+        let frames = spinner_frames();
+        let glyph = frames.get(frame % frames.len().max(1));
+        let phase = PHASE.lock().unwrap();
+        let text = match (glyph, phase.is_empty()) {
+            (Some(glyph), false) => format!("{} {}", glyph, phase),
+            (Some(glyph), true) => glyph.clone(),
+            (None, _) => phase.clone(),
+        };
+        tty.status(&text);
+    }
+
+    /// Drive `fut` to completion while animating the spinner on the status line.
+    /// Ctrl-C or the timeout drops the future, which aborts any in-flight request.
+    pub(crate) fn run<T>(
+        &self,
+        fut: impl std::future::Future<Output = Result<T, Failure>>,
+    ) -> Result<T, Failure> {
+        STREAMING.store(false, std::sync::atomic::Ordering::Relaxed);
+        let started = Instant::now();
+        let ctrl_c = CtrlCGuard::new();
+        let result = runtime().block_on(async {
+            tokio::pin!(fut);
+            let deadline = tokio::time::sleep(self.timeout);
+            tokio::pin!(deadline);
+            let mut ticker = tokio::time::interval(Duration::from_millis(100));
+            let mut idx = 0;
+            loop {
+                tokio::select! {
+                    result = &mut fut => break result,
+                    _ = tokio::signal::ctrl_c() => break Err(Failure::Cancelled),
+                    _ = &mut deadline => break Err(Failure::Timeout {
+                        provider: self.provider,
+                        after: self.timeout,
+                    }),
+                    _ = ticker.tick() => {
+                        if STREAMING.load(std::sync::atomic::Ordering::Relaxed) {
+                            continue;
+                        }
+                        self.draw_status(idx);
+                        idx += 1;
+                    }
+                }
+            }
+        });
+        drop(ctrl_c);
+        clear_status();
+        // Plain mode has no status line but still gets the slow-request bell
+        if let Some(ref mut t) = Console::open().filter(|_| self.spinner) {
+            let notify_after = env_parse("SMSH_NOTIFY_AFTER").unwrap_or(DEFAULT_NOTIFY_AFTER_SECS);
+            let slow = notify_after > 0 && started.elapsed() >= Duration::from_secs(notify_after);
+            if slow && !matches!(result, Err(Failure::Cancelled)) {
+                notify_done(t, result.is_ok());
+            }
+        }
+        result
+    }
+}
+
+/// Tell the user a slow request finished: a notification on macOS, the terminal
+/// bell elsewhere or when the notification can't be shown
+fn notify_done(tty: &mut Console, ok: bool) {
+    let message = if ok { "Answer ready" } else { "Request failed" };
+    let script = format!(
+        "display notification \"{}\" with title \"smartshell\"",
+        message
+    );
+    let notified = cfg!(target_os = "macos")
+        && Command::new("osascript")
+            .args(["-e", &script])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|s| s.success());
+    if !notified {
+        let _ = write!(tty, "\x07");
+        let _ = tty.flush();
+    }
+}
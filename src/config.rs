@@ -0,0 +1,833 @@
+//! Environment, directories and the debug log
+
+use crate::audit::{hostname, sha256_hex};
+use crate::logrotate;
+use crate::provider::Provider;
+use crate::redact::{redact, redact_for_log};
+use crate::spend::Usage;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::time::Duration;
+
+pub(crate) fn get_os_context() -> String {
+    if cfg!(target_os = "macos") {
+        "The target system is macOS.".to_string()
+    } else if cfg!(target_os = "linux") && is_wsl() {
+        WSL_CONTEXT.to_string()
+    } else if cfg!(target_os = "linux") {
+        "The target system is Linux.".to_string()
+    } else if cfg!(windows) {
+        windows_context().to_string()
+    } else if cfg!(any(
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    )) {
+        bsd_context().to_string()
+    } else {
+        String::new()
+    }
+}
+
+/// The BSD's name, release, package manager and rc system; its userland is not
+/// GNU, so `sed -i`, `find -printf`, `ls --color` and `grep -P` don't carry over
+fn bsd_context() -> &'static str {
+    static CONTEXT: OnceLock<String> = OnceLock::new();
+    CONTEXT.get_or_init(bsd_description)
+}
+
+fn bsd_description() -> String {
+    let (name, specifics) = if cfg!(target_os = "freebsd") {
+        (
+            "FreeBSD",
+            "Install packages with pkg (pkg install, pkg info) or from ports. \
+            Manage services with service NAME start|stop|status and enable them in \
+            /etc/rc.conf with sysrc NAME_enable=YES. sed -i needs an explicit \
+            suffix argument, as in sed -i '' 's/a/b/' file.",
+        )
+    } else if cfg!(target_os = "openbsd") {
+        (
+            "OpenBSD",
+            "Install packages with pkg_add and list them with pkg_info. Manage \
+            services with rcctl (rcctl enable|start|check NAME). Use doas, not sudo, \
+            for root.",
+        )
+    } else {
+        (
+            "NetBSD",
+            "Install packages with pkgin (or pkg_add) from pkgsrc. Services are \
+            /etc/rc.d scripts, enabled with NAME=YES in /etc/rc.conf and run with \
+            service NAME start|stop|status.",
+        )
+    };
+    let release = std::process::Command::new("uname")
+        .arg("-r")
+        .output()
+        .ok()
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .filter(|r| !r.is_empty());
+    format!(
+        "The target system is {}{}. It has a BSD userland, not GNU: use only BSD \
+        flags for sed, find, grep, date, stat, xargs, tar and ls (no --long options \
+        they lack); GNU versions, where installed, have a g prefix (gsed, gfind, gdate). {}",
+        name,
+        release.map(|r| format!(" {}", r)).unwrap_or_default(),
+        specifics
+    )
+}
+
+const WSL_CONTEXT: &str = "The target system is Linux running under WSL (Windows \
+    Subsystem for Linux) on a Windows host. Windows drives are mounted under /mnt \
+    (C:\\Users is /mnt/c/Users) and wslpath converts paths between the two. Windows \
+    programs run from here by their full name with .exe (explorer.exe ., code.exe, \
+    powershell.exe -Command ...). For the Windows clipboard, copy with clip.exe and \
+    paste with powershell.exe -NoProfile -Command Get-Clipboard.";
+
+/// Whether this is Linux under WSL, whose kernel names Microsoft in its version
+pub(crate) fn is_wsl() -> bool {
+    static WSL: OnceLock<bool> = OnceLock::new();
+    *WSL.get_or_init(|| {
+        cfg!(target_os = "linux")
+            && (env::var_os("WSL_DISTRO_NAME").is_some()
+                || ["/proc/sys/kernel/osrelease", "/proc/version"]
+                    .iter()
+                    .filter_map(|path| std::fs::read_to_string(path).ok())
+                    .any(|version| version.to_lowercase().contains("microsoft")))
+    })
+}
+
+/// The Windows version from `ver` and which PowerShell is installed, since
+/// Windows PowerShell 5.1 lacks `&&`, `||`, `?:` and other PowerShell 7 syntax
+fn windows_context() -> &'static str {
+    static CONTEXT: OnceLock<String> = OnceLock::new();
+    CONTEXT.get_or_init(|| {
+        let version = std::process::Command::new("cmd")
+            .args(["/C", "ver"])
+            .output()
+            .ok()
+            .and_then(|out| {
+                let text = String::from_utf8_lossy(&out.stdout).to_string();
+                text.split(|c: char| !(c.is_ascii_digit() || c == '.'))
+                    .find(|part| part.contains('.'))
+                    .map(str::to_string)
+            });
+        let windows = match version {
+            Some(version) => format!("The target system is Windows (version {}).", version),
+            None => "The target system is Windows.".to_string(),
+        };
+        let powershell = if which::which("pwsh").is_ok() {
+            " PowerShell 7 (pwsh) is installed."
+        } else {
+            " Only Windows PowerShell 5.1 is installed, not PowerShell 7."
+        };
+        format!("{}{}", windows, powershell)
+    })
+}
+
+/// The user's own prompt instructions: SMSH_PROMPT, or the SMSH_PROMPT_FILE
+/// file (default `~/.config/smartshell/prompt`)
+fn prompt_template() -> Option<String> {
+    if let Some(text) = env::var("SMSH_PROMPT")
+        .ok()
+        .filter(|t| !t.trim().is_empty())
+    {
+        return Some(text);
+    }
+    let path = env::var_os("SMSH_PROMPT_FILE")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| config_dir().map(|d| d.join("prompt")))?;
+    match std::fs::read_to_string(&path) {
+        Ok(text) => Some(text).filter(|t| !t.trim().is_empty()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            eprintln!(
+                "{}",
+                crate::style::warning(&format!("{}: {}; prompt skipped", path.display(), e))
+            );
+            None
+        }
+    }
+}
+
+/// Resolve `{{cwd}}`, `{{hostname}}`, `{{user}}`, `{{os}}`, `{{date}}` and
+/// `{{time}}` in `template` at call time. Unknown variables are left as they are,
+/// with a warning.
+pub(crate) fn interpolate(template: &str) -> String {
+    static VAR: OnceLock<regex::Regex> = OnceLock::new();
+    let var = VAR
+        .get_or_init(|| regex::Regex::new(r"\{\{\s*(\w+)\s*\}\}").expect("valid variable pattern"));
+    var.replace_all(template, |caps: &regex::Captures| {
+        let now = chrono::Local::now();
+        match &caps[1] {
+            "cwd" => env::current_dir()
+                .map(|d| d.display().to_string())
+                .unwrap_or_default(),
+            "hostname" => hostname(),
+            "user" => env::var("USER").unwrap_or_default(),
+            "os" => env::consts::OS.to_string(),
+            "date" => now.format("%Y-%m-%d (%A)").to_string(),
+            "time" => now.format("%H:%M (UTC%:z)").to_string(),
+            other => {
+                eprintln!(
+                    "{}",
+                    crate::style::warning(&format!("unknown prompt variable {{{{{}}}}}", other))
+                );
+                caps[0].to_string()
+            }
+        }
+    })
+    .into_owned()
+}
+
+/// The user's prompt instructions with their variables resolved, to append to
+/// a system prompt
+pub(crate) fn custom_instructions() -> String {
+    match prompt_template() {
+        Some(template) => format!(" {}", interpolate(template.trim())),
+        None => String::new(),
+    }
+}
+
+pub(crate) fn get_api_key(provider: Provider) -> Option<String> {
+    api_key_source(provider).map(|(_, key)| key)
+}
+
+/// The API key for `provider` and where it came from: an environment variable,
+/// the provider's key command or the keychain
+pub(crate) fn api_key_source(provider: Provider) -> Option<(String, String)> {
+    // Check env vars first
+    let vendor = provider.vendor()?.to_ascii_uppercase();
+    let vars = [
+        "SMSH_API_KEY".to_string(),
+        format!("SMSH_{}_API_KEY", vendor),
+        format!("{}_API_KEY", vendor),
+    ];
+    for var in vars {
+        if let Some(key) = env::var(&var).ok().filter(|k| !k.is_empty()) {
+            return Some((var, key));
+        }
+    }
+    if let Some((var, key)) = command_key(provider) {
+        return key.map(|key| (var, key));
+    }
+    keychain_key(provider).map(|key| ("keychain".to_string(), key))
+}
+
+/// Look up `provider`'s key with `lookup` at most once per process
+fn once_per_provider(
+    cache: &Mutex<BTreeMap<&'static str, Option<String>>>,
+    provider: Provider,
+    lookup: impl FnOnce() -> Option<String>,
+) -> Option<String> {
+    let vendor = provider.vendor()?;
+    let mut keys = cache.lock().unwrap_or_else(|e| e.into_inner());
+    keys.entry(vendor).or_insert_with(lookup).clone()
+}
+
+/// The key printed by SMSH_<VENDOR>_KEY_COMMAND (e.g. `op read
+/// op://Private/OpenAI/credential`), run at most once per process. None when no
+/// command is set; a command that fails yields no key, not the keychain's.
+fn command_key(provider: Provider) -> Option<(String, Option<String>)> {
+    static KEYS: Mutex<BTreeMap<&'static str, Option<String>>> = Mutex::new(BTreeMap::new());
+    let var = format!("{}_KEY_COMMAND", provider.env_prefix()?);
+    let command = env::var(&var).ok().filter(|c| !c.trim().is_empty())?;
+    let key = once_per_provider(&KEYS, provider, || {
+        let (shell, flag) = if cfg!(windows) {
+            ("cmd", "/C")
+        } else {
+            ("sh", "-c")
+        };
+        let out = match std::process::Command::new(shell)
+            .args([flag, &command])
+            .stdin(std::process::Stdio::null())
+            .output()
+        {
+            Ok(out) => out,
+            Err(e) => {
+                eprintln!("smartshell: {} failed to start: {}", var, e);
+                return None;
+            }
+        };
+        let key = String::from_utf8_lossy(&out.stdout).trim().to_string();
+        if !out.status.success() || key.is_empty() {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            let reason = match stderr.trim() {
+                "" => out.status.to_string(),
+                message => message.to_string(),
+            };
+            eprintln!("smartshell: {} printed no key ({})", var, redact(&reason));
+            return None;
+        }
+        Some(key)
+    });
+    Some((var, key))
+}
+
+/// The keychain service and account a provider's key is stored under, the same
+/// ones the zsh widget reads
+pub(crate) fn keychain_entry_name(provider: Provider) -> Option<(String, String)> {
+    let vendor = provider.vendor()?;
+    let prefix = format!("SMSH_{}", vendor.to_ascii_uppercase());
+    let setting = |name: &str| {
+        env::var(format!("{}_KEYCHAIN_{}", prefix, name))
+            .ok()
+            .filter(|v| !v.is_empty())
+    };
+    let service = setting("SERVICE").unwrap_or_else(|| format!("smartshell.{}", vendor));
+    let account = setting("ACCOUNT")
+        .or_else(|| env::var("USER").ok().filter(|u| !u.is_empty()))
+        .unwrap_or_else(keychain_user);
+    Some((service, account))
+}
+
+#[cfg(all(
+    any(target_os = "macos", target_os = "linux", windows),
+    feature = "keychain"
+))]
+fn keychain_user() -> String {
+    whoami::username()
+}
+
+#[cfg(not(all(
+    any(target_os = "macos", target_os = "linux", windows),
+    feature = "keychain"
+)))]
+fn keychain_user() -> String {
+    String::new()
+}
+
+/// The key stored in the macOS Keychain, the Linux Secret Service or the Windows
+/// Credential Manager, looked up at most once per process since each lookup costs
+/// tens of milliseconds
+#[cfg(all(
+    any(target_os = "macos", target_os = "linux", windows),
+    feature = "keychain"
+))]
+fn keychain_key(provider: Provider) -> Option<String> {
+    static KEYS: Mutex<BTreeMap<&'static str, Option<String>>> = Mutex::new(BTreeMap::new());
+    once_per_provider(&KEYS, provider, || {
+        let (service, account) = keychain_entry_name(provider)?;
+        keyring::Entry::new(&service, &account)
+            .ok()
+            .and_then(|entry| entry.get_password().ok())
+            .or_else(|| protected_key(service, account))
+    })
+}
+
+/// Keys stored with `auth set --require-presence` live in the data protection
+/// keychain behind Touch ID or the login password, out of the plain lookup's
+/// sight. Waits SMSH_KEYCHAIN_TIMEOUT seconds (default 30) for the user to
+/// answer the prompt; a cancelled or unanswered prompt means no key.
+#[cfg(all(target_os = "macos", feature = "keychain"))]
+fn protected_key(service: String, account: String) -> Option<String> {
+    use security_framework::passwords::{generic_password, PasswordOptions};
+    const ERR_SEC_USER_CANCELED: i32 = -128;
+    const ERR_SEC_ITEM_NOT_FOUND: i32 = -25300;
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut options = PasswordOptions::new_generic_password(&service, &account);
+        options.use_protected_keychain();
+        let _ = tx.send(generic_password(options));
+    });
+    let timeout = Duration::from_secs(env_parse("SMSH_KEYCHAIN_TIMEOUT").unwrap_or(30));
+    match rx.recv_timeout(timeout) {
+        Ok(Ok(bytes)) => String::from_utf8(bytes).ok(),
+        Ok(Err(e)) if e.code() == ERR_SEC_ITEM_NOT_FOUND => None,
+        Ok(Err(e)) if e.code() == ERR_SEC_USER_CANCELED => {
+            eprintln!("smartshell: keychain access cancelled");
+            None
+        }
+        Ok(Err(e)) => {
+            eprintln!("smartshell: keychain access failed: {}", e);
+            None
+        }
+        Err(_) => {
+            eprintln!(
+                "smartshell: no answer to the keychain prompt after {}s",
+                timeout.as_secs()
+            );
+            None
+        }
+    }
+}
+
+#[cfg(all(any(target_os = "linux", windows), feature = "keychain"))]
+fn protected_key(_service: String, _account: String) -> Option<String> {
+    None
+}
+
+#[cfg(not(all(
+    any(target_os = "macos", target_os = "linux", windows),
+    feature = "keychain"
+)))]
+fn keychain_key(_provider: Provider) -> Option<String> {
+    None
+}
+
+enum LogMessage {
+    Line(String),
+    Flush(mpsc::Sender<()>),
+}
+
+/// How long `flush_log` waits for a slow (e.g. NFS) log file before giving up
+const LOG_FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+
+static LOGGER: OnceLock<Option<mpsc::Sender<LogMessage>>> = OnceLock::new();
+
+/// Queue for the SMSH_LOG writer thread, started on first use; None if logging is off
+fn logger() -> Option<&'static mpsc::Sender<LogMessage>> {
+    LOGGER
+        .get_or_init(|| {
+            let path = env::var("SMSH_LOG").ok().filter(|p| !p.is_empty())?;
+            let (tx, rx) = mpsc::channel();
+            std::thread::Builder::new()
+                .name("smartshell-log".to_string())
+                .spawn(move || write_log(&path, rx))
+                .ok()?;
+            Some(tx)
+        })
+        .as_ref()
+}
+
+/// Append queued lines in batches, flushing whenever the queue runs dry and
+/// rotating the file when it is due. Errors are ignored: logging must never change
+/// what the user sees.
+fn write_log(path: &str, rx: mpsc::Receiver<LogMessage>) {
+    let path = std::path::Path::new(path);
+    let mut file = None;
+    while let Ok(first) = rx.recv() {
+        let mut next = Some(first);
+        while let Some(message) = next.take().or_else(|| rx.try_recv().ok()) {
+            match message {
+                LogMessage::Line(line) => {
+                    if file.is_none() {
+                        logrotate::rotate_if_due(path);
+                        file = private_file(OpenOptions::new().create(true).append(true))
+                            .open(path)
+                            .ok()
+                            .map(BufWriter::new);
+                    }
+                    if let Some(f) = file.as_mut() {
+                        let _ = f.write_all(line.as_bytes());
+                    }
+                }
+                LogMessage::Flush(done) => {
+                    if let Some(f) = file.as_mut() {
+                        let _ = f.flush();
+                    }
+                    let _ = done.send(());
+                }
+            }
+        }
+        if let Some(f) = file.as_mut() {
+            let _ = f.flush();
+            if logrotate::rotate_if_due(path) {
+                file = None;
+            }
+        }
+    }
+}
+
+/// One SMSH_LOG entry. The text format shows the subcommand, query and result
+/// (prefixed with the outcome when it isn't a plain suggestion); the JSON Lines
+/// format has every field that is set.
+#[derive(Debug, Default, Clone)]
+pub struct LogRecord<'a> {
+    pub cmd: &'a str,
+    pub query: &'a str,
+    pub result: &'a str,
+    pub provider: Option<Provider>,
+    pub latency_ms: Option<u64>,
+    pub usage: Option<Usage>,
+    /// `suggested`, `candidate`, `executed`, `rejected`, `needs_confirmation`,
+    /// `explained`, `refused`, `error` or `cancelled`
+    pub outcome: Option<&'a str>,
+    /// The command line `complete` was asked to change, if any
+    pub buffer: Option<&'a str>,
+    /// For failures, their kind as in `--json-errors`: `rate_limited`,
+    /// `network`, `refusal`, ...
+    pub error_type: Option<&'a str>,
+}
+
+impl LogRecord<'_> {
+    fn text_line(&self, mode: LogMode) -> String {
+        let time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+        if mode == LogMode::Metadata {
+            let mut line = format!("[{}] [{}] {}", time, request_id(), self.cmd);
+            if let Some(provider) = self.provider {
+                line.push_str(&format!(
+                    " | provider: {} ({})",
+                    provider.as_str(),
+                    provider.model()
+                ));
+            }
+            if let Some(outcome) = self.outcome {
+                line.push_str(&format!(" | outcome: {}", outcome));
+            }
+            if let Some(error_type) = self.error_type {
+                line.push_str(&format!(" | error: {}", error_type));
+            }
+            if let Some(latency) = self.latency_ms {
+                line.push_str(&format!(" | {}ms", latency));
+            }
+            if let Some(usage) = self.usage {
+                line.push_str(&format!(
+                    " | {} input, {} output tokens",
+                    usage.input_tokens, usage.output_tokens
+                ));
+            }
+            return line + "\n";
+        }
+        let label = match self.outcome {
+            Some("candidate") => "CANDIDATE: ",
+            Some("executed") => "EXECUTE: ",
+            Some("rejected") => "REJECTED: ",
+            Some("needs_confirmation") => "DESTRUCTIVE: ",
+            Some("refused") => "REFUSED: ",
+            Some("error") => "ERROR: ",
+            Some("cancelled") => "CANCELLED: ",
+            _ => "",
+        };
+        format!(
+            "[{}] [{}] {} | query: {} | result: {}{}\n",
+            time,
+            request_id(),
+            self.cmd,
+            redact_for_log(self.query),
+            label,
+            redact_for_log(self.result)
+        )
+    }
+
+    fn json_line(&self, mode: LogMode) -> String {
+        let mut json = serde_json::json!({
+            "ts": chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            "request_id": request_id(),
+            "cmd": self.cmd,
+        });
+        if mode == LogMode::Full {
+            json["prompt_hash"] = sha256_hex(self.query.as_bytes()).into();
+            json["query"] = redact_for_log(self.query).into();
+            json["result"] = redact_for_log(self.result).into();
+            if let Some(buffer) = self.buffer.filter(|b| !b.is_empty()) {
+                json["buffer"] = redact_for_log(buffer).into();
+            }
+        }
+        if let Some(provider) = self.provider {
+            json["provider"] = provider.as_str().into();
+            json["model"] = provider.model().into();
+        }
+        if let Some(latency) = self.latency_ms {
+            json["latency_ms"] = latency.into();
+        }
+        if let Some(usage) = self.usage {
+            json["usage"] = serde_json::json!(usage);
+        }
+        if let Some(outcome) = self.outcome {
+            json["outcome"] = outcome.into();
+        }
+        if let Some(error_type) = self.error_type {
+            json["error_type"] = error_type.into();
+        }
+        format!("{}\n", json)
+    }
+}
+
+/// Whether SMSH_LOG_FORMAT asks for JSON Lines instead of the text format
+fn json_log() -> bool {
+    env::var("SMSH_LOG_FORMAT").is_ok_and(|f| matches!(f.as_str(), "json" | "jsonl"))
+}
+
+/// How much SMSH_LOG and the history keep, from SMSH_LOG_MODE: everything
+/// (`full`, the default), only timing, provider, outcome and token counts
+/// (`metadata`), or nothing (`off`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogMode {
+    Off,
+    Metadata,
+    Full,
+}
+
+/// The SMSH_LOG_MODE setting. A value that isn't understood keeps nothing rather
+/// than more than was asked for.
+pub(crate) fn log_mode() -> LogMode {
+    static MODE: OnceLock<LogMode> = OnceLock::new();
+    *MODE.get_or_init(|| match env::var("SMSH_LOG_MODE") {
+        Err(_) => LogMode::Full,
+        Ok(mode) => match mode.trim().to_ascii_lowercase().as_str() {
+            "" | "full" => LogMode::Full,
+            "metadata" => LogMode::Metadata,
+            "off" => LogMode::Off,
+            other => {
+                eprintln!(
+                    "{}",
+                    crate::style::warning(&format!(
+                        "SMSH_LOG_MODE={} is not off, metadata or full; logging is off",
+                        other
+                    ))
+                );
+                LogMode::Off
+            }
+        },
+    })
+}
+
+/// Queue a line for the SMSH_LOG debug log, with credentials and anything the
+/// user's log redaction rules match masked; the file is written on a background
+/// thread. In `metadata` mode only the subcommand and time are kept, as `query`
+/// and `result` hold what the user typed or was shown.
+pub fn log_entry(cmd: &str, query: &str, result: &str) {
+    log_record(&LogRecord {
+        cmd,
+        query,
+        result,
+        ..LogRecord::default()
+    });
+}
+
+/// Queue a full record for the SMSH_LOG debug log, like [`log_entry`]
+pub fn log_record(record: &LogRecord) {
+    write_record(record, log_mode());
+}
+
+/// Queue an entry about smartshell itself rather than a request: a circuit
+/// opening, a retry, a key switch. `subject` is a provider, model or URL, never
+/// user input, so it is kept in `metadata` mode too.
+pub(crate) fn log_event(kind: &str, subject: &str, detail: &str) {
+    let mode = match log_mode() {
+        LogMode::Off => return,
+        _ => LogMode::Full,
+    };
+    let record = LogRecord {
+        cmd: kind,
+        query: subject,
+        result: detail,
+        ..LogRecord::default()
+    };
+    write_record(&record, mode);
+}
+
+fn write_record(record: &LogRecord, mode: LogMode) {
+    if mode == LogMode::Off {
+        return;
+    }
+    if let Some(tx) = logger() {
+        let line = if json_log() {
+            record.json_line(mode)
+        } else {
+            record.text_line(mode)
+        };
+        let _ = tx.send(LogMessage::Line(line));
+    }
+}
+
+/// Wait (briefly) until queued log lines are written; call before exiting the process
+pub fn flush_log() {
+    let Some(tx) = LOGGER.get().and_then(Option::as_ref) else {
+        return;
+    };
+    let (done, wait) = mpsc::channel();
+    if tx.send(LogMessage::Flush(done)).is_ok() {
+        let _ = wait.recv_timeout(LOG_FLUSH_TIMEOUT);
+    }
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+/// How much to trace on stderr: 1 (`-v`) for provider, HTTP status, timing and
+/// retry decisions, 2 (`-vv`) also for the prompts sent
+pub fn set_verbosity(level: u8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+/// Whether traces at verbosity `level` are printed
+pub(crate) fn tracing(level: u8) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level
+}
+
+/// Print a trace line on stderr at verbosity `level` and above, with credentials
+/// masked and the request id
+pub(crate) fn trace(level: u8, message: std::fmt::Arguments) {
+    if tracing(level) {
+        eprintln!(
+            "smartshell [{}]: {}",
+            request_id(),
+            redact(&message.to_string())
+        );
+    }
+}
+
+tokio::task_local! {
+    /// The request a daemon task is answering, as sent by the calling process
+    static TASK_REQUEST_ID: String;
+}
+
+/// A short id for one request, shown in traces, the debug log, the history and
+/// error messages, and sent to providers that accept one. The daemon answers for
+/// other processes and uses their ids; everything else has one per process, from
+/// SMSH_REQUEST_ID (so a caller such as the widget can choose it) or random.
+pub fn request_id() -> String {
+    if let Ok(id) = TASK_REQUEST_ID.try_with(String::clone) {
+        return id;
+    }
+    static ID: OnceLock<String> = OnceLock::new();
+    ID.get_or_init(|| {
+        env::var("SMSH_REQUEST_ID")
+            .ok()
+            .and_then(|id| valid_request_id(&id))
+            .unwrap_or_else(new_request_id)
+    })
+    .clone()
+}
+
+/// `id` if it is safe to put in a header and a log line
+pub(crate) fn valid_request_id(id: &str) -> Option<String> {
+    let id = id.trim();
+    let valid = (1..=64).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| id.to_string())
+}
+
+fn new_request_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    format!("{:012x}", hasher.finish() & 0xffff_ffff_ffff)
+}
+
+/// Run `f` as a request of its own, with a fresh id (in the HTTP server)
+pub(crate) fn with_new_request_id<T>(f: impl FnOnce() -> T) -> T {
+    TASK_REQUEST_ID.sync_scope(new_request_id(), f)
+}
+
+/// Run `future` as part of request `id` (in the daemon)
+#[cfg(unix)]
+pub(crate) async fn with_request_id<F: std::future::Future>(id: String, future: F) -> F::Output {
+    TASK_REQUEST_ID.scope(id, future).await
+}
+
+pub(crate) fn env_parse<T: std::str::FromStr>(var: &str) -> Option<T> {
+    env::var(var).ok().and_then(|v| v.trim().parse().ok())
+}
+
+pub(crate) fn state_dir() -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")))
+        .map(|d| d.join("smartshell"))
+}
+
+/// `name` in the state dir, open and locked: shared for reading, exclusive for
+/// writing. None if it can't be opened or locked, or (when reading) doesn't exist.
+fn locked_state(name: &str, write: bool) -> Option<std::fs::File> {
+    let path = state_dir()?.join(name);
+    if write {
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(write)
+        .create(write)
+        .truncate(false)
+        .open(path)
+        .ok()?;
+    let locked = if write {
+        file.lock()
+    } else {
+        file.lock_shared()
+    };
+    locked.ok().map(|_| file)
+}
+
+fn parse_state<S: serde::de::DeserializeOwned + Default>(file: &mut std::fs::File) -> S {
+    use std::io::Read;
+    let mut text = String::new();
+    let _ = file.read_to_string(&mut text);
+    serde_json::from_str(&text).unwrap_or_default()
+}
+
+/// The JSON state kept in `name` in the state dir, or the default when there is
+/// none yet, read under a shared lock so a write in progress isn't seen half done
+pub(crate) fn read_state<S: serde::de::DeserializeOwned + Default>(name: &str) -> S {
+    locked_state(name, false)
+        .map(|mut file| parse_state(&mut file))
+        .unwrap_or_default()
+}
+
+/// Change the JSON state kept in `name` in the state dir with `update`, holding
+/// an exclusive lock from reading to writing, so concurrent processes and
+/// threads don't lose each other's updates. The state
+/// is saved unless `update` returns false. An unusable state file is treated as
+/// empty and left alone.
+pub(crate) fn update_state<S>(name: &str, update: impl FnOnce(&mut S) -> bool)
+where
+    S: serde::Serialize + serde::de::DeserializeOwned + Default,
+{
+    use std::io::Seek;
+    let Some(mut file) = locked_state(name, true) else {
+        update(&mut S::default());
+        return;
+    };
+    let mut state = parse_state(&mut file);
+    if !update(&mut state) {
+        return;
+    }
+    if let Ok(json) = serde_json::to_string(&state) {
+        let _ = file.set_len(0);
+        let _ = file.rewind();
+        let _ = file.write_all(json.as_bytes());
+    }
+}
+
+/// `options` set to create files readable and writable only by the user, from the
+/// start rather than by a chmod afterwards that leaves a window open
+pub(crate) fn private_file(options: &mut OpenOptions) -> &mut OpenOptions {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .map(|d| d.join("smartshell"))
+}
+
+pub(crate) fn cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+        .map(|d| d.join("smartshell"))
+}
+
+/// A fresh, empty directory for a test that needs real files
+#[cfg(test)]
+pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("smartshell-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,95 @@
+//! The controlling terminal, for the spinner, streamed previews and prompts:
+//! /dev/tty on Unix, the console (CONOUT$ / CONIN$) on Windows
+
+use crate::style;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+#[cfg(not(windows))]
+const CONSOLE_OUT: &str = "/dev/tty";
+#[cfg(not(windows))]
+const CONSOLE_IN: &str = "/dev/tty";
+#[cfg(windows)]
+const CONSOLE_OUT: &str = "CONOUT$";
+#[cfg(windows)]
+const CONSOLE_IN: &str = "CONIN$";
+
+/// A one-line status area on the terminal, drawn with ANSI escapes where the
+/// terminal understands them and with carriage returns and padding elsewhere
+pub(crate) struct Console {
+    out: File,
+    ansi: bool,
+    /// Characters on the status line, for clearing it without ANSI escapes
+    shown: usize,
+}
+
+impl Console {
+    /// The terminal, or None when there is none (cron, CI, detached daemons)
+    pub(crate) fn open() -> Option<Self> {
+        let out = OpenOptions::new().write(true).open(CONSOLE_OUT).ok()?;
+        Some(Console {
+            out,
+            ansi: supports_ansi() && !style::plain(),
+            shown: 0,
+        })
+    }
+
+    /// A separate handle for reading the user's reply to a prompt
+    pub(crate) fn input() -> Option<File> {
+        OpenOptions::new().read(true).open(CONSOLE_IN).ok()
+    }
+
+    /// Replace the status line with `text`
+    pub(crate) fn status(&mut self, text: &str) {
+        let width = text.chars().count();
+        let _ = if self.ansi {
+            write!(self.out, "\r\x1b[K{}", text)
+        } else {
+            let pad = self.shown.saturating_sub(width);
+            write!(self.out, "\r{}{}", text, " ".repeat(pad))
+        };
+        self.shown = width;
+        let _ = self.out.flush();
+    }
+
+    /// Clear the status line
+    pub(crate) fn clear(&mut self) {
+        self.status("");
+        let _ = write!(self.out, "\r");
+        let _ = self.out.flush();
+    }
+
+    pub(crate) fn show_cursor(&mut self, visible: bool) {
+        if self.ansi {
+            let _ = write!(
+                self.out,
+                "{}",
+                if visible { "\x1b[?25h" } else { "\x1b[?25l" }
+            );
+            let _ = self.out.flush();
+        }
+    }
+}
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Unix terminals other than `TERM=dumb` take ANSI escapes. On Windows the
+/// legacy console only does when a terminal that enables them says so.
+fn supports_ansi() -> bool {
+    if cfg!(windows) {
+        env::var_os("WT_SESSION").is_some()
+            || env::var_os("TERM").is_some()
+            || env::var("ConEmuANSI").is_ok_and(|v| v == "ON")
+    } else {
+        env::var_os("TERM").is_none_or(|t| t != "dumb")
+    }
+}
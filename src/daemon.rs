@@ -0,0 +1,340 @@
+//! Daemon mode: serve provider requests over a unix socket with warm connections
+
+#[cfg(unix)]
+use crate::audit::sha256_hex;
+use crate::client::SmartshellClient;
+#[cfg(unix)]
+use crate::client::{runtime, CtrlCGuard};
+use crate::config::state_dir;
+#[cfg(unix)]
+use crate::config::{request_id, valid_request_id, with_request_id};
+#[cfg(unix)]
+use crate::error::Failure;
+use crate::error::Outcome;
+#[cfg(unix)]
+use crate::provider::{http_client, provider_call, provider_client, Provider};
+#[cfg(unix)]
+use crate::response::{parse_structured, Schema};
+#[cfg(unix)]
+use crate::spend::{add_session_usage, measure_usage, Usage};
+#[cfg(unix)]
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+fn daemon_socket() -> Option<PathBuf> {
+    env::var_os("SMSH_SOCKET")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("XDG_RUNTIME_DIR")
+                .filter(|p| !p.is_empty())
+                .map(|d| PathBuf::from(d).join("smartshell.sock"))
+        })
+        .or_else(|| state_dir().map(|d| d.join("smartshell.sock")))
+}
+
+/// Send one request line to a running daemon and read its one-line reply; None if
+/// there is no daemon (or it is unreachable or disabled with SMSH_NO_DAEMON)
+#[cfg(unix)]
+async fn daemon_exchange(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    prefetch: bool,
+) -> Option<serde_json::Value> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    if env::var_os("SMSH_NO_DAEMON").is_some() {
+        return None;
+    }
+    let path = daemon_socket().filter(|p| p.exists())?;
+    let mut stream = tokio::net::UnixStream::connect(&path).await.ok()?;
+    // The key goes only to a daemon run by this user
+    if !same_user(&stream) {
+        return None;
+    }
+    let request = serde_json::json!({
+        "provider": client.provider.as_str(),
+        "intro": intro,
+        "prompt": prompt,
+        "api_key": client.api_key_for(client.provider),
+        "timeout": client.timeout.as_secs(),
+        "prefetch": prefetch,
+        "request_id": request_id(),
+        "schema": client.schema.as_str(),
+    });
+    stream
+        .write_all(format!("{}\n", request).as_bytes())
+        .await
+        .ok()?;
+    let mut line = String::new();
+    tokio::io::BufReader::new(stream)
+        .read_line(&mut line)
+        .await
+        .ok()?;
+    serde_json::from_str(&line).ok()
+}
+
+/// Whether the other end of `stream` runs as this process's user
+#[cfg(unix)]
+fn same_user(stream: &tokio::net::UnixStream) -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let uid = unsafe { libc::geteuid() };
+    stream.peer_cred().is_ok_and(|cred| cred.uid() == uid)
+}
+
+/// Hand the request to a running daemon; None if there is none, so the caller
+/// can go direct
+#[cfg(unix)]
+pub(crate) async fn daemon_call(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+) -> Option<Outcome> {
+    let reply = daemon_exchange(client, intro, prompt, false).await?;
+    if let Ok(usage) = serde_json::from_value(reply["usage"].clone()) {
+        add_session_usage(usage);
+    }
+    Some(if reply.get("error").is_some_and(|e| e.is_object()) {
+        Err(Failure::from_envelope(&reply, client.provider))
+    } else {
+        parse_structured(&reply)
+    })
+}
+
+/// Ask a running daemon to start answering the request in the background and keep
+/// the answer for the next identical `daemon_call`; false if there is no daemon
+#[cfg(unix)]
+pub(crate) async fn daemon_prefetch(client: &SmartshellClient, intro: &str, prompt: &str) -> bool {
+    daemon_exchange(client, intro, prompt, true)
+        .await
+        .is_some_and(|reply| reply["prefetched"] == true)
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn daemon_call(
+    _client: &SmartshellClient,
+    _intro: &str,
+    _prompt: &str,
+) -> Option<Outcome> {
+    None
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn daemon_prefetch(
+    _client: &SmartshellClient,
+    _intro: &str,
+    _prompt: &str,
+) -> bool {
+    false
+}
+
+/// How long a prefetched answer waits to be picked up
+#[cfg(unix)]
+const PREFETCH_TTL: Duration = Duration::from_secs(120);
+
+/// Prefetched replies by request key, with the time they arrived
+#[cfg(unix)]
+type PrefetchTable = std::sync::Mutex<HashMap<String, (Instant, serde_json::Value)>>;
+
+#[cfg(unix)]
+fn prefetched() -> &'static PrefetchTable {
+    static PREFETCHED: std::sync::OnceLock<PrefetchTable> = std::sync::OnceLock::new();
+    PREFETCHED.get_or_init(Default::default)
+}
+
+/// Identifies requests that must get the same answer
+#[cfg(unix)]
+fn request_key(req: &serde_json::Value) -> String {
+    let fields = serde_json::json!([req["provider"], req["intro"], req["prompt"], req["schema"]]);
+    sha256_hex(fields.to_string().as_bytes())
+}
+
+/// Replies still being fetched, by request key; later identical requests wait on
+/// the first one instead of sending their own
+#[cfg(unix)]
+type InFlightTable =
+    std::sync::Mutex<HashMap<String, tokio::sync::watch::Receiver<Option<serde_json::Value>>>>;
+
+#[cfg(unix)]
+fn in_flight() -> &'static InFlightTable {
+    static IN_FLIGHT: std::sync::OnceLock<InFlightTable> = std::sync::OnceLock::new();
+    IN_FLIGHT.get_or_init(Default::default)
+}
+
+/// Removes a finished (or abandoned) request from the in-flight table
+#[cfg(unix)]
+struct InFlightGuard(String);
+
+#[cfg(unix)]
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        in_flight().lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Answer `req`, sharing one upstream request between concurrent identical ones
+#[cfg(unix)]
+async fn coalesced_reply(key: &str, req: &serde_json::Value) -> serde_json::Value {
+    let waiting = in_flight().lock().unwrap().get(key).cloned();
+    if let Some(mut rx) = waiting {
+        if let Ok(reply) = rx.wait_for(Option::is_some).await {
+            return reply.clone().unwrap_or_default();
+        }
+        // The first request was dropped before answering; fetch our own
+    }
+    let (tx, rx) = tokio::sync::watch::channel(None);
+    in_flight().lock().unwrap().insert(key.to_string(), rx);
+    let _guard = InFlightGuard(key.to_string());
+    let reply = daemon_reply(req).await;
+    tx.send_replace(Some(reply.clone()));
+    reply
+}
+
+/// Answer one request, as the JSON line to send back
+#[cfg(unix)]
+async fn daemon_reply(req: &serde_json::Value) -> serde_json::Value {
+    let client = req["provider"]
+        .as_str()
+        .unwrap_or("openai")
+        .parse()
+        .map(|provider| {
+            let mut client = SmartshellClient::new(provider);
+            client.api_key = req["api_key"].as_str().map(String::from);
+            if let Some(secs) = req["timeout"].as_u64() {
+                client = client.timeout(Duration::from_secs(secs));
+            }
+            client.schema = Schema::parse(req["schema"].as_str().unwrap_or_default());
+            client
+        });
+    let intro = req["intro"].as_str().unwrap_or_default();
+    let prompt = req["prompt"].as_str().unwrap_or_default();
+    let (outcome, usage) = match client {
+        Ok(client) => measure_usage(provider_call(&client, intro, prompt)).await,
+        Err(failure) => (Err(failure), Usage::default()),
+    };
+    match outcome {
+        Ok(resp) => {
+            let mut reply = resp.structured();
+            reply["usage"] = serde_json::json!(usage);
+            reply
+        }
+        Err(Failure::Refusal { reason }) => {
+            serde_json::json!({ "result": reason, "error": true })
+        }
+        Err(failure) => failure.envelope(),
+    }
+}
+
+#[cfg(unix)]
+async fn handle_daemon_connection(stream: tokio::net::UnixStream) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    let (read, mut write) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let Ok(req) = serde_json::from_str::<serde_json::Value>(&line) else {
+            break;
+        };
+        let key = request_key(&req);
+        // Traces, log entries and provider headers carry the caller's id
+        let id = req["request_id"]
+            .as_str()
+            .and_then(valid_request_id)
+            .unwrap_or_else(request_id);
+        let reply = if req["prefetch"] == true {
+            prefetched()
+                .lock()
+                .unwrap()
+                .retain(|_, (at, _)| at.elapsed() < PREFETCH_TTL);
+            tokio::spawn(with_request_id(id, async move {
+                let reply = coalesced_reply(&key, &req).await;
+                // Failures are not kept: the real request retries them
+                if !reply["error"].is_object() {
+                    prefetched()
+                        .lock()
+                        .unwrap()
+                        .insert(key, (Instant::now(), reply));
+                }
+            }));
+            serde_json::json!({ "prefetched": true })
+        } else {
+            let hit = prefetched()
+                .lock()
+                .unwrap()
+                .remove(&key)
+                .filter(|(at, _)| at.elapsed() < PREFETCH_TTL);
+            match hit {
+                Some((_, reply)) => reply,
+                None => with_request_id(id, coalesced_reply(&key, &req)).await,
+            }
+        };
+        if write
+            .write_all(format!("{}\n", reply).as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Accept connections forever, answering each request with a shared, keep-alive HTTP client
+#[cfg(unix)]
+pub fn run_daemon(socket: Option<PathBuf>) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+    let path = socket
+        .or_else(daemon_socket)
+        .ok_or("Cannot determine socket path; set SMSH_SOCKET")?;
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        return Err(format!(
+            "A daemon is already listening on {}",
+            path.display()
+        ));
+    }
+    let _ = std::fs::remove_file(&path);
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    http_client();
+    let _ = provider_client(Provider::OpenAi);
+    let _ = provider_client(Provider::Claude);
+    let _ctrl_c = CtrlCGuard::new();
+    runtime().block_on(async {
+        // Created without group or other access, so no one else can connect
+        // between binding and the chmod
+        // SAFETY: umask has no preconditions and cannot fail
+        let umask = unsafe { libc::umask(0o077) };
+        let bound = tokio::net::UnixListener::bind(&path);
+        unsafe { libc::umask(umask) };
+        let listener = bound.map_err(|e| format!("Cannot bind {}: {}", path.display(), e))?;
+        let _ = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600));
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .map_err(|e| format!("Cannot install signal handler: {}", e))?;
+        eprintln!("smartshell daemon listening on {}", path.display());
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    match accepted {
+                        // Requests carry API keys; only this user's may be answered
+                        Ok((stream, _)) if same_user(&stream) => {
+                            tokio::spawn(handle_daemon_connection(stream));
+                        }
+                        _ => {}
+                    }
+                }
+                _ = tokio::signal::ctrl_c() => break,
+                _ = terminate.recv() => break,
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    })
+}
+
+#[cfg(not(unix))]
+pub fn run_daemon(_socket: Option<PathBuf>) -> Result<(), String> {
+    Err("Daemon mode requires unix domain sockets".to_string())
+}
@@ -0,0 +1,224 @@
+//! Typed failures and the exit codes the CLI maps them to
+
+use crate::provider::Provider;
+use crate::redact::redact;
+use crate::response::LlmResponse;
+use std::time::Duration;
+
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_REFUSED: i32 = 2;
+pub const EXIT_CONFIRM: i32 = 3;
+pub const EXIT_AUTH: i32 = 4;
+pub const EXIT_NETWORK: i32 = 5;
+pub const EXIT_TIMEOUT: i32 = 6;
+pub const EXIT_RATE_LIMITED: i32 = 7;
+pub const EXIT_CANCELLED: i32 = 130;
+
+/// Why a request did not produce a usable result
+#[derive(Debug, thiserror::Error)]
+pub enum Failure {
+    /// The model or a local safety check declined the request
+    #[error("# {reason}")]
+    Refusal { reason: String },
+    /// The API key is missing or the provider rejected it
+    #[error("{message}")]
+    Auth { provider: Provider, message: String },
+    /// The provider could not be reached
+    #[error("{message}")]
+    Network { provider: Provider, message: String },
+    /// Still rate limited after the configured retries
+    #[error("{message}")]
+    RateLimited {
+        provider: Provider,
+        retry_after: Option<Duration>,
+        message: String,
+    },
+    /// The provider's answer did not have the expected structure
+    #[error("{message}")]
+    Parse { provider: Provider, message: String },
+    /// No answer within the request timeout
+    #[error("Request timed out after {}s", after.as_secs())]
+    Timeout { provider: Provider, after: Duration },
+    /// Any other error reported by the provider's API
+    #[error("{message}")]
+    Api {
+        provider: Provider,
+        status: Option<u16>,
+        message: String,
+    },
+    /// The destination host is not in the egress allowlist
+    #[error("{0}")]
+    EgressBlocked(String),
+    /// The request could not be assembled (unknown provider, unreadable clipboard,
+    /// invalid policy file)
+    #[error("{0}")]
+    InvalidInput(String),
+    /// The provider failed repeatedly and its circuit breaker is still open
+    #[error(
+        "{} is failing; not retrying for another {}s",
+        provider.as_str(),
+        retry_in.as_secs()
+    )]
+    Unavailable {
+        provider: Provider,
+        retry_in: Duration,
+    },
+    /// The local request rate limit (`SMSH_RATE_LIMIT`) is used up
+    #[error(
+        "Local rate limit of {per_minute} requests per minute reached; try again in {}s",
+        retry_in.as_secs()
+    )]
+    Throttled { per_minute: f64, retry_in: Duration },
+    /// Offline (`--offline`, or the provider is unreachable) and nothing cached
+    #[error("offline, no cached answer")]
+    Offline,
+    /// The user pressed Ctrl-C
+    #[error("Cancelled.")]
+    Cancelled,
+}
+
+impl Failure {
+    pub(crate) fn refused(reason: impl Into<String>) -> Self {
+        Failure::Refusal {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Refusal { .. } => EXIT_REFUSED,
+            Failure::Auth { .. } => EXIT_AUTH,
+            Failure::Network { .. } | Failure::Unavailable { .. } | Failure::Offline => {
+                EXIT_NETWORK
+            }
+            Failure::RateLimited { .. } | Failure::Throttled { .. } => EXIT_RATE_LIMITED,
+            Failure::Timeout { .. } => EXIT_TIMEOUT,
+            Failure::Cancelled => EXIT_CANCELLED,
+            Failure::Parse { .. }
+            | Failure::Api { .. }
+            | Failure::EgressBlocked(_)
+            | Failure::InvalidInput(_) => EXIT_ERROR,
+        }
+    }
+
+    /// The provider the failed request was sent to, if it got that far
+    pub fn provider(&self) -> Option<Provider> {
+        match self {
+            Failure::Auth { provider, .. }
+            | Failure::Network { provider, .. }
+            | Failure::RateLimited { provider, .. }
+            | Failure::Parse { provider, .. }
+            | Failure::Timeout { provider, .. }
+            | Failure::Api { provider, .. }
+            | Failure::Unavailable { provider, .. } => Some(*provider),
+            _ => None,
+        }
+    }
+
+    /// The failure without the `#` a refusal is displayed with, and with any
+    /// credentials it quotes masked
+    pub fn message(&self) -> String {
+        match self {
+            Failure::Refusal { reason } => redact(reason),
+            other => redact(&other.to_string()),
+        }
+    }
+
+    /// The failure as displayed, with any credentials it quotes masked
+    pub fn display(&self) -> String {
+        redact(&self.to_string())
+    }
+
+    /// The failure's kind, `error.type` in the envelope: `rate_limited`,
+    /// `network`, `refusal`, ...
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Failure::Refusal { .. } => "refusal",
+            Failure::Auth { .. } => "auth",
+            Failure::Network { .. } => "network",
+            Failure::RateLimited { .. } => "rate_limited",
+            Failure::Parse { .. } => "parse",
+            Failure::Timeout { .. } => "timeout",
+            Failure::Api { .. } => "api",
+            Failure::EgressBlocked(_) => "egress_blocked",
+            Failure::InvalidInput(_) => "invalid_input",
+            Failure::Unavailable { .. } => "unavailable",
+            Failure::Throttled { .. } => "throttled",
+            Failure::Offline => "offline",
+            Failure::Cancelled => "cancelled",
+        }
+    }
+
+    /// Inverse of `envelope`, used to decode failures relayed by the daemon for a
+    /// request sent to `provider`
+    pub(crate) fn from_envelope(value: &serde_json::Value, provider: Provider) -> Self {
+        let err = &value["error"];
+        let message = err["message"].as_str().unwrap_or_default().to_string();
+        let secs = |key: &str| err[key].as_u64().map(Duration::from_secs);
+        match err["type"].as_str() {
+            Some("refusal") => Failure::refused(message),
+            Some("auth") => Failure::Auth { provider, message },
+            Some("network") => Failure::Network { provider, message },
+            Some("rate_limited") => Failure::RateLimited {
+                provider,
+                retry_after: secs("retry_after"),
+                message,
+            },
+            Some("parse") => Failure::Parse { provider, message },
+            Some("timeout") => Failure::Timeout {
+                provider,
+                after: secs("after").unwrap_or_default(),
+            },
+            Some("egress_blocked") => Failure::EgressBlocked(message),
+            Some("invalid_input") => Failure::InvalidInput(message),
+            Some("unavailable") => Failure::Unavailable {
+                provider,
+                retry_in: secs("retry_in").unwrap_or_default(),
+            },
+            Some("throttled") => Failure::Throttled {
+                per_minute: err["per_minute"].as_f64().unwrap_or_default(),
+                retry_in: secs("retry_in").unwrap_or_default(),
+            },
+            Some("offline") => Failure::Offline,
+            Some("cancelled") => Failure::Cancelled,
+            _ => Failure::Api {
+                provider,
+                status: err["status"].as_u64().map(|s| s as u16),
+                message,
+            },
+        }
+    }
+
+    pub fn envelope(&self) -> serde_json::Value {
+        let mut error = serde_json::json!({
+            "type": self.type_name(),
+            "provider": self.provider().map(Provider::as_str),
+            "message": self.message(),
+            "exit_code": self.exit_code(),
+        });
+        match self {
+            Failure::RateLimited {
+                retry_after: Some(after),
+                ..
+            } => error["retry_after"] = after.as_secs().into(),
+            Failure::Timeout { after, .. } => error["after"] = after.as_secs().into(),
+            Failure::Unavailable { retry_in, .. } => error["retry_in"] = retry_in.as_secs().into(),
+            Failure::Throttled {
+                per_minute,
+                retry_in,
+            } => {
+                error["per_minute"] = (*per_minute).into();
+                error["retry_in"] = retry_in.as_secs().into();
+            }
+            Failure::Api {
+                status: Some(status),
+                ..
+            } => error["status"] = (*status).into(),
+            _ => {}
+        }
+        serde_json::json!({ "error": error })
+    }
+}
+
+/// Result of a provider call after all post-processing; success is `Ok`
+pub type Outcome = Result<LlmResponse, Failure>;
@@ -0,0 +1,175 @@
+//! The user's own teaching set: annotated query and command pairs from
+//! SMSH_EXAMPLES_FILE (default `~/.config/smartshell/examples.yaml`) that are
+//! compiled into the completion prompt
+
+use crate::config::{config_dir, trace};
+use std::env;
+use std::path::PathBuf;
+
+/// At most this many examples go into a prompt; larger files contribute the ones
+/// that share the most words with the query
+const MAX_EXAMPLES: usize = 12;
+
+/// A request and the command the user wants for it, with an optional note on why
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Example {
+    pub(crate) query: String,
+    pub(crate) command: String,
+    pub(crate) note: Option<String>,
+}
+
+fn examples_path() -> Option<PathBuf> {
+    match env::var("SMSH_EXAMPLES_FILE") {
+        Ok(path) if !path.is_empty() => match path.strip_prefix("~/") {
+            Some(rest) => env::var_os("HOME").map(|h| PathBuf::from(h).join(rest)),
+            None => Some(PathBuf::from(path)),
+        },
+        _ => config_dir().map(|d| d.join("examples.yaml")),
+    }
+}
+
+/// A YAML scalar: double-quoted (with escapes), single-quoted (`''` for a quote)
+/// or plain, where a ` #` starts a comment
+fn scalar(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    if value.starts_with('"') {
+        serde_json::from_str::<String>(value)
+            .map_err(|_| "unterminated or invalid double-quoted string".to_string())
+    } else if let Some(inner) = value.strip_prefix('\'') {
+        inner
+            .strip_suffix('\'')
+            .map(|s| s.replace("''", "'"))
+            .ok_or_else(|| "unterminated single-quoted string".to_string())
+    } else if value.starts_with(['|', '>']) {
+        Err("block scalars are not supported; quote the value on one line".to_string())
+    } else {
+        let value = value.find(" #").map_or(value, |i| &value[..i]);
+        Ok(value.trim_end().to_string())
+    }
+}
+
+/// The examples file: a YAML list (optionally under `examples:`) of mappings
+/// with `query`, `command` and an optional `note`
+///
+/// ```yaml
+/// - query: find large files
+///   command: du -ah . | sort -rh | head -20
+///   note: human-readable sizes, biggest first
+/// ```
+pub(crate) fn parse(text: &str) -> Result<Vec<Example>, String> {
+    let mut examples = Vec::new();
+    let mut current: Option<(usize, Example)> = None;
+    let finish = |item: Option<(usize, Example)>, out: &mut Vec<Example>| match item {
+        Some((line, example)) if example.query.is_empty() || example.command.is_empty() => Err(
+            format!("line {}: an example needs both query and command", line),
+        ),
+        Some((_, example)) => {
+            out.push(example);
+            Ok(())
+        }
+        None => Ok(()),
+    };
+    for (n, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') || line == "---" {
+            continue;
+        }
+        if !raw.starts_with([' ', '-']) && line == "examples:" {
+            continue;
+        }
+        let entry = match line.strip_prefix("- ") {
+            Some(rest) => {
+                finish(current.take(), &mut examples)?;
+                current = Some((
+                    n + 1,
+                    Example {
+                        query: String::new(),
+                        command: String::new(),
+                        note: None,
+                    },
+                ));
+                rest
+            }
+            None => line,
+        };
+        let Some((_, example)) = current.as_mut() else {
+            return Err(format!(
+                "line {}: expected a list item starting with `- `",
+                n + 1
+            ));
+        };
+        let Some((key, value)) = entry.split_once(':') else {
+            return Err(format!("line {}: expected `key: value`", n + 1));
+        };
+        let value = scalar(value).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        match key.trim() {
+            "query" => example.query = value,
+            "command" => example.command = value,
+            "note" => example.note = Some(value).filter(|v| !v.is_empty()),
+            other => return Err(format!("line {}: unknown key `{}`", n + 1, other)),
+        }
+    }
+    finish(current, &mut examples)?;
+    Ok(examples)
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() > 1)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// The user's examples for `query`: all of them, in file order, or from a long
+/// file the ones closest to the query. A missing file means no examples; an
+/// unreadable or invalid one is reported and skipped, since a bad teaching set
+/// shouldn't stop completions.
+pub(crate) fn curated(query: &str) -> Vec<Example> {
+    let Some(path) = examples_path() else {
+        return Vec::new();
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            eprintln!(
+                "{}",
+                crate::style::warning(&format!("{}: {}; examples skipped", path.display(), e))
+            );
+            return Vec::new();
+        }
+    };
+    let examples = match parse(&text) {
+        Ok(examples) => examples,
+        Err(e) => {
+            eprintln!(
+                "{}",
+                crate::style::warning(&format!("{}: {}; examples skipped", path.display(), e))
+            );
+            return Vec::new();
+        }
+    };
+    trace(
+        2,
+        format_args!("{} example(s) in {}", examples.len(), path.display()),
+    );
+    if examples.len() <= MAX_EXAMPLES {
+        return examples;
+    }
+    let wanted = words(query);
+    let mut scored: Vec<_> = examples
+        .into_iter()
+        .enumerate()
+        .map(|(i, example)| {
+            let shared = words(&example.query)
+                .iter()
+                .filter(|w| wanted.contains(w))
+                .count();
+            (shared, i, example)
+        })
+        .collect();
+    scored.sort_by_key(|(shared, i, _)| (std::cmp::Reverse(*shared), *i));
+    scored.truncate(MAX_EXAMPLES);
+    scored.sort_by_key(|(_, i, _)| *i);
+    scored.into_iter().map(|(_, _, example)| example).collect()
+}
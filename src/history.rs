@@ -0,0 +1,1021 @@
+//! Query history in a SQLite database in the state dir: every answered or failed
+//! `complete` and `explain`, indexed by time and subcommand, with full-text search
+//! over queries and results. On unless SMSH_HISTORY=0.
+
+use crate::config::{
+    get_os_context, log_mode, private_file, request_id, state_dir, LogMode, LogRecord,
+};
+use crate::redact::redact;
+use crate::shell::canonical_command;
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use std::env;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+
+/// Bumped with each schema change; migrations run from the stored version up
+const SCHEMA_VERSION: i32 = 5;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS history (
+    id INTEGER PRIMARY KEY,
+    ts INTEGER NOT NULL,
+    cmd TEXT NOT NULL,
+    provider TEXT,
+    model TEXT,
+    cwd TEXT,
+    query TEXT NOT NULL,
+    result TEXT NOT NULL,
+    outcome TEXT,
+    latency_ms INTEGER,
+    input_tokens INTEGER,
+    output_tokens INTEGER
+);
+CREATE INDEX IF NOT EXISTS history_ts ON history (ts);
+CREATE INDEX IF NOT EXISTS history_cmd ON history (cmd, ts);
+CREATE VIRTUAL TABLE IF NOT EXISTS history_fts USING fts5 (
+    query, result, content = 'history', content_rowid = 'id'
+);
+CREATE TRIGGER IF NOT EXISTS history_insert AFTER INSERT ON history BEGIN
+    INSERT INTO history_fts (rowid, query, result) VALUES (new.id, new.query, new.result);
+END;
+CREATE TRIGGER IF NOT EXISTS history_delete AFTER DELETE ON history BEGIN
+    INSERT INTO history_fts (history_fts, rowid, query, result)
+        VALUES ('delete', old.id, old.query, old.result);
+END;
+";
+
+/// Statements that bring a database from version `n + 1` to `n + 2`
+const MIGRATIONS: [&str; 4] = [
+    // 2: whether a suggestion was run, and how it was edited first
+    "ALTER TABLE history ADD COLUMN accepted_at INTEGER;
+     ALTER TABLE history ADD COLUMN ran TEXT;",
+    // 3: the id in traces, the debug log and error messages
+    "ALTER TABLE history ADD COLUMN request_id TEXT;",
+    // 4: the command line a completion changed
+    "ALTER TABLE history ADD COLUMN buffer TEXT;",
+    // 5: what kind of failure an error or refusal was
+    "ALTER TABLE history ADD COLUMN error_type TEXT;
+     UPDATE history SET error_type = 'refusal' WHERE outcome = 'refused';",
+];
+
+/// One stored request
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HistoryEntry {
+    pub id: i64,
+    /// Unix time in milliseconds
+    pub ts: i64,
+    pub cmd: String,
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub cwd: Option<String>,
+    pub query: String,
+    pub result: String,
+    pub outcome: Option<String>,
+    pub latency_ms: Option<u64>,
+    pub input_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+    /// When the suggestion was run (Unix milliseconds); None if it never was, or
+    /// the shell integration doesn't report it
+    pub accepted_at: Option<i64>,
+    /// The command that was run, when it was edited from `result` first
+    pub ran: Option<String>,
+    /// The request id, as in traces and the debug log
+    pub request_id: Option<String>,
+    /// The command line `complete` was asked to change
+    pub buffer: Option<String>,
+    /// For failures, their kind: `rate_limited`, `network`, `refusal`, ...
+    pub error_type: Option<String>,
+}
+
+fn enabled() -> bool {
+    env::var("SMSH_HISTORY").map_or(true, |v| v != "0") && log_mode() != LogMode::Off
+}
+
+fn db_path() -> Option<PathBuf> {
+    state_dir().map(|d| d.join("history.db"))
+}
+
+/// Open the database, creating or migrating it first. A new database starts with
+/// the answers already in a text or JSON Lines SMSH_LOG.
+pub fn open() -> Result<Connection, String> {
+    let path = db_path().ok_or("No state directory for the history")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    // An empty file is an empty database; creating it here makes it private
+    // from the start, and SQLite gives its journal the same mode
+    let created = private_file(OpenOptions::new().write(true).create_new(true))
+        .open(&path)
+        .is_ok();
+    let conn = Connection::open(&path).map_err(|e| e.to_string())?;
+    // Concurrent shells write at the same time; wait for the lock briefly
+    conn.busy_timeout(std::time::Duration::from_secs(2))
+        .map_err(|e| e.to_string())?;
+    migrate(&conn)?;
+    if created && log_mode() == LogMode::Full {
+        if let Some(text) = env::var_os("SMSH_LOG").and_then(|p| std::fs::read_to_string(p).ok()) {
+            import_log(&conn, &text);
+        }
+    }
+    Ok(conn)
+}
+
+/// Create the schema, or bring an older one up to SCHEMA_VERSION
+fn migrate(conn: &Connection) -> Result<(), String> {
+    let version: i32 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    if version < SCHEMA_VERSION {
+        // Take the write lock before looking again: another process opening the
+        // same old database may have migrated it in the meantime
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)
+            .map_err(|e| e.to_string())?;
+        let version: i32 = tx
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        if version < 1 {
+            tx.execute_batch(SCHEMA).map_err(|e| e.to_string())?;
+        }
+        for migration in &MIGRATIONS[(version.max(1) - 1) as usize..] {
+            tx.execute_batch(migration).map_err(|e| e.to_string())?;
+        }
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn insert(conn: &Connection, entry: &HistoryEntry) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO history (ts, cmd, provider, model, cwd, query, result, outcome,
+                              latency_ms, input_tokens, output_tokens, request_id, buffer,
+                              error_type)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        params![
+            entry.ts,
+            entry.cmd,
+            entry.provider,
+            entry.model,
+            entry.cwd,
+            entry.query,
+            entry.result,
+            entry.outcome,
+            entry.latency_ms,
+            entry.input_tokens,
+            entry.output_tokens,
+            entry.request_id,
+            entry.buffer,
+            entry.error_type,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Store a request's outcome, with credentials masked, and without the query and
+/// result in SMSH_LOG_MODE=metadata. Returns the new entry's id. Errors are
+/// ignored: history must never change what the user sees.
+pub fn record(record: &LogRecord) -> Option<i64> {
+    if !enabled() {
+        return None;
+    }
+    let conn = open().ok()?;
+    let content = |text: &str| match log_mode() {
+        LogMode::Full => redact(text),
+        _ => String::new(),
+    };
+    let entry = HistoryEntry {
+        id: 0,
+        ts: chrono::Utc::now().timestamp_millis(),
+        cmd: record.cmd.to_string(),
+        provider: record.provider.map(|p| p.as_str().to_string()),
+        model: record.provider.map(|p| p.model().to_string()),
+        cwd: env::current_dir()
+            .ok()
+            .filter(|_| log_mode() == LogMode::Full)
+            .map(|d| d.to_string_lossy().into_owned()),
+        query: content(record.query),
+        result: content(record.result),
+        outcome: record.outcome.map(String::from),
+        latency_ms: record.latency_ms,
+        input_tokens: record.usage.map(|u| u.input_tokens),
+        output_tokens: record.usage.map(|u| u.output_tokens),
+        accepted_at: None,
+        ran: None,
+        request_id: Some(request_id()),
+        buffer: record
+            .buffer
+            .filter(|b| !b.is_empty() && log_mode() == LogMode::Full)
+            .map(redact),
+        error_type: record.error_type.map(String::from),
+    };
+    insert(&conn, &entry).ok()?;
+    Some(conn.last_insert_rowid())
+}
+
+/// Mark entry `id` as run, as `ran` if that differs from the suggestion. Returns
+/// false if there is no such entry.
+pub fn accept(conn: &Connection, id: i64, ran: Option<&str>) -> Result<bool, String> {
+    let ran = ran
+        .map(str::trim)
+        .filter(|_| log_mode() == LogMode::Full)
+        .map(redact);
+    let updated = conn
+        .execute(
+            "UPDATE history
+             SET accepted_at = ?1, ran = CASE WHEN ?2 = trim(result) THEN NULL ELSE ?2 END
+             WHERE id = ?3",
+            params![chrono::Utc::now().timestamp_millis(), ran, id],
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(updated > 0)
+}
+
+const COLUMNS: &str = "id, ts, cmd, provider, model, cwd, query, result, outcome, latency_ms, \
+                       input_tokens, output_tokens, accepted_at, ran, request_id, buffer, \
+                       error_type";
+
+fn entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<HistoryEntry> {
+    Ok(HistoryEntry {
+        id: row.get(0)?,
+        ts: row.get(1)?,
+        cmd: row.get(2)?,
+        provider: row.get(3)?,
+        model: row.get(4)?,
+        cwd: row.get(5)?,
+        query: row.get(6)?,
+        result: row.get(7)?,
+        outcome: row.get(8)?,
+        latency_ms: row.get(9)?,
+        input_tokens: row.get(10)?,
+        output_tokens: row.get(11)?,
+        accepted_at: row.get(12)?,
+        ran: row.get(13)?,
+        request_id: row.get(14)?,
+        buffer: row.get(15)?,
+        error_type: row.get(16)?,
+    })
+}
+
+/// The latest `limit` entries, newest first, optionally only for subcommand `cmd`
+pub fn recent(
+    conn: &Connection,
+    cmd: Option<&str>,
+    limit: usize,
+) -> Result<Vec<HistoryEntry>, String> {
+    let sql = format!(
+        "SELECT {} FROM history WHERE ?1 IS NULL OR cmd = ?1 ORDER BY ts DESC, id DESC LIMIT ?2",
+        COLUMNS
+    );
+    let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map(params![cmd, limit as i64], entry_from_row)
+        .map_err(|e| e.to_string())?;
+    rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+}
+
+/// Entries whose query or result match the full-text search `terms`, best match
+/// first. Each word is matched as a prefix, so `dock comp` finds `docker compose`.
+pub fn search(conn: &Connection, terms: &str, limit: usize) -> Result<Vec<HistoryEntry>, String> {
+    let fts_query = terms
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if fts_query.is_empty() {
+        return recent(conn, None, limit);
+    }
+    let columns = COLUMNS
+        .split(", ")
+        .map(|c| format!("h.{}", c.trim()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!(
+        "SELECT {} FROM history_fts JOIN history h ON h.id = history_fts.rowid
+         WHERE history_fts MATCH ?1 ORDER BY bm25(history_fts), h.ts DESC LIMIT ?2",
+        columns
+    );
+    let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map(params![fts_query, limit as i64], entry_from_row)
+        .map_err(|e| e.to_string())?;
+    rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+}
+
+/// Up to `k` earlier completions that were run, from scratch, whose queries best
+/// match `query`: (query, command as run) pairs, best first. Empty if the history
+/// is off or holds no such entries.
+pub fn accepted_examples(query: &str, k: usize) -> Vec<(String, String)> {
+    let words: Vec<_> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() > 1)
+        .map(|w| format!("\"{}\"", w))
+        .collect();
+    if k == 0 || words.is_empty() || !enabled() {
+        return Vec::new();
+    }
+    let Ok(conn) = open() else {
+        return Vec::new();
+    };
+    let sql = "SELECT h.query, coalesce(h.ran, h.result) FROM history_fts
+               JOIN history h ON h.id = history_fts.rowid
+               WHERE history_fts MATCH ?1 AND h.cmd = 'complete'
+                 AND h.accepted_at IS NOT NULL AND h.buffer IS NULL
+               ORDER BY bm25(history_fts), h.ts DESC LIMIT ?2";
+    let Ok(mut stmt) = conn.prepare(sql) else {
+        return Vec::new();
+    };
+    // Fetch extra rows, since the same command may have been run for several queries
+    let rows = stmt.query_map(params![words.join(" OR "), (k * 4) as i64], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    });
+    let mut seen = std::collections::HashSet::new();
+    rows.map(|rows| {
+        rows.filter_map(Result::ok)
+            .filter(|(query, command)| !query.is_empty() && !command.trim().is_empty())
+            .filter(|(_, command)| seen.insert(command.trim().to_string()))
+            .take(k)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// The latest earlier completion whose command, as suggested or as run, is the
+/// same as `command` up to quoting, spacing and flag order; one that was run wins
+/// over one that was only suggested. Only the newest entries are searched.
+pub fn seen_before(command: &str) -> Option<HistoryEntry> {
+    if !enabled() || command.trim().is_empty() {
+        return None;
+    }
+    seen_in(&open().ok()?, command)
+}
+
+/// Like `seen_before`, in the database `conn`
+fn seen_in(conn: &Connection, command: &str) -> Option<HistoryEntry> {
+    let wanted = canonical_command(&redact(command));
+    let sql = format!(
+        "SELECT {} FROM history WHERE cmd = 'complete' AND result != ''
+         ORDER BY ts DESC, id DESC LIMIT 5000",
+        COLUMNS
+    );
+    let mut stmt = conn.prepare(&sql).ok()?;
+    let rows = stmt.query_map([], entry_from_row).ok()?;
+    let mut suggested = None;
+    for entry in rows.filter_map(Result::ok) {
+        let same = |text: &str| canonical_command(text) == wanted;
+        if entry.accepted_at.is_some() && entry.ran.as_deref().map_or(same(&entry.result), same) {
+            return Some(entry);
+        }
+        if suggested.is_none() && same(&entry.result) {
+            suggested = Some(entry);
+        }
+    }
+    suggested
+}
+
+/// The query and command (as run, if it was edited first) of the latest
+/// completion that produced one, for a follow-up that refines it. Fails if the
+/// history is off or keeps no queries and results (SMSH_LOG_MODE other than full).
+pub fn previous_turn() -> Result<Option<(String, String)>, String> {
+    if !enabled() || log_mode() != LogMode::Full {
+        return Err("Refining needs the history with SMSH_LOG_MODE=full".to_string());
+    }
+    let conn = open()?;
+    let sql = format!(
+        "SELECT {} FROM history WHERE cmd = 'complete' AND query != '' AND result != ''
+           AND outcome IN ('suggested', 'executed', 'rejected', 'needs_confirmation', 'candidate')
+         ORDER BY ts DESC, id DESC LIMIT 1",
+        COLUMNS
+    );
+    let entry = conn
+        .query_row(&sql, [], entry_from_row)
+        .optional()
+        .map_err(|e| e.to_string())?;
+    Ok(entry.map(|e| (e.query, e.ran.unwrap_or(e.result))))
+}
+
+/// The entry with this id
+pub fn get(conn: &Connection, id: i64) -> Result<Option<HistoryEntry>, String> {
+    let sql = format!("SELECT {} FROM history WHERE id = ?1", COLUMNS);
+    conn.query_row(&sql, [id], entry_from_row)
+        .optional()
+        .map_err(|e| e.to_string())
+}
+
+/// Every entry from `since` (Unix milliseconds) on, oldest first
+pub fn since(conn: &Connection, since: Option<i64>) -> Result<Vec<HistoryEntry>, String> {
+    let sql = format!(
+        "SELECT {} FROM history WHERE ts >= ?1 ORDER BY ts, id",
+        COLUMNS
+    );
+    let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map([since.unwrap_or(i64::MIN)], entry_from_row)
+        .map_err(|e| e.to_string())?;
+    rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+}
+
+/// A `--since` value as Unix milliseconds: a date (`2026-01-31`), a date and time
+/// (RFC 3339 or `2026-01-31 14:00`, local time) or an age such as `90m`, `12h`,
+/// `7d` or `4w`
+pub fn parse_since(text: &str) -> Result<i64, String> {
+    let text = text.trim();
+    let local = |t: chrono::NaiveDateTime| {
+        t.and_local_timezone(chrono::Local)
+            .earliest()
+            .map(|t| t.timestamp_millis())
+    };
+    let age = text
+        .strip_suffix(['m', 'h', 'd', 'w'])
+        .and_then(|n| n.parse::<i64>().ok())
+        .map(|n| {
+            let unit = match text.chars().last() {
+                Some('m') => 60,
+                Some('h') => 3600,
+                Some('d') => 86400,
+                _ => 7 * 86400,
+            };
+            chrono::Utc::now().timestamp_millis() - n * unit * 1000
+        });
+    age.or_else(|| {
+        chrono::DateTime::parse_from_rfc3339(text)
+            .ok()
+            .map(|t| t.timestamp_millis())
+    })
+    .or_else(|| {
+        chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M")
+            .ok()
+            .and_then(local)
+    })
+    .or_else(|| {
+        chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d")
+            .ok()
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .and_then(local)
+    })
+    .ok_or_else(|| {
+        format!(
+            "Can't read --since {:?}; use a date (2026-01-31), a time (2026-01-31 14:00) or an age (12h, 7d, 4w)",
+            text
+        )
+    })
+}
+
+/// Formats for [`export`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// An array of objects with every field, times as RFC 3339 local times
+    Json,
+    /// A header row and one row per entry
+    Csv,
+    /// A table for notes and reports
+    Markdown,
+    /// OpenAI chat fine-tuning data: one `{"messages": [system, user, assistant]}`
+    /// line per completion that was run, answered with the command as it was run
+    OpenAiJsonl,
+}
+
+/// The system message of fine-tuning examples: the task without the structured
+/// output format, which a model trained on these answers doesn't use
+fn fine_tuning_system_prompt() -> String {
+    format!(
+        "Generate a zsh command for the request. Reply with the command only. {}",
+        get_os_context()
+    )
+    .trim_end()
+    .to_string()
+}
+
+/// One fine-tuning example, if `entry` is a completion that was run
+fn fine_tuning_example(entry: &HistoryEntry, system: &str) -> Option<serde_json::Value> {
+    if entry.cmd != "complete" || entry.accepted_at.is_none() || entry.query.is_empty() {
+        return None;
+    }
+    let command = entry.ran.as_deref().unwrap_or(&entry.result).trim();
+    if command.is_empty() {
+        return None;
+    }
+    let user = match entry.buffer.as_deref().filter(|b| !b.is_empty()) {
+        Some(buffer) => format!("{}\n\nChange this command:\n{}", entry.query, buffer),
+        None => entry.query.clone(),
+    };
+    Some(serde_json::json!({
+        "messages": [
+            { "role": "system", "content": system },
+            { "role": "user", "content": user },
+            { "role": "assistant", "content": command },
+        ]
+    }))
+}
+
+/// An entry's Unix milliseconds in the local time zone
+pub fn local_time(ts: i64) -> chrono::DateTime<chrono::Local> {
+    chrono::DateTime::from_timestamp_millis(ts)
+        .unwrap_or_default()
+        .with_timezone(&chrono::Local)
+}
+
+fn export_time(ts: i64) -> String {
+    local_time(ts).to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+}
+
+const EXPORT_FIELDS: [&str; 16] = [
+    "ts",
+    "cmd",
+    "provider",
+    "model",
+    "cwd",
+    "buffer",
+    "query",
+    "result",
+    "outcome",
+    "latency_ms",
+    "input_tokens",
+    "output_tokens",
+    "accepted_at",
+    "ran",
+    "request_id",
+    "error_type",
+];
+
+fn export_row(entry: &HistoryEntry) -> [String; 16] {
+    let text = |v: &Option<String>| v.clone().unwrap_or_default();
+    let number = |v: Option<u64>| v.map(|n| n.to_string()).unwrap_or_default();
+    [
+        export_time(entry.ts),
+        entry.cmd.clone(),
+        text(&entry.provider),
+        text(&entry.model),
+        text(&entry.cwd),
+        text(&entry.buffer),
+        entry.query.clone(),
+        entry.result.clone(),
+        text(&entry.outcome),
+        number(entry.latency_ms),
+        number(entry.input_tokens),
+        number(entry.output_tokens),
+        entry.accepted_at.map(export_time).unwrap_or_default(),
+        text(&entry.ran),
+        text(&entry.request_id),
+        text(&entry.error_type),
+    ]
+}
+
+/// `entries` as a document in `format`
+pub fn export(entries: &[HistoryEntry], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Json => {
+            let items: Vec<_> = entries
+                .iter()
+                .map(|entry| {
+                    let mut json = serde_json::json!(entry);
+                    let time = |ts: i64| {
+                        local_time(ts).to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+                    };
+                    json["ts"] = time(entry.ts).into();
+                    json["accepted_at"] = entry.accepted_at.map(time).into();
+                    json
+                })
+                .collect();
+            serde_json::to_string_pretty(&items).unwrap_or_default() + "\n"
+        }
+        ExportFormat::Csv => {
+            let field = |value: &str| {
+                if value.contains([',', '"', '\n', '\r']) {
+                    format!("\"{}\"", value.replace('"', "\"\""))
+                } else {
+                    value.to_string()
+                }
+            };
+            let mut out = EXPORT_FIELDS.join(",") + "\r\n";
+            for entry in entries {
+                let row: Vec<_> = export_row(entry).iter().map(|v| field(v)).collect();
+                out.push_str(&row.join(","));
+                out.push_str("\r\n");
+            }
+            out
+        }
+        ExportFormat::Markdown => {
+            let cell = |value: &str| {
+                value
+                    .replace('\\', "\\\\")
+                    .replace('|', "\\|")
+                    .replace('\n', "<br>")
+            };
+            let mut out = format!(
+                "| {} |\n|{}\n",
+                EXPORT_FIELDS.join(" | "),
+                "---|".repeat(EXPORT_FIELDS.len())
+            );
+            for entry in entries {
+                let row: Vec<_> = export_row(entry).iter().map(|v| cell(v)).collect();
+                out.push_str(&format!("| {} |\n", row.join(" | ")));
+            }
+            out
+        }
+        ExportFormat::OpenAiJsonl => {
+            let system = fine_tuning_system_prompt();
+            entries
+                .iter()
+                .filter_map(|entry| fine_tuning_example(entry, &system))
+                .map(|example| format!("{}\n", example))
+                .collect()
+        }
+    }
+}
+
+/// Copy the `complete` and `explain` answers of an existing SMSH_LOG, in either
+/// format, into a new database
+fn import_log(conn: &Connection, text: &str) {
+    let line_re = regex::Regex::new(
+        r"^\[(\d{4}-\d\d-\d\d \d\d:\d\d:\d\d)\] (?:\[([\w-]+)\] )?(complete|explain) \| query: (.*?) \| result: (.*)$",
+    )
+    .expect("valid log pattern");
+    let labels = [
+        ("CANDIDATE: ", "candidate"),
+        ("EXECUTE: ", "executed"),
+        ("REJECTED: ", "rejected"),
+        ("DESTRUCTIVE: ", "needs_confirmation"),
+        ("REFUSED: ", "refused"),
+        ("ERROR: ", "error"),
+        ("CANCELLED: ", "cancelled"),
+    ];
+    let Ok(tx) = conn.unchecked_transaction() else {
+        return;
+    };
+    for line in text.lines() {
+        let entry = if line.starts_with('{') {
+            let Ok(json) = serde_json::from_str::<serde_json::Value>(line) else {
+                continue;
+            };
+            let cmd = json["cmd"].as_str().unwrap_or_default();
+            if cmd != "complete" && cmd != "explain" {
+                continue;
+            }
+            let ts = json["ts"]
+                .as_str()
+                .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+                .map_or(0, |t| t.timestamp_millis());
+            let text = |key: &str| json[key].as_str().map(String::from);
+            HistoryEntry {
+                id: 0,
+                ts,
+                cmd: cmd.to_string(),
+                provider: text("provider"),
+                model: text("model"),
+                cwd: None,
+                query: text("query").unwrap_or_default(),
+                result: text("result").unwrap_or_default(),
+                outcome: text("outcome"),
+                latency_ms: json["latency_ms"].as_u64(),
+                input_tokens: json["usage"]["input_tokens"].as_u64(),
+                output_tokens: json["usage"]["output_tokens"].as_u64(),
+                accepted_at: None,
+                ran: None,
+                request_id: text("request_id"),
+                buffer: text("buffer"),
+                error_type: text("error_type"),
+            }
+        } else {
+            let Some(caps) = line_re.captures(line) else {
+                continue;
+            };
+            let ts = chrono::NaiveDateTime::parse_from_str(&caps[1], "%Y-%m-%d %H:%M:%S")
+                .ok()
+                .and_then(|t| t.and_local_timezone(chrono::Local).single())
+                .map_or(0, |t| t.timestamp_millis());
+            let (result, outcome) = labels
+                .iter()
+                .find_map(|(label, outcome)| {
+                    caps[5].strip_prefix(label).map(|r| (r, Some(*outcome)))
+                })
+                .unwrap_or((&caps[5], None));
+            HistoryEntry {
+                id: 0,
+                ts,
+                cmd: caps[3].to_string(),
+                provider: None,
+                model: None,
+                cwd: None,
+                query: caps[4].to_string(),
+                result: result.to_string(),
+                outcome: outcome.map(String::from),
+                latency_ms: None,
+                input_tokens: None,
+                output_tokens: None,
+                accepted_at: None,
+                ran: None,
+                request_id: caps.get(2).map(|m| m.as_str().to_string()),
+                buffer: None,
+                error_type: (outcome == Some("refused")).then(|| "refusal".to_string()),
+            }
+        };
+        let _ = insert(&tx, &entry);
+    }
+    let _ = tx.commit();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A migrated in-memory database
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        conn
+    }
+
+    fn entry(ts: i64, cmd: &str, query: &str, result: &str) -> HistoryEntry {
+        HistoryEntry {
+            id: 0,
+            ts,
+            cmd: cmd.to_string(),
+            provider: Some("openai".to_string()),
+            model: None,
+            cwd: None,
+            query: query.to_string(),
+            result: result.to_string(),
+            outcome: None,
+            latency_ms: Some(850),
+            input_tokens: None,
+            output_tokens: None,
+            accepted_at: None,
+            ran: None,
+            request_id: None,
+            buffer: None,
+            error_type: None,
+        }
+    }
+
+    fn results(entries: Vec<HistoryEntry>) -> Vec<String> {
+        entries.into_iter().map(|e| e.result).collect()
+    }
+
+    #[test]
+    fn migrate_creates_the_schema_once() {
+        let conn = database();
+        let version: i32 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        migrate(&conn).unwrap();
+        insert(&conn, &entry(1, "complete", "list files", "ls")).unwrap();
+        let stored = get(&conn, 1).unwrap().unwrap();
+        assert_eq!(stored.result, "ls");
+        assert_eq!(stored.latency_ms, Some(850));
+        assert!(get(&conn, 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn migrate_upgrades_a_version_1_database() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute(
+            "INSERT INTO history (ts, cmd, query, result, outcome)
+             VALUES (1, 'complete', 'weather', 'Not a shell task', 'refused')",
+            [],
+        )
+        .unwrap();
+        migrate(&conn).unwrap();
+        let version: i32 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        let old = get(&conn, 1).unwrap().unwrap();
+        assert_eq!(old.error_type.as_deref(), Some("refusal"));
+        assert_eq!(
+            (old.accepted_at, old.request_id, old.buffer),
+            (None, None, None)
+        );
+        let mut new = entry(2, "complete", "q", "ls");
+        new.request_id = Some("r1".to_string());
+        new.buffer = Some("ls".to_string());
+        new.error_type = Some("network".to_string());
+        insert(&conn, &new).unwrap();
+        let stored = get(&conn, 2).unwrap().unwrap();
+        assert_eq!(stored.request_id.as_deref(), Some("r1"));
+        assert_eq!(stored.buffer.as_deref(), Some("ls"));
+        assert_eq!(stored.error_type.as_deref(), Some("network"));
+        assert_eq!(accept(&conn, 2, None), Ok(true));
+        assert!(get(&conn, 2).unwrap().unwrap().accepted_at.is_some());
+    }
+
+    #[test]
+    fn recent_is_newest_first_and_filters_by_subcommand() {
+        let conn = database();
+        insert(&conn, &entry(1, "complete", "list files", "ls")).unwrap();
+        insert(&conn, &entry(2, "explain", "ls -la", "# Lists all files")).unwrap();
+        insert(&conn, &entry(3, "complete", "disk usage", "du -sh")).unwrap();
+        assert_eq!(
+            results(recent(&conn, None, 10).unwrap()),
+            ["du -sh", "# Lists all files", "ls"]
+        );
+        assert_eq!(
+            results(recent(&conn, Some("complete"), 1).unwrap()),
+            ["du -sh"]
+        );
+    }
+
+    #[test]
+    fn search_matches_word_prefixes_in_queries_and_results() {
+        let conn = database();
+        insert(
+            &conn,
+            &entry(1, "complete", "start the stack", "docker compose up"),
+        )
+        .unwrap();
+        insert(&conn, &entry(2, "complete", "list files", "ls")).unwrap();
+        assert_eq!(
+            results(search(&conn, "dock comp", 10).unwrap()),
+            ["docker compose up"]
+        );
+        assert_eq!(
+            results(search(&conn, "stack", 10).unwrap()),
+            ["docker compose up"]
+        );
+        assert!(search(&conn, "kubectl", 10).unwrap().is_empty());
+        assert_eq!(search(&conn, "  ", 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn search_terms_are_not_query_syntax() {
+        let conn = database();
+        insert(&conn, &entry(1, "complete", "say \"hi\" OR bye", "echo hi")).unwrap();
+        for terms in ["\"hi\"", "OR", "hi*", "NEAR(", "-x", "a:b"] {
+            assert!(search(&conn, terms, 10).is_ok(), "{}", terms);
+        }
+        assert_eq!(results(search(&conn, "\"hi", 10).unwrap()), ["echo hi"]);
+    }
+
+    #[test]
+    fn import_log_reads_both_log_formats() {
+        let conn = database();
+        let log = "\
+[2025-01-02 03:04:05] complete | query: list files | result: EXECUTE: ls -la
+[2025-01-02 03:04:06] explain | query: ls -la | result: # Lists all files
+[2025-01-02 03:04:07] probe | query: x | result: y
+not a log line
+{\"ts\":\"2025-01-03T03:04:08Z\",\"cmd\":\"complete\",\"provider\":\"claude\",\"query\":\"disk usage\",\"result\":\"du -sh\",\"outcome\":\"candidate\",\"latency_ms\":900,\"usage\":{\"input_tokens\":10,\"output_tokens\":4}}
+{\"ts\":\"2025-01-02T03:04:09Z\",\"cmd\":\"breaker\",\"query\":\"x\",\"result\":\"y\"}
+";
+        import_log(&conn, log);
+        let entries = recent(&conn, None, 10).unwrap();
+        assert_eq!(entries.len(), 3);
+        let json = &entries[0];
+        assert_eq!(json.ts, 1_735_873_448_000);
+        assert_eq!(
+            (json.provider.as_deref(), json.outcome.as_deref()),
+            (Some("claude"), Some("candidate"))
+        );
+        assert_eq!((json.input_tokens, json.output_tokens), (Some(10), Some(4)));
+        assert_eq!(entries[2].result, "ls -la");
+        assert_eq!(entries[2].outcome.as_deref(), Some("executed"));
+        assert_eq!(entries[1].outcome, None);
+    }
+
+    #[test]
+    fn since_reads_ages_dates_and_times() {
+        let now = chrono::Utc::now().timestamp_millis();
+        let close = |text: &str, ago: i64| {
+            let ts = parse_since(text).unwrap();
+            assert!((now - ago * 1000 - ts).abs() < 5000, "{}", text);
+        };
+        close("90m", 90 * 60);
+        close("12h", 12 * 3600);
+        close(" 7d ", 7 * 86400);
+        close("4w", 28 * 86400);
+        assert_eq!(parse_since("2026-01-31T14:00:00Z"), Ok(1_769_868_000_000));
+        let local = |t: &str| {
+            chrono::NaiveDateTime::parse_from_str(t, "%Y-%m-%d %H:%M")
+                .unwrap()
+                .and_local_timezone(chrono::Local)
+                .earliest()
+                .unwrap()
+                .timestamp_millis()
+        };
+        assert_eq!(
+            parse_since("2026-01-31 14:00"),
+            Ok(local("2026-01-31 14:00"))
+        );
+        assert_eq!(parse_since("2026-01-31"), Ok(local("2026-01-31 00:00")));
+        for text in ["yesterday", "7", "d", "2026-13-01"] {
+            assert!(parse_since(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn since_returns_later_entries_oldest_first() {
+        let conn = database();
+        for (ts, result) in [(3000, "c"), (1000, "a"), (2000, "b")] {
+            insert(&conn, &entry(ts, "complete", "q", result)).unwrap();
+        }
+        assert_eq!(results(since(&conn, None).unwrap()), ["a", "b", "c"]);
+        assert_eq!(results(since(&conn, Some(2000)).unwrap()), ["b", "c"]);
+    }
+
+    #[test]
+    fn csv_export_quotes_fields_that_need_it() {
+        let entries = [entry(
+            0,
+            "complete",
+            "say \"hi\", twice",
+            "echo hi\necho hi",
+        )];
+        let csv = export(&entries, ExportFormat::Csv);
+        let mut lines = csv.split("\r\n");
+        assert_eq!(lines.next(), Some(EXPORT_FIELDS.join(",").as_str()));
+        let row = lines.next().unwrap();
+        assert!(
+            row.contains(
+                ",complete,openai,,,,\"say \"\"hi\"\", twice\",\"echo hi\necho hi\",,850,,"
+            ),
+            "{}",
+            row
+        );
+    }
+
+    #[test]
+    fn markdown_export_escapes_table_syntax() {
+        let entries = [entry(0, "complete", "a | b", "grep 'x\\|y'\nwc -l")];
+        let markdown = export(&entries, ExportFormat::Markdown);
+        let lines: Vec<_> = markdown.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[1],
+            "|---|".to_string() + &"---|".repeat(EXPORT_FIELDS.len() - 1)
+        );
+        assert!(
+            lines[2].contains(" | a \\| b | grep 'x\\\\\\|y'<br>wc -l | "),
+            "{}",
+            lines[2]
+        );
+    }
+
+    #[test]
+    fn json_export_keeps_every_field() {
+        let json: serde_json::Value = serde_json::from_str(&export(
+            &[entry(0, "explain", "q", "r")],
+            ExportFormat::Json,
+        ))
+        .unwrap();
+        assert_eq!(json[0]["cmd"], "explain");
+        assert_eq!(json[0]["latency_ms"], 850);
+        assert_eq!(
+            json[0]["ts"],
+            local_time(0).to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+        );
+    }
+
+    #[test]
+    fn fine_tuning_examples_are_completions_that_were_run() {
+        let mut run = entry(0, "complete", "list files", "ls");
+        run.accepted_at = Some(1);
+        let example = fine_tuning_example(&run, "system").unwrap();
+        let messages = example["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["content"], "system");
+        assert_eq!(messages[1]["content"], "list files");
+        assert_eq!(messages[2]["content"], "ls");
+
+        run.ran = Some(" ls -la ".to_string());
+        run.buffer = Some("ls".to_string());
+        let example = fine_tuning_example(&run, "system").unwrap();
+        assert_eq!(
+            example["messages"][1]["content"],
+            "list files\n\nChange this command:\nls"
+        );
+        assert_eq!(example["messages"][2]["content"], "ls -la");
+
+        assert!(fine_tuning_example(&entry(0, "complete", "list files", "ls"), "s").is_none());
+        let mut explained = entry(0, "explain", "ls", "# Lists files");
+        explained.accepted_at = Some(1);
+        assert!(fine_tuning_example(&explained, "s").is_none());
+        run.ran = Some("  ".to_string());
+        assert!(fine_tuning_example(&run, "s").is_none());
+    }
+
+    #[test]
+    fn seen_before_ignores_quoting_spacing_and_flag_order() {
+        let conn = database();
+        insert(&conn, &entry(1, "complete", "q", "ls -la  'My Files'")).unwrap();
+        insert(&conn, &entry(2, "explain", "q", "du -sh")).unwrap();
+        let seen = seen_in(&conn, "ls -al \"My Files\"").unwrap();
+        assert_eq!(seen.ts, 1);
+        assert!(seen_in(&conn, "ls -la Files").is_none());
+        assert!(seen_in(&conn, "du -sh").is_none());
+    }
+
+    #[test]
+    fn seen_before_prefers_a_command_that_was_run() {
+        let conn = database();
+        insert(&conn, &entry(1, "complete", "q", "git status --short")).unwrap();
+        assert_eq!(accept(&conn, 1, Some("git status -s")), Ok(true));
+        insert(&conn, &entry(2, "complete", "q", "git status -s")).unwrap();
+        assert_eq!(seen_in(&conn, "git status -s").unwrap().ts, 1);
+        // Only suggested, since it was edited before it ran
+        let suggested = seen_in(&conn, "git status --short").unwrap();
+        assert_eq!(suggested.ran.as_deref(), Some("git status -s"));
+    }
+}
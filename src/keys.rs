@@ -0,0 +1,191 @@
+//! Several API keys per provider: any key source may hold a list separated by
+//! commas or newlines, and a key that is rejected or out of quota hands over to the
+//! next one. Which key is current is shared by all processes through the state dir.
+
+use crate::client::SmartshellClient;
+use crate::config::{log_event, read_state, trace, update_state};
+use crate::error::Failure;
+use crate::oauth::uses_oauth;
+use crate::provider::Provider;
+use std::collections::HashMap;
+use std::future::Future;
+
+/// The keys in a configured value, in order
+pub(crate) fn split_keys(raw: &str) -> Vec<&str> {
+    raw.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|k| !k.is_empty())
+        .collect()
+}
+
+/// Index of the current key per provider, persisted in the state dir
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct KeyState {
+    current: HashMap<String, usize>,
+}
+
+const KEYS_FILE: &str = "keys.json";
+
+impl KeyState {
+    /// Move `provider` from key `from` to the next of `count`, unless another
+    /// process already has. Returns whether the state changed.
+    fn advance(&mut self, provider: Provider, from: usize, count: usize) -> bool {
+        let current = self
+            .current
+            .entry(provider.as_str().to_string())
+            .or_default();
+        if *current % count != from {
+            return false;
+        }
+        *current = (from + 1) % count;
+        true
+    }
+}
+
+/// Which of `count` keys `provider` is on
+fn current_index(provider: Provider, count: usize) -> usize {
+    read_state::<KeyState>(KEYS_FILE)
+        .current
+        .get(provider.as_str())
+        .copied()
+        .unwrap_or(0)
+        % count.max(1)
+}
+
+/// The key to sign the next request with, out of the configured `raw` value
+pub(crate) fn current_key(provider: Provider, raw: &str) -> Option<String> {
+    let keys = split_keys(raw);
+    let key = keys.get(current_index(provider, keys.len()))?;
+    Some(key.to_string())
+}
+
+/// Failures another key may not run into: the key was rejected (401/403,
+/// `invalid_api_key`) or its account is out of quota, as opposed to being briefly
+/// rate limited
+fn rotates(failure: &Failure) -> bool {
+    match failure {
+        Failure::Auth { .. } => true,
+        Failure::RateLimited { message, .. } => message.to_lowercase().contains("quota"),
+        _ => false,
+    }
+}
+
+/// Move on from key `from`, unless a concurrent request already has
+fn advance(provider: Provider, from: usize, count: usize, failure: &Failure) {
+    update_state(KEYS_FILE, |state: &mut KeyState| {
+        state.advance(provider, from, count)
+    });
+    trace(
+        1,
+        format_args!(
+            "key {} of {} failed, switching to key {}",
+            from + 1,
+            count,
+            (from + 1) % count + 1
+        ),
+    );
+    log_event(
+        "key",
+        provider.as_str(),
+        &format!(
+            "key {} of {} failed ({}), switching to key {}",
+            from + 1,
+            count,
+            failure.message(),
+            (from + 1) % count + 1
+        ),
+    );
+}
+
+/// Run `send` (which signs its request through `authorize`) with the current key,
+/// and again with each next key while they are rejected or out of quota. With
+/// more than one key, the debug log records which one served the request.
+pub(crate) async fn with_key_rotation<T, F>(
+    client: &SmartshellClient,
+    provider: Provider,
+    mut send: impl FnMut() -> F,
+) -> Result<T, Failure>
+where
+    F: Future<Output = Result<T, Failure>>,
+{
+    let count = match client.api_key_for(provider) {
+        Some(raw) if !uses_oauth(provider) => split_keys(&raw).len(),
+        _ => 0,
+    };
+    if count < 2 {
+        return send().await;
+    }
+    let mut tried = 0;
+    loop {
+        let index = current_index(provider, count);
+        let result = send().await;
+        tried += 1;
+        match result {
+            Err(failure) if tried < count && rotates(&failure) => {
+                advance(provider, index, count, &failure)
+            }
+            result => {
+                if result.is_ok() {
+                    trace(1, format_args!("served by key {} of {}", index + 1, count));
+                    log_event(
+                        "key",
+                        provider.as_str(),
+                        &format!("served by key {} of {}", index + 1, count),
+                    );
+                }
+                return result;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_split_on_commas_and_whitespace() {
+        assert_eq!(split_keys("sk-a"), ["sk-a"]);
+        assert_eq!(split_keys("sk-a, sk-b,sk-c"), ["sk-a", "sk-b", "sk-c"]);
+        assert_eq!(split_keys("sk-a\nsk-b\n\n"), ["sk-a", "sk-b"]);
+        assert!(split_keys(" , ").is_empty());
+    }
+
+    #[test]
+    fn rejected_or_exhausted_keys_rotate() {
+        let provider = Provider::OpenAi;
+        let rate_limited = |message: &str| Failure::RateLimited {
+            provider,
+            retry_after: None,
+            message: message.to_string(),
+        };
+        assert!(rotates(&Failure::Auth {
+            provider,
+            message: "invalid_api_key".to_string()
+        }));
+        assert!(rotates(&rate_limited("You exceeded your current Quota")));
+        assert!(!rotates(&rate_limited("Rate limit reached, retry in 2s")));
+        assert!(!rotates(&Failure::Network {
+            provider,
+            message: "connection refused".to_string()
+        }));
+    }
+
+    #[test]
+    fn advance_moves_to_the_next_key_and_wraps() {
+        let mut state = KeyState::default();
+        assert!(state.advance(Provider::OpenAi, 0, 3));
+        assert_eq!(state.current["openai"], 1);
+        assert!(state.advance(Provider::OpenAi, 1, 3));
+        assert!(state.advance(Provider::OpenAi, 2, 3));
+        assert_eq!(state.current["openai"], 0);
+        assert!(!state.current.contains_key("claude"));
+    }
+
+    #[test]
+    fn advance_leaves_a_key_another_process_already_switched_to() {
+        let mut state = KeyState::default();
+        state.current.insert("openai".to_string(), 2);
+        assert!(!state.advance(Provider::OpenAi, 1, 3));
+        assert_eq!(state.current["openai"], 2);
+    }
+}
@@ -0,0 +1,64 @@
+//! smartshell: generate and explain zsh (or PowerShell) commands with an LLM.
+//!
+//! The `smartshell` binary is a thin CLI over this crate; other frontends can
+//! use [`SmartshellClient`] directly:
+//!
+//! ```no_run
+//! use smartshell::{CompletionRequest, ContextSource, SmartshellClient};
+//!
+//! let client = SmartshellClient::from_env()?.spinner(false);
+//! let mut request = CompletionRequest::new("only show the first 10 lines");
+//! request.context = ContextSource::Buffer("cat server.log".to_string());
+//! let resp = client.complete(&request)?;
+//! println!("{}", resp.result);
+//! # Ok::<(), smartshell::Failure>(())
+//! ```
+
+pub mod aliases;
+pub mod audit;
+pub mod auth;
+mod breaker;
+pub mod buildfile;
+mod cache;
+mod client;
+mod config;
+mod console;
+pub mod daemon;
+mod error;
+mod examples;
+pub mod history;
+mod injection;
+mod keys;
+mod logrotate;
+mod mock;
+mod oauth;
+mod policy;
+mod probe;
+mod provider;
+mod ratelimit;
+mod redact;
+mod replay;
+pub mod report;
+mod response;
+mod review;
+pub mod sandbox;
+mod sanitize;
+pub mod serve;
+pub mod shell;
+pub mod spend;
+pub mod style;
+pub mod telemetry;
+mod versions;
+
+pub use cache::caches_answers;
+pub use client::{CompletionRequest, ContextSource, Detail, SmartshellClient};
+pub use config::{flush_log, log_entry, log_record, request_id, set_verbosity, LogRecord};
+pub use error::{
+    Failure, Outcome, EXIT_AUTH, EXIT_CANCELLED, EXIT_CONFIRM, EXIT_ERROR, EXIT_NETWORK,
+    EXIT_RATE_LIMITED, EXIT_REFUSED, EXIT_TIMEOUT,
+};
+pub use injection::injection_lines;
+pub use provider::Provider;
+pub use response::{LlmResponse, Risk, Step, TokenMeaning, LOW_CONFIDENCE};
+pub use shell::TargetShell;
+pub use versions::parse_tool_version;
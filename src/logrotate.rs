@@ -0,0 +1,296 @@
+//! Rotation of the SMSH_LOG debug log: once the file is larger than
+//! SMSH_LOG_MAX_SIZE (10 MB unless set; 0 = never) or its first entry is older than
+//! SMSH_LOG_MAX_AGE days, it is renamed to `<log>.1` (shifting older ones up to
+//! SMSH_LOG_KEEP, default 3) and a new file is started. SMSH_LOG_COMPRESS=1 gzips
+//! the rotated files.
+
+use crate::config::{env_parse, private_file};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: usize = 3;
+
+/// A rotation lock left behind by a crashed process is ignored after this long
+const STALE_LOCK: Duration = Duration::from_secs(60);
+
+/// A size such as `500000`, `512K`, `10M` or `1G`
+fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let (digits, unit) = match text.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((at, _)) => text.split_at(at),
+        None => (text, ""),
+    };
+    let scale = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches(['B', 'I'])
+    {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(scale)
+}
+
+fn max_size() -> Option<u64> {
+    let size = match env::var("SMSH_LOG_MAX_SIZE") {
+        Ok(value) => parse_size(&value).unwrap_or(DEFAULT_MAX_SIZE),
+        Err(_) => DEFAULT_MAX_SIZE,
+    };
+    Some(size).filter(|&s| s > 0)
+}
+
+fn max_age() -> Option<Duration> {
+    env_parse::<f64>("SMSH_LOG_MAX_AGE").and_then(age_limit)
+}
+
+/// `days` as a duration, or None (no age limit) unless it is positive and
+/// fits: `inf`, `NaN` and absurdly many days don't
+fn age_limit(days: f64) -> Option<Duration> {
+    if days.is_finite() && days > 0.0 {
+        Duration::try_from_secs_f64(days * 86400.0).ok()
+    } else {
+        None
+    }
+}
+
+fn keep() -> usize {
+    env_parse("SMSH_LOG_KEEP").unwrap_or(DEFAULT_KEEP)
+}
+
+fn compress() -> bool {
+    env::var("SMSH_LOG_COMPRESS").is_ok_and(|v| v == "1")
+}
+
+/// When the log's first entry was written, from its timestamp in either format
+fn first_entry_time(path: &Path) -> Option<SystemTime> {
+    let mut line = String::new();
+    BufReader::new(File::open(path).ok()?)
+        .read_line(&mut line)
+        .ok()?;
+    let time = if line.starts_with('{') {
+        let json: serde_json::Value = serde_json::from_str(&line).ok()?;
+        chrono::DateTime::parse_from_rfc3339(json["ts"].as_str()?)
+            .ok()?
+            .timestamp()
+    } else {
+        let stamp = line.strip_prefix('[')?.get(..19)?;
+        chrono::NaiveDateTime::parse_from_str(stamp, "%Y-%m-%d %H:%M:%S")
+            .ok()?
+            .and_local_timezone(chrono::Local)
+            .earliest()?
+            .timestamp()
+    };
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(time.max(0) as u64))
+}
+
+fn due(path: &Path) -> bool {
+    exceeds(path, max_size(), max_age())
+}
+
+/// Whether the log at `path` is non-empty and at least `max_size` bytes, or its
+/// first entry at least `max_age` old
+fn exceeds(path: &Path, max_size: Option<u64>, max_age: Option<Duration>) -> bool {
+    let Ok(meta) = fs::metadata(path) else {
+        return false;
+    };
+    if meta.len() == 0 {
+        return false;
+    }
+    if max_size.is_some_and(|max| meta.len() >= max) {
+        return true;
+    }
+    max_age.is_some_and(|max| {
+        first_entry_time(path)
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age >= max)
+    })
+}
+
+fn rotated(path: &Path, n: usize, gz: bool) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}{}", n, if gz { ".gz" } else { "" }));
+    PathBuf::from(name)
+}
+
+/// Whichever of `<log>.n` and `<log>.n.gz` exists
+fn existing(path: &Path, n: usize) -> Option<(PathBuf, bool)> {
+    [false, true]
+        .into_iter()
+        .map(|gz| (rotated(path, n, gz), gz))
+        .find(|(p, _)| p.exists())
+}
+
+fn gzip(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut input = File::open(from)?;
+    let file = private_file(OpenOptions::new().write(true).create(true).truncate(true)).open(to)?;
+    let mut output = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    std::io::copy(&mut input, &mut output)?;
+    output.finish()?;
+    fs::remove_file(from)
+}
+
+/// Rotate the log at `path` if it is over its size or age limit. Returns whether
+/// it did, so the writer knows to reopen the file. Shells writing the same log
+/// take turns through `<log>.lock`; errors leave the log as it is.
+pub(crate) fn rotate_if_due(path: &Path) -> bool {
+    if !due(path) {
+        return false;
+    }
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(".lock");
+    let lock = PathBuf::from(lock);
+    let stale = fs::metadata(&lock)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.elapsed().ok())
+        .is_some_and(|age| age > STALE_LOCK);
+    if stale {
+        let _ = fs::remove_file(&lock);
+    }
+    if OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+        .is_err()
+    {
+        return false;
+    }
+    // Another shell may have rotated it between the check and taking the lock
+    let rotated_now = due(path) && rotate(path);
+    let _ = fs::remove_file(&lock);
+    rotated_now
+}
+
+fn rotate(path: &Path) -> bool {
+    shift(path, keep(), compress())
+}
+
+/// Move the log at `path` to `<log>.1` (gzipped if `compress`), shifting the
+/// older ones up and dropping any beyond `keep`
+fn shift(path: &Path, keep: usize, compress: bool) -> bool {
+    if keep == 0 {
+        return fs::remove_file(path).is_ok();
+    }
+    if let Some((oldest, _)) = existing(path, keep) {
+        let _ = fs::remove_file(oldest);
+    }
+    for n in (1..keep).rev() {
+        if let Some((from, gz)) = existing(path, n) {
+            let _ = fs::rename(from, rotated(path, n + 1, gz));
+        }
+    }
+    let first = rotated(path, 1, false);
+    if fs::rename(path, &first).is_err() {
+        return false;
+    }
+    if compress && gzip(&first, &rotated(path, 1, true)).is_err() {
+        let _ = fs::remove_file(rotated(path, 1, true));
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::scratch_dir;
+
+    #[test]
+    fn sizes_take_binary_units() {
+        assert_eq!(parse_size("500000"), Some(500_000));
+        assert_eq!(parse_size("512K"), Some(512 * 1024));
+        assert_eq!(parse_size(" 10 MB "), Some(10 << 20));
+        assert_eq!(parse_size("10MiB"), Some(10 << 20));
+        assert_eq!(parse_size("1g"), Some(1 << 30));
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size("10T"), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("99999999999999G"), None);
+        assert_eq!(parse_size("99999999999999999999"), None);
+    }
+
+    #[test]
+    fn ages_must_be_positive_and_finite() {
+        assert_eq!(age_limit(1.5), Some(Duration::from_secs(129_600)));
+        for days in [0.0, -1.0, f64::INFINITY, f64::NAN, 1e300] {
+            assert_eq!(age_limit(days), None, "{}", days);
+        }
+    }
+
+    #[test]
+    fn due_by_size_or_age_of_the_first_entry() {
+        let path = scratch_dir("logrotate-due").join("smartshell.log");
+        assert!(!exceeds(&path, Some(1), None));
+        fs::write(&path, "").unwrap();
+        assert!(!exceeds(&path, Some(0), Some(Duration::ZERO)));
+        fs::write(
+            &path,
+            "[2020-01-01 00:00:00] complete | query: a | result: b\n",
+        )
+        .unwrap();
+        assert!(exceeds(&path, Some(10), None));
+        assert!(!exceeds(&path, Some(1 << 20), None));
+        let day = Duration::from_secs(86400);
+        assert!(exceeds(&path, None, Some(day)));
+        let now = chrono::Utc::now().to_rfc3339();
+        fs::write(
+            &path,
+            format!("{{\"ts\":\"{}\",\"cmd\":\"complete\"}}\n", now),
+        )
+        .unwrap();
+        assert!(!exceeds(&path, None, Some(day)));
+        assert!(!exceeds(&path, None, None));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn shift_moves_rotated_logs_up_and_drops_the_oldest() {
+        let path = scratch_dir("logrotate-shift").join("smartshell.log");
+        fs::write(&path, "current").unwrap();
+        fs::write(rotated(&path, 1, false), "one").unwrap();
+        fs::write(rotated(&path, 2, true), "two").unwrap();
+        fs::write(rotated(&path, 3, false), "three").unwrap();
+        assert!(shift(&path, 3, false));
+        assert!(!path.exists());
+        let read = |n, gz| fs::read_to_string(rotated(&path, n, gz)).ok();
+        assert_eq!(read(1, false).as_deref(), Some("current"));
+        assert_eq!(read(2, false).as_deref(), Some("one"));
+        assert_eq!(read(3, true).as_deref(), Some("two"));
+        assert_eq!(read(3, false), None);
+        assert_eq!(existing(&path, 4), None);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn shift_can_compress_or_keep_nothing() {
+        let path = scratch_dir("logrotate-gzip").join("smartshell.log");
+        fs::write(&path, "current").unwrap();
+        assert!(shift(&path, 2, true));
+        assert_eq!(existing(&path, 1), Some((rotated(&path, 1, true), true)));
+        let mut text = String::new();
+        let file = File::open(rotated(&path, 1, true)).unwrap();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(file), &mut text).unwrap();
+        assert_eq!(text, "current");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(rotated(&path, 1, true))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::write(&path, "next").unwrap();
+        assert!(shift(&path, 0, false));
+        assert!(!path.exists());
+        assert!(!rotated(&path, 1, false).exists());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -1,13 +1,90 @@
-use clap::{Parser, Subcommand};
-use std::env;
-use std::fs::OpenOptions;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use smartshell::buildfile::BuildTool;
+use smartshell::shell::{TargetShell, WordChange};
+use smartshell::style::{ColorChoice, Style};
+use smartshell::{
+    aliases, audit, auth, buildfile, caches_answers, daemon, flush_log, history, log_record,
+    parse_tool_version, report, request_id, sandbox, serve, set_verbosity, shell, spend, style,
+    telemetry, CompletionRequest, ContextSource, Detail, Failure, LlmResponse, LogRecord, Provider,
+    SmartshellClient, TokenMeaning, EXIT_CONFIRM, EXIT_ERROR, LOW_CONFIDENCE,
+};
+use std::cell::{Cell, OnceCell};
 use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 #[derive(Parser)]
 #[command(author, version, about = "smartshell: LLM-powered zsh CLI helper")]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Print failures as a JSON envelope on stdout
+    #[arg(long, global = true)]
+    json_errors: bool,
+    /// Print the result as text, or as one JSON object for other programs
+    #[arg(long, global = true, value_enum, default_value_t = Output::Text)]
+    output: Output,
+    /// Color output: auto (terminals, unless NO_COLOR is set), always or never
+    #[arg(long, global = true, value_enum, default_value_t = Color::Auto)]
+    color: Color,
+    /// Trace provider, HTTP status, timing and retries on stderr (-vv: also the prompts)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Screen-reader friendly output: no spinner, colors or symbols (or $SMSH_PLAIN, TERM=dumb)
+    #[arg(long, global = true)]
+    plain: bool,
+    /// Print tokens, estimated cost and latency after the answer (or $SMSH_SHOW_USAGE)
+    #[arg(long, global = true)]
+    show_usage: bool,
+    #[command(flatten)]
+    client: ClientArgs,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DetailArg {
+    Brief,
+    Normal,
+    Deep,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    Text,
+    Json,
+}
+
+/// Global flags that configure the client
+#[derive(Args)]
+struct ClientArgs {
+    /// Seconds to wait for the provider before giving up (default 30, or $SMSH_TIMEOUT)
+    #[arg(long, global = true)]
+    timeout: Option<u64>,
+    /// Ignore cached responses and ask the provider again
+    #[arg(long, global = true, alias = "refresh")]
+    no_cache: bool,
+    /// Answer only from the local cache, without contacting the provider
+    #[arg(long, global = true)]
+    offline: bool,
+    /// openai, claude, replay or mock (default $SMSH_LLM_PROVIDER, or openai)
+    #[arg(long, global = true)]
+    provider: Option<String>,
+    /// Language for explanations, e.g. sv, es or ja (default $SMSH_LANG, or English)
+    #[arg(long, global = true)]
+    lang: Option<String>,
+    /// No spinner, streaming or decorative output on the terminal (or $SMSH_NO_SPINNER)
+    #[arg(long, global = true)]
+    quiet: bool,
+    /// Shell to write commands for: zsh, pwsh or cmd (default $SMSH_SHELL, or
+    /// pwsh on Windows and zsh elsewhere)
+    #[arg(long, global = true)]
+    shell: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -18,230 +95,883 @@ enum Commands {
         buffer: Option<String>,
         #[arg(short, long)]
         query: Option<String>,
+        /// Use the clipboard contents as the buffer to modify
+        #[arg(long, conflicts_with = "buffer")]
+        from_clipboard: bool,
+        /// Run the generated command after confirmation
+        #[arg(short = 'x', long)]
+        execute: bool,
+        /// Lint the result with shellcheck and ask the model to fix warnings
+        #[arg(long)]
+        lint: bool,
+        /// Run the result on a scratch copy of the cwd without network and list changed files
+        #[arg(long)]
+        dry_run_sandbox: bool,
+        /// Let the model run approved read-only probes (ls, which, git status, ...) first
+        #[arg(long)]
+        probe: bool,
+        /// Sample N alternatives at once and print the distinct ones, one per line
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u8).range(1..=10),
+            conflicts_with_all = ["execute", "dry_run_sandbox", "probe"]
+        )]
+        candidates: u8,
+        /// Start fetching the answer in the background (daemon or cache) and print nothing
+        #[arg(long, conflicts_with_all = ["execute", "dry_run_sandbox", "probe", "candidates"])]
+        prefetch: bool,
+        /// Also put the command on the clipboard (pbcopy, wl-copy, xclip, or OSC 52 over SSH)
+        #[arg(long, conflicts_with_all = ["candidates", "prefetch"])]
+        copy: bool,
+        /// Sample several answers and return the command most agree on; without a
+        /// majority, print the alternatives and exit 3 ($SMSH_CAREFUL_SAMPLES, default 5)
+        #[arg(long, conflicts_with_all = ["probe", "candidates", "prefetch"])]
+        careful: bool,
+        /// Ask for noticeably different approaches at a higher temperature (coreutils,
+        /// a python one-liner, a specialized tool) and print the distinct ones
+        #[arg(
+            long,
+            conflicts_with_all = ["execute", "dry_run_sandbox", "probe", "candidates", "prefetch", "careful"]
+        )]
+        creative: bool,
+        /// Below this confidence (0 to 1), sample again as with --careful
+        /// (or $SMSH_MIN_CONFIDENCE)
+        #[arg(
+            long,
+            value_name = "0-1",
+            value_parser = parse_confidence,
+            conflicts_with_all = ["probe", "candidates", "prefetch", "careful", "creative"]
+        )]
+        min_confidence: Option<f64>,
+        /// Change the previous answer from the history as FOLLOW_UP asks ("make it
+        /// recursive"), without restating the original request
+        #[arg(
+            long,
+            value_name = "FOLLOW_UP",
+            conflicts_with_all = ["query", "buffer", "from_clipboard"]
+        )]
+        refine: Option<String>,
+        /// Write the command for this version of a tool, as ffmpeg=4.4 (repeatable;
+        /// installed versions of tools the query names are detected otherwise)
+        #[arg(long, value_name = "TOOL=VERSION", value_parser = parse_tool_version)]
+        tool_version: Vec<(String, String)>,
+        /// Read one query per line from stdin and print one command (or JSON
+        /// record) per line, in order; blank and `#` lines are copied through
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "query", "buffer", "from_clipboard", "execute", "dry_run_sandbox", "probe",
+                "candidates", "prefetch", "copy", "careful", "creative", "min_confidence", "refine"
+            ]
+        )]
+        batch: bool,
+        /// How many --batch queries to answer at once (default 4)
+        #[arg(
+            long,
+            value_name = "N",
+            value_parser = clap::value_parser!(u8).range(1..=16),
+            requires = "batch"
+        )]
+        jobs: Option<u8>,
+    },
+    /// Break a task into numbered steps, each a command with a note
+    Plan {
+        #[arg(short, long)]
+        query: Option<String>,
+        /// A command line the plan should start from
+        #[arg(short, long)]
+        buffer: Option<String>,
+        /// Plan for this version of a tool, as ffmpeg=4.4 (repeatable)
+        #[arg(long, value_name = "TOOL=VERSION", value_parser = parse_tool_version)]
+        tool_version: Vec<(String, String)>,
+    },
+    /// Write a Makefile or justfile target in the style of the existing file,
+    /// to append to it
+    Make {
+        #[arg(short, long)]
+        query: Option<String>,
+        /// The Makefile or justfile to match (default: the one in the current
+        /// directory); it need not exist yet
+        #[arg(short, long)]
+        file: Option<PathBuf>,
     },
     /// Explain the current zsh command
     Explain {
         #[arg(short, long)]
         buffer: Option<String>,
+        /// Explain the command currently on the clipboard
+        #[arg(long, conflicts_with = "buffer")]
+        from_clipboard: bool,
+        /// Explain the script in this file (- for stdin)
+        #[arg(long, conflicts_with_all = ["buffer", "from_clipboard"])]
+        file: Option<PathBuf>,
+        /// Follow each line or block with an indented explanation of it
+        #[arg(long)]
+        annotated: bool,
+        /// Append shellcheck warnings for the command
+        #[arg(long)]
+        lint: bool,
+        /// brief (one line), normal (a paragraph) or deep (flag by flag, with caveats)
+        #[arg(long, value_enum, default_value_t = DetailArg::Brief)]
+        detail: DetailArg,
+        /// Persona and tone of the explanation, e.g. "terse sysadmin" (or
+        /// $SMSH_PERSONA_EXPLAIN, $SMSH_PERSONA)
+        #[arg(long)]
+        persona: Option<String>,
+    },
+    /// Show usage for the current month and the remaining budget
+    Stats {
+        /// Propose aliases or functions for long commands you run over and over
+        /// (sends the most repeated ones to the provider)
+        #[arg(long, conflicts_with = "report")]
+        suggest_aliases: bool,
+        /// Print a Markdown digest of the history: requests, acceptance rate,
+        /// spend, top tools and the slowest provider days
+        #[arg(long, value_enum)]
+        report: Option<ReportArg>,
+        /// Write the report to this file instead of stdout
+        #[arg(long, requires = "report")]
+        file: Option<PathBuf>,
+        /// Break failures down by kind (rate limits, network, refusals, ...)
+        #[arg(long, conflicts_with_all = ["report", "suggest_aliases"])]
+        errors: bool,
+        /// With --errors, only failures since this date, time or age (7d, 12h)
+        #[arg(long, requires = "errors")]
+        since: Option<String>,
+    },
+    /// Serve provider requests over a unix socket, keeping connections warm
+    Daemon {
+        /// Socket path (defaults to $SMSH_SOCKET or $XDG_RUNTIME_DIR/smartshell.sock)
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Answer /complete and /explain as JSON over HTTP on localhost, for editor
+    /// plugins and launchers; requests need the bearer token from
+    /// $SMSH_SERVE_TOKEN or ~/.local/state/smartshell/serve-token
+    Serve {
+        /// Loopback address and port to listen on
+        #[arg(long, default_value = serve::DEFAULT_LISTEN)]
+        listen: String,
+    },
+    /// Check the hash chain of the audit log
+    VerifyAudit {
+        /// Audit log to verify (defaults to $SMSH_AUDIT_LOG)
+        path: Option<PathBuf>,
+    },
+    /// Manage API keys in the system keychain (Keychain, Secret Service, Credential Manager)
+    Auth {
+        #[command(subcommand)]
+        action: AuthAction,
+    },
+    /// Work with the history of generated commands and explanations
+    History {
+        #[command(subcommand)]
+        action: HistoryAction,
+    },
+    /// Record that a suggestion was run (called by the zsh widget)
+    Accepted {
+        /// The suggestion's history id ($SMSH_HISTORY_ID_FILE, or history_id in --output json)
+        #[arg(long)]
+        id: i64,
+        /// The command line as it was run, if it may have been edited
+        #[arg(long)]
+        command: Option<String>,
     },
 }
 
-fn get_os_context() -> String {
-    if cfg!(target_os = "macos") {
-        "The target system is macOS.".to_string()
-    } else if cfg!(target_os = "linux") {
-        "The target system is Linux.".to_string()
-    } else {
-        String::new()
-    }
+#[derive(Subcommand)]
+enum HistoryAction {
+    /// Print the history, oldest first, for archiving or analysis elsewhere
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormatArg::Json)]
+        format: ExportFormatArg,
+        /// Only entries from this date (2026-01-31), time (2026-01-31 14:00) or age (12h, 7d, 4w) on
+        #[arg(long)]
+        since: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ExportFormatArg {
+    Json,
+    Csv,
+    Markdown,
+    /// Chat fine-tuning examples from the completions you ran
+    OpenaiJsonl,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ReportArg {
+    /// The last seven days
+    Weekly,
 }
 
-fn get_api_key(provider: &str) -> Option<String> {
-    // Check env vars first
-    if let Some(key) = env::var("SMSH_API_KEY").ok().filter(|k| !k.is_empty()) {
-        return Some(key);
-    }
-    let env_key = match provider {
-        "openai" => env::var("SMSH_OPENAI_API_KEY").or_else(|_| env::var("OPENAI_API_KEY")),
-        "claude" => env::var("SMSH_ANTHROPIC_API_KEY").or_else(|_| env::var("ANTHROPIC_API_KEY")),
-        _ => return None,
-    }
-    .ok()
-    .filter(|k| !k.is_empty());
-    if env_key.is_some() {
-        return env_key;
-    }
-
-    // Fall back to macOS Keychain
-    #[cfg(target_os = "macos")]
-    {
-        let service = match provider {
-            "openai" => "smartshell.openai",
-            "claude" => "smartshell.anthropic",
-            _ => return None,
+#[derive(Subcommand)]
+enum AuthAction {
+    /// Store a provider's key, typed without echo or piped on stdin
+    Set {
+        /// openai or claude
+        provider: String,
+        /// Ask for Touch ID or the login password whenever the key is read (macOS)
+        #[arg(long)]
+        require_presence: bool,
+    },
+    /// Show where each provider's key comes from
+    Status,
+    /// Sign in to a provider's OAuth gateway with a device code
+    Login {
+        /// openai or claude
+        provider: String,
+    },
+    /// Forget a provider's OAuth gateway token
+    Logout {
+        /// openai or claude
+        provider: String,
+    },
+    /// Remove a provider's key from the keychain
+    Delete {
+        /// openai or claude
+        provider: String,
+    },
+}
+
+/// How the outcome of this run is printed
+struct Report {
+    output: Output,
+    json_errors: bool,
+    started: Instant,
+    /// The configured provider, for failures that don't name one (refusals)
+    provider: Option<Provider>,
+    /// Leave out decorations such as the `(cached)` marker
+    quiet: bool,
+    /// Follow text answers with their tokens, cost and latency
+    show_usage: bool,
+    /// The history entry of the first answer logged, for `smartshell accepted`
+    history_id: Cell<Option<i64>>,
+    /// The command line `complete` was asked to change
+    buffer: OnceCell<String>,
+    /// The kind of failure being logged, by `exit_with`
+    error_type: Cell<Option<&'static str>>,
+    /// An earlier completion with the same command, looked up before this one
+    /// was added to the history
+    seen_before: OnceCell<history::HistoryEntry>,
+}
+
+impl Report {
+    /// The `--output json` object: `result` is the first answer, all answers are
+    /// also listed under `candidates` when there are several
+    fn json(&self, outcome: Result<&[LlmResponse], &Failure>) -> serde_json::Value {
+        let (result, error, refused, provider) = match outcome {
+            Ok(resps) => (
+                resps.first().map(|r| r.result.as_str()),
+                None,
+                false,
+                resps.first().and_then(|r| r.provider),
+            ),
+            Err(failure) => (
+                None,
+                Some(failure.message()),
+                matches!(failure, Failure::Refusal { .. }),
+                failure.provider().or(self.provider),
+            ),
         };
-        if let Ok(entry) = keyring::Entry::new(service, &whoami::username()) {
-            return entry.get_password().ok();
+        let mut json = serde_json::json!({
+            "result": result,
+            "error": error,
+            "refused": refused,
+            "provider": provider.map(Provider::as_str),
+            "model": provider.map(Provider::model),
+            "latency_ms": self.started.elapsed().as_millis() as u64,
+            "usage": spend::session_usage(),
+            "history_id": self.history_id.get(),
+            "request_id": request_id(),
+        });
+        if let Some(entry) = self.seen_before.get() {
+            json["seen_before"] = serde_json::json!({
+                "history_id": entry.id,
+                "query": entry.query,
+                "ran": entry.accepted_at.is_some(),
+                "at": history::local_time(entry.accepted_at.unwrap_or(entry.ts)).to_rfc3339(),
+            });
+        }
+        if let Ok([resp, ..]) = outcome {
+            json["explanation"] = resp.explanation.as_deref().into();
+            json["confidence"] = resp.confidence.into();
+            if !resp.steps.is_empty() {
+                json["steps"] = serde_json::json!(resp.steps);
+            }
+        }
+        if let Ok(resps @ [_, _, ..]) = outcome {
+            json["candidates"] = resps.iter().map(|r| r.result.as_str()).collect();
         }
+        json
+    }
+
+    /// Log the request's outcome with its provider, latency and token usage, and
+    /// keep it in the history. The first entry's id is also written to
+    /// SMSH_HISTORY_ID_FILE, where the zsh widget picks it up.
+    fn log(&self, cmd: &str, query: &str, result: &str, outcome: &str, provider: Option<Provider>) {
+        let spent = (spend::session_usage(), self.started.elapsed());
+        self.log_request(cmd, query, result, outcome, provider, spent);
+    }
+
+    /// `log` for one of several requests this run makes (`--batch`), with the
+    /// tokens and time `spent` on that request alone
+    fn log_request(
+        &self,
+        cmd: &str,
+        query: &str,
+        result: &str,
+        outcome: &str,
+        provider: Option<Provider>,
+        (usage, latency): (spend::Usage, Duration),
+    ) {
+        let record = LogRecord {
+            cmd,
+            query,
+            result,
+            provider: provider.or(self.provider),
+            latency_ms: Some(latency.as_millis() as u64),
+            usage: Some(usage),
+            outcome: Some(outcome),
+            buffer: self.buffer.get().map(String::as_str),
+            error_type: self.error_type.get(),
+        };
+        // A new history imports SMSH_LOG, which must not have this entry yet
+        let id = history::record(&record);
+        log_record(&record);
+        telemetry::outcome(outcome, record.provider);
+        if let (Some(id), None) = (id, self.history_id.get()) {
+            self.history_id.set(Some(id));
+            if let Some(path) = std::env::var_os("SMSH_HISTORY_ID_FILE").filter(|p| !p.is_empty()) {
+                let _ = std::fs::write(path, id.to_string());
+            }
+        }
+    }
+
+    /// Add the outcome to the audit log, under the provider that answered or
+    /// failed, or else the configured one
+    fn audit(
+        &self,
+        cmd: &str,
+        query: &str,
+        result: &str,
+        decision: &str,
+        provider: Option<Provider>,
+    ) {
+        audit::audit_entry(cmd, query, result, decision, provider.or(self.provider));
     }
-    None
-}
 
-fn log_entry(cmd: &str, query: &str, result: &str) {
-    if let Some(path) = env::var("SMSH_LOG").ok().filter(|p| !p.is_empty()) {
-        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&path) {
-            let ts = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
-            let _ = writeln!(
-                file,
-                "[{}] {} | query: {} | result: {}",
-                ts, cmd, query, result
+    /// Print the command's one-line explanation on stderr as a dimmed `# ` comment,
+    /// followed by a numbered note per step when the answer has several and a
+    /// `Low confidence` line when the model was unsure, which the zsh widget
+    /// shows under the prompt
+    fn explanation(&self, resp: &LlmResponse) {
+        if self.output != Output::Text {
+            return;
+        }
+        if let Some(confidence) = resp.confidence.filter(|c| *c < LOW_CONFIDENCE) {
+            let line = format!(
+                "Low confidence ({:.0}%): check the command before running it",
+                confidence * 100.0
             );
+            eprintln!("{}", style::for_stderr(Style::Yellow, &line));
+        }
+        if let Some(explanation) = &resp.explanation {
+            let line = format!("# {}", explanation);
+            eprintln!("{}", style::for_stderr(Style::Dim, &line));
+        }
+        for (n, step) in resp.steps.iter().enumerate() {
+            let line = format!("# {}. {}", n + 1, step.note);
+            eprintln!("{}", style::for_stderr(Style::Dim, &line));
+        }
+    }
+
+    /// Print the response's warnings on stderr, after the `(cached)` marker
+    fn warnings(&self, resp: &LlmResponse) {
+        if resp.cached && !self.quiet {
+            eprintln!("{}", style::for_stderr(Style::Dim, "(cached)"));
+        }
+        for line in resp.warning_lines() {
+            eprintln!("{}", line);
+        }
+    }
+
+    /// Look `command` up in the history before this run's answer is logged
+    fn check_seen(&self, command: &str) {
+        if let Some(entry) = history::seen_before(command) {
+            let _ = self.seen_before.set(entry);
+        }
+    }
+
+    /// `Seen before: you ran this on 2024-05-02 for "query"` on stderr, last, so
+    /// the zsh widget can show it
+    fn seen_hint(&self) {
+        let Some(entry) = self.seen_before.get() else {
+            return;
+        };
+        if self.output == Output::Json {
+            return;
+        }
+        let mut line = match entry.accepted_at {
+            Some(at) => format!(
+                "Seen before: you ran this on {}",
+                history::local_time(at).format("%Y-%m-%d")
+            ),
+            None => format!(
+                "Seen before: suggested on {}",
+                history::local_time(entry.ts).format("%Y-%m-%d")
+            ),
+        };
+        if !entry.query.is_empty() {
+            line.push_str(&format!(" for \"{}\"", entry.query));
+        }
+        eprintln!("{}", style::for_stderr(Style::Dim, &line));
+    }
+
+    /// Print `(~1.2k tokens, ≈$0.004, 870ms)` on stderr after a text answer
+    fn usage(&self, resp: &LlmResponse) {
+        if !self.show_usage || self.output == Output::Json {
+            return;
+        }
+        let model = resp.provider.or(self.provider).map_or("", Provider::model);
+        let line = format!(
+            "({}, {}ms)",
+            spend::session_usage().summary(model),
+            self.started.elapsed().as_millis()
+        );
+        eprintln!("{}", style::for_stderr(Style::Dim, &line));
+    }
+
+    /// Print answers, as `text` or as the JSON object
+    fn answer(&self, resps: &[LlmResponse], text: &str) {
+        match self.output {
+            Output::Json => println!("{}", self.json(Ok(resps))),
+            Output::Text => println!("{}", style::for_stdout(Style::Bold, text)),
         }
     }
 }
 
-fn response_schema() -> serde_json::Value {
-    serde_json::json!({
-        "name": "response",
-        "strict": true,
-        "schema": {
-            "type": "object",
-            "properties": {
-                "result": { "type": "string", "description": "The command or explanation" },
-                "error": { "type": "boolean", "description": "Set to true if the request is unclear, impossible, or not a valid shell task" }
-            },
-            "required": ["result", "error"],
-            "additionalProperties": false
-        }
-    })
+/// The words the model changed in a buffer: removed words in red and added ones
+/// in green, or marked `[-old-]{+new+}` without colors
+fn word_diff_line(old: &str, new: &str) -> String {
+    let color = style::stderr_colored();
+    shell::word_diff(old, new)
+        .into_iter()
+        .map(|change| match change {
+            WordChange::Same(word) => word.to_string(),
+            WordChange::Removed(word) if color => style::for_stderr(Style::Red, word),
+            WordChange::Removed(word) => format!("[-{}-]", word),
+            WordChange::Added(word) if color => style::for_stderr(Style::Green, word),
+            WordChange::Added(word) => format!("{{+{}+}}", word),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
-fn llm_api_call(intro: &str, prompt: &str) -> Result<String, String> {
-    let provider = env::var("SMSH_LLM_PROVIDER").unwrap_or_else(|_| "openai".to_string());
-    let spinner = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
-    let (tx, rx) = std::sync::mpsc::channel();
-    let schema = response_schema();
-
-    let intro = intro.to_string();
-    let prompt = prompt.to_string();
-    std::thread::spawn(move || {
-        let result = match provider.as_str() {
-            "openai" => openai_call(&intro, &prompt, &schema),
-            "claude" => claude_call(&intro, &prompt, &schema["schema"]),
-            _ => Err(format!("Unknown provider: {}", provider)),
-        };
-        let _ = tx.send(result);
-    });
+/// An explanation's breakdown as rows of aligned tokens and meanings, or as
+/// `token: meaning` lines in plain mode
+fn breakdown_rows(breakdown: &[TokenMeaning]) -> Vec<String> {
+    if style::plain() {
+        return breakdown
+            .iter()
+            .map(|t| format!("{}: {}", t.token, t.meaning))
+            .collect();
+    }
+    // Longer tokens (long paths, inline scripts) don't widen the column
+    const MAX_TOKEN_WIDTH: usize = 24;
+    let width = breakdown
+        .iter()
+        .map(|t| t.token.chars().count())
+        .filter(|&w| w <= MAX_TOKEN_WIDTH)
+        .max()
+        .unwrap_or(0);
+    breakdown
+        .iter()
+        .map(|t| {
+            let pad = width.saturating_sub(t.token.chars().count());
+            format!(
+                "{}{}  {}",
+                style::for_stdout(Style::Bold, &t.token),
+                " ".repeat(pad),
+                t.meaning
+            )
+        })
+        .collect()
+}
 
-    let mut idx = 0;
-    let mut tty = std::fs::OpenOptions::new()
-        .write(true)
-        .open("/dev/tty")
-        .ok();
-    loop {
-        match rx.try_recv() {
-            Ok(result) => {
-                if let Some(ref mut t) = tty {
-                    let _ = write!(t, "\r\x1b[K");
-                }
-                return result;
+/// Log and print alternative commands, one per line (or as the JSON object), and
+/// exit with EXIT_CONFIRM if any of them needs confirmation
+fn print_candidates(query: &str, resps: &[LlmResponse], report: &Report) {
+    for resp in resps {
+        report.log("complete", query, &resp.result, "candidate", resp.provider);
+        report.audit("complete", query, &resp.result, "suggested", resp.provider);
+        if report.output == Output::Text {
+            println!("{}", style::for_stdout(Style::Bold, &resp.result));
+            report.explanation(resp);
+        }
+        report.warnings(resp);
+    }
+    if report.output == Output::Json {
+        report.answer(resps, "");
+    }
+    if let Some(resp) = resps.first() {
+        report.usage(resp);
+    }
+    if resps.iter().any(|r| r.needs_confirmation()) {
+        exit(EXIT_CONFIRM);
+    }
+}
+
+/// `complete --batch`: a command for each query on stdin, in order, as the lines
+/// of a script skeleton (a failed query becomes a `#` comment naming it) or as
+/// one JSON record per query. Exits with EXIT_ERROR if any query failed.
+fn complete_batch(
+    args: &ClientArgs,
+    lint: bool,
+    tool_versions: Vec<(String, String)>,
+    jobs: usize,
+    report: &Report,
+) {
+    // Answers arrive from several threads, with no terminal to ask questions on
+    let client = client(args)
+        .unwrap_or_else(|failure| exit_with("complete", "", failure, report))
+        .spinner(false)
+        .questions(false);
+    let lines: Vec<String> = io::stdin().lines().map_while(Result::ok).collect();
+    let queries: Vec<(usize, &str)> = lines
+        .iter()
+        .enumerate()
+        .map(|(n, line)| (n, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    let requests: Vec<CompletionRequest> = queries
+        .iter()
+        .map(|(_, query)| CompletionRequest {
+            lint,
+            tool_versions: tool_versions.clone(),
+            ..CompletionRequest::new(*query)
+        })
+        .collect();
+    let text = report.output == Output::Text;
+    // Input lines up to here are printed
+    let mut copied = 0;
+    let mut answered = 0;
+    let mut failed = false;
+    client.complete_batch(&requests, jobs, |i, outcome, usage, latency| {
+        let (n, query) = queries[i];
+        answered += 1;
+        if text {
+            for line in &lines[copied..n] {
+                println!("{}", line);
             }
-            Err(std::sync::mpsc::TryRecvError::Empty) => {
-                if let Some(ref mut t) = tty {
-                    let _ = write!(t, "\r{}", spinner[idx % spinner.len()]);
-                    let _ = t.flush();
+        }
+        copied = n + 1;
+        match outcome {
+            Ok(resp) => {
+                report.log_request(
+                    "complete",
+                    query,
+                    &resp.result,
+                    "suggested",
+                    resp.provider,
+                    (usage, latency),
+                );
+                report.audit("complete", query, &resp.result, "suggested", resp.provider);
+                for warning in resp.warning_lines() {
+                    eprintln!("line {}: {}", n + 1, warning);
+                }
+                if text {
+                    println!("{}", style::for_stdout(Style::Bold, &resp.result));
+                } else {
+                    let mut record = serde_json::json!({
+                        "line": n + 1,
+                        "query": query,
+                        "result": resp.result,
+                        "error": null,
+                        "refused": false,
+                        "risk": resp.risk.as_str(),
+                        "needs_sudo": resp.needs_sudo,
+                        "needs_confirmation": resp.needs_confirmation(),
+                        "explanation": resp.explanation,
+                        "confidence": resp.confidence,
+                        "provider": resp.provider.map(Provider::as_str),
+                    });
+                    if !resp.steps.is_empty() {
+                        record["steps"] = serde_json::json!(resp.steps);
+                    }
+                    println!("{}", record);
                 }
-                std::thread::sleep(std::time::Duration::from_millis(100));
-                idx += 1;
             }
-            Err(std::sync::mpsc::TryRecvError::Disconnected) => {
-                if let Some(ref mut t) = tty {
-                    let _ = write!(t, "\r\x1b[K");
+            Err(failure) => {
+                failed = true;
+                let outcome = match failure {
+                    Failure::Refusal { .. } => "refused",
+                    Failure::Cancelled => "cancelled",
+                    _ => "error",
+                };
+                report.log_request(
+                    "complete",
+                    query,
+                    &failure.message(),
+                    outcome,
+                    failure.provider(),
+                    (usage, latency),
+                );
+                report.audit(
+                    "complete",
+                    query,
+                    &failure.message(),
+                    outcome,
+                    failure.provider(),
+                );
+                if text {
+                    println!("# {}: {}", query, failure.message());
+                } else {
+                    let record = serde_json::json!({
+                        "line": n + 1,
+                        "query": query,
+                        "result": null,
+                        "error": failure.message(),
+                        "refused": outcome == "refused",
+                        "provider": failure.provider().map(Provider::as_str),
+                    });
+                    println!("{}", record);
                 }
-                return Err("Background thread failed".to_string());
             }
         }
+        let _ = io::stdout().flush();
+    });
+    // After Ctrl-C, the queries that weren't answered are left out
+    if text && answered == queries.len() {
+        for line in &lines[copied..] {
+            println!("{}", line);
+        }
+    }
+    if failed {
+        exit(EXIT_ERROR);
     }
 }
 
-fn openai_call(intro: &str, prompt: &str, schema: &serde_json::Value) -> Result<String, String> {
-    let api_key = get_api_key("openai").ok_or("OpenAI API key not set")?;
-    let resp = reqwest::blocking::Client::new()
-        .post("https://api.openai.com/v1/chat/completions")
-        .bearer_auth(api_key)
-        .json(&serde_json::json!({
-            "model": "gpt-4o",
-            "max_tokens": 256,
-            "temperature": 0,
-            "messages": [
-                {"role": "system", "content": intro},
-                {"role": "user", "content": prompt}
-            ],
-            "response_format": {
-                "type": "json_schema",
-                "json_schema": schema
-            }
-        }))
-        .send()
-        .map_err(|e| format!("Request failed: {}", e))?;
-
-    let json: serde_json::Value = resp
-        .json()
-        .map_err(|e| format!("Invalid response: {}", e))?;
-    if let Some(err) = json
-        .get("error")
-        .and_then(|e| e.get("message"))
-        .and_then(|m| m.as_str())
-    {
-        return Err(format!("API error: {}", err));
-    }
-    let content = json["choices"][0]["message"]["content"]
-        .as_str()
-        .ok_or("Missing content in response")?;
-    let parsed: serde_json::Value = serde_json::from_str(content)
-        .map_err(|e| format!("Failed to parse response JSON: {}", e))?;
-    let result = parsed["result"].as_str().unwrap_or("").to_string();
-    let is_error = parsed["error"].as_bool().unwrap_or(false);
-    if is_error {
-        Err(result)
-    } else {
-        Ok(result)
+/// `explain --annotated`: the summary as `# ` lines, then the script with each
+/// annotated line or block followed by its explanation, indented like the code
+/// and commented out, so the output is still a valid script
+fn print_annotated(script: &str, resp: &LlmResponse) {
+    let hash = style::for_stdout(Style::Dim, "#");
+    for line in resp.result.lines() {
+        println!("{} {}", hash, line);
+    }
+    println!();
+    let lines: Vec<&str> = script.lines().collect();
+    let mut annotations = resp
+        .breakdown
+        .iter()
+        .filter_map(|a| a.lines().map(|range| (range, a.meaning.trim())))
+        .peekable();
+    for (i, line) in lines.iter().enumerate() {
+        println!("{}", style::for_stdout(Style::Bold, line));
+        while let Some(((first, _), meaning)) = annotations.next_if(|((_, last), _)| *last == i + 1)
+        {
+            // Indented like the first line of the block it explains
+            let code = lines.get(first - 1).unwrap_or(line);
+            let indent = &code[..code.len() - code.trim_start().len()];
+            let note = format!("{}    # {}", indent, meaning);
+            println!("{}", style::for_stdout(Style::Cyan, &note));
+        }
     }
 }
 
-fn claude_call(intro: &str, prompt: &str, schema: &serde_json::Value) -> Result<String, String> {
-    let api_key = get_api_key("claude").ok_or("Anthropic API key not set")?;
-    let tool = serde_json::json!({
-        "name": "structured_response",
-        "description": "Return the structured response",
-        "input_schema": schema
-    });
-    let resp = reqwest::blocking::Client::new()
-        .post("https://api.anthropic.com/v1/messages")
-        .header("x-api-key", api_key)
-        .header("anthropic-version", "2023-06-01")
-        .json(&serde_json::json!({
-            "model": "claude-sonnet-4-5-20250929",
-            "max_tokens": 512,
-            "temperature": 0,
-            "system": intro,
-            "messages": [{"role": "user", "content": prompt}],
-            "tools": [tool],
-            "tool_choice": {"type": "tool", "name": "structured_response"}
-        }))
-        .send()
-        .map_err(|e| format!("Request failed: {}", e))?;
-
-    let json: serde_json::Value = resp
-        .json()
-        .map_err(|e| format!("Invalid response: {}", e))?;
-    if let Some(err) = json
-        .get("error")
-        .and_then(|e| e.get("message"))
-        .and_then(|m| m.as_str())
-    {
-        return Err(format!("API error: {}", err));
-    }
-    let input = &json["content"][0]["input"];
-    let result = input["result"].as_str().unwrap_or("").to_string();
-    let is_error = input["error"].as_bool().unwrap_or(false);
-    if is_error {
-        Err(result)
+/// A `--min-confidence` threshold between 0 and 1
+fn parse_confidence(text: &str) -> Result<f64, String> {
+    match text.trim().parse::<f64>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => Err(format!("`{}` is not a confidence between 0 and 1", text)),
+    }
+}
+
+/// Log and print a failure, then exit with its documented status. Only
+/// `--output json` puts it on stdout, which otherwise carries nothing but results.
+fn exit_with(cmd: &str, query: &str, failure: Failure, report: &Report) -> ! {
+    let label = match failure {
+        Failure::Refusal { .. } => "REFUSED",
+        Failure::Cancelled => "CANCELLED",
+        _ => "ERROR",
+    };
+    if !matches!(failure, Failure::Cancelled) {
+        report.error_type.set(Some(failure.type_name()));
+    }
+    report.log(
+        cmd,
+        query,
+        &failure.message(),
+        &label.to_lowercase(),
+        failure.provider(),
+    );
+    report.audit(
+        cmd,
+        query,
+        &failure.message(),
+        &label.to_lowercase(),
+        failure.provider(),
+    );
+    telemetry::failed(&failure);
+    if report.output == Output::Json {
+        println!("{}", report.json(Err(&failure)));
+    } else if report.json_errors {
+        let mut envelope = failure.envelope();
+        envelope["error"]["request_id"] = request_id().into();
+        eprintln!("{}", envelope);
     } else {
-        Ok(result)
+        // Refusals and cancellations are answers, not something to trace
+        let (color, message) = match failure {
+            Failure::Refusal { .. } => (Style::Yellow, failure.display()),
+            Failure::Cancelled => (Style::Red, failure.display()),
+            _ => (
+                Style::Red,
+                format!("{} (request {})", failure.display(), request_id()),
+            ),
+        };
+        eprintln!("{}", style::for_stderr(color, &message));
     }
+    exit(failure.exit_code());
+}
+
+/// Exit with `code` once the debug log has been written
+fn exit(code: i32) -> ! {
+    telemetry::finish();
+    flush_log();
+    std::process::exit(code);
+}
+
+fn parse_provider(name: &str) -> Result<Provider, String> {
+    name.parse().map_err(|e: Failure| e.to_string())
+}
+
+/// The client configured by the environment and the global flags
+fn client(args: &ClientArgs) -> Result<SmartshellClient, Failure> {
+    let mut client = SmartshellClient::from_env()?;
+    if let Some(name) = &args.provider {
+        client = client.with_provider(name.parse()?);
+    }
+    if let Some(secs) = args.timeout {
+        client = client.timeout(Duration::from_secs(secs));
+    }
+    if args.no_cache {
+        client = client.cache(false);
+    }
+    if args.offline {
+        client = client.offline(true);
+    }
+    if args.quiet {
+        client = client.spinner(false);
+    }
+    if let Some(lang) = &args.lang {
+        client = client.language(lang);
+    }
+    if let Some(name) = &args.shell {
+        client = client.shell(name.parse()?);
+    }
+    Ok(client)
 }
 
 fn main() {
-    let cli = Cli::parse();
-    let os = get_os_context();
+    run(Cli::parse());
+    telemetry::finish();
+    flush_log();
+}
+
+fn run(cli: Cli) {
+    set_verbosity(cli.verbose);
+    style::set_plain(cli.plain);
+    style::set_color(match cli.color {
+        Color::Auto => ColorChoice::Auto,
+        Color::Always => ColorChoice::Always,
+        Color::Never => ColorChoice::Never,
+    });
+    let configured = client(&cli.client).ok();
+    if configured.as_ref().is_some_and(|c| c.is_offline()) && !caches_answers() {
+        eprintln!(
+            "{}",
+            style::warning("offline, but the caches keep no answers unless SMSH_LOG_MODE is full")
+        );
+    }
+    let report = &Report {
+        output: cli.output,
+        json_errors: cli.json_errors,
+        started: Instant::now(),
+        provider: configured.as_ref().map(|c| c.provider()),
+        quiet: configured.as_ref().is_some_and(|c| !c.shows_spinner()),
+        show_usage: cli.show_usage
+            || std::env::var("SMSH_SHOW_USAGE").is_ok_and(|v| !v.is_empty() && v != "0"),
+        history_id: Cell::new(None),
+        buffer: OnceCell::new(),
+        error_type: Cell::new(None),
+        seen_before: OnceCell::new(),
+    };
+    let args = &cli.client;
+    // The daemon and bookkeeping commands don't talk to providers for the user
+    match cli.command {
+        Commands::Complete { .. } => telemetry::begin("smartshell complete"),
+        Commands::Plan { .. } => telemetry::begin("smartshell plan"),
+        Commands::Make { .. } => telemetry::begin("smartshell make"),
+        Commands::Explain { .. } => telemetry::begin("smartshell explain"),
+        _ => {}
+    }
 
     match cli.command {
-        Commands::Complete { buffer, query } => {
-            let query = query
+        Commands::Complete {
+            buffer,
+            query,
+            from_clipboard,
+            execute,
+            lint,
+            dry_run_sandbox,
+            probe,
+            candidates,
+            prefetch,
+            copy,
+            careful,
+            creative,
+            min_confidence,
+            refine,
+            tool_version,
+            batch,
+            jobs,
+        } => {
+            if batch {
+                complete_batch(args, lint, tool_version, jobs.unwrap_or(4).into(), report);
+                return;
+            }
+            // Read once, so the history keeps the command line that was changed
+            let buffer = if from_clipboard {
+                let text = ContextSource::Clipboard
+                    .read()
+                    .unwrap_or_else(|failure| exit_with("complete", "", failure, report));
+                Some(text)
+            } else {
+                buffer
+            };
+            if let Some(text) = &buffer {
+                let _ = report.buffer.set(text.clone());
+            }
+            let context = buffer.map(ContextSource::Buffer).unwrap_or_default();
+            let previous = refine.as_ref().map(|_| match history::previous_turn() {
+                Ok(Some(turn)) => turn,
+                Ok(None) => exit_with(
+                    "complete",
+                    "",
+                    Failure::InvalidInput("Nothing to refine: no earlier command".to_string()),
+                    report,
+                ),
+                Err(e) => exit_with("complete", "", Failure::InvalidInput(e), report),
+            });
+            let query = refine
+                .or(query)
                 .or_else(|| {
-                    print!("> Query: ");
-                    io::stdout().flush().unwrap();
+                    eprint!("> Query: ");
+                    io::stderr().flush().unwrap();
                     let mut s = String::new();
                     io::stdin()
                         .read_line(&mut s)
@@ -251,70 +981,520 @@ fn main() {
                 .unwrap_or_default();
 
             if query.is_empty() {
-                println!("Completion aborted (empty input).");
-                return;
+                eprintln!("Completion aborted (empty input).");
+                exit(EXIT_ERROR);
             }
 
-            let intro = format!(
-                "Generate a zsh command. Use only ASCII characters (straight quotes, no curly quotes). \
-                If the request is unclear or not a valid shell task, set error=true and put an explanation in result. {}", os
-            );
-            let prompt = match &buffer {
-                Some(b) if !b.is_empty() => {
-                    format!("Alter zsh command `{}` to comply with query `{}`", b, query)
+            let request = CompletionRequest {
+                query: query.clone(),
+                context,
+                lint,
+                probe,
+                previous,
+                tool_versions: tool_version,
+            };
+            // The history keeps the whole request, so a refinement can be refined again
+            let query = match &request.previous {
+                Some((earlier, _)) => format!("{}; {}", earlier, query),
+                None => query,
+            };
+            if prefetch {
+                let result = client(args).and_then(|c| c.spinner(false).prefetch(&request));
+                if let Err(failure) = result {
+                    exit_with("prefetch", &query, failure, report);
+                }
+                return;
+            }
+            if creative {
+                match client(args).and_then(|c| c.complete_creative(&request)) {
+                    Ok(resps) => print_candidates(&query, &resps, report),
+                    Err(failure) => exit_with("complete", &query, failure, report),
+                }
+                return;
+            }
+            if candidates > 1 {
+                let result =
+                    client(args).and_then(|c| c.complete_candidates(&request, candidates.into()));
+                match result {
+                    Ok(resps) => print_candidates(&query, &resps, report),
+                    Err(failure) => exit_with("complete", &query, failure, report),
+                }
+                return;
+            }
+            let min_confidence = min_confidence.or_else(|| {
+                let value = std::env::var("SMSH_MIN_CONFIDENCE").ok()?;
+                parse_confidence(&value)
+                    .inspect_err(|e| {
+                        eprintln!("{}", style::warning(&format!("SMSH_MIN_CONFIDENCE: {}", e)))
+                    })
+                    .ok()
+            });
+            // The answer of a single request, unless --careful asked for samples or
+            // the model's confidence fell below --min-confidence
+            let mut single = None;
+            if !careful {
+                let result = client(args).and_then(|c| c.complete(&request));
+                match (&result, min_confidence) {
+                    // Unsure answers get a second opinion from several samples
+                    (Ok(resp), Some(min))
+                        if !request.probe && resp.confidence.is_some_and(|c| c < min) =>
+                    {
+                        let note = format!(
+                            "confidence {:.0}% is below {:.0}%; sampling again",
+                            resp.confidence.unwrap_or_default() * 100.0,
+                            min * 100.0
+                        );
+                        eprintln!("{}", style::for_stderr(Style::Dim, &note));
+                    }
+                    _ => single = Some(result),
+                }
+            }
+            let result = if let Some(result) = single {
+                result
+            } else {
+                match client(args).and_then(|c| c.complete_careful(&request)) {
+                    Ok(mut resps) if resps.len() == 1 => Ok(resps.remove(0)),
+                    // The samples disagree: the alternatives are flagged and never run
+                    Ok(resps) => {
+                        print_candidates(&query, &resps, report);
+                        return;
+                    }
+                    Err(failure) => Err(failure),
+                }
+            };
+            if let Ok(resp) = &result {
+                report.check_seen(&resp.result);
+            }
+            if let (Output::Text, Ok(resp)) = (report.output, &result) {
+                let original = request.context.read().unwrap_or_default();
+                if !original.is_empty() && original.trim() != resp.result.trim() {
+                    eprintln!("{}", word_diff_line(&original, &resp.result));
+                }
+            }
+            if let (true, Ok(resp)) = (copy, &result) {
+                if let Err(e) = shell::copy_to_clipboard(&resp.result) {
+                    eprintln!("{}", style::for_stderr(Style::Yellow, &e.to_string()));
+                }
+            }
+            if let (true, Ok(resp)) = (dry_run_sandbox, &result) {
+                match sandbox::sandbox_dry_run(&resp.result) {
+                    Ok(report) => {
+                        eprintln!("Sandbox dry run:");
+                        for line in report {
+                            eprintln!("  {}", line);
+                        }
+                    }
+                    Err(e) => eprintln!("{}", style::for_stderr(Style::Red, &e.to_string())),
+                }
+            }
+            match result {
+                Ok(resp) if execute => {
+                    report.answer(std::slice::from_ref(&resp), &resp.result);
+                    report.explanation(&resp);
+                    report.warnings(&resp);
+                    report.usage(&resp);
+                    report.seen_hint();
+                    // Logged once the user has decided, so declined commands
+                    // don't count as run, with the latency of the answer alone
+                    let spent = (spend::session_usage(), report.started.elapsed());
+                    let log = |outcome| {
+                        report.log_request(
+                            "complete",
+                            &query,
+                            &resp.result,
+                            outcome,
+                            resp.provider,
+                            spent,
+                        )
+                    };
+                    if !shell::confirm_execution(&resp) {
+                        log("rejected");
+                        report.audit("complete", &query, &resp.result, "rejected", resp.provider);
+                        eprintln!("Aborted.");
+                        exit(EXIT_ERROR);
+                    }
+                    log("executed");
+                    report.audit("complete", &query, &resp.result, "executed", resp.provider);
+                    if let Some(id) = report.history_id.get() {
+                        let _ = history::open().and_then(|conn| history::accept(&conn, id, None));
+                    }
+                    let target = configured
+                        .as_ref()
+                        .map_or_else(TargetShell::from_env, |c| c.target_shell());
+                    exit(shell::execute_command(&resp.result, target));
+                }
+                Ok(resp) if resp.needs_confirmation() => {
+                    report.log(
+                        "complete",
+                        &query,
+                        &resp.result,
+                        "needs_confirmation",
+                        resp.provider,
+                    );
+                    report.audit(
+                        "complete",
+                        &query,
+                        &resp.result,
+                        "needs_confirmation",
+                        resp.provider,
+                    );
+                    report.answer(std::slice::from_ref(&resp), &resp.result);
+                    report.explanation(&resp);
+                    report.warnings(&resp);
+                    report.usage(&resp);
+                    report.seen_hint();
+                    exit(EXIT_CONFIRM);
                 }
-                _ => query.clone(),
+                Ok(resp) => {
+                    report.log("complete", &query, &resp.result, "suggested", resp.provider);
+                    report.audit("complete", &query, &resp.result, "suggested", resp.provider);
+                    report.answer(std::slice::from_ref(&resp), &resp.result);
+                    report.explanation(&resp);
+                    report.warnings(&resp);
+                    report.usage(&resp);
+                    report.seen_hint();
+                }
+                Err(failure) => exit_with("complete", &query, failure, report),
+            }
+        }
+        Commands::Plan {
+            query,
+            buffer,
+            tool_version,
+        } => {
+            if let Some(text) = &buffer {
+                let _ = report.buffer.set(text.clone());
+            }
+            let query = query.unwrap_or_default();
+            if query.is_empty() {
+                eprintln!("Nothing to plan.");
+                exit(EXIT_ERROR);
+            }
+            let request = CompletionRequest {
+                query: query.clone(),
+                context: buffer.map(ContextSource::Buffer).unwrap_or_default(),
+                lint: false,
+                probe: false,
+                previous: None,
+                tool_versions: tool_version,
             };
+            match client(args).and_then(|c| c.plan(&request)) {
+                Ok(resp) => {
+                    report.log("plan", &query, &resp.result, "planned", resp.provider);
+                    report.audit("plan", &query, &resp.result, "planned", resp.provider);
+                    if report.output == Output::Json {
+                        report.answer(std::slice::from_ref(&resp), &resp.result);
+                    } else {
+                        for (n, step) in resp.steps.iter().enumerate() {
+                            let command = format!("{}. {}", n + 1, step.command);
+                            println!("{}", style::for_stdout(Style::Bold, &command));
+                            if !step.note.is_empty() {
+                                let note = format!("   {}", step.note);
+                                println!("{}", style::for_stdout(Style::Dim, &note));
+                            }
+                        }
+                    }
+                    report.warnings(&resp);
+                    report.usage(&resp);
+                }
+                Err(failure) => exit_with("plan", &query, failure, report),
+            }
+        }
+        Commands::Make { query, file } => {
+            let query = query.unwrap_or_default();
+            if query.trim().is_empty() {
+                eprintln!("Nothing to write.");
+                exit(EXIT_ERROR);
+            }
+            let found = buildfile::find(file.as_deref())
+                .unwrap_or_else(|e| exit_with("make", &query, Failure::InvalidInput(e), report));
+            let (tool, existing) = match &found {
+                Some(file) => {
+                    if file.truncated {
+                        let note = format!(
+                            "{} is long; only its beginning is sent",
+                            file.path.display()
+                        );
+                        eprintln!("{}", style::for_stderr(Style::Dim, &note));
+                    }
+                    (file.tool, file.text.as_str())
+                }
+                None => (BuildTool::Make, ""),
+            };
+            match client(args).and_then(|c| c.build_target(&query, tool, existing)) {
+                Ok(resp) => {
+                    report.log("make", &query, &resp.result, "suggested", resp.provider);
+                    report.audit("make", &query, &resp.result, "suggested", resp.provider);
+                    report.answer(std::slice::from_ref(&resp), &resp.result);
+                    report.explanation(&resp);
+                    report.warnings(&resp);
+                    report.usage(&resp);
+                }
+                Err(failure) => exit_with("make", &query, failure, report),
+            }
+        }
+        Commands::Explain {
+            buffer,
+            from_clipboard,
+            file,
+            annotated,
+            lint,
+            detail,
+            persona,
+        } => {
+            let buffer = if from_clipboard {
+                ContextSource::Clipboard
+                    .read()
+                    .unwrap_or_else(|failure| exit_with("explain", "", failure, report))
+            } else if let Some(path) = &file {
+                let read = if path.as_os_str() == "-" {
+                    io::read_to_string(io::stdin())
+                } else {
+                    std::fs::read_to_string(path)
+                };
+                read.map(|text| text.trim_end().to_string())
+                    .unwrap_or_else(|e| {
+                        let message = format!("{}: {}", path.display(), e);
+                        exit_with("explain", "", Failure::InvalidInput(message), report)
+                    })
+            } else {
+                buffer.unwrap_or_default()
+            };
+            let detail = match detail {
+                DetailArg::Brief => Detail::Brief,
+                DetailArg::Normal => Detail::Normal,
+                DetailArg::Deep => Detail::Deep,
+            };
+            if buffer.is_empty() {
+                eprintln!("Nothing to explain.");
+                exit(EXIT_ERROR);
+            }
 
-            match llm_api_call(&intro, &prompt) {
-                Ok(text) if text.starts_with('#') => {
-                    log_entry("complete", &query, &text);
-                    println!("{}", text);
-                    std::process::exit(1);
+            let client = client(args).map(|c| match persona {
+                Some(persona) => c.persona(persona),
+                None => c,
+            });
+            let explained = client.and_then(|c| {
+                if annotated {
+                    c.explain_annotated(&buffer)
+                } else {
+                    c.explain_with_detail(&buffer, detail)
                 }
-                Ok(text) => {
-                    log_entry("complete", &query, &text);
-                    println!("{}", text);
+            });
+            match explained {
+                Ok(resp) => {
+                    report.log("explain", &buffer, &resp.result, "explained", resp.provider);
+                    report.audit("explain", &buffer, &resp.result, "explained", resp.provider);
+                    report.warnings(&resp);
+                    let warnings = if lint {
+                        shell::shellcheck_warnings(&buffer).unwrap_or_default()
+                    } else {
+                        Vec::new()
+                    };
+                    if report.output == Output::Json {
+                        let mut json = report.json(Ok(std::slice::from_ref(&resp)));
+                        if !resp.breakdown.is_empty() {
+                            json["breakdown"] = serde_json::json!(resp.breakdown);
+                        }
+                        if lint {
+                            json["shellcheck"] = serde_json::json!(warnings);
+                        }
+                        println!("{}", json);
+                    } else if annotated {
+                        print_annotated(&buffer, &resp);
+                        for w in warnings {
+                            let line = format!("# shellcheck: {}", w);
+                            println!("{}", style::for_stdout(Style::Yellow, &line));
+                        }
+                        report.usage(&resp);
+                    } else {
+                        let hash = style::for_stdout(Style::Dim, "#");
+                        for line in style::render_markdown(&resp.result).lines() {
+                            println!("{} {}", hash, line);
+                        }
+                        for row in breakdown_rows(&resp.breakdown) {
+                            println!("{}   {}", hash, row);
+                        }
+                        for w in warnings {
+                            let line = format!("# shellcheck: {}", w);
+                            println!("{}", style::for_stdout(Style::Yellow, &line));
+                        }
+                        report.usage(&resp);
+                    }
                 }
-                Err(e)
-                    if e.starts_with("Request failed")
-                        || e.starts_with("API error")
-                        || e.starts_with("Invalid response")
-                        || e.starts_with("Missing")
-                        || e.starts_with("Failed to parse")
-                        || e.contains("API key") =>
-                {
-                    log_entry("complete", &query, &format!("ERROR: {}", e));
-                    println!("{}", e);
-                    std::process::exit(1);
+                Err(failure) => exit_with("explain", &buffer, failure, report),
+            }
+        }
+        Commands::Stats {
+            report: Some(period),
+            file,
+            ..
+        } => {
+            let period = match period {
+                ReportArg::Weekly => report::Period::Weekly,
+            };
+            let written = report::build(period).and_then(|text| match &file {
+                Some(path) => std::fs::write(path, text)
+                    .map(|_| eprintln!("Wrote the report to {}", path.display()))
+                    .map_err(|e| format!("{}: {}", path.display(), e)),
+                None => {
+                    print!("{}", text);
+                    Ok(())
                 }
+            });
+            if let Err(e) = written {
+                eprintln!("{}", e);
+                exit(EXIT_ERROR);
+            }
+        }
+        Commands::Stats {
+            errors: true,
+            since,
+            ..
+        } => {
+            let text = since
+                .as_deref()
+                .map(history::parse_since)
+                .transpose()
+                .and_then(report::errors);
+            match text {
+                Ok(text) => print!("{}", text),
                 Err(e) => {
-                    log_entry("complete", &query, &format!("REFUSED: {}", e));
-                    println!("# {}", e);
-                    std::process::exit(2);
+                    eprintln!("{}", e);
+                    exit(EXIT_ERROR);
                 }
             }
         }
-        Commands::Explain { buffer } => {
-            let buffer = buffer.unwrap_or_default();
-            if buffer.is_empty() {
-                println!("Nothing to explain.");
+        Commands::Stats {
+            suggest_aliases: false,
+            ..
+        } => spend::print_stats(),
+        Commands::Stats {
+            suggest_aliases: true,
+            ..
+        } => {
+            let commands = aliases::frequent_commands(10);
+            if commands.is_empty() {
+                eprintln!("No long command was run often enough to need an alias.");
                 return;
             }
-
-            let intro = format!(
-                "Explain zsh commands. Return a short, single-line explanation in the result field. {}", os
-            );
-
-            match llm_api_call(&intro, &buffer) {
-                Ok(text) => {
-                    log_entry("explain", &buffer, &text);
-                    println!("# {}", text);
+            match client(args).and_then(|c| c.suggest_aliases(&commands)) {
+                Ok(resp) => {
+                    report.warnings(&resp);
+                    if report.output == Output::Json {
+                        let mut json = report.json(Ok(std::slice::from_ref(&resp)));
+                        json["commands"] = commands
+                            .iter()
+                            .map(|f| serde_json::json!({ "command": f.command, "count": f.count }))
+                            .collect();
+                        println!("{}", json);
+                    } else {
+                        for f in &commands {
+                            let line = format!("# {}x {}", f.count, f.command);
+                            println!("{}", style::for_stdout(Style::Dim, &line));
+                        }
+                        println!("{}", resp.result.trim());
+                        report.usage(&resp);
+                    }
                 }
+                Err(failure) => exit_with("stats", "", failure, report),
+            }
+        }
+        Commands::Daemon { socket } => {
+            if let Err(e) = daemon::run_daemon(socket) {
+                eprintln!("{}", e);
+                exit(EXIT_ERROR);
+            }
+        }
+        Commands::Serve { listen } => {
+            let result = client(&cli.client)
+                .map_err(|failure| failure.to_string())
+                .and_then(|client| serve::run_server(&listen, client));
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                exit(EXIT_ERROR);
+            }
+        }
+        Commands::Auth { action } => {
+            let result = match action {
+                AuthAction::Set {
+                    provider,
+                    require_presence,
+                } => parse_provider(&provider)
+                    .and_then(|p| auth::set_key(p, require_presence))
+                    .map(|_| format!("Stored the {} key in the keychain.", provider)),
+                AuthAction::Delete { provider } => parse_provider(&provider)
+                    .and_then(auth::delete_key)
+                    .map(|_| format!("Deleted the {} key from the keychain.", provider)),
+                AuthAction::Status => Ok(auth::key_status().join("\n")),
+                AuthAction::Login { provider } => parse_provider(&provider)
+                    .and_then(auth::login)
+                    .map(|_| format!("Logged in to the {} gateway.", provider)),
+                AuthAction::Logout { provider } => parse_provider(&provider)
+                    .and_then(auth::logout)
+                    .map(|_| format!("Logged out of the {} gateway.", provider)),
+            };
+            match result {
+                Ok(message) => println!("{}", message),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(EXIT_ERROR);
+                }
+            }
+        }
+        Commands::History {
+            action: HistoryAction::Export { format, since },
+        } => {
+            let format = match format {
+                ExportFormatArg::Json => history::ExportFormat::Json,
+                ExportFormatArg::Csv => history::ExportFormat::Csv,
+                ExportFormatArg::Markdown => history::ExportFormat::Markdown,
+                ExportFormatArg::OpenaiJsonl => history::ExportFormat::OpenAiJsonl,
+            };
+            let exported = since
+                .as_deref()
+                .map(history::parse_since)
+                .transpose()
+                .and_then(|since| history::since(&history::open()?, since))
+                .map(|entries| history::export(&entries, format));
+            match exported {
+                Ok(text) => print!("{}", text),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(EXIT_ERROR);
+                }
+            }
+        }
+        Commands::Accepted { id, command } => {
+            let result =
+                history::open().and_then(|conn| history::accept(&conn, id, command.as_deref()));
+            match result {
+                Ok(true) => {}
+                Ok(false) => {
+                    eprintln!("No history entry {}.", id);
+                    exit(EXIT_ERROR);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(EXIT_ERROR);
+                }
+            }
+        }
+        Commands::VerifyAudit { path } => {
+            let Some(path) = path.or_else(audit::audit_path) else {
+                eprintln!("No audit log given and SMSH_AUDIT_LOG is not set.");
+                exit(EXIT_ERROR);
+            };
+            match audit::verify_audit(&path) {
+                Ok((count, last)) => println!(
+                    "{}: {} entries, chain intact, last hash {}",
+                    path.display(),
+                    count,
+                    last
+                ),
                 Err(e) => {
-                    log_entry("explain", &buffer, &format!("ERROR: {}", e));
-                    println!("{}", e);
-                    std::process::exit(1);
+                    eprintln!("{}", e);
+                    exit(EXIT_ERROR);
                 }
             }
         }
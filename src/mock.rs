@@ -0,0 +1,46 @@
+//! The `mock` provider: canned answers chosen by matching the prompt against the
+//! rules in a fixture file, so the whole pipeline runs in CI without an API key
+
+use crate::error::{Failure, Outcome};
+use crate::provider::Provider;
+use crate::response::parse_structured;
+use regex::Regex;
+use std::env;
+
+/// The answer to every prompt when SMSH_MOCK_FIXTURES is unset
+const DEFAULT_ANSWER: &str = "echo smartshell-mock";
+
+/// The answer of the first rule in SMSH_MOCK_FIXTURES whose `match` regex is found
+/// in the prompt. The file holds a JSON array of
+/// `{"match": "<regex>", "response": {"result": ..., "risk": ..., ...}}`, where a
+/// plain string response stands for `{"result": <string>}`.
+pub(crate) fn mock_answer(prompt: &str) -> Outcome {
+    let Some(path) = env::var_os("SMSH_MOCK_FIXTURES").filter(|p| !p.is_empty()) else {
+        return parse_structured(&serde_json::json!({ "result": DEFAULT_ANSWER }));
+    };
+    let path = std::path::PathBuf::from(path);
+    let invalid = |message: String| {
+        Failure::InvalidInput(format!("Mock fixtures {}: {}", path.display(), message))
+    };
+    let text = std::fs::read_to_string(&path).map_err(|e| invalid(e.to_string()))?;
+    let rules: Vec<serde_json::Value> =
+        serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+    for rule in &rules {
+        let pattern = rule["match"].as_str().unwrap_or("");
+        let re = Regex::new(pattern).map_err(|e| invalid(e.to_string()))?;
+        if !re.is_match(prompt) {
+            continue;
+        }
+        return match &rule["response"] {
+            serde_json::Value::String(result) => {
+                parse_structured(&serde_json::json!({ "result": result }))
+            }
+            response => parse_structured(response),
+        };
+    }
+    Err(Failure::Api {
+        provider: Provider::Mock,
+        status: None,
+        message: format!("No mock fixture matches this prompt in {}", path.display()),
+    })
+}
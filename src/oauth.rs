@@ -0,0 +1,368 @@
+//! OAuth device-code login (RFC 8628) for LLM gateways that take short-lived
+//! bearer tokens instead of API keys. A provider uses it once
+//! SMSH_<PROVIDER>_OAUTH_DEVICE_URL, _TOKEN_URL and _CLIENT_ID are set, where
+//! <PROVIDER> is OPENAI or ANTHROPIC; _SCOPE is optional.
+
+use crate::client::{runtime, CtrlCGuard};
+use crate::config::{private_file, state_dir};
+use crate::error::Failure;
+use crate::provider::{check_egress, provider_client, Provider};
+use crate::redact::redact;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Tokens are refreshed this long before they expire, so a request in flight
+/// doesn't outlive its token
+const REFRESH_MARGIN_SECS: i64 = 60;
+/// Polling interval when the device endpoint doesn't suggest one
+const DEFAULT_POLL_SECS: u64 = 5;
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+struct OAuthConfig {
+    provider: Provider,
+    device_url: String,
+    token_url: String,
+    client_id: String,
+    scope: Option<String>,
+}
+
+fn config(provider: Provider) -> Option<OAuthConfig> {
+    let prefix = format!("{}_OAUTH", provider.env_prefix()?);
+    let setting = |name: &str| {
+        env::var(format!("{}_{}", prefix, name))
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+    };
+    Some(OAuthConfig {
+        provider,
+        device_url: setting("DEVICE_URL")?,
+        token_url: setting("TOKEN_URL")?,
+        client_id: setting("CLIENT_ID")?,
+        scope: setting("SCOPE"),
+    })
+}
+
+/// Whether `provider` authenticates with OAuth tokens rather than an API key
+pub(crate) fn uses_oauth(provider: Provider) -> bool {
+    config(provider).is_some()
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Token {
+    access_token: String,
+    refresh_token: Option<String>,
+    /// Unix time the access token expires at, if the server said
+    expires_at: Option<i64>,
+}
+
+impl Token {
+    fn expiring(&self) -> bool {
+        self.expiring_at(chrono::Utc::now().timestamp())
+    }
+
+    /// Whether the token is due for a refresh at unix time `now`
+    fn expiring_at(&self, now: i64) -> bool {
+        self.expires_at
+            .is_some_and(|at| at - REFRESH_MARGIN_SECS <= now)
+    }
+}
+
+fn token_path(provider: Provider) -> Option<PathBuf> {
+    state_dir().map(|d| d.join(format!("oauth-{}.json", provider.as_str())))
+}
+
+fn load_token(provider: Provider) -> Option<Token> {
+    let text = std::fs::read_to_string(token_path(provider)?).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+/// `provider`'s token file, open and locked so that one process at a time reads
+/// and replaces the token: a rotated refresh token can only be spent once. None
+/// when there is no file and `create` is false. The lock is taken on a blocking
+/// thread, since another process may hold it for the length of a refresh.
+async fn locked_token_file(provider: Provider, create: bool) -> Result<Option<File>, String> {
+    let path = token_path(provider).ok_or("No state directory for the token")?;
+    if create {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+    }
+    tokio::task::spawn_blocking(move || {
+        // Created readable only by the user, since the token grants API access
+        let opened = private_file(OpenOptions::new().read(true).write(true))
+            .create(create)
+            .truncate(false)
+            .open(&path);
+        let file = match opened {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        file.lock()
+            .map_err(|e| format!("Cannot lock {}: {}", path.display(), e))?;
+        Ok(Some(file))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+fn read_token(file: &mut File) -> Option<Token> {
+    let mut text = String::new();
+    file.read_to_string(&mut text).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+fn write_token(file: &mut File, token: &Token) -> Result<(), String> {
+    let json = serde_json::to_string(token).map_err(|e| e.to_string())?;
+    file.set_len(0).map_err(|e| e.to_string())?;
+    file.rewind().map_err(|e| e.to_string())?;
+    file.write_all(json.as_bytes()).map_err(|e| e.to_string())
+}
+
+async fn save_token(provider: Provider, token: &Token) -> Result<(), String> {
+    let mut file = locked_token_file(provider, true)
+        .await?
+        .ok_or("No state directory for the token")?;
+    write_token(&mut file, token)
+}
+
+/// The bearer token for `provider`'s gateway, refreshed first when it is about to
+/// expire. None when the provider isn't set up for OAuth. The token file stays
+/// locked until the refreshed token is saved, so a daemon and a shell refreshing
+/// at once don't both spend the same refresh token.
+pub(crate) async fn bearer_token(provider: Provider) -> Result<Option<String>, Failure> {
+    let Some(config) = config(provider) else {
+        return Ok(None);
+    };
+    let auth = |message: String| Failure::Auth { provider, message };
+    let login_hint = format!("run `smartshell auth login {}`", provider.as_str());
+    let not_logged_in = || auth(format!("Not logged in to the gateway; {}", login_hint));
+    let mut file = locked_token_file(provider, false)
+        .await
+        .map_err(auth)?
+        .ok_or_else(not_logged_in)?;
+    let token = read_token(&mut file).ok_or_else(not_logged_in)?;
+    if !token.expiring() {
+        return Ok(Some(token.access_token));
+    }
+    let refresh_token = token
+        .refresh_token
+        .ok_or_else(|| auth(format!("Gateway token expired; {}", login_hint)))?;
+    let fresh = token_request(
+        &config,
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &refresh_token),
+            ("client_id", &config.client_id),
+        ],
+    )
+    .await
+    .map_err(|e| {
+        auth(format!(
+            "Could not refresh the gateway token ({}); {}",
+            e, login_hint
+        ))
+    })?;
+    // Servers that don't rotate refresh tokens leave it out of the response
+    let fresh = Token {
+        refresh_token: fresh.refresh_token.or(Some(refresh_token)),
+        ..fresh
+    };
+    write_token(&mut file, &fresh).map_err(auth)?;
+    Ok(Some(fresh.access_token))
+}
+
+/// POST a form to the token endpoint. Errors carry the OAuth `error` code, such
+/// as `authorization_pending`, or a description of what went wrong.
+async fn token_request(config: &OAuthConfig, form: &[(&str, &str)]) -> Result<Token, String> {
+    let json = post_form(config.provider, &config.token_url, form).await?;
+    let Some(access_token) = json["access_token"].as_str() else {
+        return Err(json["error"]
+            .as_str()
+            .unwrap_or("no access token")
+            .to_string());
+    };
+    Ok(Token {
+        access_token: access_token.to_string(),
+        refresh_token: json["refresh_token"].as_str().map(String::from),
+        expires_at: json["expires_in"]
+            .as_i64()
+            .map(|secs| chrono::Utc::now().timestamp() + secs),
+    })
+}
+
+/// POST a form with `provider`'s client, which presents the same client
+/// certificate to the gateway as API requests do
+async fn post_form(
+    provider: Provider,
+    url: &str,
+    form: &[(&str, &str)],
+) -> Result<serde_json::Value, String> {
+    let url = check_egress(url).map_err(|e| e.to_string())?;
+    let resp = provider_client(provider)
+        .map_err(|e| e.to_string())?
+        .post(url)
+        .timeout(Duration::from_secs(30))
+        .header("Accept", "application/json")
+        .form(form)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = resp.status();
+    let json: serde_json::Value = resp.json().await.map_err(|e| e.to_string())?;
+    if !status.is_success() && json["error"].is_null() {
+        return Err(format!("HTTP {}", status.as_u16()));
+    }
+    Ok(json)
+}
+
+/// Sign in with the device-code flow: show the verification URL and code, then
+/// poll the token endpoint until the user has approved (or denied) the request
+pub fn login(provider: Provider) -> Result<(), String> {
+    let config = config(provider).ok_or_else(|| {
+        let prefix = format!("{}_OAUTH", provider.env_prefix().unwrap_or_default());
+        format!(
+            "{} is not set up for OAuth; set {}_DEVICE_URL, {}_TOKEN_URL and {}_CLIENT_ID.",
+            provider.as_str(),
+            prefix,
+            prefix,
+            prefix
+        )
+    })?;
+    let _ctrl_c = CtrlCGuard::new();
+    runtime()
+        .block_on(async {
+            tokio::select! {
+                result = device_flow(provider, &config) => result,
+                _ = tokio::signal::ctrl_c() => Err("Cancelled.".to_string()),
+            }
+        })
+        .map_err(|e| redact(&e))
+}
+
+async fn device_flow(provider: Provider, config: &OAuthConfig) -> Result<(), String> {
+    let mut form = vec![("client_id", config.client_id.as_str())];
+    if let Some(scope) = &config.scope {
+        form.push(("scope", scope));
+    }
+    let device = post_form(provider, &config.device_url, &form).await?;
+    let field = |name: &str| device[name].as_str().map(String::from);
+    let (Some(device_code), Some(user_code)) = (field("device_code"), field("user_code")) else {
+        let error = device["error"].as_str().unwrap_or("no device code");
+        return Err(format!("The gateway refused the login: {}", error));
+    };
+    // Some servers use the older `verification_url` spelling
+    let uri = field("verification_uri")
+        .or_else(|| field("verification_url"))
+        .unwrap_or_default();
+    match field("verification_uri_complete") {
+        Some(complete) => eprintln!("To sign in, open {}", complete),
+        None => eprintln!("To sign in, open {} and enter the code {}", uri, user_code),
+    }
+    let mut interval = device["interval"].as_u64().unwrap_or(DEFAULT_POLL_SECS);
+    let expires_in = device["expires_in"].as_u64().unwrap_or(15 * 60);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(expires_in);
+    loop {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        if tokio::time::Instant::now() >= deadline {
+            return Err("The login code expired before it was approved.".to_string());
+        }
+        let poll = token_request(
+            config,
+            &[
+                ("grant_type", DEVICE_CODE_GRANT),
+                ("device_code", &device_code),
+                ("client_id", &config.client_id),
+            ],
+        )
+        .await;
+        match poll {
+            Ok(token) => return save_token(provider, &token).await,
+            Err(e) => interval = next_interval(&e, interval)?,
+        }
+    }
+}
+
+/// The polling interval after the token endpoint answered with the OAuth `error`
+/// code, or why the login is over
+fn next_interval(error: &str, interval: u64) -> Result<u64, String> {
+    match error {
+        "authorization_pending" => Ok(interval),
+        "slow_down" => Ok(interval + 5),
+        "access_denied" => Err("The login was denied.".to_string()),
+        "expired_token" => Err("The login code expired before it was approved.".to_string()),
+        other => Err(format!("Login failed: {}", other)),
+    }
+}
+
+/// Forget the stored token
+pub fn logout(provider: Provider) -> Result<(), String> {
+    let Some(path) = token_path(provider).filter(|p| p.exists()) else {
+        return Err(format!(
+            "Not logged in to the {} gateway.",
+            provider.as_str()
+        ));
+    };
+    std::fs::remove_file(path).map_err(|e| e.to_string())
+}
+
+/// `oauth (expires in 42m)`, `oauth (expired)` or `oauth (not logged in)`
+pub(crate) fn status(provider: Provider) -> String {
+    let Some(token) = load_token(provider) else {
+        return "oauth (not logged in)".to_string();
+    };
+    match token.expires_at {
+        None => "oauth".to_string(),
+        Some(at) => {
+            let left = at - chrono::Utc::now().timestamp();
+            match (left > 0, token.refresh_token.is_some()) {
+                (true, _) => format!("oauth (expires in {}m)", left / 60),
+                (false, true) => "oauth (expired, refreshed on next use)".to_string(),
+                (false, false) => "oauth (expired)".to_string(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(expires_at: Option<i64>) -> Token {
+        Token {
+            access_token: "access".to_string(),
+            refresh_token: None,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn tokens_are_refreshed_a_margin_before_they_expire() {
+        let now = 1_000_000;
+        assert!(!token(None).expiring_at(now));
+        assert!(!token(Some(now + REFRESH_MARGIN_SECS + 1)).expiring_at(now));
+        assert!(token(Some(now + REFRESH_MARGIN_SECS)).expiring_at(now));
+        assert!(token(Some(now - 1)).expiring_at(now));
+    }
+
+    #[test]
+    fn polling_slows_down_when_asked_and_stops_on_final_errors() {
+        assert_eq!(next_interval("authorization_pending", 5), Ok(5));
+        assert_eq!(next_interval("slow_down", 5), Ok(10));
+        assert_eq!(next_interval("slow_down", 10), Ok(15));
+        assert_eq!(
+            next_interval("access_denied", 5),
+            Err("The login was denied.".to_string())
+        );
+        assert!(next_interval("expired_token", 5)
+            .unwrap_err()
+            .contains("expired"));
+        assert_eq!(
+            next_interval("invalid_grant", 5),
+            Err("Login failed: invalid_grant".to_string())
+        );
+    }
+}
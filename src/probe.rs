@@ -0,0 +1,296 @@
+//! Read-only probes the model may run (with approval) before answering
+
+use crate::client::SmartshellClient;
+use crate::config::log_entry;
+use crate::error::{Failure, Outcome};
+use crate::injection::untrusted_block;
+use crate::provider::{
+    claude_request, claude_structured_tool, openai_content, openai_request, Provider, CLAUDE_MODEL,
+    MAX_TOKENS, OPENAI_MODEL,
+};
+use crate::response::{parse_structured, response_schema};
+use crate::shell::{is_operator, prompt_tty, scrub_secrets, shell_words};
+use crate::spend::check_budget;
+use std::process::Command;
+
+const MAX_PROBE_ROUNDS: usize = 5;
+const PROBE_OUTPUT_LIMIT: usize = 4000;
+
+const PROBE_DESCRIPTION: &str = "Run a read-only command on the user's machine to inspect \
+    the environment before answering. Allowed: ls, which, uname, pwd, whoami, id, git status, \
+    git branch, git remote -v, git log. The user approves each call.";
+
+/// Check that a probe is one of the allowed read-only commands and split it into argv
+fn validate_probe(cmd: &str) -> Result<Vec<String>, String> {
+    if cmd.contains(['>', '<', '`']) || cmd.contains("$(") {
+        return Err("redirection and substitution are not allowed".to_string());
+    }
+    let words = shell_words(cmd);
+    if words.iter().any(|w| is_operator(w)) {
+        return Err("only a single command is allowed".to_string());
+    }
+    let args: Vec<&str> = words.iter().map(String::as_str).collect();
+    let allowed = match args.as_slice() {
+        ["ls" | "which" | "uname" | "pwd" | "whoami" | "id", ..] => true,
+        ["git", "status", rest @ ..] | ["git", "log", rest @ ..] => {
+            rest.iter().all(|a| !a.starts_with("--output"))
+        }
+        ["git", "branch", rest @ ..] => rest
+            .iter()
+            .all(|a| matches!(*a, "-a" | "-r" | "-v" | "-vv" | "--list" | "--show-current")),
+        ["git", "remote"] | ["git", "remote", "-v"] => true,
+        _ => false,
+    };
+    if allowed {
+        Ok(words)
+    } else {
+        Err(format!("`{}` is not an allowed read-only probe", cmd))
+    }
+}
+
+/// Ask the user to approve a probe and return its output (or why it didn't run),
+/// marked as untrusted for the model, and add it to `probed`
+fn approve_and_run_probe(cmd: &str, probed: &mut String) -> String {
+    let output = run_probe(cmd);
+    probed.push_str(&output);
+    probed.push('\n');
+    untrusted_block("probe", &output)
+}
+
+fn run_probe(cmd: &str) -> String {
+    let argv = match validate_probe(cmd) {
+        Ok(argv) => argv,
+        Err(why) => return format!("Rejected: {}", why),
+    };
+    let reply = prompt_tty(&format!("Model wants to run `{}`. Allow? [y/N] ", cmd));
+    if !matches!(reply.as_deref(), Some("y" | "Y" | "yes")) {
+        log_entry("probe", cmd, "denied");
+        return "The user declined to run this command.".to_string();
+    }
+    log_entry("probe", cmd, "approved");
+    match scrub_secrets(Command::new(&argv[0]).args(&argv[1..])).output() {
+        Ok(out) => {
+            let mut text = String::from_utf8_lossy(&out.stdout).into_owned();
+            text.push_str(&String::from_utf8_lossy(&out.stderr));
+            if text.len() > PROBE_OUTPUT_LIMIT {
+                let mut end = PROBE_OUTPUT_LIMIT;
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                text.truncate(end);
+                text.push_str("\n[truncated]");
+            }
+            format!("exit status {}\n{}", out.status.code().unwrap_or(-1), text)
+        }
+        Err(e) => format!("Failed to run: {}", e),
+    }
+}
+
+/// Like `SmartshellClient::llm_api_call`, but lets the model request approved
+/// read-only probes via tool use before it produces the final structured answer.
+/// The probes' output is added to `probed`, to be checked for injected
+/// instructions like the buffer.
+pub(crate) fn probe_call(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    probed: &mut String,
+) -> Outcome {
+    check_budget()?;
+    match client.provider {
+        Provider::OpenAi => openai_probe_loop(client, intro, prompt, probed),
+        Provider::Claude => claude_probe_loop(client, intro, prompt, probed),
+        // Nothing to probe with: the answers are canned
+        Provider::Replay | Provider::Mock => client.llm_api_call(intro, prompt),
+    }
+}
+
+fn openai_probe_loop(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    probed: &mut String,
+) -> Outcome {
+    let probe_tool = serde_json::json!({
+        "type": "function",
+        "function": {
+            "name": "run_probe",
+            "description": PROBE_DESCRIPTION,
+            "parameters": {
+                "type": "object",
+                "properties": { "command": { "type": "string" } },
+                "required": ["command"],
+                "additionalProperties": false
+            }
+        }
+    });
+    let mut messages = vec![
+        serde_json::json!({"role": "system", "content": intro}),
+        serde_json::json!({"role": "user", "content": prompt}),
+    ];
+    for round in 0..MAX_PROBE_ROUNDS {
+        let mut body = serde_json::json!({
+            "model": OPENAI_MODEL,
+            "max_tokens": MAX_TOKENS,
+            "temperature": 0,
+            "messages": messages,
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": response_schema()
+            }
+        });
+        if round + 1 < MAX_PROBE_ROUNDS {
+            body["tools"] = serde_json::json!([probe_tool]);
+        }
+        let json = client.run(openai_request(client, &body))?;
+        let message = &json["choices"][0]["message"];
+        let Some(calls) = message["tool_calls"].as_array().filter(|c| !c.is_empty()) else {
+            return openai_content(&json);
+        };
+        messages.push(message.clone());
+        for call in calls {
+            let args: serde_json::Value =
+                serde_json::from_str(call["function"]["arguments"].as_str().unwrap_or("{}"))
+                    .unwrap_or_default();
+            let output = approve_and_run_probe(args["command"].as_str().unwrap_or(""), probed);
+            messages.push(serde_json::json!({
+                "role": "tool",
+                "tool_call_id": call["id"],
+                "content": output
+            }));
+        }
+    }
+    Err(Failure::Parse {
+        provider: client.provider,
+        message: "Model did not answer after probing".to_string(),
+    })
+}
+
+fn claude_probe_loop(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    probed: &mut String,
+) -> Outcome {
+    let schema = response_schema();
+    let probe_tool = serde_json::json!({
+        "name": "run_probe",
+        "description": PROBE_DESCRIPTION,
+        "input_schema": {
+            "type": "object",
+            "properties": { "command": { "type": "string" } },
+            "required": ["command"]
+        }
+    });
+    let mut messages = vec![serde_json::json!({"role": "user", "content": prompt})];
+    for round in 0..MAX_PROBE_ROUNDS {
+        let (tools, choice) = if round + 1 < MAX_PROBE_ROUNDS {
+            (
+                serde_json::json!([claude_structured_tool(&schema["schema"]), probe_tool]),
+                serde_json::json!({"type": "any"}),
+            )
+        } else {
+            (
+                serde_json::json!([claude_structured_tool(&schema["schema"])]),
+                serde_json::json!({"type": "tool", "name": "structured_response"}),
+            )
+        };
+        let body = serde_json::json!({
+            "model": CLAUDE_MODEL,
+            "max_tokens": MAX_TOKENS,
+            "temperature": 0,
+            "system": intro,
+            "messages": messages,
+            "tools": tools,
+            "tool_choice": choice
+        });
+        let json = client.run(claude_request(client, &body))?;
+        let blocks = json["content"].as_array().cloned().unwrap_or_default();
+        let tool_uses = || blocks.iter().filter(|b| b["type"] == "tool_use");
+        if let Some(answer) = tool_uses().find(|b| b["name"] == "structured_response") {
+            return parse_structured(&answer["input"]);
+        }
+        let results: Vec<serde_json::Value> = tool_uses()
+            .filter(|b| b["name"] == "run_probe")
+            .map(|b| {
+                let command = b["input"]["command"].as_str().unwrap_or("");
+                serde_json::json!({
+                    "type": "tool_result",
+                    "tool_use_id": b["id"],
+                    "content": approve_and_run_probe(command, probed)
+                })
+            })
+            .collect();
+        if results.is_empty() {
+            break;
+        }
+        messages.push(serde_json::json!({"role": "assistant", "content": blocks}));
+        messages.push(serde_json::json!({"role": "user", "content": results}));
+    }
+    Err(Failure::Parse {
+        provider: client.provider,
+        message: "Model did not answer after probing".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_read_only_probes() {
+        assert_eq!(
+            validate_probe("ls -la /etc").unwrap(),
+            ["ls", "-la", "/etc"]
+        );
+        for cmd in [
+            "which rg",
+            "uname -a",
+            "git status --short",
+            "git log --oneline -5",
+            "git branch -vv",
+            "git remote -v",
+        ] {
+            assert!(validate_probe(cmd).is_ok(), "refused: {}", cmd);
+        }
+    }
+
+    #[test]
+    fn rejects_other_commands_and_writing_options() {
+        for cmd in [
+            "cat ~/.ssh/id_rsa",
+            "git push",
+            "git branch -D main",
+            "git remote add evil https://example.com",
+            "git log --output=/tmp/x",
+        ] {
+            let err = validate_probe(cmd).unwrap_err();
+            assert!(err.contains("not an allowed read-only probe"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rejects_chaining_redirection_and_substitution() {
+        for cmd in ["ls; rm -rf ~", "ls && curl x", "ls | sh"] {
+            assert_eq!(
+                validate_probe(cmd).unwrap_err(),
+                "only a single command is allowed"
+            );
+        }
+        for cmd in ["ls > out", "ls $(whoami)", "ls `whoami`", "which < x"] {
+            assert_eq!(
+                validate_probe(cmd).unwrap_err(),
+                "redirection and substitution are not allowed"
+            );
+        }
+    }
+
+    #[test]
+    fn probe_results_are_marked_untrusted_and_kept() {
+        let mut probed = String::new();
+        let result = approve_and_run_probe("cat ~/.ssh/id_rsa", &mut probed);
+        assert!(result.starts_with("<untrusted-probe-"), "{}", result);
+        assert!(result.contains("Rejected: "), "{}", result);
+        assert!(probed.starts_with("Rejected: "), "{}", probed);
+    }
+}
@@ -0,0 +1,1133 @@
+//! Provider requests: HTTP plumbing, retries, egress checks and streaming
+
+use crate::client::{SmartshellClient, STREAMING};
+use crate::config::{env_parse, log_event, request_id, trace, tracing};
+use crate::console::Console;
+use crate::error::{Failure, Outcome};
+use crate::keys::{current_key, with_key_rotation};
+use crate::mock::mock_answer;
+use crate::oauth::bearer_token;
+use crate::policy::Policy;
+use crate::ratelimit::take_request;
+use crate::redact::{describe_request, redact};
+use crate::replay::replay_answer;
+use crate::response::{parse_structured, response_schema};
+use crate::spend::{record_spend, Usage};
+use crate::style;
+use std::env;
+use std::time::{Duration, Instant};
+
+pub(crate) const OPENAI_MODEL: &str = "gpt-4o";
+pub(crate) const CLAUDE_MODEL: &str = "claude-sonnet-4-5-20250929";
+/// Room for the structured answer, the same for every request that asks for one
+pub(crate) const MAX_TOKENS: u32 = 1024;
+
+/// The LLM API a client talks to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    OpenAi,
+    Claude,
+    /// Answers recorded earlier with `SMSH_RECORD`, without network access
+    Replay,
+    /// Canned answers from the `SMSH_MOCK_FIXTURES` rules, for integration tests
+    Mock,
+}
+
+impl Provider {
+    /// The provider named by `SMSH_LLM_PROVIDER`, defaulting to OpenAI
+    pub fn from_env() -> Result<Self, Failure> {
+        env::var("SMSH_LLM_PROVIDER")
+            .as_deref()
+            .unwrap_or("openai")
+            .parse()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Provider::OpenAi => "openai",
+            Provider::Claude => "claude",
+            Provider::Replay => "replay",
+            Provider::Mock => "mock",
+        }
+    }
+
+    pub fn model(self) -> &'static str {
+        match self {
+            Provider::OpenAi => OPENAI_MODEL,
+            Provider::Claude => CLAUDE_MODEL,
+            Provider::Replay => "replay",
+            Provider::Mock => "mock",
+        }
+    }
+
+    /// The company behind a remote provider's API. It names the provider's keychain
+    /// entry (`smartshell.<vendor>`) and its settings (`SMSH_<VENDOR>_API_KEY`,
+    /// `_KEY_COMMAND`, `_KEYCHAIN_SERVICE`, ...). None for the local providers.
+    pub fn vendor(self) -> Option<&'static str> {
+        match self {
+            Provider::OpenAi => Some("openai"),
+            Provider::Claude => Some("anthropic"),
+            Provider::Replay | Provider::Mock => None,
+        }
+    }
+
+    /// `SMSH_<VENDOR>`, the prefix of the provider's settings
+    pub(crate) fn env_prefix(self) -> Option<String> {
+        self.vendor()
+            .map(|vendor| format!("SMSH_{}", vendor.to_ascii_uppercase()))
+    }
+
+    /// Whether requests leave the machine (and so need keys, budget and the network)
+    pub fn is_remote(self) -> bool {
+        matches!(self, Provider::OpenAi | Provider::Claude)
+    }
+}
+
+impl std::str::FromStr for Provider {
+    type Err = Failure;
+
+    fn from_str(name: &str) -> Result<Self, Failure> {
+        match name {
+            "openai" => Ok(Provider::OpenAi),
+            "claude" => Ok(Provider::Claude),
+            "replay" => Ok(Provider::Replay),
+            "mock" => Ok(Provider::Mock),
+            other => Err(Failure::InvalidInput(format!(
+                "Unknown provider: {}",
+                other
+            ))),
+        }
+    }
+}
+
+pub(crate) fn api_failure(
+    provider: Provider,
+    status: reqwest::StatusCode,
+    retry_after: Option<Duration>,
+    message: &str,
+) -> Failure {
+    // Providers quote the rejected key back in some auth errors
+    let message = format!("API error: {}", redact(message));
+    match status.as_u16() {
+        401 | 403 => Failure::Auth { provider, message },
+        429 => Failure::RateLimited {
+            provider,
+            retry_after,
+            message,
+        },
+        code => Failure::Api {
+            provider,
+            status: Some(code),
+            message,
+        },
+    }
+}
+
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_JITTER: f64 = 0.25;
+const MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(20);
+
+/// A Retry-After header in either of its forms (seconds or an HTTP date)
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let v = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    v.trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+        .or_else(|| {
+            let at = chrono::DateTime::parse_from_rfc2822(v.trim()).ok()?;
+            (at.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                .to_std()
+                .ok()
+        })
+}
+
+/// Delay before retry number `attempt` (0-based): the server's Retry-After if
+/// given, otherwise 500ms doubling per attempt, plus up to SMSH_RETRY_JITTER
+/// (fraction of the delay) of random jitter. None when the server asks for a
+/// longer wait than MAX_RETRY_DELAY: the failure is reported with its
+/// Retry-After rather than retried early.
+fn retry_delay(attempt: u32, retry_after: Option<Duration>) -> Option<std::time::Duration> {
+    if retry_after.is_some_and(|after| after > MAX_RETRY_DELAY) {
+        return None;
+    }
+    let base = retry_after
+        .unwrap_or_else(|| std::time::Duration::from_millis(500 * 2u64.pow(attempt.min(6))));
+    let jitter = env_parse::<f64>("SMSH_RETRY_JITTER")
+        .unwrap_or(DEFAULT_RETRY_JITTER)
+        .clamp(0.0, 1.0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let random = (nanos % 1000) as f64 / 1000.0;
+    let delay = base.mul_f64(1.0 + jitter * random).min(MAX_RETRY_DELAY);
+    Some(delay.max(retry_after.unwrap_or_default()))
+}
+
+pub(crate) fn transport_failure(e: reqwest::Error, client: &SmartshellClient) -> Failure {
+    if e.is_timeout() {
+        Failure::Timeout {
+            provider: client.provider,
+            after: client.timeout,
+        }
+    } else {
+        Failure::Network {
+            provider: client.provider,
+            message: format!("Request failed: {}", e),
+        }
+    }
+}
+
+/// Send a request body to a provider endpoint, retrying rate limits and server
+/// errors (SMSH_RETRIES times, default 2)
+async fn send_with_retries(
+    client: &SmartshellClient,
+    request: reqwest::RequestBuilder,
+    body: &serde_json::Value,
+) -> Result<reqwest::Response, Failure> {
+    let request = request.json(body);
+    let retries = env_parse("SMSH_RETRIES").unwrap_or(DEFAULT_RETRIES);
+    let mut attempt = 0;
+    let resp = loop {
+        take_request()?;
+        if tracing(2) {
+            if let Some(Ok(built)) = request.try_clone().map(|r| r.build()) {
+                trace(2, format_args!("{}", describe_request(&built)));
+            }
+        }
+        let started = Instant::now();
+        let resp = request
+            .try_clone()
+            .expect("JSON requests can be cloned")
+            .send()
+            .await
+            .map_err(|e| {
+                trace(1, format_args!("request failed: {}", e));
+                transport_failure(e, client)
+            })?;
+        let status = resp.status();
+        // The provider's own id for the request (OpenAI, Anthropic), for their support
+        let upstream_id = ["x-request-id", "request-id"]
+            .iter()
+            .find_map(|name| resp.headers().get(*name)?.to_str().ok())
+            .map(|id| format!(", provider request id {}", id))
+            .unwrap_or_default();
+        trace(
+            1,
+            format_args!(
+                "POST {} -> HTTP {} in {}ms{}",
+                resp.url(),
+                status.as_u16(),
+                started.elapsed().as_millis(),
+                upstream_id
+            ),
+        );
+        let retryable = status.as_u16() == 429 || status.is_server_error();
+        if attempt >= retries || !retryable {
+            if retryable {
+                trace(1, format_args!("giving up after {} retries", retries));
+            }
+            break resp;
+        }
+        let retry_after = parse_retry_after(resp.headers());
+        let Some(delay) = retry_delay(attempt, retry_after) else {
+            trace(
+                1,
+                format_args!(
+                    "server asks to wait {}s, longer than {}s; not retrying",
+                    retry_after.unwrap_or_default().as_secs(),
+                    MAX_RETRY_DELAY.as_secs()
+                ),
+            );
+            break resp;
+        };
+        attempt += 1;
+        trace(
+            1,
+            format_args!(
+                "retrying in {}ms (attempt {}/{})",
+                delay.as_millis(),
+                attempt,
+                retries
+            ),
+        );
+        log_event(
+            "retry",
+            resp.url().as_str(),
+            &format!(
+                "HTTP {}, attempt {}/{} in {}ms",
+                status.as_u16(),
+                attempt,
+                retries,
+                delay.as_millis()
+            ),
+        );
+        tokio::time::sleep(delay).await;
+    };
+    Ok(resp)
+}
+
+/// Send a request body to a provider endpoint and return the decoded JSON,
+/// mapping transport and API errors to failures
+pub(crate) async fn post_json(
+    client: &SmartshellClient,
+    request: reqwest::RequestBuilder,
+    body: &serde_json::Value,
+) -> Result<serde_json::Value, Failure> {
+    let resp = send_with_retries(client, request, body).await?;
+    let status = resp.status();
+    let retry_after = parse_retry_after(resp.headers());
+    let text = resp
+        .text()
+        .await
+        .map_err(|e| transport_failure(e, client))?;
+    let json: serde_json::Value = match serde_json::from_str(&text) {
+        Ok(json) => json,
+        Err(_) if !status.is_success() => {
+            let reason = status.canonical_reason().unwrap_or("request failed");
+            return Err(api_failure(client.provider, status, retry_after, reason));
+        }
+        Err(e) => {
+            return Err(Failure::Parse {
+                provider: client.provider,
+                message: format!("Invalid response: {}", e),
+            })
+        }
+    };
+    if let Some(err) = json
+        .get("error")
+        .and_then(|e| e.get("message"))
+        .and_then(|m| m.as_str())
+    {
+        return Err(api_failure(client.provider, status, retry_after, err));
+    }
+    Ok(json)
+}
+
+const DEFAULT_PROVIDER_HOSTS: &[&str] = &["api.openai.com", "api.anthropic.com"];
+
+pub(crate) fn provider_url(base_var: &str, default_base: &str, path: &str) -> String {
+    let base = env::var(base_var)
+        .ok()
+        .filter(|b| !b.is_empty())
+        .unwrap_or_else(|| default_base.to_string());
+    format!("{}{}", base.trim_end_matches('/'), path)
+}
+
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The proxy from SMSH_PROXY, which takes precedence over HTTPS_PROXY/ALL_PROXY
+fn explicit_proxy() -> Option<String> {
+    env::var("SMSH_PROXY").ok().filter(|p| !p.trim().is_empty())
+}
+
+/// Whether a TCP connection to the provider's API host can be opened quickly. A
+/// refused connection still proves the network is up. Always true behind a proxy,
+/// where direct connections are not expected to work, and when the egress
+/// allowlist doesn't let requests reach the host anyway.
+pub(crate) async fn provider_reachable(provider: Provider) -> bool {
+    let proxied = ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        .iter()
+        .any(|v| env::var_os(v).is_some_and(|p| !p.is_empty()));
+    if proxied || explicit_proxy().is_some() {
+        return true;
+    }
+    let base = match provider {
+        Provider::OpenAi => provider_url("SMSH_OPENAI_BASE_URL", "https://api.openai.com/v1", ""),
+        Provider::Claude => provider_url(
+            "SMSH_ANTHROPIC_BASE_URL",
+            "https://api.anthropic.com/v1",
+            "",
+        ),
+        Provider::Replay | Provider::Mock => return true,
+    };
+    let Some((host, port)) = check_egress(&base)
+        .ok()
+        .and_then(|u| Some((u.host_str()?.to_string(), u.port_or_known_default()?)))
+    else {
+        return true;
+    };
+    let connect = tokio::net::TcpStream::connect((host.as_str(), port));
+    match tokio::time::timeout(REACHABILITY_TIMEOUT, connect).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => e.kind() == std::io::ErrorKind::ConnectionRefused,
+        Err(_) => false,
+    }
+}
+
+fn egress_locked() -> bool {
+    env::var("SMSH_EGRESS_LOCK").is_ok_and(|v| !v.is_empty() && v != "0")
+}
+
+/// Whether `url` is HTTPS to one of the `allowed` hosts (lowercase, exact match)
+fn egress_allowed(url: &reqwest::Url, allowed: &[String]) -> bool {
+    let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    url.scheme() == "https" && allowed.contains(&host)
+}
+
+/// Refuse to send a request anywhere but an allowlisted HTTPS host. The allowlist
+/// comes from `egress` policy rules, or the built-in provider hosts when
+/// `SMSH_EGRESS_LOCK` is set.
+pub(crate) fn check_egress(url: &str) -> Result<reqwest::Url, Failure> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|e| Failure::InvalidInput(format!("Invalid URL {}: {}", url, e)))?;
+    let policy = Policy::load()?;
+    let allowed: Vec<String> = if !policy.egress.is_empty() {
+        policy.egress
+    } else if egress_locked() {
+        DEFAULT_PROVIDER_HOSTS
+            .iter()
+            .map(|h| h.to_string())
+            .collect()
+    } else {
+        return Ok(parsed);
+    };
+    if egress_allowed(&parsed, &allowed) {
+        Ok(parsed)
+    } else {
+        Err(Failure::EgressBlocked(format!(
+            "Refusing to send request to {} (not in egress allowlist)",
+            url
+        )))
+    }
+}
+
+/// HTTP client for provider calls. Redirects are not followed so an allowlisted
+/// endpoint cannot bounce a prompt to another host.
+static SHARED_CLIENT: std::sync::OnceLock<reqwest::Client> = std::sync::OnceLock::new();
+
+/// One pooled client per process, so retries, probe rounds and (in the daemon)
+/// successive requests reuse open keep-alive connections
+pub(crate) fn http_client() -> &'static reqwest::Client {
+    SHARED_CLIENT.get_or_init(|| client_builder().build().unwrap_or_default())
+}
+
+/// The client for requests to `provider`'s API: the shared one, or one presenting
+/// the client certificate in SMSH_OPENAI_CLIENT_CERT / SMSH_ANTHROPIC_CLIENT_CERT
+/// to gateways that require mutual TLS
+pub(crate) fn provider_client(provider: Provider) -> Result<&'static reqwest::Client, Failure> {
+    static OPENAI: std::sync::OnceLock<Result<reqwest::Client, String>> =
+        std::sync::OnceLock::new();
+    static CLAUDE: std::sync::OnceLock<Result<reqwest::Client, String>> =
+        std::sync::OnceLock::new();
+    let (cell, prefix) = match provider {
+        Provider::OpenAi => (&OPENAI, "SMSH_OPENAI"),
+        Provider::Claude => (&CLAUDE, "SMSH_ANTHROPIC"),
+        Provider::Replay | Provider::Mock => return Ok(http_client()),
+    };
+    let var = format!("{}_CLIENT_CERT", prefix);
+    let Some(path) = env::var_os(&var).filter(|p| !p.is_empty()) else {
+        return Ok(http_client());
+    };
+    cell.get_or_init(|| {
+        let identity = client_identity(prefix, std::path::Path::new(&path))?;
+        client_builder()
+            .identity(identity)
+            .build()
+            .map_err(|e| e.to_string())
+    })
+    .as_ref()
+    .map_err(|e| {
+        Failure::InvalidInput(format!(
+            "{} {}: {}",
+            var,
+            std::path::Path::new(&path).display(),
+            e
+        ))
+    })
+}
+
+/// A PEM certificate with its PKCS#8 key in <prefix>_CLIENT_KEY (or in the same
+/// file), or a PKCS#12 bundle unlocked with <prefix>_CLIENT_CERT_PASSWORD
+fn client_identity(prefix: &str, path: &std::path::Path) -> Result<reqwest::Identity, String> {
+    let cert = std::fs::read(path).map_err(|e| e.to_string())?;
+    if !cert.windows(10).any(|w| w == b"-----BEGIN") {
+        let password = env::var(format!("{}_CLIENT_CERT_PASSWORD", prefix)).unwrap_or_default();
+        return reqwest::Identity::from_pkcs12_der(&cert, &password)
+            .map_err(|e| format!("not a usable PKCS#12 bundle ({})", tls_error(&e)));
+    }
+    let (cert, key) = match env::var_os(format!("{}_CLIENT_KEY", prefix)).filter(|p| !p.is_empty())
+    {
+        Some(key_path) => {
+            let key = std::fs::read(&key_path).map_err(|e| {
+                format!(
+                    "cannot read {}_CLIENT_KEY {}: {}",
+                    prefix,
+                    std::path::Path::new(&key_path).display(),
+                    e
+                )
+            })?;
+            (cert, key)
+        }
+        None => split_pem_key(&String::from_utf8_lossy(&cert)),
+    };
+    if key.is_empty() {
+        return Err(format!(
+            "no private key in the file; set {}_CLIENT_KEY",
+            prefix
+        ));
+    }
+    reqwest::Identity::from_pkcs8_pem(&cert, &key).map_err(|e| {
+        format!(
+            "not a certificate with a PKCS#8 key ({}); convert an RSA key with `openssl pkcs8 -topk8 -nocrypt`",
+            tls_error(&e)
+        )
+    })
+}
+
+/// The certificate blocks and the private key block of a combined PEM file
+fn split_pem_key(pem: &str) -> (Vec<u8>, Vec<u8>) {
+    let blocks = regex::Regex::new(r"(?s)-----BEGIN ([A-Z0-9 ]+)-----.*?-----END [A-Z0-9 ]+-----")
+        .expect("valid PEM pattern");
+    let (mut certs, mut key) = (String::new(), String::new());
+    for block in blocks.captures_iter(pem) {
+        let target = if block[1].ends_with("PRIVATE KEY") {
+            &mut key
+        } else {
+            &mut certs
+        };
+        target.push_str(&block[0]);
+        target.push('\n');
+    }
+    (certs.into_bytes(), key.into_bytes())
+}
+
+/// reqwest wraps TLS setup errors as a bare "builder error"; show the cause
+fn tls_error(e: &reqwest::Error) -> String {
+    std::error::Error::source(e).map_or_else(|| e.to_string(), |cause| cause.to_string())
+}
+
+/// Redirects off and keep-alive on. HTTPS_PROXY, ALL_PROXY (http or socks5) and
+/// NO_PROXY apply as usual unless SMSH_PROXY names a proxy for everything;
+/// SMSH_CA_BUNDLE adds the root certificates in a PEM file, for proxies that
+/// intercept TLS.
+pub(crate) fn client_builder() -> reqwest::ClientBuilder {
+    let (proxy, certs) = network_settings();
+    let mut builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .connect_timeout(std::time::Duration::from_secs(10))
+        .pool_idle_timeout(std::time::Duration::from_secs(90))
+        .tcp_keepalive(std::time::Duration::from_secs(60));
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.clone());
+    }
+    for cert in certs {
+        builder = builder.add_root_certificate(cert.clone());
+    }
+    builder
+}
+
+/// SMSH_PROXY and SMSH_CA_BUNDLE, read once so a bad setting is warned about once
+fn network_settings() -> &'static (Option<reqwest::Proxy>, Vec<reqwest::Certificate>) {
+    static SETTINGS: std::sync::OnceLock<(Option<reqwest::Proxy>, Vec<reqwest::Certificate>)> =
+        std::sync::OnceLock::new();
+    SETTINGS.get_or_init(|| {
+        let proxy = explicit_proxy().and_then(|url| match reqwest::Proxy::all(url.trim()) {
+            Ok(proxy) => Some(proxy.no_proxy(reqwest::NoProxy::from_env())),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    style::warning(&format!("Ignoring SMSH_PROXY {}: {}", url, e))
+                );
+                None
+            }
+        });
+        let Some(path) = env::var_os("SMSH_CA_BUNDLE").filter(|p| !p.is_empty()) else {
+            return (proxy, Vec::new());
+        };
+        let path = std::path::PathBuf::from(path);
+        let certs = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| reqwest::Certificate::from_pem_bundle(&pem).map_err(|e| e.to_string()));
+        let certs = match certs {
+            Ok(certs) if certs.is_empty() => {
+                eprintln!(
+                    "{}",
+                    style::warning(&format!(
+                        "SMSH_CA_BUNDLE {} holds no PEM certificates",
+                        path.display()
+                    ))
+                );
+                certs
+            }
+            Ok(certs) => certs,
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    style::warning(&format!(
+                        "Ignoring SMSH_CA_BUNDLE {}: {}",
+                        path.display(),
+                        e
+                    ))
+                );
+                Vec::new()
+            }
+        };
+        (proxy, certs)
+    })
+}
+
+/// Sign a request to `provider`: with the gateway's OAuth bearer token when the
+/// provider is set up for one, otherwise with its API key
+pub(crate) async fn authorize(
+    client: &SmartshellClient,
+    provider: Provider,
+    builder: reqwest::RequestBuilder,
+) -> Result<reqwest::RequestBuilder, Failure> {
+    // OpenAI keeps the id with the request, so their support can find it
+    let builder = match provider {
+        Provider::OpenAi => builder.header("X-Client-Request-Id", request_id()),
+        _ => builder,
+    };
+    if let Some(token) = bearer_token(provider).await? {
+        return Ok(builder.bearer_auth(token));
+    }
+    let api_key = client
+        .api_key_for(provider)
+        .and_then(|raw| current_key(provider, &raw))
+        .ok_or_else(|| Failure::Auth {
+            provider,
+            message: match provider {
+                Provider::Claude => "Anthropic API key not set",
+                _ => "OpenAI API key not set",
+            }
+            .to_string(),
+        })?;
+    Ok(match provider {
+        Provider::Claude => builder.header("x-api-key", api_key),
+        _ => builder.bearer_auth(api_key),
+    })
+}
+
+async fn openai_builder(client: &SmartshellClient) -> Result<reqwest::RequestBuilder, Failure> {
+    let url = check_egress(&provider_url(
+        "SMSH_OPENAI_BASE_URL",
+        "https://api.openai.com/v1",
+        "/chat/completions",
+    ))?;
+    let builder = provider_client(Provider::OpenAi)?
+        .post(url)
+        .timeout(client.timeout);
+    authorize(client, Provider::OpenAi, builder).await
+}
+
+async fn claude_builder(client: &SmartshellClient) -> Result<reqwest::RequestBuilder, Failure> {
+    let url = check_egress(&provider_url(
+        "SMSH_ANTHROPIC_BASE_URL",
+        "https://api.anthropic.com/v1",
+        "/messages",
+    ))?;
+    let builder = provider_client(Provider::Claude)?
+        .post(url)
+        .timeout(client.timeout)
+        .header("anthropic-version", "2023-06-01");
+    authorize(client, Provider::Claude, builder).await
+}
+
+pub(crate) async fn openai_request(
+    client: &SmartshellClient,
+    body: &serde_json::Value,
+) -> Result<serde_json::Value, Failure> {
+    let json = with_key_rotation(client, Provider::OpenAi, || async move {
+        post_json(client, openai_builder(client).await?, body).await
+    })
+    .await?;
+    record_spend(
+        OPENAI_MODEL,
+        Usage {
+            input_tokens: json["usage"]["prompt_tokens"].as_u64().unwrap_or(0),
+            output_tokens: json["usage"]["completion_tokens"].as_u64().unwrap_or(0),
+        },
+    );
+    Ok(json)
+}
+
+pub(crate) async fn claude_request(
+    client: &SmartshellClient,
+    body: &serde_json::Value,
+) -> Result<serde_json::Value, Failure> {
+    let json = with_key_rotation(client, Provider::Claude, || async move {
+        post_json(client, claude_builder(client).await?, body).await
+    })
+    .await?;
+    record_spend(
+        CLAUDE_MODEL,
+        Usage {
+            input_tokens: json["usage"]["input_tokens"].as_u64().unwrap_or(0),
+            output_tokens: json["usage"]["output_tokens"].as_u64().unwrap_or(0),
+        },
+    );
+    Ok(json)
+}
+
+/// Parse the structured answer out of an OpenAI chat completion
+pub(crate) fn openai_content(json: &serde_json::Value) -> Outcome {
+    let content = json["choices"][0]["message"]["content"]
+        .as_str()
+        .ok_or_else(|| Failure::Parse {
+            provider: Provider::OpenAi,
+            message: "Missing content in response".to_string(),
+        })?;
+    parse_structured_json(Provider::OpenAi, content)
+}
+
+/// Decode a structured answer that arrived as a JSON string
+fn parse_structured_json(provider: Provider, text: &str) -> Outcome {
+    let parsed: serde_json::Value = serde_json::from_str(text).map_err(|e| Failure::Parse {
+        provider,
+        message: format!("Failed to parse response JSON: {}", e),
+    })?;
+    parse_structured(&parsed)
+}
+
+pub(crate) fn claude_structured_tool(schema: &serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "name": "structured_response",
+        "description": "Return the structured response",
+        "input_schema": schema
+    })
+}
+
+fn openai_body(intro: &str, prompt: &str, schema: &serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "model": OPENAI_MODEL,
+        "max_tokens": MAX_TOKENS,
+        "temperature": 0,
+        "messages": [
+            {"role": "system", "content": intro},
+            {"role": "user", "content": prompt}
+        ],
+        "response_format": {
+            "type": "json_schema",
+            "json_schema": schema
+        }
+    })
+}
+
+fn claude_body(intro: &str, prompt: &str, schema: &serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "model": CLAUDE_MODEL,
+        "max_tokens": MAX_TOKENS,
+        "temperature": 0,
+        "system": intro,
+        "messages": [{"role": "user", "content": prompt}],
+        "tools": [claude_structured_tool(schema)],
+        "tool_choice": {"type": "tool", "name": "structured_response"}
+    })
+}
+
+/// The body for a streamed Claude answer; `schema` is the OpenAI `json_schema`
+/// wrapper, whose inner schema becomes the tool's input schema
+fn claude_stream_body(intro: &str, prompt: &str, schema: &serde_json::Value) -> serde_json::Value {
+    let mut body = claude_body(intro, prompt, &schema["schema"]);
+    body["stream"] = serde_json::json!(true);
+    body
+}
+
+async fn openai_call(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    schema: &serde_json::Value,
+) -> Outcome {
+    let json = openai_request(client, &openai_body(intro, prompt, schema)).await?;
+    openai_content(&json)
+}
+
+async fn claude_call(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    schema: &serde_json::Value,
+) -> Outcome {
+    let json = claude_request(client, &claude_body(intro, prompt, schema)).await?;
+    parse_structured(&json["content"][0]["input"])
+}
+
+pub(crate) async fn provider_call(client: &SmartshellClient, intro: &str, prompt: &str) -> Outcome {
+    let schema = client.schema.json();
+    match client.provider {
+        Provider::OpenAi => openai_call(client, intro, prompt, &schema).await,
+        Provider::Claude => claude_call(client, intro, prompt, &schema["schema"]).await,
+        Provider::Replay => replay_answer(intro, prompt),
+        Provider::Mock => mock_answer(prompt),
+    }
+}
+
+const CANDIDATE_TEMPERATURE: f64 = 0.7;
+
+/// Sample `n` answers at once: one request with `n` choices for OpenAI, `n`
+/// concurrent requests for Claude. Each entry is that sample's own outcome.
+pub(crate) async fn provider_candidates(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    n: usize,
+) -> Result<Vec<Outcome>, Failure> {
+    let schema = response_schema();
+    match client.provider {
+        Provider::OpenAi => {
+            let mut body = openai_body(intro, prompt, &schema);
+            body["n"] = n.into();
+            body["temperature"] = CANDIDATE_TEMPERATURE.into();
+            let json = openai_request(client, &body).await?;
+            let choices = json["choices"].as_array().cloned().unwrap_or_default();
+            if choices.is_empty() {
+                return Err(Failure::Parse {
+                    provider: Provider::OpenAi,
+                    message: "Missing content in response".to_string(),
+                });
+            }
+            Ok(choices
+                .iter()
+                .map(|c| openai_content(&serde_json::json!({ "choices": [c] })))
+                .collect())
+        }
+        Provider::Claude => {
+            let mut body = claude_body(intro, prompt, &schema["schema"]);
+            body["temperature"] = CANDIDATE_TEMPERATURE.into();
+            let mut samples = tokio::task::JoinSet::new();
+            for _ in 0..n {
+                let (client, body) = (client.clone(), body.clone());
+                samples.spawn(async move {
+                    let json = claude_request(&client, &body).await?;
+                    parse_structured(&json["content"][0]["input"])
+                });
+            }
+            let mut outcomes = Vec::with_capacity(n);
+            while let Some(joined) = samples.join_next().await {
+                outcomes.push(joined.unwrap_or_else(|e| {
+                    Err(Failure::Api {
+                        provider: Provider::Claude,
+                        status: None,
+                        message: e.to_string(),
+                    })
+                }));
+            }
+            Ok(outcomes)
+        }
+        Provider::Replay => Ok(vec![replay_answer(intro, prompt)]),
+        Provider::Mock => Ok(vec![mock_answer(prompt)]),
+    }
+}
+
+const CREATIVE_TEMPERATURE: f64 = 1.0;
+
+/// One answer per system prompt in `intros`, requested concurrently at a high
+/// temperature, for `--creative`'s differently styled variants. Each entry is
+/// that request's own outcome.
+pub(crate) async fn provider_variants(
+    client: &SmartshellClient,
+    intros: &[String],
+    prompt: &str,
+) -> Result<Vec<Outcome>, Failure> {
+    let schema = response_schema();
+    let mut variants = tokio::task::JoinSet::new();
+    for (i, intro) in intros.iter().enumerate() {
+        let (client, intro, prompt) = (client.clone(), intro.clone(), prompt.to_string());
+        let schema = schema.clone();
+        variants.spawn(async move {
+            let outcome = match client.provider {
+                Provider::OpenAi => {
+                    let mut body = openai_body(&intro, &prompt, &schema);
+                    body["temperature"] = CREATIVE_TEMPERATURE.into();
+                    let json = openai_request(&client, &body).await;
+                    json.and_then(|json| openai_content(&json))
+                }
+                Provider::Claude => {
+                    let mut body = claude_body(&intro, &prompt, &schema["schema"]);
+                    body["temperature"] = CREATIVE_TEMPERATURE.into();
+                    let json = claude_request(&client, &body).await;
+                    json.and_then(|json| parse_structured(&json["content"][0]["input"]))
+                }
+                Provider::Replay => replay_answer(&intro, &prompt),
+                Provider::Mock => mock_answer(&prompt),
+            };
+            (i, outcome)
+        });
+    }
+    // Keep the order of `intros`, so the variants come back in the order asked for
+    let mut outcomes: Vec<Option<Outcome>> = intros.iter().map(|_| None).collect();
+    while let Some(joined) = variants.join_next().await {
+        match joined {
+            Ok((i, outcome)) => outcomes[i] = Some(outcome),
+            Err(e) => {
+                return Err(Failure::Api {
+                    provider: client.provider,
+                    status: None,
+                    message: e.to_string(),
+                })
+            }
+        }
+    }
+    Ok(outcomes.into_iter().flatten().collect())
+}
+
+/// The terminal to stream answers to, unless SMSH_STREAM=0
+pub(crate) fn stream_tty() -> Option<Console> {
+    if env::var("SMSH_STREAM").is_ok_and(|v| v == "0") || style::plain() {
+        return None;
+    }
+    Console::open()
+}
+
+/// The response of a streamed request, or the failure its error status stands for
+async fn check_status(
+    client: &SmartshellClient,
+    resp: reqwest::Response,
+) -> Result<reqwest::Response, Failure> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let retry_after = parse_retry_after(resp.headers());
+    let json: serde_json::Value = resp.json().await.unwrap_or_default();
+    let message = json["error"]["message"]
+        .as_str()
+        .or(status.canonical_reason())
+        .unwrap_or("request failed");
+    Err(api_failure(client.provider, status, retry_after, message))
+}
+
+/// Hand each `data:` payload of a server-sent event stream to `on_event`
+async fn read_sse(
+    client: &SmartshellClient,
+    mut resp: reqwest::Response,
+    mut on_event: impl FnMut(&serde_json::Value),
+) -> Result<(), Failure> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .map_err(|e| transport_failure(e, client))?
+    {
+        buf.extend_from_slice(&chunk);
+        while let Some(end) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data:") else {
+                continue;
+            };
+            if data.trim() == "[DONE]" {
+                return Ok(());
+            }
+            if let Ok(event) = serde_json::from_str(data) {
+                on_event(&event);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The decoded value of string field `key` in a JSON object that may still be
+/// arriving, as far as it has been received
+fn partial_json_string(json: &str, key: &str) -> Option<String> {
+    let start = json.find(&format!("\"{}\"", key))? + key.len() + 2;
+    let rest = json[start..].trim_start().strip_prefix(':')?;
+    let mut chars = rest.trim_start().strip_prefix('"')?.chars();
+    let mut out = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n' | 'r' | 't') => out.push(' '),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    out.extend(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32));
+                }
+                Some(c) => out.push(c),
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+/// Request the structured answer as a stream and draw the `result` field on the
+/// terminal line the spinner uses while it arrives. The final answer is still
+/// returned (and printed on stdout) as usual.
+pub(crate) async fn stream_call(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    mut tty: Console,
+) -> Outcome {
+    let schema = response_schema();
+    let width: usize = env_parse("COLUMNS").unwrap_or(80).max(10);
+    let mut shown = String::new();
+    let mut preview = |text: &str| {
+        let Some(partial) = partial_json_string(text, "result").filter(|p| *p != shown) else {
+            return;
+        };
+        shown.clone_from(&partial);
+        STREAMING.store(true, std::sync::atomic::Ordering::Relaxed);
+        // Multi-line answers are previewed on the one line the spinner uses
+        let partial = partial.replace('\n', " ");
+        let chars = partial.chars().count();
+        let line: String = if chars < width {
+            partial
+        } else {
+            let skip = chars + 2 - width;
+            format!("…{}", partial.chars().skip(skip).collect::<String>())
+        };
+        tty.status(&line);
+    };
+    let mut text = String::new();
+    let mut usage = Usage::default();
+    let mut error = None;
+    let model = match client.provider {
+        Provider::Replay => return replay_answer(intro, prompt),
+        Provider::Mock => return mock_answer(prompt),
+        Provider::OpenAi => {
+            let mut body = openai_body(intro, prompt, &schema);
+            body["stream"] = serde_json::json!(true);
+            body["stream_options"] = serde_json::json!({ "include_usage": true });
+            let body = &body;
+            let resp = with_key_rotation(client, Provider::OpenAi, || async move {
+                let resp = send_with_retries(client, openai_builder(client).await?, body).await?;
+                check_status(client, resp).await
+            })
+            .await?;
+            read_sse(client, resp, |event| {
+                if let Some(delta) = event["choices"][0]["delta"]["content"].as_str() {
+                    text.push_str(delta);
+                    preview(&text);
+                }
+                if let Some(u) = event["usage"].as_object() {
+                    usage.input_tokens = u["prompt_tokens"].as_u64().unwrap_or(0);
+                    usage.output_tokens = u["completion_tokens"].as_u64().unwrap_or(0);
+                }
+                if let Some(message) = event["error"]["message"].as_str() {
+                    error = Some(message.to_string());
+                }
+            })
+            .await?;
+            OPENAI_MODEL
+        }
+        Provider::Claude => {
+            let body = &claude_stream_body(intro, prompt, &schema);
+            let resp = with_key_rotation(client, Provider::Claude, || async move {
+                let resp = send_with_retries(client, claude_builder(client).await?, body).await?;
+                check_status(client, resp).await
+            })
+            .await?;
+            read_sse(client, resp, |event| match event["type"].as_str() {
+                Some("message_start") => {
+                    usage.input_tokens = event["message"]["usage"]["input_tokens"]
+                        .as_u64()
+                        .unwrap_or(0);
+                }
+                Some("content_block_delta") => {
+                    if let Some(delta) = event["delta"]["partial_json"].as_str() {
+                        text.push_str(delta);
+                        preview(&text);
+                    }
+                }
+                Some("message_delta") => {
+                    usage.output_tokens = event["usage"]["output_tokens"].as_u64().unwrap_or(0);
+                }
+                Some("error") => {
+                    error = event["error"]["message"].as_str().map(String::from);
+                }
+                _ => {}
+            })
+            .await?;
+            CLAUDE_MODEL
+        }
+    };
+    record_spend(model, usage);
+    if let Some(message) = error {
+        return Err(Failure::Api {
+            provider: client.provider,
+            status: None,
+            message: format!("API error: {}", message),
+        });
+    }
+    parse_structured_json(client.provider, &text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(url: &str) -> bool {
+        let hosts = ["api.openai.com".to_string(), "llm.corp.example".to_string()];
+        egress_allowed(&reqwest::Url::parse(url).unwrap(), &hosts)
+    }
+
+    #[test]
+    fn egress_allows_listed_https_hosts() {
+        assert!(allowed("https://api.openai.com/v1/chat/completions"));
+        assert!(allowed("https://API.OpenAI.com/v1/models"));
+        assert!(allowed("https://llm.corp.example:8443/v1"));
+    }
+
+    #[test]
+    fn egress_refuses_other_hosts_and_plain_http() {
+        assert!(!allowed("http://api.openai.com/v1/chat/completions"));
+        assert!(!allowed("https://evil.example/v1"));
+        assert!(!allowed("https://api.openai.com.evil.example/v1"));
+        assert!(!allowed("https://openai.com/v1"));
+        assert!(!allowed("https://user@evil.example/?api.openai.com"));
+    }
+
+    #[test]
+    fn claude_stream_body_takes_the_inner_schema() {
+        let body = claude_stream_body("intro", "prompt", &response_schema());
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["tools"][0]["input_schema"]["type"], "object");
+        assert!(body["tools"][0]["input_schema"]["properties"]["result"].is_object());
+    }
+
+    #[test]
+    fn retry_delay_doubles_and_is_capped() {
+        let jitter = 1.0 + DEFAULT_RETRY_JITTER;
+        for attempt in 0..4 {
+            let base = Duration::from_millis(500 * 2u64.pow(attempt));
+            let delay = retry_delay(attempt, None).unwrap();
+            assert!(
+                delay >= base && delay <= base.mul_f64(jitter),
+                "{:?}",
+                delay
+            );
+        }
+        assert_eq!(retry_delay(30, None), Some(MAX_RETRY_DELAY));
+    }
+
+    #[test]
+    fn retry_delay_follows_retry_after() {
+        let after = Duration::from_secs(3);
+        let delay = retry_delay(0, Some(after)).unwrap();
+        assert!(delay >= after && delay <= after.mul_f64(1.0 + DEFAULT_RETRY_JITTER));
+        // Never sooner than the server asked, even with the jitter capped
+        assert!(retry_delay(0, Some(MAX_RETRY_DELAY)).unwrap() >= MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(0, Some(Duration::from_secs(120))), None);
+    }
+
+    #[test]
+    fn long_retry_after_is_reported_not_retried() {
+        let headers = |value: &str| {
+            let mut headers = reqwest::header::HeaderMap::new();
+            headers.insert(reqwest::header::RETRY_AFTER, value.parse().unwrap());
+            headers
+        };
+        let retry_after = parse_retry_after(&headers("120"));
+        assert_eq!(retry_after, Some(Duration::from_secs(120)));
+        assert_eq!(retry_delay(0, retry_after), None);
+        let at = (chrono::Utc::now() + chrono::Duration::minutes(5)).to_rfc2822();
+        assert_eq!(retry_delay(0, parse_retry_after(&headers(&at))), None);
+        match api_failure(
+            Provider::OpenAi,
+            reqwest::StatusCode::TOO_MANY_REQUESTS,
+            retry_after,
+            "Rate limit reached",
+        ) {
+            Failure::RateLimited { retry_after, .. } => {
+                assert_eq!(retry_after, Some(Duration::from_secs(120)))
+            }
+            other => panic!("expected a rate limit, got {:?}", other),
+        }
+    }
+}
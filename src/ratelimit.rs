@@ -0,0 +1,102 @@
+//! Local token-bucket rate limit on provider requests, shared by every process
+//! (CLI invocations and the daemon) through a locked file in the state dir
+
+use crate::config::{env_parse, update_state};
+use crate::error::Failure;
+use std::time::Duration;
+
+const DEFAULT_RATE_LIMIT: f64 = 60.0;
+const DEFAULT_RATE_BURST: f64 = 20.0;
+const RATELIMIT_FILE: &str = "ratelimit.json";
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Bucket {
+    tokens: f64,
+    /// Unix time in seconds (fractional) of the last refill
+    updated: f64,
+}
+
+impl Bucket {
+    /// Refill for the time since the last update at `per_minute`, up to `burst`,
+    /// then take one token, or return how long until one is available
+    fn take(&mut self, now: f64, per_minute: f64, burst: f64) -> Result<(), Duration> {
+        let elapsed = (now - self.updated).max(0.0);
+        self.tokens = (self.tokens + elapsed * per_minute / 60.0).min(burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = (1.0 - self.tokens) * 60.0 / per_minute;
+        Err(Duration::from_secs_f64(wait.ceil()))
+    }
+}
+
+/// Take one request from the bucket, which refills at SMSH_RATE_LIMIT requests per
+/// minute (default 60, 0 disables) up to SMSH_RATE_BURST (default 20). Fails
+/// instead of waiting, so a runaway loop stops rather than queueing up.
+pub(crate) fn take_request() -> Result<(), Failure> {
+    let per_minute = env_parse("SMSH_RATE_LIMIT").unwrap_or(DEFAULT_RATE_LIMIT);
+    if per_minute <= 0.0 {
+        return Ok(());
+    }
+    let burst = env_parse("SMSH_RATE_BURST")
+        .unwrap_or(DEFAULT_RATE_BURST)
+        .max(1.0);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+    // A new bucket starts empty at the epoch, so it refills to a full burst
+    let mut taken = Ok(());
+    update_state(RATELIMIT_FILE, |bucket: &mut Bucket| {
+        taken = bucket.take(now, per_minute, burst);
+        true
+    });
+    taken.map_err(|retry_in| Failure::Throttled {
+        per_minute,
+        retry_in,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_then_throttles() {
+        let mut bucket = Bucket {
+            tokens: 3.0,
+            updated: 0.0,
+        };
+        for _ in 0..3 {
+            assert_eq!(bucket.take(0.0, 60.0, 3.0), Ok(()));
+        }
+        assert_eq!(bucket.take(0.0, 60.0, 3.0), Err(Duration::from_secs(1)));
+        assert_eq!(bucket.take(0.0, 6.0, 3.0), Err(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn bucket_refills_over_time_up_to_the_burst() {
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            updated: 0.0,
+        };
+        assert!(bucket.take(0.5, 60.0, 3.0).is_err());
+        assert_eq!(bucket.take(1.0, 60.0, 3.0), Ok(()));
+        bucket.take(3600.0, 60.0, 3.0).unwrap();
+        assert_eq!(bucket.tokens, 2.0);
+        // A clock that went backwards refills nothing
+        bucket.take(0.0, 60.0, 3.0).unwrap();
+        assert_eq!(bucket.tokens, 1.0);
+    }
+
+    #[test]
+    fn a_new_bucket_starts_full() {
+        let mut bucket = Bucket::default();
+        for _ in 0..3 {
+            assert_eq!(bucket.take(1e9, 60.0, 3.0), Ok(()));
+        }
+        assert!(bucket.take(1e9, 60.0, 3.0).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+//! Recording provider answers (`SMSH_RECORD`) and serving them back with the
+//! `replay` provider, for developing the widget, tests and demos offline
+
+use crate::error::{Failure, Outcome};
+use crate::provider::Provider;
+use crate::response::{parse_structured, LlmResponse};
+use std::env;
+use std::io::Write;
+use std::path::PathBuf;
+
+fn record_path() -> Option<PathBuf> {
+    env::var_os("SMSH_RECORD")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Append a request and the provider's answer to the SMSH_RECORD file
+pub(crate) fn record_exchange(provider: Provider, intro: &str, prompt: &str, resp: &LlmResponse) {
+    let Some(path) = record_path() else {
+        return;
+    };
+    let entry = serde_json::json!({
+        "provider": provider.as_str(),
+        "model": provider.model(),
+        "intro": intro,
+        "prompt": prompt,
+        "response": resp.structured(),
+    });
+    if let Ok(mut file) = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+    {
+        let _ = writeln!(file, "{}", entry);
+    }
+}
+
+/// The recorded answer to `prompt` from the SMSH_RECORD file: the latest one
+/// recorded with the same system prompt, else the latest for the prompt alone (the
+/// system prompt names the OS, so recordings still replay on another machine)
+pub(crate) fn replay_answer(intro: &str, prompt: &str) -> Outcome {
+    let path = record_path().ok_or_else(|| {
+        Failure::InvalidInput("The replay provider reads SMSH_RECORD=<file>".to_string())
+    })?;
+    let text = std::fs::read_to_string(&path).map_err(|e| {
+        Failure::InvalidInput(format!("Cannot read recording {}: {}", path.display(), e))
+    })?;
+    let entries: Vec<serde_json::Value> = text
+        .lines()
+        .filter_map(|l| serde_json::from_str(l).ok())
+        .filter(|e: &serde_json::Value| e["prompt"] == prompt)
+        .collect();
+    let entry = entries
+        .iter()
+        .rev()
+        .find(|e| e["intro"] == intro)
+        .or(entries.last())
+        .ok_or_else(|| Failure::Api {
+            provider: Provider::Replay,
+            status: None,
+            message: format!("No recorded answer for this prompt in {}", path.display()),
+        })?;
+    parse_structured(&entry["response"])
+}
@@ -0,0 +1,258 @@
+//! Usage digests built from the history: what was asked, how much of it was run,
+//! what it cost, where the provider was slow and how requests failed
+
+use crate::history::{self, HistoryEntry};
+use crate::shell::shell_words;
+use crate::spend::Usage;
+use chrono::{Duration, Local};
+use std::collections::HashMap;
+
+/// How many rows the category and slow-day tables show
+const TOP: usize = 5;
+
+/// The period a report covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Weekly,
+}
+
+impl Period {
+    fn days(self) -> i64 {
+        match self {
+            Period::Weekly => 7,
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Period::Weekly => "Weekly",
+        }
+    }
+}
+
+/// Outcomes where a command was put on the command line (or offered to run)
+fn suggested(entry: &HistoryEntry) -> bool {
+    entry.cmd == "complete"
+        && matches!(
+            entry.outcome.as_deref(),
+            Some("suggested" | "executed" | "rejected" | "candidate" | "needs_confirmation")
+        )
+}
+
+/// The program a generated command runs, past `sudo`, `env` and variable
+/// assignments: `git`, `docker`, `find`
+fn category(entry: &HistoryEntry) -> String {
+    let command = entry.ran.as_deref().unwrap_or(&entry.result);
+    shell_words(command)
+        .into_iter()
+        .find(|w| !matches!(w.as_str(), "sudo" | "env" | "time" | "nohup") && !w.contains('='))
+        .unwrap_or_else(|| "(not logged)".to_string())
+}
+
+fn usage(entry: &HistoryEntry) -> Usage {
+    Usage {
+        input_tokens: entry.input_tokens.unwrap_or(0),
+        output_tokens: entry.output_tokens.unwrap_or(0),
+    }
+}
+
+fn percent(part: usize, whole: usize) -> String {
+    if whole == 0 {
+        "n/a".to_string()
+    } else {
+        format!("{:.0}%", part as f64 * 100.0 / whole as f64)
+    }
+}
+
+/// Failures since `since` (Unix milliseconds; all of the history if None) by
+/// kind and provider, with the most common refusal reasons, as aligned text
+pub fn errors(since: Option<i64>) -> Result<String, String> {
+    let entries = history::since(&history::open()?, since)?;
+    let failures: Vec<_> = entries
+        .iter()
+        .filter(|e| matches!(e.outcome.as_deref(), Some("error" | "refused")))
+        .collect();
+    let mut out = format!(
+        "Failures: {} of {} requests ({})\n",
+        failures.len(),
+        entries.len(),
+        percent(failures.len(), entries.len())
+    );
+    if failures.is_empty() {
+        return Ok(out);
+    }
+
+    let mut kinds: HashMap<&str, (usize, HashMap<&str, usize>)> = HashMap::new();
+    for entry in &failures {
+        let kind = entry.error_type.as_deref().unwrap_or("unknown");
+        let (count, providers) = kinds.entry(kind).or_default();
+        *count += 1;
+        *providers
+            .entry(entry.provider.as_deref().unwrap_or("-"))
+            .or_default() += 1;
+    }
+    let mut kinds: Vec<_> = kinds.into_iter().collect();
+    kinds.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then_with(|| a.0.cmp(b.0)));
+    out.push_str(&format!(
+        "\n{:<16} {:>6} {:>6}  Providers\n",
+        "Kind", "Count", "Share"
+    ));
+    for (kind, (count, providers)) in &kinds {
+        let mut providers: Vec<_> = providers.iter().collect();
+        providers.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let providers: Vec<_> = providers
+            .iter()
+            .map(|(provider, n)| format!("{} {}", provider, n))
+            .collect();
+        out.push_str(&format!(
+            "{:<16} {:>6} {:>6}  {}\n",
+            kind,
+            count,
+            percent(*count, failures.len()),
+            providers.join(", ")
+        ));
+    }
+
+    // Refusal reasons are only kept with SMSH_LOG_MODE=full
+    let mut reasons: HashMap<&str, (usize, &str)> = HashMap::new();
+    for entry in failures.iter().rev() {
+        if entry.outcome.as_deref() == Some("refused") && !entry.result.is_empty() {
+            let reason = reasons
+                .entry(entry.result.trim())
+                .or_insert((0, &entry.query));
+            reason.0 += 1;
+        }
+    }
+    let mut reasons: Vec<_> = reasons.into_iter().collect();
+    reasons.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then_with(|| a.0.cmp(b.0)));
+    if !reasons.is_empty() {
+        out.push_str("\nMost common refusals:\n");
+        for (reason, (count, query)) in reasons.iter().take(TOP) {
+            out.push_str(&format!("{:>6}  {}", count, reason));
+            if !query.is_empty() {
+                out.push_str(&format!(" (latest: \"{}\")", query));
+            }
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+/// A Markdown digest of the last period of history
+pub fn build(period: Period) -> Result<String, String> {
+    let until = Local::now();
+    let from = until - Duration::days(period.days());
+    let entries = history::since(&history::open()?, Some(from.timestamp_millis()))?;
+    Ok(render(period, &entries, from, until))
+}
+
+fn render(
+    period: Period,
+    entries: &[HistoryEntry],
+    from: chrono::DateTime<Local>,
+    until: chrono::DateTime<Local>,
+) -> String {
+    let mut out = format!(
+        "# {} smartshell report, {} to {}\n\n",
+        period.title(),
+        from.format("%Y-%m-%d"),
+        until.format("%Y-%m-%d")
+    );
+
+    let completions = entries.iter().filter(|e| e.cmd == "complete").count();
+    let explanations = entries.iter().filter(|e| e.cmd == "explain").count();
+    let failed = entries
+        .iter()
+        .filter(|e| matches!(e.outcome.as_deref(), Some("error" | "timeout")))
+        .count();
+    let suggestions: Vec<_> = entries.iter().filter(|e| suggested(e)).collect();
+    let accepted = suggestions
+        .iter()
+        .filter(|e| e.accepted_at.is_some())
+        .count();
+    let mut tokens = Usage::default();
+    let mut spent = 0.0;
+    for entry in entries {
+        let used = usage(entry);
+        tokens.input_tokens += used.input_tokens;
+        tokens.output_tokens += used.output_tokens;
+        spent += used.cost_usd(entry.model.as_deref().unwrap_or_default());
+    }
+    out.push_str(&format!(
+        "- Requests: {} ({} commands, {} explanations, {} failed)\n",
+        entries.len(),
+        completions,
+        explanations,
+        failed
+    ));
+    out.push_str(&format!(
+        "- Acceptance rate: {} ({} of {} suggested commands were run)\n",
+        percent(accepted, suggestions.len()),
+        accepted,
+        suggestions.len()
+    ));
+    out.push_str(&format!(
+        "- Tokens: {} in, {} out\n- Estimated spend: ${:.4}\n",
+        tokens.input_tokens, tokens.output_tokens, spent
+    ));
+
+    let mut categories: HashMap<String, (usize, usize)> = HashMap::new();
+    for entry in &suggestions {
+        let counts = categories.entry(category(entry)).or_default();
+        counts.0 += 1;
+        counts.1 += usize::from(entry.accepted_at.is_some());
+    }
+    let mut categories: Vec<_> = categories.into_iter().collect();
+    categories.sort_by(|a, b| b.1 .0.cmp(&a.1 .0).then_with(|| a.0.cmp(&b.0)));
+    out.push_str("\n## Top query categories\n\n");
+    if categories.is_empty() {
+        out.push_str("No commands were suggested.\n");
+    } else {
+        out.push_str("| Tool | Suggestions | Run |\n|---|---|---|\n");
+        for (tool, (count, run)) in categories.iter().take(TOP) {
+            out.push_str(&format!(
+                "| `{}` | {} | {} |\n",
+                tool.replace('|', "\\|"),
+                count,
+                percent(*run, *count)
+            ));
+        }
+    }
+
+    let mut days: HashMap<(String, String), Vec<u64>> = HashMap::new();
+    for entry in entries {
+        if let (Some(provider), Some(latency)) = (&entry.provider, entry.latency_ms) {
+            let day = chrono::DateTime::from_timestamp_millis(entry.ts)
+                .unwrap_or_default()
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %a")
+                .to_string();
+            days.entry((day, provider.clone()))
+                .or_default()
+                .push(latency);
+        }
+    }
+    let mut days: Vec<_> = days
+        .into_iter()
+        .map(|(key, mut latencies)| {
+            latencies.sort_unstable();
+            let average = latencies.iter().sum::<u64>() / latencies.len() as u64;
+            let slowest = *latencies.last().unwrap_or(&0);
+            (key, latencies.len(), average, slowest)
+        })
+        .collect();
+    days.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    out.push_str("\n## Slowest provider days\n\n");
+    if days.is_empty() {
+        out.push_str("No provider requests.\n");
+    } else {
+        out.push_str("| Day | Provider | Requests | Average | Slowest |\n|---|---|---|---|---|\n");
+        for ((day, provider), count, average, slowest) in days.iter().take(TOP) {
+            out.push_str(&format!(
+                "| {} | {} | {} | {}ms | {}ms |\n",
+                day, provider, count, average, slowest
+            ));
+        }
+    }
+    out
+}
@@ -0,0 +1,373 @@
+//! The structured answer every provider call produces
+
+use crate::error::{Failure, Outcome};
+use crate::provider::Provider;
+use crate::style::{for_stderr, warning, Style};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Risk {
+    None,
+    Low,
+    Destructive,
+}
+
+impl Risk {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Risk::None => "none",
+            Risk::Low => "low",
+            Risk::Destructive => "destructive",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Self {
+        match s {
+            "destructive" => Risk::Destructive,
+            "low" => Risk::Low,
+            _ => Risk::None,
+        }
+    }
+}
+
+/// One command of a multi-step plan, with what it is for
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Step {
+    pub command: String,
+    pub note: String,
+}
+
+/// One command, flag or argument of an explained command line and what it does
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct TokenMeaning {
+    pub token: String,
+    pub meaning: String,
+}
+
+impl TokenMeaning {
+    /// The first and last line an annotation covers, when its token is a line
+    /// number or range (`12`, `12-18`, `lines 12-18`)
+    pub fn lines(&self) -> Option<(usize, usize)> {
+        let token = self.token.trim();
+        let token = token
+            .strip_prefix("lines")
+            .or_else(|| token.strip_prefix("line"))
+            .or_else(|| token.strip_prefix('L'))
+            .unwrap_or(token)
+            .trim();
+        let (first, last) = token.split_once(['-', '–']).unwrap_or((token, token));
+        let first: usize = first.trim().parse().ok()?;
+        let last: usize = last.trim().parse().ok()?;
+        (first >= 1 && last >= first).then_some((first, last))
+    }
+}
+
+/// A generated command or explanation, with what the model and the local
+/// checks found out about it
+#[derive(Debug, Clone)]
+pub struct LlmResponse {
+    pub result: String,
+    pub risk: Risk,
+    pub needs_sudo: bool,
+    pub affects: Vec<String>,
+    /// For explanations, the command line taken apart token by token
+    pub breakdown: Vec<TokenMeaning>,
+    /// A question the model needs answered before it can write the command
+    pub question: Option<String>,
+    /// For generated commands, a one-line summary of what the command does
+    pub explanation: Option<String>,
+    /// For generated commands, the model's own estimate (0 to 1) that the command
+    /// does what was asked on the target system
+    pub confidence: Option<f64>,
+    /// For a plan, the commands to run one after another with a note each;
+    /// `result` then holds the commands, one per line
+    pub steps: Vec<Step>,
+    /// Reasons the result needs explicit confirmation (policy flags, suspected injection)
+    pub flagged: Vec<String>,
+    /// Served from the response cache rather than the provider
+    pub cached: bool,
+    /// The provider whose answer (or cached answer) this is; differs from the
+    /// client's provider after a failover
+    pub provider: Option<Provider>,
+}
+
+/// Below this, a command is shown as low confidence
+pub const LOW_CONFIDENCE: f64 = 0.5;
+
+/// How the model should calibrate `confidence`
+const CONFIDENCE_DESCRIPTION: &str =
+    "From 0 to 1, how likely the answer does exactly what was asked on the target system. \
+    0.9 or more only for standard commands whose flags you are sure of; 0.6 to 0.9 when a flag, \
+    tool version or the tool being installed is uncertain; below 0.6 when guessing at the intent, \
+    the files involved or the syntax. 1 for explanations and refusals";
+
+/// Which structured answer a provider is asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Schema {
+    /// A command or explanation, see `response_schema`
+    #[default]
+    Response,
+    /// Numbered steps, see `plan_schema`
+    Plan,
+}
+
+impl Schema {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Schema::Response => "response",
+            Schema::Plan => "plan",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Self {
+        match s {
+            "plan" => Schema::Plan,
+            _ => Schema::Response,
+        }
+    }
+
+    /// The schema in OpenAI's `json_schema` shape (Claude takes its `schema`)
+    pub(crate) fn json(self) -> serde_json::Value {
+        match self {
+            Schema::Response => response_schema(),
+            Schema::Plan => plan_schema(),
+        }
+    }
+}
+
+fn steps_schema(description: &str) -> serde_json::Value {
+    serde_json::json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "properties": {
+                "command": { "type": "string" },
+                "note": { "type": "string", "description": "What the step does and what to check before the next one, in one short line" }
+            },
+            "required": ["command", "note"],
+            "additionalProperties": false
+        },
+        "description": description
+    })
+}
+
+/// The alternate schema of `smartshell plan`, where `result` is the list of steps
+pub(crate) fn plan_schema() -> serde_json::Value {
+    serde_json::json!({
+        "name": "plan",
+        "strict": true,
+        "schema": {
+            "type": "object",
+            "properties": {
+                "result": steps_schema("The commands to run in order, one per step"),
+                "error": { "type": "boolean", "description": "Set to true if the request is unclear, impossible, or not a valid shell task" },
+                "reason": { "type": "string", "description": "Only when error is true: why. Empty otherwise" },
+                "risk": {
+                    "type": "string",
+                    "enum": ["none", "low", "destructive"],
+                    "description": "The highest risk of any step: destructive if it deletes, overwrites or irreversibly changes data; low if it modifies state recoverably; none if read-only"
+                },
+                "needs_sudo": { "type": "boolean", "description": "Set to true if any step must run as root" },
+                "confidence": { "type": "number", "description": CONFIDENCE_DESCRIPTION },
+                "affects": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Files, directories, devices, branches or services the steps modify"
+                }
+            },
+            "required": ["result", "error", "reason", "risk", "needs_sudo", "confidence", "affects"],
+            "additionalProperties": false
+        }
+    })
+}
+
+pub(crate) fn response_schema() -> serde_json::Value {
+    serde_json::json!({
+        "name": "response",
+        "strict": true,
+        "schema": {
+            "type": "object",
+            "properties": {
+                "result": { "type": "string", "description": "The command or explanation" },
+                "error": { "type": "boolean", "description": "Set to true if the request is unclear, impossible, or not a valid shell task" },
+                "risk": {
+                    "type": "string",
+                    "enum": ["none", "low", "destructive"],
+                    "description": "destructive if the command deletes, overwrites or irreversibly changes data; low if it modifies state recoverably; none if read-only"
+                },
+                "needs_sudo": { "type": "boolean", "description": "Set to true if the command must run as root" },
+                "affects": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Files, directories, devices, branches or services the command modifies"
+                },
+                "breakdown": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "token": { "type": "string" },
+                            "meaning": { "type": "string" }
+                        },
+                        "required": ["token", "meaning"],
+                        "additionalProperties": false
+                    },
+                    "description": "Only when explaining and asked for a breakdown: each command, flag and argument in order with its meaning. Empty otherwise"
+                },
+                "question": {
+                    "type": "string",
+                    "description": "Only when generating a command and the request is ambiguous in a way that changes it: one short question for the user. Empty otherwise"
+                },
+                "explanation": {
+                    "type": "string",
+                    "description": "Only when generating a command: what it does, in one short line (under 80 characters). Empty otherwise"
+                },
+                "steps": steps_schema("Only when generating a command and no single command can do the job safely: the commands to run one after another, with result left empty. Empty otherwise"),
+                "confidence": { "type": "number", "description": CONFIDENCE_DESCRIPTION }
+            },
+            "required": ["result", "error", "risk", "needs_sudo", "affects", "breakdown", "question", "explanation", "steps", "confidence"],
+            "additionalProperties": false
+        }
+    })
+}
+
+impl LlmResponse {
+    /// The response in the shape of `response_schema`, for relaying over the daemon socket
+    pub(crate) fn structured(&self) -> serde_json::Value {
+        serde_json::json!({
+            "result": self.result,
+            "error": false,
+            "risk": self.risk.as_str(),
+            "needs_sudo": self.needs_sudo,
+            "affects": self.affects,
+            "breakdown": self.breakdown,
+            "question": self.question.as_deref().unwrap_or_default(),
+            "explanation": self.explanation.as_deref().unwrap_or_default(),
+            "steps": self.steps,
+            "confidence": self.confidence,
+        })
+    }
+
+    /// Whether the result must be confirmed explicitly before it runs
+    pub fn needs_confirmation(&self) -> bool {
+        self.risk == Risk::Destructive || !self.flagged.is_empty()
+    }
+
+    /// Warnings to show on stderr, colored when stderr gets colors
+    pub fn warning_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if self.needs_sudo {
+            lines.push((Style::Yellow, "[sudo] requires root privileges".to_string()));
+        }
+        if self.risk == Risk::Destructive {
+            let mut line = "destructive command".to_string();
+            if !self.affects.is_empty() {
+                line.push_str(&format!(" (affects: {})", self.affects.join(", ")));
+            }
+            lines.push((Style::Red, warning(&line)));
+        }
+        for reason in &self.flagged {
+            lines.push((Style::Red, warning(reason)));
+        }
+        lines
+            .into_iter()
+            .map(|(style, l)| for_stderr(style, &l))
+            .collect()
+    }
+}
+
+/// Decode an answer in either schema: a plan's steps come from `result` (plan
+/// schema) or `steps` (response schema), and their commands become `result`
+pub(crate) fn parse_structured(value: &serde_json::Value) -> Outcome {
+    if value["error"].as_bool().unwrap_or(false) {
+        let reason = value["reason"]
+            .as_str()
+            .filter(|r| !r.is_empty())
+            .or_else(|| value["result"].as_str());
+        return Err(Failure::refused(reason.unwrap_or_default()));
+    }
+    let steps: Vec<Step> = value["result"]
+        .as_array()
+        .or_else(|| value["steps"].as_array())
+        .map(|a| {
+            a.iter()
+                .filter_map(|v| {
+                    Some(Step {
+                        command: v["command"].as_str()?.trim().to_string(),
+                        note: v["note"].as_str().unwrap_or_default().trim().to_string(),
+                    })
+                })
+                .filter(|s| !s.command.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    let result = if steps.is_empty() {
+        value["result"].as_str().unwrap_or("").to_string()
+    } else {
+        steps
+            .iter()
+            .map(|s| s.command.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    Ok(LlmResponse {
+        result,
+        steps,
+        risk: Risk::parse(value["risk"].as_str().unwrap_or("none")),
+        needs_sudo: value["needs_sudo"].as_bool().unwrap_or(false),
+        affects: value["affects"]
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|v| v.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        breakdown: value["breakdown"]
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|v| {
+                        Some(TokenMeaning {
+                            token: v["token"].as_str()?.to_string(),
+                            meaning: v["meaning"].as_str()?.to_string(),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        question: value["question"]
+            .as_str()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(String::from),
+        explanation: value["explanation"]
+            .as_str()
+            .map(|e| e.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|e| !e.is_empty()),
+        confidence: value["confidence"].as_f64().map(|c| c.clamp(0.0, 1.0)),
+        flagged: Vec::new(),
+        cached: false,
+        provider: None,
+    })
+}
+
+#[cfg(test)]
+impl LlmResponse {
+    /// A plain response with `result` and nothing flagged
+    pub(crate) fn from_result(result: &str) -> Self {
+        LlmResponse {
+            result: result.to_string(),
+            risk: Risk::None,
+            needs_sudo: false,
+            affects: Vec::new(),
+            breakdown: Vec::new(),
+            question: None,
+            explanation: None,
+            confidence: None,
+            steps: Vec::new(),
+            flagged: Vec::new(),
+            cached: false,
+            provider: None,
+        }
+    }
+}
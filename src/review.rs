@@ -0,0 +1,113 @@
+//! An optional second pass in which the model reviews a generated command against
+//! the request before it is shown, and the command is regenerated once if the
+//! review finds a problem
+
+use crate::client::SmartshellClient;
+use crate::config::{get_os_context, log_entry, trace};
+use crate::error::{Failure, Outcome};
+use crate::injection::{untrusted_block, UNTRUSTED_NOTICE};
+use crate::response::{LlmResponse, Risk};
+use crate::sanitize::sanitize_response;
+use crate::telemetry;
+use std::env;
+use std::sync::OnceLock;
+
+/// Which answers SMSH_REVIEW sends for review, by the risk the model gave them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReviewLevel {
+    Off,
+    Destructive,
+    Low,
+    All,
+}
+
+/// The SMSH_REVIEW setting: `off` (the default), `destructive`, `low` (low and
+/// destructive) or `all`. A value that isn't understood reviews everything
+/// rather than less than was asked for.
+fn review_level() -> ReviewLevel {
+    static LEVEL: OnceLock<ReviewLevel> = OnceLock::new();
+    *LEVEL.get_or_init(|| {
+        let Ok(level) = env::var("SMSH_REVIEW") else {
+            return ReviewLevel::Off;
+        };
+        match level.trim().to_ascii_lowercase().as_str() {
+            "" | "0" | "off" => ReviewLevel::Off,
+            "destructive" => ReviewLevel::Destructive,
+            "low" => ReviewLevel::Low,
+            "1" | "all" => ReviewLevel::All,
+            other => {
+                eprintln!(
+                    "{}",
+                    crate::style::warning(&format!(
+                        "SMSH_REVIEW={} is not off, destructive, low or all; reviewing every command",
+                        other
+                    ))
+                );
+                ReviewLevel::All
+            }
+        }
+    })
+}
+
+fn needs_review(risk: Risk) -> bool {
+    match review_level() {
+        ReviewLevel::Off => false,
+        ReviewLevel::Destructive => risk == Risk::Destructive,
+        ReviewLevel::Low => risk != Risk::None,
+        ReviewLevel::All => true,
+    }
+}
+
+/// Have the model check `resp` against the request in `prompt`. An approved
+/// command is returned as it is; otherwise the model gets one chance to correct
+/// it with the review's objection in hand.
+pub(crate) fn review(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    resp: LlmResponse,
+) -> Outcome {
+    if !needs_review(resp.risk) {
+        return Ok(resp);
+    }
+    let mut span = telemetry::span("review");
+    let review_intro = format!(
+        "Review a {} command generated for a user's request. Check that it does what \
+        was asked, no more and no less, works on the target system and has no dangerous \
+        side effects the request does not call for (deleting or overwriting data, \
+        touching other files, needing root). If it is correct, return it \
+        unchanged in result with its risk. If not, set error=true and put the problem in \
+        result in one sentence. {} {}",
+        client.target_shell().name(),
+        UNTRUSTED_NOTICE,
+        get_os_context()
+    );
+    let review_prompt = format!(
+        "The request:\n{}\n\nThe generated command:\n{}",
+        untrusted_block("request", prompt),
+        untrusted_block("command", &resp.result)
+    );
+    match client.llm_api_call(&review_intro, &review_prompt) {
+        Ok(_) => {
+            trace(1, format_args!("review approved the command"));
+            span.set("smartshell.review", "approved");
+            Ok(resp)
+        }
+        Err(Failure::Refusal { reason }) => {
+            trace(1, format_args!("review objected: {}", reason));
+            span.set("smartshell.review", "regenerated");
+            log_entry("review", &resp.result, &reason);
+            let retry = format!(
+                "{}\n\nA review of your previous answer `{}` found this problem: {}\nReturn a corrected command.",
+                prompt, resp.result, reason
+            );
+            client
+                .llm_api_call(intro, &retry)
+                .and_then(sanitize_response)
+        }
+        Err(failure) => {
+            span.fail(&failure);
+            Err(failure)
+        }
+    }
+}
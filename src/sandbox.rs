@@ -0,0 +1,257 @@
+//! Dry runs on a scratch copy of the working directory
+
+use crate::shell::scrub_secrets;
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const SANDBOX_MAX_FILES: usize = 20_000;
+const SANDBOX_MAX_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Size and modification time of each file, and None for each directory, by
+/// path relative to the scratch copy
+type Snapshot = BTreeMap<PathBuf, Option<(u64, std::time::SystemTime)>>;
+
+/// How many more files and bytes the scratch copy may take
+struct Budget {
+    files: usize,
+    bytes: u64,
+}
+
+/// Copy `src` into `dst`, leaving out `scratch` itself: when the cwd is the temp
+/// dir or above it, the scratch copy would otherwise be copied into itself
+fn copy_tree(src: &Path, dst: &Path, scratch: &Path, budget: &mut Budget) -> io::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        if entry.path() == scratch {
+            continue;
+        }
+        let kind = entry.file_type()?;
+        let target = dst.join(entry.file_name());
+        if kind.is_dir() {
+            copy_tree(&entry.path(), &target, scratch, budget)?;
+        } else if kind.is_file() {
+            budget.files = budget.files.checked_sub(1).ok_or_else(|| {
+                io::Error::other(format!(
+                    "directory has more than {} files",
+                    SANDBOX_MAX_FILES
+                ))
+            })?;
+            budget.bytes = budget
+                .bytes
+                .checked_sub(entry.metadata()?.len())
+                .ok_or_else(|| {
+                    io::Error::other(format!(
+                        "directory holds more than {} MB",
+                        SANDBOX_MAX_BYTES / (1024 * 1024)
+                    ))
+                })?;
+            std::fs::copy(entry.path(), target)?;
+        } else if kind.is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, target)?;
+        }
+    }
+    Ok(())
+}
+
+fn snapshot(root: &Path, dir: &Path, out: &mut Snapshot) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(meta) = entry.metadata() else { continue };
+        let Ok(rel) = path.strip_prefix(root) else {
+            continue;
+        };
+        if meta.is_dir() {
+            out.insert(rel.to_path_buf(), None);
+            snapshot(root, &path, out);
+        } else {
+            let mtime = meta.modified().unwrap_or(std::time::UNIX_EPOCH);
+            out.insert(rel.to_path_buf(), Some((meta.len(), mtime)));
+        }
+    }
+}
+
+fn sandbox_command(cmd: &str, scratch: &Path) -> Result<Command, String> {
+    let shell = if which::which("zsh").is_ok() {
+        "zsh"
+    } else {
+        "sh"
+    };
+    if cfg!(target_os = "linux") && which::which("bwrap").is_ok() {
+        let mut c = Command::new("bwrap");
+        c.args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"])
+            .args(["--tmpfs", "/tmp", "--tmpfs", "/run"]);
+        // Sockets for the session bus, the keyring and the like live here
+        if let Some(runtime) = env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) {
+            c.arg("--tmpfs").arg(runtime);
+        }
+        c.arg("--bind")
+            .arg(scratch)
+            .arg(scratch)
+            .args([
+                "--unshare-all",
+                "--new-session",
+                "--die-with-parent",
+                "--chdir",
+            ])
+            .arg(scratch)
+            .args([shell, "-c", cmd]);
+        scrub_secrets(&mut c);
+        Ok(c)
+    } else if cfg!(target_os = "macos") && which::which("sandbox-exec").is_ok() {
+        let profile = format!(
+            "(version 1)(allow default)(deny network*)(deny mach-lookup)(deny ipc*)\
+             (deny file-write*)(allow file-write* (subpath \"{}\") \
+             (literal \"/dev/null\") (literal \"/dev/tty\"))",
+            scratch.display()
+        );
+        let mut c = Command::new("sandbox-exec");
+        c.args(["-p", &profile, shell, "-c", cmd])
+            .current_dir(scratch);
+        scrub_secrets(&mut c);
+        Ok(c)
+    } else {
+        Err("No sandbox available (requires bwrap on Linux or sandbox-exec on macOS)".to_string())
+    }
+}
+
+/// A new directory for the scratch copy, with a random name so no one can
+/// prepare it in advance, created only for the user and failing if it exists
+fn create_scratch() -> io::Result<PathBuf> {
+    use std::hash::{BuildHasher, Hasher};
+    loop {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        let dir = env::temp_dir().join(format!("smartshell-sandbox-{:016x}", hasher.finish()));
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        match builder.create(&dir) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            created => return created.map(|()| dir),
+        }
+    }
+}
+
+/// Run the command against a scratch copy of the cwd with no network access and
+/// describe the files and directories (with a trailing `/`) it created (+),
+/// modified (~) or deleted (-)
+pub fn sandbox_dry_run(cmd: &str) -> Result<Vec<String>, String> {
+    let cwd = env::current_dir().map_err(|e| format!("Sandbox failed: {}", e))?;
+    let scratch = create_scratch().map_err(|e| format!("Sandbox failed: {}", e))?;
+    let run = || -> Result<Vec<String>, String> {
+        let mut budget = Budget {
+            files: SANDBOX_MAX_FILES,
+            bytes: SANDBOX_MAX_BYTES,
+        };
+        copy_tree(&cwd, &scratch, &scratch, &mut budget)
+            .map_err(|e| format!("Sandbox failed to copy {}: {}", cwd.display(), e))?;
+        let mut before = Snapshot::new();
+        snapshot(&scratch, &scratch, &mut before);
+        let out = sandbox_command(cmd, &scratch)?
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Sandbox failed: {}", e))?;
+        let mut after = Snapshot::new();
+        snapshot(&scratch, &scratch, &mut after);
+
+        let mut report = vec![format!("exit status {}", out.status.code().unwrap_or(-1))];
+        report.extend(
+            String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .chain(String::from_utf8_lossy(&out.stderr).lines())
+                .map(|l| format!("| {}", l)),
+        );
+        let shown = |path: &Path, meta: &Option<_>| match meta {
+            Some(_) => path.display().to_string(),
+            None => format!("{}/", path.display()),
+        };
+        for (path, meta) in &after {
+            match before.get(path) {
+                None => report.push(format!("+ {}", shown(path, meta))),
+                Some(old) if old != meta => report.push(format!("~ {}", shown(path, meta))),
+                _ => {}
+            }
+        }
+        for (path, meta) in before.iter().filter(|(p, _)| !after.contains_key(*p)) {
+            report.push(format!("- {}", shown(path, meta)));
+        }
+        Ok(report)
+    };
+    let report = run();
+    let _ = std::fs::remove_dir_all(&scratch);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::scratch_dir;
+
+    #[test]
+    fn copy_leaves_out_a_scratch_dir_inside_the_source() {
+        let src = scratch_dir("nested");
+        std::fs::write(src.join("a.txt"), "a").unwrap();
+        let scratch = src.join("scratch");
+        let mut budget = Budget {
+            files: 10,
+            bytes: 1024,
+        };
+        copy_tree(&src, &scratch, &scratch, &mut budget).unwrap();
+        assert!(scratch.join("a.txt").is_file());
+        assert!(!scratch.join("scratch").exists());
+        let _ = std::fs::remove_dir_all(&src);
+    }
+
+    #[test]
+    fn copy_stops_at_the_byte_budget() {
+        let src = scratch_dir("bytes");
+        std::fs::write(src.join("big"), vec![0u8; 2048]).unwrap();
+        let dst = scratch_dir("bytes-copy");
+        let mut budget = Budget {
+            files: 10,
+            bytes: 1024,
+        };
+        assert!(copy_tree(&src, &dst, &dst, &mut budget).is_err());
+        let _ = std::fs::remove_dir_all(&src);
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[test]
+    fn snapshot_lists_directories() {
+        let root = scratch_dir("snapshot");
+        std::fs::create_dir_all(root.join("foo/bar")).unwrap();
+        std::fs::write(root.join("foo/file"), "x").unwrap();
+        let mut out = Snapshot::new();
+        snapshot(&root, &root, &mut out);
+        assert_eq!(out.get(Path::new("foo")), Some(&None));
+        assert_eq!(out.get(Path::new("foo/bar")), Some(&None));
+        assert!(matches!(out.get(Path::new("foo/file")), Some(Some((1, _)))));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn scratch_dirs_are_new_and_private() {
+        let first = create_scratch().unwrap();
+        let second = create_scratch().unwrap();
+        assert_ne!(first, second);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&first).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        let _ = std::fs::remove_dir(&first);
+        let _ = std::fs::remove_dir(&second);
+    }
+}
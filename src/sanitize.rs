@@ -0,0 +1,212 @@
+//! Cleaning up generated commands that arrive dressed as chat: Markdown code
+//! fences, inline backticks, a `Here's the command:` lead-in, `$ ` prompts and a
+//! sentence's trailing period, none of which belong on the command line
+
+use crate::config::trace;
+use crate::error::{Failure, Outcome};
+use crate::response::LlmResponse;
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// A lead-in sentence ending in a colon: `Here's the command:`, `Sure! You can
+/// use:`, `The zsh command is:`. A bare `command` only counts as prose right
+/// before the colon, since `command -v git` is the builtin
+fn lead_in() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(
+            r"(?i)^(?:(?:sure|ok(?:ay)?|certainly|of course)\b[!,.]?\s*)?(?:(?:here(?:'s| is| are)|(?:the|this|your) (?:zsh |shell |powershell )?command|you can (?:use|run)|try(?: this| running)?|use(?: this)?|run(?: this)?)\b[^:`\n]{0,60}:|command(?: is)?:)\s*",
+        )
+        .expect("valid lead-in pattern")
+    })
+}
+
+/// The body of the first fenced code block in `text`, without its language tag,
+/// or None if there is no fence
+pub(crate) fn fenced(text: &str) -> Option<&str> {
+    let start = text.find("```")?;
+    let after = &text[start + 3..];
+    // The info string (`bash`, `zsh`, `shell`...) runs to the end of the line
+    let body = match after.find('\n') {
+        Some(newline) if !after[..newline].contains("```") => &after[newline + 1..],
+        _ => after,
+    };
+    Some(match body.find("```") {
+        Some(end) => &body[..end],
+        None => body,
+    })
+}
+
+/// `text` without the Markdown and prose a model wrapped around the command
+pub(crate) fn sanitize_command(text: &str) -> String {
+    let mut command = text.trim();
+    if let Some(body) = fenced(command) {
+        command = body.trim();
+    }
+    if let Some(m) = lead_in().find(command) {
+        command = command[m.end()..].trim_start();
+        if let Some(body) = fenced(command) {
+            command = body.trim();
+        }
+    }
+    // A command wrapped whole in backticks, as inline code
+    if let Some(inner) = command
+        .strip_prefix('`')
+        .and_then(|c| c.strip_suffix('`'))
+        .filter(|inner| !inner.is_empty() && !inner.contains('`'))
+    {
+        command = inner.trim();
+    }
+    let mut lines: Vec<&str> = command
+        .lines()
+        .map(|line| line.strip_prefix("$ ").unwrap_or(line))
+        .collect();
+    // A sentence's full stop, but not `.` or `..` as a path argument
+    if let Some(last) = lines.last_mut() {
+        let trimmed = last.trim_end();
+        if let Some(rest) = trimmed.strip_suffix('.') {
+            if rest.ends_with(|c: char| c.is_alphanumeric() || matches!(c, '"' | '\'' | ')')) {
+                *last = rest;
+            }
+        }
+    }
+    lines.join("\n").trim().to_string()
+}
+
+/// Clean up `resp`'s command, or each step's command in a plan. A command that
+/// turns out to be a shell comment is the model declining without setting
+/// error; other answers (explanations, aliases, build targets) may well start
+/// with a comment or a markdown heading, so only commands are checked.
+pub(crate) fn sanitize_response(mut resp: LlmResponse) -> Outcome {
+    let original = resp.result.clone();
+    if resp.steps.is_empty() {
+        resp.result = sanitize_command(&resp.result);
+    } else {
+        for step in &mut resp.steps {
+            step.command = sanitize_command(&step.command);
+        }
+        resp.steps.retain(|s| !s.command.is_empty());
+        resp.result = resp
+            .steps
+            .iter()
+            .map(|s| s.command.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+    if resp.result != original {
+        trace(1, format_args!("stripped formatting around the command"));
+    }
+    if let Some(reason) = resp.result.strip_prefix('#') {
+        return Err(Failure::refused(reason.trim()));
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::Step;
+
+    fn response(result: &str, steps: Vec<Step>) -> LlmResponse {
+        LlmResponse {
+            steps,
+            ..LlmResponse::from_result(result)
+        }
+    }
+
+    #[test]
+    fn strips_code_fences() {
+        assert_eq!(sanitize_command("```bash\nls -la\n```"), "ls -la");
+        assert_eq!(sanitize_command("```\nls -la\n```"), "ls -la");
+        assert_eq!(sanitize_command("```zsh\nls -la"), "ls -la");
+        assert_eq!(sanitize_command("```ls -la```"), "ls -la");
+    }
+
+    #[test]
+    fn keeps_multiline_commands_in_fences() {
+        let text = "```sh\nfor f in *.txt; do\n  wc -l \"$f\"\ndone\n```";
+        assert_eq!(
+            sanitize_command(text),
+            "for f in *.txt; do\n  wc -l \"$f\"\ndone"
+        );
+    }
+
+    #[test]
+    fn strips_lead_in_prose() {
+        assert_eq!(sanitize_command("Here's the command: ls -la"), "ls -la");
+        assert_eq!(
+            sanitize_command("Here is the zsh command you need:\nls -la"),
+            "ls -la"
+        );
+        assert_eq!(sanitize_command("Sure! You can use: du -sh *"), "du -sh *");
+        assert_eq!(
+            sanitize_command(
+                "Here's the command:\n```bash\nfind . -name '*.log'\n```\nThis finds log files."
+            ),
+            "find . -name '*.log'"
+        );
+        assert_eq!(sanitize_command("Command: git status"), "git status");
+    }
+
+    #[test]
+    fn strips_inline_backticks_and_prompts() {
+        assert_eq!(sanitize_command("`git log --oneline`"), "git log --oneline");
+        assert_eq!(sanitize_command("$ docker ps -a"), "docker ps -a");
+        assert_eq!(sanitize_command("echo `date`"), "echo `date`");
+    }
+
+    #[test]
+    fn strips_trailing_periods() {
+        assert_eq!(sanitize_command("ls -la."), "ls -la");
+        assert_eq!(
+            sanitize_command("grep -r 'TODO' src."),
+            "grep -r 'TODO' src"
+        );
+        assert_eq!(sanitize_command("echo \"done\"."), "echo \"done\"");
+    }
+
+    #[test]
+    fn leaves_clean_commands_alone() {
+        for command in [
+            "find . -type f",
+            "cd ..",
+            "ls ./",
+            "git add .",
+            "awk -F: '{print $1}' /etc/passwd",
+            "echo 'Note: run this first' && make",
+            "curl -s https://example.com/api | jq .",
+            "ssh host 'cat file.txt'",
+            "command -v git >/dev/null && git clone https://github.com/a/b",
+            "command -v jq || echo 'install: brew install jq'",
+        ] {
+            assert_eq!(sanitize_command(command), command);
+        }
+    }
+
+    #[test]
+    fn fenced_comment_is_a_refusal() {
+        let resp = response("```\n# I can't help with that\n```", Vec::new());
+        assert!(matches!(
+            sanitize_response(resp),
+            Err(Failure::Refusal { .. })
+        ));
+    }
+
+    #[test]
+    fn cleans_each_step_of_a_plan() {
+        let steps = vec![
+            Step {
+                command: "`git fetch`".to_string(),
+                note: "get updates".to_string(),
+            },
+            Step {
+                command: "$ git rebase origin/main.".to_string(),
+                note: "check for conflicts".to_string(),
+            },
+        ];
+        let resp = sanitize_response(response("", steps)).unwrap();
+        assert_eq!(resp.steps[0].command, "git fetch");
+        assert_eq!(resp.steps[1].command, "git rebase origin/main");
+        assert_eq!(resp.result, "git fetch\ngit rebase origin/main");
+    }
+}
@@ -0,0 +1,698 @@
+//! Server mode: `/complete` and `/explain` as JSON over HTTP on localhost, so
+//! editor plugins and launchers reuse the configured providers and prompts
+
+use crate::audit::{self, sha256_hex};
+use crate::client::{
+    runtime, CompletionRequest, ContextSource, CtrlCGuard, Detail, SmartshellClient,
+};
+use crate::config::{
+    log_record, private_file, request_id, state_dir, trace, with_new_request_id, LogRecord,
+};
+use crate::error::{Failure, Outcome};
+use crate::history;
+use crate::shell::TargetShell;
+use crate::spend::{measure_request, Usage};
+use crate::versions::parse_tool_version;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Where `serve` listens unless told otherwise
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:7878";
+
+/// Largest request body accepted, well above any command or script worth sending
+const MAX_BODY: usize = 1 << 20;
+
+/// Most header lines read before giving up on a request
+const MAX_HEADERS: usize = 100;
+
+/// Longest request line or header line, in bytes
+const MAX_LINE: usize = 8 << 10;
+
+/// How long a client gets to send its request, in total
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most connections handled at once; more are turned away with a 503
+const MAX_CONNECTIONS: usize = 32;
+
+fn token_path() -> Option<PathBuf> {
+    state_dir().map(|d| d.join("serve-token"))
+}
+
+/// 256 bits from /dev/urandom where there is one, mixed with the OS-seeded
+/// hasher keys of `RandomState`
+fn random_token() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let mut seed = Vec::new();
+    if let Ok(mut urandom) = std::fs::File::open("/dev/urandom") {
+        let mut bytes = [0u8; 32];
+        if urandom.read_exact(&mut bytes).is_ok() {
+            seed.extend_from_slice(&bytes);
+        }
+    }
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    for i in 0..4u8 {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_u8(i);
+        seed.extend_from_slice(&hasher.finish().to_le_bytes());
+    }
+    sha256_hex(&seed)
+}
+
+/// SMSH_SERVE_TOKEN, or the token kept in the state directory, created readable
+/// only by the user on first use so plugins can be set up once. Returns the
+/// token and where it came from, for the startup message.
+fn server_token() -> Result<(String, String), String> {
+    if let Some(token) = env::var("SMSH_SERVE_TOKEN")
+        .ok()
+        .filter(|t| !t.trim().is_empty())
+    {
+        return Ok((token.trim().to_string(), "$SMSH_SERVE_TOKEN".to_string()));
+    }
+    let path = token_path().ok_or("Cannot determine the state directory; set SMSH_SERVE_TOKEN")?;
+    if let Ok(token) = std::fs::read_to_string(&path) {
+        if !token.trim().is_empty() {
+            return Ok((token.trim().to_string(), path.display().to_string()));
+        }
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+    let token = random_token();
+    let created = private_file(OpenOptions::new().write(true).create_new(true))
+        .open(&path)
+        .and_then(|mut file| writeln!(file, "{}", token));
+    match created {
+        Ok(()) => Ok((token, path.display().to_string())),
+        // Another server started at the same time and wrote its own
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            match std::fs::read_to_string(&path) {
+                Ok(token) if !token.trim().is_empty() => {
+                    Ok((token.trim().to_string(), path.display().to_string()))
+                }
+                _ => Err(format!(
+                    "{}: the token file is empty or unreadable; delete it to make a new one",
+                    path.display()
+                )),
+            }
+        }
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// Compare without stopping at the first difference, so response times don't
+/// give the token away
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Whether the Host header names this machine. Anything else is a page on
+/// another site that got the browser to resolve its name to 127.0.0.1.
+fn local_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A status and JSON body to answer with
+type Reply = (u16, serde_json::Value);
+
+fn error_reply(status: u16, kind: &str, message: &str) -> Reply {
+    (
+        status,
+        serde_json::json!({
+            "error": { "type": kind, "message": message, "request_id": request_id() }
+        }),
+    )
+}
+
+/// Read one line of the request head into `line`, stopping once it is longer
+/// than MAX_LINE; returns whether the line fit
+fn read_head_line(reader: &mut impl BufRead, line: &mut String) -> std::io::Result<bool> {
+    line.clear();
+    reader.take(MAX_LINE as u64 + 1).read_line(line)?;
+    Ok(line.len() <= MAX_LINE)
+}
+
+/// Read one HTTP/1.1 request with a `Content-Length` body
+fn read_request(stream: impl Read) -> Result<HttpRequest, Reply> {
+    let bad = |message: &str| error_reply(400, "invalid_input", message);
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if !read_head_line(&mut reader, &mut line).map_err(|_| bad("could not read the request"))? {
+        return Err(bad("request line is longer than 8 KiB"));
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(bad("malformed request line"));
+    };
+    let method = method.to_string();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let mut headers = Vec::new();
+    loop {
+        if !read_head_line(&mut reader, &mut line).map_err(|_| bad("could not read the headers"))? {
+            return Err(error_reply(
+                431,
+                "invalid_input",
+                "header line is longer than 8 KiB",
+            ));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(bad("too many headers"));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let mut request = HttpRequest {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    let length = match request.header("content-length") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| bad("invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err(error_reply(
+            413,
+            "invalid_input",
+            "request body is larger than 1 MiB",
+        ));
+    }
+    request.body = vec![0; length];
+    reader
+        .read_exact(&mut request.body)
+        .map_err(|_| bad("request body shorter than its Content-Length"))?;
+    Ok(request)
+}
+
+/// A connection that stops reading once `deadline` has passed, so a client
+/// sending a byte at a time can't hold its thread past READ_TIMEOUT
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+fn write_reply(mut stream: &TcpStream, (status, body): &Reply) {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Bad Gateway",
+    };
+    let body = body.to_string();
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    if *status == 401 {
+        head.push_str("WWW-Authenticate: Bearer\r\n");
+    }
+    let _ = stream
+        .write_all(format!("{}\r\n{}", head, body).as_bytes())
+        .and_then(|_| stream.flush());
+}
+
+/// The HTTP status for a failed request; refusals are answers the caller can
+/// show, everything after a bad request is the provider's doing
+fn failure_status(failure: &Failure) -> u16 {
+    match failure {
+        Failure::InvalidInput(_) => 400,
+        Failure::Refusal { .. } => 422,
+        Failure::RateLimited { .. } | Failure::Throttled { .. } => 429,
+        Failure::Cancelled | Failure::Unavailable { .. } | Failure::Offline => 503,
+        Failure::Timeout { .. } => 504,
+        _ => 502,
+    }
+}
+
+/// The JSON for an outcome: the structured answer with its provider and what
+/// the CLI would warn about, or the failure's error envelope
+fn outcome_reply(outcome: Outcome) -> Reply {
+    match outcome {
+        Ok(resp) => {
+            let mut body = resp.structured();
+            body["needs_confirmation"] = resp.needs_confirmation().into();
+            body["flagged"] = serde_json::json!(resp.flagged);
+            body["provider"] = resp.provider.map(|p| p.as_str()).into();
+            body["model"] = resp.provider.map(|p| p.model()).into();
+            body["cached"] = resp.cached.into();
+            body["request_id"] = request_id().into();
+            (200, body)
+        }
+        Err(failure) => {
+            let mut body = failure.envelope();
+            body["error"]["request_id"] = request_id().into();
+            (failure_status(&failure), body)
+        }
+    }
+}
+
+/// `POST /complete` with `{"query": ..., "buffer": ..., "lint": ..., "shell":
+/// ..., "tool_versions": ["ffmpeg=4.4"]}`; only the query is required
+fn complete(client: &SmartshellClient, body: &serde_json::Value) -> Outcome {
+    let query = body["query"].as_str().unwrap_or_default().trim();
+    if query.is_empty() {
+        return Err(Failure::InvalidInput("`query` is required".to_string()));
+    }
+    let mut request = CompletionRequest::new(query);
+    if let Some(buffer) = body["buffer"].as_str().filter(|b| !b.trim().is_empty()) {
+        request.context = ContextSource::Buffer(buffer.to_string());
+    }
+    request.lint = body["lint"].as_bool().unwrap_or(false);
+    if let Some(pins) = body["tool_versions"].as_array() {
+        for pin in pins {
+            let pin = pin.as_str().unwrap_or_default();
+            request
+                .tool_versions
+                .push(parse_tool_version(pin).map_err(Failure::InvalidInput)?);
+        }
+    }
+    match body["shell"].as_str() {
+        Some(name) => client
+            .clone()
+            .shell(name.parse::<TargetShell>()?)
+            .complete(&request),
+        None => client.complete(&request),
+    }
+}
+
+/// `POST /explain` with `{"command": ..., "detail": "brief" | "normal" | "deep"}`
+fn explain(client: &SmartshellClient, body: &serde_json::Value) -> Outcome {
+    let command = body["command"].as_str().unwrap_or_default();
+    if command.trim().is_empty() {
+        return Err(Failure::InvalidInput("`command` is required".to_string()));
+    }
+    let detail = match body["detail"].as_str().unwrap_or("brief") {
+        "brief" => Detail::Brief,
+        "normal" => Detail::Normal,
+        "deep" => Detail::Deep,
+        other => {
+            return Err(Failure::InvalidInput(format!(
+                "Unknown detail: {} (expected brief, normal or deep)",
+                other
+            )))
+        }
+    };
+    client.explain_with_detail(command, detail)
+}
+
+fn route(client: &SmartshellClient, token: &str, request: &HttpRequest) -> Reply {
+    if !request.header("host").is_some_and(local_host) {
+        return error_reply(403, "forbidden", "requests must be addressed to localhost");
+    }
+    let given = request
+        .header("authorization")
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !same_token(given.trim(), token) {
+        return error_reply(401, "auth", "missing or wrong bearer token");
+    }
+    let (cmd, handler): (&str, fn(&SmartshellClient, &serde_json::Value) -> Outcome) =
+        match request.path.as_str() {
+            "/complete" => ("complete", complete),
+            "/explain" => ("explain", explain),
+            _ => return error_reply(404, "not_found", "expected /complete or /explain"),
+        };
+    if request.method != "POST" {
+        return error_reply(405, "invalid_input", "use POST");
+    }
+    let body = match serde_json::from_slice::<serde_json::Value>(&request.body) {
+        Ok(body) if body.is_object() => body,
+        _ => return error_reply(400, "invalid_input", "the body must be a JSON object"),
+    };
+    let started = Instant::now();
+    let (outcome, usage) = measure_request(|| handler(client, &body));
+    record(client, cmd, &body, &outcome, started, usage);
+    outcome_reply(outcome)
+}
+
+/// Keep the request in the history, the debug log and the audit log, as the
+/// CLI does for its own
+fn record(
+    client: &SmartshellClient,
+    cmd: &str,
+    body: &serde_json::Value,
+    outcome: &Outcome,
+    started: Instant,
+    usage: Usage,
+) {
+    let query = match cmd {
+        "explain" => body["command"].as_str(),
+        _ => body["query"].as_str(),
+    }
+    .unwrap_or_default();
+    let (result, label, provider, error_type) = match outcome {
+        Ok(resp) => {
+            let label = match cmd {
+                "explain" => "explained",
+                _ => "suggested",
+            };
+            (resp.result.clone(), label, resp.provider, None)
+        }
+        Err(failure) => {
+            let (label, error_type) = match failure {
+                Failure::Refusal { .. } => ("refused", Some(failure.type_name())),
+                Failure::Cancelled => ("cancelled", None),
+                _ => ("error", Some(failure.type_name())),
+            };
+            (failure.message(), label, failure.provider(), error_type)
+        }
+    };
+    let record = LogRecord {
+        cmd,
+        query,
+        result: &result,
+        provider: provider.or(Some(client.provider)),
+        latency_ms: Some(started.elapsed().as_millis() as u64),
+        usage: Some(usage),
+        outcome: Some(label),
+        buffer: body["buffer"].as_str(),
+        error_type,
+    };
+    history::record(&record);
+    log_record(&record);
+    audit::audit_entry(cmd, query, &result, label, record.provider);
+}
+
+fn handle_connection(client: &SmartshellClient, token: &str, stream: TcpStream) {
+    with_new_request_id(|| {
+        let started = Instant::now();
+        let deadline = Deadline {
+            stream: &stream,
+            deadline: started + READ_TIMEOUT,
+        };
+        let (reply, line) = match read_request(deadline) {
+            Ok(request) => {
+                let line = format!("{} {}", request.method, request.path);
+                (route(client, token, &request), line)
+            }
+            Err(reply) => (reply, "unreadable request".to_string()),
+        };
+        trace(
+            1,
+            format_args!(
+                "{} -> {} in {}ms",
+                line,
+                reply.0,
+                started.elapsed().as_millis()
+            ),
+        );
+        write_reply(&stream, &reply);
+    });
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on unix
+async fn shutdown() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Answer `/complete` and `/explain` on `listen`, a loopback address, until
+/// interrupted. Each connection gets its own thread, since the client blocks, up
+/// to MAX_CONNECTIONS at a time.
+pub fn run_server(listen: &str, client: SmartshellClient) -> Result<(), String> {
+    let addr: SocketAddr = listen.parse().map_err(|_| {
+        format!(
+            "`{}` is not an address and port, as in {}",
+            listen, DEFAULT_LISTEN
+        )
+    })?;
+    if !addr.ip().is_loopback() {
+        return Err(format!(
+            "Refusing to listen on {}: the server only takes local connections (127.0.0.1 or [::1])",
+            addr
+        ));
+    }
+    let (token, source) = server_token()?;
+    // There is no terminal to draw on or ask questions at
+    let client = client.spinner(false).questions(false);
+    // Requests finishing must not hand Ctrl-C back to the default action
+    let _ctrl_c = CtrlCGuard::new();
+    runtime().block_on(async {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .map_err(|e| format!("Cannot listen on {}: {}", addr, e))?;
+        eprintln!("smartshell serving on http://{}", addr);
+        eprintln!("Bearer token in {}", source);
+        let stop = shutdown();
+        tokio::pin!(stop);
+        let slots = std::sync::Arc::new(tokio::sync::Semaphore::new(MAX_CONNECTIONS));
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let Ok((stream, _)) = accepted else { continue };
+                    let Ok(stream) = stream.into_std() else { continue };
+                    let Ok(slot) = slots.clone().try_acquire_owned() else {
+                        // Still non-blocking: the reply is dropped rather than wait
+                        write_reply(&stream, &error_reply(503, "unavailable", "too many connections"));
+                        continue;
+                    };
+                    let _ = stream.set_nonblocking(false);
+                    let (client, token) = (client.clone(), token.clone());
+                    std::thread::spawn(move || {
+                        handle_connection(&client, &token, stream);
+                        drop(slot);
+                    });
+                }
+                _ = &mut stop => break,
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::Provider;
+
+    #[test]
+    fn only_names_of_this_machine_are_local() {
+        for host in [
+            "localhost",
+            "LOCALHOST:8731",
+            "127.0.0.1",
+            "127.0.0.1:8731",
+            "[::1]:8731",
+            "[::1]",
+        ] {
+            assert!(local_host(host), "{}", host);
+        }
+        for host in [
+            "evil.example",
+            "localhost.evil.example:8731",
+            "10.0.0.5:8731",
+            "[::2]:8731",
+            "",
+        ] {
+            assert!(!local_host(host), "{}", host);
+        }
+    }
+
+    #[test]
+    fn tokens_must_match_exactly() {
+        assert!(same_token("abc123", "abc123"));
+        assert!(!same_token("abc124", "abc123"));
+        assert!(!same_token("abc12", "abc123"));
+        assert!(!same_token("abc1234", "abc123"));
+        assert!(!same_token("", "abc123"));
+    }
+
+    fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            body: b"{}".to_vec(),
+        }
+    }
+
+    fn status(method: &str, path: &str, headers: &[(&str, &str)]) -> u16 {
+        let client = SmartshellClient::new(Provider::Mock);
+        route(&client, "secret", &request(method, path, headers)).0
+    }
+
+    #[test]
+    fn route_checks_host_then_token_then_endpoint() {
+        let auth = ("Authorization", "Bearer secret");
+        let local = ("Host", "127.0.0.1:8731");
+        assert_eq!(status("POST", "/complete", &[auth]), 403);
+        assert_eq!(
+            status("POST", "/complete", &[("host", "evil.example"), auth]),
+            403
+        );
+        assert_eq!(status("POST", "/complete", &[local]), 401);
+        assert_eq!(
+            status(
+                "POST",
+                "/complete",
+                &[local, ("Authorization", "Bearer wrong!")]
+            ),
+            401
+        );
+        assert_eq!(
+            status("POST", "/complete", &[local, ("Authorization", "secret")]),
+            401
+        );
+        assert_eq!(status("POST", "/history", &[local, auth]), 404);
+        assert_eq!(status("GET", "/complete", &[local, auth]), 405);
+        assert_eq!(status("GET", "/explain", &[local, auth]), 405);
+    }
+
+    #[test]
+    fn route_needs_a_json_object_body() {
+        let client = SmartshellClient::new(Provider::Mock);
+        let mut request = request(
+            "POST",
+            "/explain",
+            &[("Host", "localhost"), ("Authorization", "Bearer secret")],
+        );
+        for body in ["", "[1]", "not json"] {
+            request.body = body.as_bytes().to_vec();
+            let (status, reply) = route(&client, "secret", &request);
+            assert_eq!(status, 400, "{}", body);
+            assert_eq!(reply["error"]["type"], "invalid_input");
+        }
+    }
+
+    fn read_status(request: impl Read) -> u16 {
+        match read_request(request) {
+            Ok(_) => 200,
+            Err((status, _)) => status,
+        }
+    }
+
+    #[test]
+    fn reads_a_request() {
+        let text = "POST /complete HTTP/1.1\r\nContent-Type: application/json\r\n\
+                    Content-Length: 2\r\n\r\n{}";
+        let request = read_request(text.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/complete");
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(request.body, b"{}");
+    }
+
+    #[test]
+    fn refuses_an_endless_header_line() {
+        let head = "POST /complete HTTP/1.1\r\nX-Padding: ".as_bytes();
+        assert_eq!(read_status(head.chain(std::io::repeat(b'a'))), 431);
+        let long = format!(
+            "GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(MAX_LINE)
+        );
+        assert_eq!(read_status(long.as_bytes()), 431);
+        let fits = format!("GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(100));
+        assert_eq!(read_status(fits.as_bytes()), 200);
+    }
+
+    #[test]
+    fn gives_up_on_a_slow_request_at_the_deadline() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.write_all(b"POST /complete HTTP/1.1\r\n").unwrap();
+        let started = Instant::now();
+        let slow = Deadline {
+            stream: &server,
+            deadline: started + Duration::from_millis(200),
+        };
+        assert_eq!(read_status(slow), 400);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn refuses_an_endless_request_line() {
+        let head = "GET /".as_bytes();
+        assert_eq!(read_status(head.chain(std::io::repeat(b'a'))), 400);
+    }
+
+    #[test]
+    fn refuses_too_many_headers_and_large_bodies() {
+        let many = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X-A: b\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert_eq!(read_status(many.as_bytes()), 400);
+        let large = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert_eq!(read_status(large.as_bytes()), 413);
+    }
+}
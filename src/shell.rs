@@ -0,0 +1,799 @@
+//! Parsing, checking, repairing and running generated commands
+
+use crate::client::{clear_status, runtime, CtrlCGuard, SmartshellClient};
+use crate::config::{flush_log, is_wsl, log_entry};
+use crate::console::Console;
+use crate::error::{Failure, Outcome, EXIT_CANCELLED};
+use crate::provider::Provider;
+use crate::response::LlmResponse;
+use crate::sanitize::sanitize_response;
+use crate::style::{for_stderr, Style};
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process::{Command, Stdio};
+
+/// The shell commands are generated for and run in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetShell {
+    Zsh,
+    /// PowerShell: `pwsh` 7, or Windows PowerShell 5.1 where that is all there is
+    Pwsh,
+    /// The Windows Command Prompt
+    Cmd,
+}
+
+impl TargetShell {
+    /// The shell's name as prompts refer to it
+    pub fn name(self) -> &'static str {
+        match self {
+            TargetShell::Zsh => "zsh",
+            TargetShell::Pwsh => "PowerShell",
+            TargetShell::Cmd => "cmd.exe",
+        }
+    }
+
+    /// SMSH_SHELL, or PowerShell on Windows and zsh elsewhere; an unknown name is
+    /// reported and the default used
+    pub fn from_env() -> Self {
+        let default = if cfg!(windows) {
+            TargetShell::Pwsh
+        } else {
+            TargetShell::Zsh
+        };
+        match env::var("SMSH_SHELL") {
+            Ok(name) if !name.trim().is_empty() => name.trim().parse().unwrap_or_else(|e| {
+                // Clients are built more than once per run
+                static WARNED: std::sync::Once = std::sync::Once::new();
+                WARNED.call_once(|| {
+                    eprintln!(
+                        "{}",
+                        crate::style::warning(&format!(
+                            "SMSH_SHELL: {}; using {}",
+                            e,
+                            default.name()
+                        ))
+                    );
+                });
+                default
+            }),
+            _ => default,
+        }
+    }
+
+    /// Syntax rules for the prompt, beyond naming the shell. From WSL, PowerShell
+    /// and cmd.exe mean the Windows side, reached through interop.
+    pub(crate) fn instructions(self) -> String {
+        if self != TargetShell::Zsh && is_wsl() {
+            format!("{}{}", self.syntax(), WINDOWS_SIDE)
+        } else {
+            self.syntax().to_string()
+        }
+    }
+
+    fn syntax(self) -> &'static str {
+        match self {
+            TargetShell::Zsh => "",
+            TargetShell::Pwsh => {
+                " Write PowerShell, not a POSIX shell: use cmdlets with their full names \
+                and full parameter names (Get-ChildItem -Recurse -Filter, Where-Object, \
+                Select-Object, Remove-Item, Get-Content), never aliases such as ls, rm, \
+                cat or curl, which are missing or behave differently. Quote literal text \
+                in single quotes, doubling a single quote inside it; use double quotes \
+                only to expand $variables or $(...). Escape with a backtick, not a \
+                backslash; paths may use backslashes freely. Chain with ; or a pipeline \
+                of objects; use && and || only for PowerShell 7. Use $env:NAME for \
+                environment variables."
+            }
+            TargetShell::Cmd => {
+                " Write a cmd.exe command line, not a POSIX shell: use built-ins and \
+                Windows tools (dir, copy, move, del, findstr, where, type) with /switches. \
+                Quote paths in double quotes, use %NAME% for environment variables and \
+                escape special characters with ^. Chain with & or &&."
+            }
+        }
+    }
+
+    /// The program and arguments that run a command line in this shell
+    fn runner(self) -> (&'static str, &'static [&'static str]) {
+        // Windows programs are only found by their .exe name from WSL
+        let wsl = is_wsl();
+        match self {
+            TargetShell::Zsh if which::which("zsh").is_ok() => ("zsh", &["-c"]),
+            TargetShell::Zsh => ("sh", &["-c"]),
+            TargetShell::Pwsh if wsl && which::which("pwsh.exe").is_ok() => {
+                ("pwsh.exe", &["-NoProfile", "-Command"])
+            }
+            TargetShell::Pwsh if wsl => ("powershell.exe", &["-NoProfile", "-Command"]),
+            TargetShell::Pwsh if which::which("pwsh").is_ok() => {
+                ("pwsh", &["-NoProfile", "-Command"])
+            }
+            TargetShell::Pwsh => ("powershell", &["-NoProfile", "-Command"]),
+            TargetShell::Cmd if wsl => ("cmd.exe", &["/C"]),
+            TargetShell::Cmd => ("cmd", &["/C"]),
+        }
+    }
+}
+
+const WINDOWS_SIDE: &str = " The command runs on the Windows host, launched from WSL: \
+    use Windows paths (C:\\Users\\name, not /mnt/c/Users/name) and Windows tools, \
+    and note that the working directory may be a \\\\wsl$ path.";
+
+impl std::str::FromStr for TargetShell {
+    type Err = Failure;
+
+    fn from_str(name: &str) -> Result<Self, Failure> {
+        match name.to_ascii_lowercase().as_str() {
+            "zsh" => Ok(TargetShell::Zsh),
+            "pwsh" | "powershell" => Ok(TargetShell::Pwsh),
+            "cmd" | "cmd.exe" => Ok(TargetShell::Cmd),
+            other => Err(Failure::InvalidInput(format!(
+                "Unknown shell: {} (expected zsh, pwsh or cmd)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Split a command line into words and control operators, honoring quotes
+pub(crate) fn shell_words(cmd: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut cur = String::new();
+    let mut chars = cmd.chars().peekable();
+    let mut quote: Option<char> = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') => {
+                if let Some(n) = chars.next() {
+                    cur.push(n);
+                }
+            }
+            (Some(_), c) => cur.push(c),
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '\\') => {
+                if let Some(n) = chars.next() {
+                    cur.push(n);
+                }
+            }
+            (None, ';' | '|' | '&' | '\n') => {
+                if !cur.is_empty() {
+                    words.push(std::mem::take(&mut cur));
+                }
+                let mut op = c.to_string();
+                if matches!(chars.peek(), Some(&n) if n == c) {
+                    op.push(chars.next().unwrap());
+                }
+                words.push(op);
+            }
+            (None, c) if c.is_whitespace() => {
+                if !cur.is_empty() {
+                    words.push(std::mem::take(&mut cur));
+                }
+            }
+            (None, c) => cur.push(c),
+        }
+    }
+    if !cur.is_empty() {
+        words.push(cur);
+    }
+    words
+}
+
+/// A spelling-independent form of a command: words are re-joined with single
+/// spaces, quoting is dropped and bundled short flags are sorted, so `ls -la 'x'`
+/// and `ls  -al x` compare equal
+pub(crate) fn canonical_command(cmd: &str) -> String {
+    shell_words(cmd)
+        .into_iter()
+        .map(|w| match w.strip_prefix('-') {
+            Some(flags) if flags.len() > 1 && flags.chars().all(|c| c.is_ascii_alphabetic()) => {
+                let mut flags: Vec<char> = flags.chars().collect();
+                flags.sort_unstable();
+                flags.dedup();
+                format!("-{}", flags.into_iter().collect::<String>())
+            }
+            _ => w,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// One word of a word-level diff between two command lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordChange<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// The changes that turn command `old` into `new`, word by word (split on
+/// whitespace, so quoting stays as written), keeping as many words as possible
+pub fn word_diff<'a>(old: &'a str, new: &'a str) -> Vec<WordChange<'a>> {
+    let old: Vec<&str> = old.split_whitespace().collect();
+    let new: Vec<&str> = new.split_whitespace().collect();
+    // kept[i][j]: longest common subsequence of old[i..] and new[j..]
+    let mut kept = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            kept[i][j] = if old[i] == new[j] {
+                kept[i + 1][j + 1] + 1
+            } else {
+                kept[i + 1][j].max(kept[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut changes = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            changes.push(WordChange::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || kept[i + 1][j] >= kept[i][j + 1]) {
+            changes.push(WordChange::Removed(old[i]));
+            i += 1;
+        } else {
+            changes.push(WordChange::Added(new[j]));
+            j += 1;
+        }
+    }
+    changes
+}
+
+pub(crate) fn is_operator(word: &str) -> bool {
+    matches!(word, ";" | ";;" | "|" | "||" | "&" | "&&" | "\n")
+}
+
+/// sudo options that take a value, as in `sudo -u root`
+const SUDO_VALUE_FLAGS: &[&str] = &[
+    "-C", "-D", "-g", "-h", "-p", "-R", "-r", "-T", "-t", "-U", "-u",
+];
+
+/// xargs options that take a value, as in `xargs -n 1`
+const XARGS_VALUE_FLAGS: &[&str] = &["-a", "-d", "-E", "-I", "-L", "-n", "-P", "-s"];
+
+/// env options that take a value, as in `env -u HOME`
+const ENV_VALUE_FLAGS: &[&str] = &["-C", "-S", "-u", "--chdir", "--split-string", "--unset"];
+
+/// Commands that run the rest of their words as a command, with their options
+/// that take a value
+const WRAPPERS: &[(&str, &[&str])] = &[
+    ("sudo", SUDO_VALUE_FLAGS),
+    ("doas", &["-C", "-u"]),
+    ("env", ENV_VALUE_FLAGS),
+    ("xargs", XARGS_VALUE_FLAGS),
+    ("command", &[]),
+    ("nohup", &[]),
+];
+
+/// The name a word runs as, without its directory: `rm` for `/bin/rm`
+fn program_name(word: &str) -> &str {
+    word.rsplit('/').next().unwrap_or(word)
+}
+
+/// The words of one command from its program on, past variable assignments and
+/// wrappers such as `sudo` or `xargs` with their options
+fn command_words(segment: &[String]) -> Vec<&str> {
+    let mut words = segment.iter().map(String::as_str).peekable();
+    while let Some(&word) = words.peek() {
+        if let Some((_, value_flags)) = WRAPPERS
+            .iter()
+            .find(|(name, _)| *name == program_name(word))
+        {
+            words.next();
+            while let Some(&option) = words.peek().filter(|w| w.starts_with('-')) {
+                words.next();
+                if option == "--" {
+                    break;
+                }
+                if value_flags.contains(&option) {
+                    words.next();
+                }
+            }
+        } else if word.contains('=') && !word.starts_with('-') {
+            words.next();
+        } else {
+            break;
+        }
+    }
+    words.collect()
+}
+
+/// Every path, device or branch the destructive commands in `cmd` operate on,
+/// across all of its commands, in order and without repeats
+fn destructive_targets(cmd: &str) -> Vec<String> {
+    let words = shell_words(cmd);
+    let mut targets: Vec<String> = Vec::new();
+    for segment in words.split(|w| is_operator(w)) {
+        let args = command_words(segment);
+        let Some((&prog, rest)) = args.split_first() else {
+            continue;
+        };
+        let prog = program_name(prog);
+        let positional: Vec<&str> = rest
+            .iter()
+            .copied()
+            .filter(|a| !a.starts_with('-'))
+            .collect();
+        let flags: String = rest
+            .iter()
+            .filter(|a| a.starts_with('-') && !a.starts_with("--"))
+            .map(|a| a.trim_start_matches('-'))
+            .collect();
+        let found: Vec<String> = match prog {
+            "rm" => {
+                let recursive = flags.contains(['r', 'R']) || rest.contains(&"--recursive");
+                let force = flags.contains('f') || rest.contains(&"--force");
+                if recursive && force {
+                    positional.iter().map(|t| t.to_string()).collect()
+                } else {
+                    Vec::new()
+                }
+            }
+            // Other positionals are option values, such as a label
+            p if p == "mkfs" || p.starts_with("mkfs.") => positional
+                .last()
+                .map(|t| t.to_string())
+                .into_iter()
+                .collect(),
+            // The starting points, which come before the first test or action
+            "find" if rest.contains(&"-delete") => {
+                let mut paths: Vec<String> = rest
+                    .iter()
+                    .skip_while(|a| matches!(**a, "-H" | "-L" | "-P"))
+                    .take_while(|a| !a.starts_with(['-', '(', '!']))
+                    .map(|a| a.to_string())
+                    .collect();
+                if paths.is_empty() {
+                    paths.push(".".to_string());
+                }
+                paths
+            }
+            "dd" => rest
+                .iter()
+                .filter_map(|a| a.strip_prefix("of="))
+                .map(String::from)
+                .collect(),
+            "truncate" => {
+                // Every file, but not the values of --size and --reference
+                let mut files = Vec::new();
+                let mut words = rest.iter();
+                while let Some(&word) = words.next() {
+                    if matches!(word, "-s" | "-r" | "--size" | "--reference") {
+                        words.next();
+                    } else if !word.starts_with('-') {
+                        files.push(word.to_string());
+                    }
+                }
+                files
+            }
+            "git" => match git_subcommand(rest) {
+                ["push", push @ ..] => {
+                    let pushed: Vec<&str> = push
+                        .iter()
+                        .copied()
+                        .filter(|a| !a.starts_with('-'))
+                        .collect();
+                    let forced = push.iter().any(|a| {
+                        matches!(*a, "-f" | "--force" | "--force-with-lease")
+                            || a.starts_with("--force-with-lease=")
+                    }) || pushed.iter().skip(1).any(|a| a.starts_with('+'));
+                    if forced {
+                        let branch = pushed
+                            .get(1)
+                            .map(|b| b.trim_start_matches('+').to_string())
+                            .or_else(current_git_branch)
+                            .unwrap_or_else(|| "HEAD".to_string());
+                        vec![branch]
+                    } else {
+                        Vec::new()
+                    }
+                }
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        for target in found {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+    }
+    targets
+}
+
+/// git's arguments from the subcommand on, past global options such as
+/// `-C <path>`, `-c <name>=<value>` and `--git-dir=<path>`
+fn git_subcommand<'a>(args: &'a [&'a str]) -> &'a [&'a str] {
+    let mut rest = args;
+    while let Some((&option, after)) = rest.split_first() {
+        if !option.starts_with('-') {
+            break;
+        }
+        rest = match option {
+            "-C" | "-c" | "--git-dir" | "--work-tree" | "--namespace" | "--config-env" => {
+                after.get(1..).unwrap_or_default()
+            }
+            _ => after,
+        };
+    }
+    rest
+}
+
+fn current_git_branch() -> Option<String> {
+    let out = Command::new("git")
+        .args(["rev-parse", "--abbrev-ref", "HEAD"])
+        .output()
+        .ok()?;
+    out.status
+        .success()
+        .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+/// Prompt on the controlling terminal and return the trimmed reply. Ctrl-C
+/// exits with `EXIT_CANCELLED`, since the async runtime owns SIGINT handling.
+pub(crate) fn prompt_tty(message: &str) -> Option<String> {
+    clear_status();
+    let mut tty = Console::open()?;
+    write!(tty, "{}", message).ok()?;
+    tty.flush().ok()?;
+    let reader = Console::input()?;
+    let _ctrl_c = CtrlCGuard::new();
+    runtime().block_on(async {
+        let read = tokio::task::spawn_blocking(move || {
+            let mut line = String::new();
+            io::BufReader::new(reader)
+                .read_line(&mut line)
+                .ok()
+                .map(|_| line)
+        });
+        tokio::select! {
+            line = read => line.ok().flatten().map(|l| l.trim().to_string()),
+            _ = tokio::signal::ctrl_c() => {
+                let _ = writeln!(tty);
+                flush_log();
+                std::process::exit(EXIT_CANCELLED);
+            }
+        }
+    })
+}
+
+pub fn confirm_execution(resp: &LlmResponse) -> bool {
+    let targets = destructive_targets(&resp.result);
+    if !targets.is_empty() {
+        // Every target, so one harmless-looking name can't stand in for the rest
+        let expected = targets.join(" ");
+        let reply = prompt_tty(&format!(
+            "Destructive command. Type `{}` to confirm: ",
+            expected
+        ));
+        return reply
+            .is_some_and(|r| r.split_whitespace().collect::<Vec<_>>().join(" ") == expected);
+    }
+    if resp.needs_confirmation() {
+        return prompt_tty("Destructive command. Type `yes` to confirm: ").as_deref()
+            == Some("yes");
+    }
+    matches!(
+        prompt_tty("Run this command? [y/N] ").as_deref(),
+        Some("y" | "Y" | "yes")
+    )
+}
+
+/// Leave smartshell's credentials out of a child's environment: SMSH_*
+/// variables holding keys, key commands, tokens, passwords, certificates or the
+/// proxy, and the providers' standard `<VENDOR>_API_KEY` variables it reads keys
+/// from, so a generated command cannot read the key it was created with
+pub(crate) fn scrub_secrets(command: &mut Command) -> &mut Command {
+    const SECRET_PARTS: [&str; 7] = [
+        "KEY", "TOKEN", "SECRET", "PASSWORD", "PASSWD", "CERT", "PROXY",
+    ];
+    for (name, _) in std::env::vars_os() {
+        let name = name.to_string_lossy();
+        if name.starts_with("SMSH_") && SECRET_PARTS.iter().any(|part| name.contains(part)) {
+            command.env_remove(name.as_ref());
+        }
+    }
+    for provider in [Provider::OpenAi, Provider::Claude] {
+        if let Some(vendor) = provider.vendor() {
+            command.env_remove(format!("{}_API_KEY", vendor.to_ascii_uppercase()));
+        }
+    }
+    command
+}
+
+pub fn execute_command(cmd: &str, target: TargetShell) -> i32 {
+    let (shell, args) = target.runner();
+    match scrub_secrets(Command::new(shell).args(args).arg(cmd)).status() {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(e) => {
+            eprintln!("Failed to run {}: {}", shell, e);
+            1
+        }
+    }
+}
+
+/// Put `text` on the system clipboard with pbcopy, wl-copy or xclip. Over SSH, or
+/// when none of them works, ask the terminal to do it with an OSC 52 escape.
+pub fn copy_to_clipboard(text: &str) -> Result<(), Failure> {
+    let over_ssh = env::var_os("SSH_TTY").is_some() || env::var_os("SSH_CONNECTION").is_some();
+    let tools: &[(&str, &[&str])] = if cfg!(target_os = "macos") {
+        &[("pbcopy", &[])]
+    } else if is_wsl() {
+        &[
+            ("clip.exe", &[]),
+            ("wl-copy", &[]),
+            ("xclip", &["-selection", "clipboard"]),
+        ]
+    } else {
+        &[("wl-copy", &[]), ("xclip", &["-selection", "clipboard"])]
+    };
+    if !over_ssh {
+        for (bin, args) in tools {
+            if which::which(bin).is_err() {
+                continue;
+            }
+            // wl-copy fails outside Wayland sessions, so fall through to the next tool
+            if pipe_to(bin, args, text).is_ok_and(|status| status.success()) {
+                return Ok(());
+            }
+        }
+    }
+    let mut tty = Console::open().ok_or_else(|| {
+        Failure::InvalidInput(
+            "Could not copy to clipboard (requires pbcopy, wl-copy, xclip, clip.exe or a terminal)"
+                .to_string(),
+        )
+    })?;
+    write!(tty, "\x1b]52;c;{}\x07", base64(text.as_bytes()))
+        .and_then(|_| tty.flush())
+        .map_err(|e| Failure::InvalidInput(format!("Could not copy to clipboard: {}", e)))
+}
+
+fn pipe_to(bin: &str, args: &[&str], text: &str) -> io::Result<std::process::ExitStatus> {
+    let mut child = Command::new(bin)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    child.wait()
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Parse the command with `zsh -n` without running it and return the parse error, if any
+fn syntax_error(cmd: &str) -> Option<String> {
+    let mut child = Command::new("zsh")
+        .arg("-n")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+    child.stdin.take()?.write_all(cmd.as_bytes()).ok()?;
+    let out = child.wait_with_output().ok()?;
+    (!out.status.success()).then(|| String::from_utf8_lossy(&out.stderr).trim().to_string())
+}
+
+/// Give the model one chance to fix a command that does not parse
+pub(crate) fn repair_syntax(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    resp: LlmResponse,
+) -> Outcome {
+    // Only zsh has a parse-only mode to check with
+    if client.target_shell() != TargetShell::Zsh {
+        return Ok(resp);
+    }
+    match syntax_error(&resp.result) {
+        Some(err) => {
+            log_entry("repair", &resp.result, &err);
+            let retry = format!(
+                "{}\n\nYour previous answer `{}` is not valid zsh syntax: {}\nReturn a corrected command.",
+                prompt, resp.result, err
+            );
+            client
+                .llm_api_call(intro, &retry)
+                .and_then(sanitize_response)
+        }
+        None => Ok(resp),
+    }
+}
+
+/// Run shellcheck on the command; None if shellcheck is not installed
+pub fn shellcheck_warnings(cmd: &str) -> Option<Vec<String>> {
+    let mut child = Command::new("shellcheck")
+        .args(["--shell=bash", "--format=gcc", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    child.stdin.take()?.write_all(cmd.as_bytes()).ok()?;
+    let out = child.wait_with_output().ok()?;
+    Some(
+        String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .map(|l| l.strip_prefix("-:").unwrap_or(l).to_string())
+            .collect(),
+    )
+}
+
+/// Ask the model to address shellcheck warnings once, reporting any that remain on stderr
+pub(crate) fn repair_lint(
+    client: &SmartshellClient,
+    intro: &str,
+    prompt: &str,
+    resp: LlmResponse,
+) -> Outcome {
+    if client.target_shell() != TargetShell::Zsh {
+        eprintln!(
+            "shellcheck does not lint {}, skipping lint",
+            client.target_shell().name()
+        );
+        return Ok(resp);
+    }
+    let Some(warnings) = shellcheck_warnings(&resp.result) else {
+        eprintln!("shellcheck not found, skipping lint");
+        return Ok(resp);
+    };
+    if warnings.is_empty() {
+        return Ok(resp);
+    }
+    let retry = format!(
+        "{}\n\nShellCheck reports these issues with your previous answer `{}`:\n{}\nReturn a corrected command.",
+        prompt,
+        resp.result,
+        warnings.join("\n")
+    );
+    let fixed = client
+        .llm_api_call(intro, &retry)
+        .and_then(sanitize_response)?;
+    for w in shellcheck_warnings(&fixed.result).unwrap_or_default() {
+        eprintln!(
+            "{}",
+            for_stderr(Style::Yellow, &format!("shellcheck: {}", w))
+        );
+    }
+    Ok(fixed)
+}
+
+pub(crate) fn sudo_forbidden() -> bool {
+    env::var("SMSH_FORBID_SUDO").is_ok_and(|v| !v.is_empty() && v != "0")
+}
+
+/// Whether any command in the line runs through a privilege escalation tool
+fn uses_sudo(cmd: &str) -> bool {
+    shell_words(cmd).split(|w| is_operator(w)).any(|segment| {
+        segment
+            .iter()
+            .find(|w| !w.contains('='))
+            .is_some_and(|w| matches!(w.as_str(), "sudo" | "doas" | "pkexec" | "su"))
+    })
+}
+
+/// Mark commands that need root, or refuse them when sudo is forbidden
+pub(crate) fn check_privileges(mut resp: LlmResponse) -> Outcome {
+    resp.needs_sudo |= uses_sudo(&resp.result);
+    if resp.needs_sudo && sudo_forbidden() {
+        return Err(Failure::refused(
+            "Command requires root privileges, which SMSH_FORBID_SUDO disallows",
+        ));
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_every_rm_target() {
+        assert_eq!(destructive_targets("rm -rf a b c"), ["a", "b", "c"]);
+        assert_eq!(destructive_targets("rm -r -f -- build"), ["build"]);
+        assert!(destructive_targets("rm a b").is_empty());
+    }
+
+    #[test]
+    fn lists_targets_from_every_command() {
+        assert_eq!(
+            destructive_targets("rm -rf build && rm -rf ~"),
+            ["build", "~"]
+        );
+        assert_eq!(
+            destructive_targets("cd /tmp; rm -rf x | dd if=/dev/zero of=/dev/sda"),
+            ["x", "/dev/sda"]
+        );
+        assert_eq!(destructive_targets("rm -rf x; rm -rf x"), ["x"]);
+    }
+
+    #[test]
+    fn looks_past_sudo_and_its_options() {
+        assert_eq!(destructive_targets("sudo -u root rm -rf x"), ["x"]);
+        assert_eq!(
+            destructive_targets("sudo -E -u root -- rm -rf /var/lib/app"),
+            ["/var/lib/app"]
+        );
+        assert_eq!(
+            destructive_targets("LC_ALL=C sudo mkfs.ext4 -L data /dev/sdb1"),
+            ["/dev/sdb1"]
+        );
+    }
+
+    #[test]
+    fn looks_past_other_wrappers() {
+        assert_eq!(destructive_targets("doas -u root rm -rf x"), ["x"]);
+        assert_eq!(destructive_targets("env -u HOME FOO=1 rm -rf x"), ["x"]);
+        assert_eq!(destructive_targets("command rm -rf x"), ["x"]);
+        assert_eq!(destructive_targets("nohup rm -rf x &"), ["x"]);
+        assert_eq!(
+            destructive_targets("ls | xargs -n 1 -I {} rm -rf x/{}"),
+            ["x/{}"]
+        );
+        assert_eq!(destructive_targets("sudo nohup env rm -rf x"), ["x"]);
+    }
+
+    #[test]
+    fn matches_programs_by_name() {
+        assert_eq!(destructive_targets("/bin/rm -rf x"), ["x"]);
+        assert_eq!(destructive_targets("\\rm -rf x"), ["x"]);
+        assert_eq!(
+            destructive_targets("/usr/bin/sudo /sbin/mkfs.ext4 /dev/sdb1"),
+            ["/dev/sdb1"]
+        );
+    }
+
+    #[test]
+    fn lists_the_starting_points_of_find_delete() {
+        assert_eq!(
+            destructive_targets("find build logs -name '*.tmp' -delete"),
+            ["build", "logs"]
+        );
+        assert_eq!(destructive_targets("find -L -mtime +7 -delete"), ["."]);
+        assert!(destructive_targets("find build -name '*.tmp'").is_empty());
+    }
+
+    #[test]
+    fn skips_option_values() {
+        assert_eq!(
+            destructive_targets("truncate -s 0 a.log b.log"),
+            ["a.log", "b.log"]
+        );
+        assert_eq!(
+            destructive_targets("git push --force origin +main"),
+            ["main"]
+        );
+        assert!(destructive_targets("git push origin main").is_empty());
+        assert_eq!(
+            destructive_targets("git -C repo push --force origin main"),
+            ["main"]
+        );
+        assert_eq!(
+            destructive_targets("git -c push.default=current --git-dir=.git push -f origin dev"),
+            ["dev"]
+        );
+        assert!(destructive_targets("git -C repo push origin main").is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+//! Estimated token spend per month and the optional budget
+
+use crate::config::{log_event, read_state, request_id, update_state};
+use crate::error::Failure;
+use crate::provider::{CLAUDE_MODEL, OPENAI_MODEL};
+use crate::style::{for_stderr, warning, Style};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Mutex;
+
+/// Tokens sent to and received from a provider
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+/// Tokens used by this process so far
+static SESSION_USAGE: Mutex<Usage> = Mutex::new(Usage {
+    input_tokens: 0,
+    output_tokens: 0,
+});
+
+/// Tokens used so far by each request `measure_request` is timing, by request id
+static REQUEST_TOTALS: Mutex<BTreeMap<String, Usage>> = Mutex::new(BTreeMap::new());
+
+tokio::task_local! {
+    /// Tokens used by the request `measure_usage` is driving
+    static REQUEST_USAGE: Cell<Usage>;
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+    }
+
+    /// Estimated cost in USD from list prices per million tokens
+    pub fn cost_usd(&self, model: &str) -> f64 {
+        let (input, output) = match model {
+            OPENAI_MODEL => (2.50, 10.00),
+            CLAUDE_MODEL => (3.00, 15.00),
+            _ => (0.0, 0.0),
+        };
+        (self.input_tokens as f64 * input + self.output_tokens as f64 * output) / 1_000_000.0
+    }
+
+    /// `~1.2k tokens, ≈$0.004` for the end of an answer
+    pub fn summary(&self, model: &str) -> String {
+        format!(
+            "~{} tokens, ≈${:.3}",
+            short_count(self.input_tokens + self.output_tokens),
+            self.cost_usd(model)
+        )
+    }
+}
+
+/// `870`, or `1.2k` from a thousand up
+fn short_count(n: u64) -> String {
+    if n < 1000 {
+        n.to_string()
+    } else {
+        format!("{:.1}k", n as f64 / 1000.0)
+    }
+}
+
+/// Estimated spend for the current calendar month, persisted in the state dir
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct SpendState {
+    month: String,
+    usd: f64,
+    requests: u64,
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+const SPEND_FILE: &str = "spend.json";
+
+impl SpendState {
+    /// The state for the current month: one saved in an earlier month starts over
+    fn this_month(self) -> Self {
+        let month = chrono::Local::now().format("%Y-%m").to_string();
+        if self.month == month {
+            return self;
+        }
+        SpendState {
+            month,
+            ..Default::default()
+        }
+    }
+
+    fn load() -> Self {
+        read_state::<SpendState>(SPEND_FILE).this_month()
+    }
+}
+
+pub(crate) fn record_spend(model: &str, usage: Usage) {
+    add_session_usage(usage);
+    let _ = REQUEST_USAGE.try_with(|cell| {
+        let mut total = cell.get();
+        total.add(usage);
+        cell.set(total);
+    });
+    log_event(
+        "usage",
+        model,
+        &format!(
+            "{} input, {} output tokens, ${:.4}",
+            usage.input_tokens,
+            usage.output_tokens,
+            usage.cost_usd(model)
+        ),
+    );
+    update_state(SPEND_FILE, |state: &mut SpendState| {
+        *state = std::mem::take(state).this_month();
+        state.usd += usage.cost_usd(model);
+        state.requests += 1;
+        state.input_tokens += usage.input_tokens;
+        state.output_tokens += usage.output_tokens;
+        true
+    });
+}
+
+/// Count tokens spent on this process's behalf elsewhere (by the daemon)
+pub(crate) fn add_session_usage(usage: Usage) {
+    SESSION_USAGE.lock().unwrap().add(usage);
+    if let Some(total) = REQUEST_TOTALS.lock().unwrap().get_mut(&request_id()) {
+        total.add(usage);
+    }
+}
+
+/// Tokens this process has used so far, including requests answered by the daemon
+pub fn session_usage() -> Usage {
+    *SESSION_USAGE.lock().unwrap()
+}
+
+/// Run `fut` and return its output along with the tokens it used
+pub(crate) async fn measure_usage<F: std::future::Future>(fut: F) -> (F::Output, Usage) {
+    REQUEST_USAGE
+        .scope(Cell::new(Usage::default()), async {
+            let output = fut.await;
+            (output, REQUEST_USAGE.with(Cell::get))
+        })
+        .await
+}
+
+/// Run `f`, one request with its own request id among others answered by the
+/// same process (`--batch`, `serve`), and return its output along with the
+/// tokens used under that id
+pub fn measure_request<T>(f: impl FnOnce() -> T) -> (T, Usage) {
+    let id = request_id();
+    REQUEST_TOTALS
+        .lock()
+        .unwrap()
+        .insert(id.clone(), Usage::default());
+    let output = f();
+    let usage = REQUEST_TOTALS
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .unwrap_or_default();
+    (output, usage)
+}
+
+fn monthly_budget() -> Option<f64> {
+    env::var("SMSH_MONTHLY_BUDGET")
+        .ok()
+        .and_then(|b| b.trim().trim_start_matches('$').parse().ok())
+}
+
+/// Warn, or refuse when `SMSH_BUDGET_ACTION=refuse`, once the monthly cap is spent
+pub(crate) fn check_budget() -> Result<(), Failure> {
+    let Some(cap) = monthly_budget() else {
+        return Ok(());
+    };
+    let spent = SpendState::load().usd;
+    if spent < cap {
+        return Ok(());
+    }
+    let message = format!(
+        "Monthly budget of ${:.2} reached (spent ${:.2})",
+        cap, spent
+    );
+    if env::var("SMSH_BUDGET_ACTION").as_deref() == Ok("refuse") {
+        return Err(Failure::refused(message));
+    }
+    eprintln!("{}", for_stderr(Style::Yellow, &warning(&message)));
+    Ok(())
+}
+
+pub fn print_stats() {
+    let state = SpendState::load();
+    println!("Month:    {}", state.month);
+    println!("Requests: {}", state.requests);
+    println!(
+        "Tokens:   {} in, {} out",
+        short_count(state.input_tokens),
+        short_count(state.output_tokens)
+    );
+    println!("Spent:    ${:.4}", state.usd);
+    match monthly_budget() {
+        Some(cap) => println!(
+            "Budget:   ${:.2} (remaining ${:.2})",
+            cap,
+            (cap - state.usd).max(0.0)
+        ),
+        None => println!("Budget:   none (set SMSH_MONTHLY_BUDGET)"),
+    }
+}
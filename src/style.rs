@@ -0,0 +1,200 @@
+//! Terminal colors and markdown. Colors are off when the output is not a
+//! terminal, `NO_COLOR` is set, `TERM=dumb`, or with `--color=never`.
+
+use crate::redact::redact;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color terminals unless `NO_COLOR` is set or `TERM=dumb`
+    Auto,
+    Always,
+    Never,
+}
+
+static COLOR: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+
+pub fn set_color(choice: ColorChoice) {
+    COLOR.store(choice as u8, Ordering::Relaxed);
+}
+
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_plain(plain: bool) {
+    PLAIN.store(plain, Ordering::Relaxed);
+}
+
+/// Output for screen readers and braille displays: no spinner or live previews,
+/// no ANSI sequences, words instead of symbols, and no column alignment. On with
+/// `--plain`, `SMSH_PLAIN=1` or `TERM=dumb`.
+pub fn plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+        || std::env::var("SMSH_PLAIN").is_ok_and(|v| !v.is_empty() && v != "0")
+        || std::env::var_os("TERM").is_some_and(|t| t == "dumb")
+}
+
+/// `⚠ message`, or `Warning: message` in plain mode, with credentials masked
+pub fn warning(message: &str) -> String {
+    let message = redact(message);
+    if plain() {
+        format!("Warning: {}", message)
+    } else {
+        format!("⚠ {}", message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Bold,
+    Dim,
+    Italic,
+    Red,
+    Yellow,
+    Green,
+    Cyan,
+}
+
+impl Style {
+    fn code(self) -> &'static str {
+        match self {
+            Style::Bold => "1",
+            Style::Dim => "2",
+            Style::Italic => "3",
+            Style::Red => "1;31",
+            Style::Yellow => "1;33",
+            Style::Green => "32",
+            Style::Cyan => "36",
+        }
+    }
+}
+
+fn use_color(terminal: bool) -> bool {
+    if plain() {
+        return false;
+    }
+    match COLOR.load(Ordering::Relaxed) {
+        c if c == ColorChoice::Always as u8 => true,
+        c if c == ColorChoice::Never as u8 => false,
+        _ => {
+            terminal
+                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                && std::env::var_os("TERM").is_none_or(|t| t != "dumb")
+        }
+    }
+}
+
+fn paint(style: Style, text: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", style.code(), text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// `text` in `style`, if stdout gets colors
+pub fn for_stdout(style: Style, text: &str) -> String {
+    paint(style, text, use_color(io::stdout().is_terminal()))
+}
+
+/// Whether stderr gets colors
+pub fn stderr_colored() -> bool {
+    use_color(io::stderr().is_terminal())
+}
+
+/// `text` in `style`, if stderr gets colors
+pub fn for_stderr(style: Style, text: &str) -> String {
+    paint(style, text, use_color(io::stderr().is_terminal()))
+}
+
+/// Render the markdown in a long-form answer for stdout: code blocks are indented,
+/// headings and `**bold**` bolded, list markers turned into bullets, and inline
+/// code and emphasis styled. Returned unchanged when stdout is not a terminal,
+/// since the raw markdown reads fine in files and editors. In plain mode the
+/// markup is dropped instead of styled.
+pub fn render_markdown(text: &str) -> String {
+    if !io::stdout().is_terminal() {
+        return text.to_string();
+    }
+    let bullet = if plain() { "-" } else { "•" };
+    let mut lines = Vec::new();
+    let mut in_code = false;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        if trimmed.starts_with("```") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            lines.push(format!("    {}", for_stdout(Style::Cyan, line)));
+        } else if let Some(heading) = trimmed
+            .strip_prefix('#')
+            .map(|h| h.trim_start_matches('#'))
+            .filter(|h| h.starts_with(' '))
+        {
+            lines.push(for_stdout(Style::Bold, heading.trim()));
+        } else if let Some(item) = ["- ", "* ", "+ "]
+            .iter()
+            .find_map(|marker| trimmed.strip_prefix(marker))
+        {
+            lines.push(format!("{}{} {}", indent, bullet, render_inline(item)));
+        } else {
+            lines.push(render_inline(line));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Style `code`, **bold** and *emphasis* spans within one line
+fn render_inline(line: &str) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(['`', '*', '_']) {
+        let (before, from) = rest.split_at(start);
+        out.push_str(before);
+        let opens_word = before.is_empty() || before.ends_with(char::is_whitespace);
+        let marker = match from {
+            _ if from.starts_with('`') => "`",
+            _ if from.starts_with("**") => "**",
+            _ if from.starts_with("__") => "__",
+            _ if from.starts_with('*') => "*",
+            _ => "_",
+        };
+        let body = &from[marker.len()..];
+        let span = match marker {
+            "`" => body.find('`').map(|end| (end, Style::Cyan)),
+            _ if !opens_word || body.starts_with(char::is_whitespace) => None,
+            _ => closing_marker(body, marker).map(|end| {
+                let style = if marker.len() == 2 {
+                    Style::Bold
+                } else {
+                    Style::Italic
+                };
+                (end, style)
+            }),
+        };
+        match span {
+            Some((end, style)) if end > 0 => {
+                out.push_str(&for_stdout(style, &body[..end]));
+                rest = &body[end + marker.len()..];
+            }
+            _ => {
+                out.push_str(marker);
+                rest = body;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Where an emphasis span opened with `marker` ends: a marker right after a
+/// non-space character and not followed by a letter or digit
+fn closing_marker(body: &str, marker: &str) -> Option<usize> {
+    body.match_indices(marker).map(|(i, _)| i).find(|&i| {
+        let closes = !body[..i].ends_with(char::is_whitespace);
+        let ends_word = !body[i + marker.len()..].starts_with(char::is_alphanumeric);
+        closes && ends_word
+    })
+}
@@ -0,0 +1,350 @@
+//! OpenTelemetry traces (feature `otel`): one trace per invocation, with spans for
+//! gathering context, each provider call and validation, sent as OTLP/HTTP JSON to
+//! OTEL_EXPORTER_OTLP_ENDPOINT when the process ends. Spans carry the provider,
+//! model, token counts and outcome, never the query or the answer. Without the
+//! feature, or without an endpoint, every function here does nothing.
+
+use crate::error::Failure;
+use crate::provider::Provider;
+
+#[cfg(feature = "otel")]
+mod otlp {
+    use crate::config::{env_parse, trace};
+    use std::collections::hash_map::RandomState;
+    use std::env;
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::Mutex;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// How long exiting waits for the collector, unless OTEL_EXPORTER_OTLP_TIMEOUT
+    /// (milliseconds) says otherwise; the shell prompt is waiting too
+    const EXPORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+    pub(super) struct Open {
+        pub id: u64,
+        pub parent: Option<u64>,
+        pub name: &'static str,
+        /// SPAN_KIND_INTERNAL (1) or SPAN_KIND_CLIENT (3)
+        pub kind: u8,
+        pub start: u128,
+        pub attributes: Vec<(&'static str, serde_json::Value)>,
+        pub error: Option<String>,
+    }
+
+    impl Open {
+        /// Set attribute `key`, replacing an earlier value
+        pub fn set(&mut self, key: &'static str, value: serde_json::Value) {
+            self.attributes.retain(|(k, _)| *k != key);
+            self.attributes.push((key, value));
+        }
+
+        pub fn fail(&mut self, failure: &crate::error::Failure) {
+            self.error = Some(failure.message());
+            self.set("error.type", failure.type_name().into());
+        }
+    }
+
+    struct Trace {
+        id: u128,
+        stack: Vec<u64>,
+        done: Vec<serde_json::Value>,
+        root: Option<Open>,
+    }
+
+    static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+    pub(super) fn random_u64() -> u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(now());
+        hasher.finish()
+    }
+
+    pub(super) fn now() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    }
+
+    /// The traces endpoint: OTEL_EXPORTER_OTLP_TRACES_ENDPOINT as is, or
+    /// OTEL_EXPORTER_OTLP_ENDPOINT with `/v1/traces` appended
+    fn endpoint() -> Option<String> {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .filter(|v: &String| !v.trim().is_empty())
+        };
+        if var("OTEL_SDK_DISABLED").is_some_and(|v| v.eq_ignore_ascii_case("true")) {
+            return None;
+        }
+        var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").or_else(|| {
+            var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                .map(|base| format!("{}/v1/traces", base.trim_end_matches('/')))
+        })
+    }
+
+    pub(super) fn begin(name: &'static str) {
+        if endpoint().is_none() {
+            return;
+        }
+        let id = random_u64();
+        *TRACE.lock().unwrap() = Some(Trace {
+            id: (u128::from(random_u64()) << 64) | u128::from(id),
+            stack: vec![id],
+            done: Vec::new(),
+            root: Some(Open {
+                id,
+                parent: None,
+                name,
+                kind: 1,
+                start: now(),
+                attributes: Vec::new(),
+                error: None,
+            }),
+        });
+    }
+
+    /// Open a span under the innermost open one, if a trace is being recorded
+    pub(super) fn open(name: &'static str, kind: u8) -> Option<Open> {
+        let mut trace = TRACE.lock().unwrap();
+        let trace = trace.as_mut()?;
+        let id = random_u64();
+        let parent = trace.stack.last().copied();
+        trace.stack.push(id);
+        Some(Open {
+            id,
+            parent,
+            name,
+            kind,
+            start: now(),
+            attributes: Vec::new(),
+            error: None,
+        })
+    }
+
+    fn to_json(span: &Open, trace_id: u128) -> serde_json::Value {
+        let attributes: Vec<_> = span
+            .attributes
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_json::Value::Bool(b) => serde_json::json!({ "boolValue": b }),
+                    serde_json::Value::Number(n) => {
+                        serde_json::json!({ "intValue": n.to_string() })
+                    }
+                    serde_json::Value::String(s) => serde_json::json!({ "stringValue": s }),
+                    other => serde_json::json!({ "stringValue": other.to_string() }),
+                };
+                serde_json::json!({ "key": key, "value": value })
+            })
+            .collect();
+        let status = match &span.error {
+            Some(message) => serde_json::json!({ "code": 2, "message": message }),
+            None => serde_json::json!({ "code": 1 }),
+        };
+        serde_json::json!({
+            "traceId": format!("{:032x}", trace_id),
+            "spanId": format!("{:016x}", span.id),
+            "parentSpanId": span.parent.map(|p| format!("{:016x}", p)).unwrap_or_default(),
+            "name": span.name,
+            "kind": span.kind,
+            "startTimeUnixNano": span.start.to_string(),
+            "endTimeUnixNano": now().to_string(),
+            "attributes": attributes,
+            "status": status,
+        })
+    }
+
+    pub(super) fn close(span: &Open) {
+        let mut trace = TRACE.lock().unwrap();
+        let Some(trace) = trace.as_mut() else {
+            return;
+        };
+        trace.stack.retain(|&id| id != span.id);
+        let json = to_json(span, trace.id);
+        trace.done.push(json);
+    }
+
+    /// Add to the invocation's own span
+    pub(super) fn with_root(f: impl FnOnce(&mut Open)) {
+        if let Some(root) = TRACE.lock().unwrap().as_mut().and_then(|t| t.root.as_mut()) {
+            f(root);
+        }
+    }
+
+    fn headers() -> reqwest::header::HeaderMap {
+        let mut headers = reqwest::header::HeaderMap::new();
+        let Ok(list) = env::var("OTEL_EXPORTER_OTLP_HEADERS") else {
+            return headers;
+        };
+        for pair in list.split(',') {
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+            if let (Ok(name), Ok(value)) = (
+                reqwest::header::HeaderName::from_bytes(name.trim().as_bytes()),
+                reqwest::header::HeaderValue::from_str(value.trim()),
+            ) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
+
+    /// Close the invocation's span and send the whole trace
+    pub(super) fn finish() {
+        let Some(mut spans) = TRACE.lock().unwrap().take() else {
+            return;
+        };
+        if let Some(root) = spans.root.take() {
+            spans.done.push(to_json(&root, spans.id));
+        }
+        let Some(url) = endpoint() else {
+            return;
+        };
+        if let Err(failure) = crate::provider::check_egress(&url) {
+            trace(1, format_args!("trace not sent: {}", failure));
+            return;
+        }
+        let service = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "smartshell".to_string());
+        let body = serde_json::json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [
+                    { "key": "service.name", "value": { "stringValue": service } },
+                    { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+                ]},
+                "scopeSpans": [{
+                    "scope": { "name": "smartshell", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans.done,
+                }],
+            }],
+        });
+        let timeout = env_parse("OTEL_EXPORTER_OTLP_TIMEOUT")
+            .map(Duration::from_millis)
+            .unwrap_or(EXPORT_TIMEOUT);
+        let send = async {
+            let client = crate::provider::client_builder()
+                .timeout(timeout)
+                .build()
+                .map_err(|e| e.to_string())?;
+            let resp = client
+                .post(&url)
+                .headers(headers())
+                .json(&body)
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            match resp.status() {
+                status if status.is_success() => Ok(()),
+                status => Err(format!("HTTP {}", status.as_u16())),
+            }
+        };
+        if let Err(e) = crate::client::runtime().block_on(send) {
+            trace(
+                1,
+                format_args!("sending the trace to {} failed: {}", url, e),
+            );
+        }
+    }
+}
+
+/// A span that ends when it is dropped
+pub(crate) struct Span {
+    #[cfg(feature = "otel")]
+    open: Option<otlp::Open>,
+}
+
+impl Span {
+    /// Set an attribute (string, number or bool)
+    #[allow(unused_variables)]
+    pub(crate) fn set(&mut self, key: &'static str, value: impl Into<serde_json::Value>) {
+        #[cfg(feature = "otel")]
+        if let Some(open) = self.open.as_mut() {
+            open.set(key, value.into());
+        }
+    }
+
+    /// The provider and model, as `gen_ai.*` and `smartshell.provider` attributes
+    pub(crate) fn set_provider(&mut self, provider: Provider) {
+        self.set("smartshell.provider", provider.as_str());
+        if let Some(vendor) = provider.vendor() {
+            self.set("gen_ai.system", vendor);
+        }
+        self.set("gen_ai.request.model", provider.model());
+    }
+
+    /// Mark the span as failed with the (redacted) failure message
+    #[allow(unused_variables)]
+    pub(crate) fn fail(&mut self, failure: &Failure) {
+        #[cfg(feature = "otel")]
+        if let Some(open) = self.open.as_mut() {
+            open.fail(failure);
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(open) = self.open.take() {
+            otlp::close(&open);
+        }
+    }
+}
+
+/// Start a span for a step of the invocation (`gather_context`, `validate`)
+#[allow(unused_variables)]
+pub(crate) fn span(name: &'static str) -> Span {
+    Span {
+        #[cfg(feature = "otel")]
+        open: otlp::open(name, 1),
+    }
+}
+
+/// Start a span for a request to a provider (or the cache or daemon answering
+/// for it)
+#[allow(unused_variables)]
+pub(crate) fn client_span(name: &'static str) -> Span {
+    Span {
+        #[cfg(feature = "otel")]
+        open: otlp::open(name, 3),
+    }
+}
+
+/// Start this process's trace, named after the subcommand (`smartshell complete`)
+#[allow(unused_variables)]
+pub fn begin(name: &'static str) {
+    #[cfg(feature = "otel")]
+    otlp::begin(name);
+}
+
+/// Record the invocation's outcome (`suggested`, `error`, ...), provider and
+/// token usage on its span
+#[allow(unused_variables)]
+pub fn outcome(outcome: &str, provider: Option<Provider>) {
+    #[cfg(feature = "otel")]
+    otlp::with_root(|root| {
+        root.set("smartshell.outcome", outcome.into());
+        root.set("smartshell.request_id", crate::config::request_id().into());
+        if let Some(provider) = provider {
+            root.set("smartshell.provider", provider.as_str().into());
+            root.set("gen_ai.request.model", provider.model().into());
+        }
+        let usage = crate::spend::session_usage();
+        root.set("gen_ai.usage.input_tokens", usage.input_tokens.into());
+        root.set("gen_ai.usage.output_tokens", usage.output_tokens.into());
+    });
+}
+
+/// Mark the invocation as failed
+#[allow(unused_variables)]
+pub fn failed(failure: &Failure) {
+    #[cfg(feature = "otel")]
+    otlp::with_root(|root| root.fail(failure));
+}
+
+/// End the trace and send it; call before exiting the process
+pub fn finish() {
+    #[cfg(feature = "otel")]
+    otlp::finish();
+}
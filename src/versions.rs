@@ -0,0 +1,181 @@
+//! Versions of the tools a request is about, so the model writes flags the
+//! installed (or targeted) version has rather than the newest release's
+
+use crate::config::trace;
+use crate::shell::scrub_secrets;
+use regex::Regex;
+use std::env;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Tools whose version is looked up when a query or buffer names them, with the
+/// arguments that print it without doing anything else
+const KNOWN_TOOLS: &[(&str, &[&str])] = &[
+    ("ffmpeg", &["-version"]),
+    ("ffprobe", &["-version"]),
+    ("git", &["--version"]),
+    ("docker", &["--version"]),
+    ("kubectl", &["version", "--client"]),
+    ("helm", &["version", "--short"]),
+    ("python", &["--version"]),
+    ("python3", &["--version"]),
+    ("node", &["--version"]),
+    ("npm", &["--version"]),
+    ("cargo", &["--version"]),
+    ("java", &["-version"]),
+    ("tar", &["--version"]),
+    ("rsync", &["--version"]),
+    ("curl", &["--version"]),
+    ("wget", &["--version"]),
+    ("openssl", &["version"]),
+    ("jq", &["--version"]),
+    ("rg", &["--version"]),
+    ("fd", &["--version"]),
+    ("sed", &["--version"]),
+    ("grep", &["--version"]),
+    ("awk", &["--version"]),
+    ("psql", &["--version"]),
+    ("pg_dump", &["--version"]),
+    ("mysql", &["--version"]),
+    ("sqlite3", &["--version"]),
+    ("ssh", &["-V"]),
+    ("tmux", &["-V"]),
+    ("magick", &["--version"]),
+    ("convert", &["--version"]),
+    ("systemctl", &["--version"]),
+    ("gh", &["--version"]),
+    ("ansible", &["--version"]),
+    ("aws", &["--version"]),
+];
+
+/// At most this many tools are looked up per request
+const MAX_DETECTED: usize = 4;
+
+/// How long a tool gets to print its version
+const DETECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A `TOOL=VERSION` pin, from `--tool-version` or SMSH_TOOL_VERSIONS
+pub fn parse_tool_version(spec: &str) -> Result<(String, String), String> {
+    match spec.split_once('=') {
+        Some((tool, version)) if !tool.trim().is_empty() && !version.trim().is_empty() => {
+            Ok((tool.trim().to_string(), version.trim().to_string()))
+        }
+        _ => Err(format!("`{}` is not TOOL=VERSION, as in ffmpeg=4.4", spec)),
+    }
+}
+
+/// Pins from SMSH_TOOL_VERSIONS (`ffmpeg=4.4,tar=1.30`), for servers that lag
+/// behind this machine; entries that don't parse are reported and skipped
+fn env_pins() -> Vec<(String, String)> {
+    let Ok(pins) = env::var("SMSH_TOOL_VERSIONS") else {
+        return Vec::new();
+    };
+    pins.split(',')
+        .filter(|p| !p.trim().is_empty())
+        .filter_map(|p| match parse_tool_version(p) {
+            Ok(pin) => Some(pin),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    crate::style::warning(&format!("SMSH_TOOL_VERSIONS: {}", e))
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// The first version number in a tool's `--version` output
+fn version_number(output: &str) -> Option<String> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r"\d+(?:\.\d+)+").expect("valid version pattern"));
+    re.find(output).map(|m| m.as_str().to_string())
+}
+
+/// Run `tool args` and read its version, giving up after `DETECT_TIMEOUT`
+fn detect(tool: &str, args: &[&str]) -> Option<String> {
+    which::which(tool).ok()?;
+    let mut child = scrub_secrets(Command::new(tool).args(args))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+    let deadline = Instant::now() + DETECT_TIMEOUT;
+    // Version banners are far smaller than a pipe buffer, so waiting can't block
+    while child.try_wait().ok()?.is_none() {
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let mut output = String::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        let _ = stdout.read_to_string(&mut output);
+    }
+    // java, ssh and tmux print theirs on stderr
+    if let Some(mut stderr) = child.stderr.take() {
+        let _ = stderr.read_to_string(&mut output);
+    }
+    version_number(&output)
+}
+
+/// The versions to target for a request: `pinned` (`--tool-version`), then
+/// SMSH_TOOL_VERSIONS, then unless SMSH_DETECT_VERSIONS=0 the installed version
+/// of each known tool the query or buffer names
+pub(crate) fn tool_versions(
+    query: &str,
+    buffer: &str,
+    pinned: &[(String, String)],
+) -> Vec<(String, String)> {
+    let mut versions = pinned.to_vec();
+    for pin in env_pins() {
+        if !versions.iter().any(|(tool, _)| *tool == pin.0) {
+            versions.push(pin);
+        }
+    }
+    if env::var("SMSH_DETECT_VERSIONS").is_ok_and(|v| v == "0") {
+        return versions;
+    }
+    let mut named: Vec<&(&str, &[&str])> = Vec::new();
+    for word in format!("{} {}", query, buffer)
+        .split(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-')))
+        .map(|w| w.trim_matches('-'))
+    {
+        let known = KNOWN_TOOLS
+            .iter()
+            .find(|(tool, _)| tool.eq_ignore_ascii_case(word));
+        if let Some(entry) = known {
+            if !named.contains(&entry) && !versions.iter().any(|(t, _)| t == entry.0) {
+                named.push(entry);
+            }
+        }
+    }
+    for (tool, args) in named.into_iter().take(MAX_DETECTED) {
+        if let Some(version) = detect(tool, args) {
+            trace(1, format_args!("{} {} is installed", tool, version));
+            versions.push((tool.to_string(), version));
+        }
+    }
+    versions
+}
+
+/// Ask for commands that work with `versions`
+pub(crate) fn version_instruction(versions: &[(String, String)]) -> String {
+    if versions.is_empty() {
+        return String::new();
+    }
+    let list: Vec<_> = versions
+        .iter()
+        .map(|(tool, version)| format!("{} {}", tool, version))
+        .collect();
+    format!(
+        " Target these tool versions, using only flags and syntax they support, \
+        not those of newer releases: {}.",
+        list.join(", ")
+    )
+}
@@ -0,0 +1,181 @@
+//! The whole CLI pipeline against the mock provider, without a key or network
+
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const FIXTURES: &str = r##"[
+  {"match": "large files", "response": {"result": "du -ah . | sort -rh | head", "explanation": "Lists the largest files"}},
+  {"match": "delete", "response": {"result": "rm -rf build", "risk": "destructive", "affects": ["build"]}},
+  {"match": "weather", "response": {"result": "Not a shell task", "error": true}},
+  {"match": "tar -xvf", "response": "Extracts the archive verbosely"},
+  {"match": "kubectl get pods", "response": "# Replaces kubectl get pods --all-namespaces -o wide\nalias kpods='kubectl get pods --all-namespaces -o wide'"},
+  {"match": "coverage report", "response": {"result": "# Build the HTML coverage report\n.PHONY: coverage\ncoverage:\n\tcargo llvm-cov --html", "explanation": "Writes an HTML coverage report"}},
+  {"match": "rsync -avz", "response": "# Mirror a directory to a remote host\n\nCopies `src/` into `backup:dst/`, keeping permissions and times.\n\n## Caveats\n\n- A missing trailing slash on `src` copies the directory itself"}
+]"##;
+
+/// A scratch home for one test, with the fixtures written into it
+fn home(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("smartshell-cli-{}-{}", std::process::id(), test));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("fixtures.json"), FIXTURES).unwrap();
+    dir
+}
+
+/// Run smartshell with the mock provider, isolated from the user's settings,
+/// state and daemon
+fn smartshell(test: &str, args: &[&str]) -> Output {
+    smartshell_in(home(test), args)
+}
+
+/// Like `smartshell`, in a home the test has already put files in
+fn smartshell_in(home: PathBuf, args: &[&str]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_smartshell"));
+    for (name, _) in std::env::vars_os() {
+        let name = name.to_string_lossy();
+        if name.starts_with("SMSH_")
+            || name.ends_with("_API_KEY")
+            || name.starts_with("XDG_")
+            || name == "HISTFILE"
+        {
+            command.env_remove(name.as_ref());
+        }
+    }
+    let output = command
+        .args(["--provider", "mock"])
+        .args(args)
+        .env("HOME", &home)
+        .env("SMSH_MOCK_FIXTURES", home.join("fixtures.json"))
+        .env("SMSH_NO_DAEMON", "1")
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_dir_all(&home);
+    output
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn complete_prints_the_command() {
+    let output = smartshell("complete", &["complete", "--query", "find large files"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(stdout(&output).trim(), "du -ah . | sort -rh | head");
+}
+
+#[test]
+fn explain_prints_the_explanation() {
+    let output = smartshell("explain", &["explain", "--buffer", "tar -xvf a.tar"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(stdout(&output).trim(), "# Extracts the archive verbosely");
+}
+
+#[test]
+fn json_output_describes_the_answer() {
+    let output = smartshell(
+        "json",
+        &[
+            "--output",
+            "json",
+            "complete",
+            "--query",
+            "find large files",
+        ],
+    );
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let json: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(json["result"], "du -ah . | sort -rh | head");
+    assert_eq!(json["explanation"], "Lists the largest files");
+    assert_eq!(json["error"], serde_json::Value::Null);
+    assert_eq!(json["refused"], false);
+    assert_eq!(json["provider"], "mock");
+    assert!(json["request_id"].is_string());
+}
+
+#[test]
+fn json_output_reports_failures() {
+    let output = smartshell(
+        "json-failure",
+        &["--output", "json", "complete", "--query", "unknown task"],
+    );
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let json: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(json["result"], serde_json::Value::Null);
+    assert!(json["error"]
+        .as_str()
+        .is_some_and(|e| e.contains("No mock fixture")));
+}
+
+#[test]
+fn refusals_exit_with_2() {
+    let output = smartshell("refusal", &["complete", "--query", "what's the weather"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    assert!(stdout(&output).is_empty());
+}
+
+#[test]
+fn destructive_commands_exit_with_3() {
+    let output = smartshell("destructive", &["complete", "--query", "delete the build"]);
+    assert_eq!(output.status.code(), Some(3), "{:?}", output);
+    assert_eq!(stdout(&output).trim(), "rm -rf build");
+}
+
+#[test]
+fn alias_suggestions_may_start_with_a_comment() {
+    let home = home("aliases");
+    let command = "kubectl get pods --all-namespaces -o wide\n";
+    std::fs::write(home.join(".zsh_history"), command.repeat(5)).unwrap();
+    let output = smartshell_in(home, &["stats", "--suggest-aliases"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let out = stdout(&output);
+    assert!(
+        out.contains("# Replaces kubectl get pods --all-namespaces -o wide\nalias kpods="),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn build_targets_may_start_with_a_comment() {
+    let home = home("make");
+    let makefile = home.join("Makefile");
+    std::fs::write(
+        &makefile,
+        "# Run the tests\n.PHONY: test\ntest:\n\tcargo test\n",
+    )
+    .unwrap();
+    let args = ["make", "--query", "coverage report", "--file"];
+    let output = smartshell_in(
+        home.clone(),
+        &[&args[..], &[makefile.to_str().unwrap()]].concat(),
+    );
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(
+        stdout(&output).trim(),
+        "# Build the HTML coverage report\n.PHONY: coverage\ncoverage:\n\tcargo llvm-cov --html"
+    );
+}
+
+#[test]
+fn deep_explanations_may_open_with_a_heading() {
+    let output = smartshell(
+        "deep",
+        &[
+            "explain",
+            "--detail",
+            "deep",
+            "--buffer",
+            "rsync -avz src/ backup:dst/",
+        ],
+    );
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let out = stdout(&output);
+    assert!(
+        out.contains("Mirror a directory to a remote host"),
+        "{}",
+        out
+    );
+    assert!(out.contains("Caveats"), "{}", out);
+}